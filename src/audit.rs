@@ -0,0 +1,399 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::{header, Method},
+    middleware::Next,
+    web::{self, Bytes},
+    Error, HttpMessage,
+};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::events::{EventBus, StackEvent};
+use crate::jobs::{JobState, JobStore};
+use crate::storage;
+
+const REDACTED: &str = "[redacted]";
+// Request body fields never written to the log
+const SECRET_FIELDS: &[&str] = &["password", "secret", "token", "key"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Completed by the request itself
+    Succeeded,
+    /// Handed over to a background job, its outcome follows in another entry
+    Accepted,
+    /// Rejected by authentication or authorization
+    Denied,
+    Failed,
+}
+
+impl TryFrom<&str> for Outcome {
+    type Error = String;
+
+    fn try_from(outcome: &str) -> Result<Self, Self::Error> {
+        match outcome {
+            "succeeded" => Ok(Outcome::Succeeded),
+            "accepted" => Ok(Outcome::Accepted),
+            "denied" => Ok(Outcome::Denied),
+            "failed" => Ok(Outcome::Failed),
+            _ => Err(format!(
+                "Invalid outcome: '{}'. Must be 'succeeded', 'accepted', 'denied' or 'failed'",
+                outcome
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub timestamp: DateTime<Utc>,
+    /// Name of the user or API key, `None` when the request was not authenticated
+    pub actor: Option<String>,
+    pub user_id: Option<Uuid>,
+    pub key_id: Option<Uuid>,
+    pub source_ip: Option<String>,
+    /// `X-Forwarded-For` or `Forwarded` client address, as claimed by the proxy
+    pub forwarded_for: Option<String>,
+    pub action: String,
+    pub stack_id: Option<String>,
+    /// Request body with secrets redacted
+    pub params: Value,
+    pub outcome: Outcome,
+    pub status_code: Option<u16>,
+    pub job_id: Option<Uuid>,
+    pub error: Option<String>,
+}
+
+/// Append-only trail of every mutating API request, stored as JSON Lines in `data/audit.jsonl`.
+///
+/// Requests handed to a background job get a second entry once the job finished.
+pub struct AuditLog {
+    path: PathBuf,
+    write_lock: tokio::sync::Mutex<()>,
+    jobs: Arc<JobStore>,
+    // Entries waiting for their job to finish
+    pending: Mutex<HashMap<Uuid, AuditEntry>>,
+}
+
+impl AuditLog {
+    pub fn load(jobs: Arc<JobStore>) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            path: storage::data_directory()?.join("audit.jsonl"),
+            write_lock: tokio::sync::Mutex::new(()),
+            jobs,
+            pending: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// Records the outcome of background jobs started by audited requests.
+    pub fn start(self: &Arc<Self>, events: &EventBus) {
+        let mut receiver = events.subscribe();
+        let audit = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Audit log missed {} events", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+
+                let StackEvent::JobProgress { job_id, state, .. } = event.payload else {
+                    continue;
+                };
+                if state.is_finished() {
+                    audit.finish(&job_id).await;
+                }
+            }
+        });
+    }
+
+    pub async fn append(&self, entry: AuditEntry) {
+        let job_id = entry.job_id.filter(|_| entry.outcome == Outcome::Accepted);
+        if let Some(job_id) = job_id {
+            self.pending.lock().unwrap().insert(job_id, entry.clone());
+        }
+
+        self.write(&entry).await;
+
+        // Jobs finishing before their entry was pending are not waited for
+        if let Some(job_id) = job_id {
+            if self
+                .jobs
+                .get(&job_id)
+                .is_some_and(|job| job.state.is_finished())
+            {
+                self.finish(&job_id).await;
+            }
+        }
+    }
+
+    /// Records the outcome of a finished job, once.
+    async fn finish(&self, job_id: &Uuid) {
+        let Some(accepted) = self.pending.lock().unwrap().remove(job_id) else {
+            return;
+        };
+
+        let job = self.jobs.get(job_id);
+        self.write(&AuditEntry {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            // Creations only learn their stack id once the job ran
+            stack_id: job
+                .as_ref()
+                .and_then(|job| job.stack_id.clone())
+                .or(accepted.stack_id),
+            outcome: if job.as_ref().map(|job| job.state) == Some(JobState::Succeeded) {
+                Outcome::Succeeded
+            } else {
+                Outcome::Failed
+            },
+            status_code: None,
+            error: job.and_then(|job| job.error),
+            ..accepted
+        })
+        .await;
+    }
+
+    async fn write(&self, entry: &AuditEntry) {
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                log::error!("Failed to serialize audit entry: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+
+        let _guard = self.write_lock.lock().await;
+        let result = async {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(line.as_bytes()).await?;
+            file.sync_data().await
+        }
+        .await;
+
+        if let Err(e) = result {
+            log::error!("Failed to write audit log: {}", e);
+        }
+    }
+
+    /// Entries in the order they were recorded, skipping lines that cannot be parsed.
+    pub async fn entries(&self) -> io::Result<Vec<AuditEntry>> {
+        let _guard = self.write_lock.lock().await;
+        let content = match tokio::fs::read_to_string(&self.path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        Ok(content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::warn!("Skipping unreadable audit entry: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Replaces the values of secret-looking fields, at any depth.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (field, value) in map.iter_mut() {
+                let field = field.to_lowercase();
+                if SECRET_FIELDS.iter().any(|secret| field.contains(secret)) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn params(body: &Bytes) -> Value {
+    if body.is_empty() {
+        return Value::Null;
+    }
+    match serde_json::from_slice(body) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value
+        }
+        Err(_) => Value::String(format!("<{} bytes, not JSON>", body.len())),
+    }
+}
+
+/// Action name and target stack of an API request.
+fn describe(method: &Method, path: &str) -> (String, Option<String>) {
+    let segments: Vec<&str> = path
+        .strip_prefix("/api/v1")
+        .or_else(|| path.strip_prefix("/api/v2"))
+        .unwrap_or(path)
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+
+    let (action, stack_id) = match (method.as_str(), segments.as_slice()) {
+        ("POST", ["stacks"]) => ("stack.create", None),
+        ("DELETE", ["stacks", id]) => ("stack.delete", Some(*id)),
+        ("POST", ["stacks", id, "restore"]) => ("stack.restore", Some(*id)),
+        ("PATCH", ["stacks", id, "status"]) => ("stack.status", Some(*id)),
+        ("PUT", ["stacks", id, "idle_timeout"]) => ("stack.idle_timeout", Some(*id)),
+        ("PUT", ["stacks", id, "restart_policy"]) => ("stack.restart_policy", Some(*id)),
+        ("POST", ["stacks", id, "backups"]) => ("stack.backup.create", Some(*id)),
+        ("DELETE", ["stacks", id, "backups", _]) => ("stack.backup.delete", Some(*id)),
+        ("POST", ["stacks", id, "backups", _, "restore"]) => ("stack.backup.restore", Some(*id)),
+        ("POST", ["stacks", id, "backups", _, "upload"]) => ("stack.backup.upload", Some(*id)),
+        ("PUT", ["stacks", id, "backup_schedule"]) => ("stack.backup_schedule.set", Some(*id)),
+        ("DELETE", ["stacks", id, "backup_schedule"]) => {
+            ("stack.backup_schedule.remove", Some(*id))
+        }
+        ("PUT", ["stacks", id, "collaborators", _]) => ("stack.collaborator.set", Some(*id)),
+        ("DELETE", ["stacks", id, "collaborators", _]) => ("stack.collaborator.remove", Some(*id)),
+        ("POST", ["webhooks"]) => ("webhook.create", None),
+        ("DELETE", ["webhooks", _]) => ("webhook.delete", None),
+        ("POST", ["webhooks", _, "test"]) => ("webhook.test", None),
+        ("POST", ["keys"]) => ("key.create", None),
+        ("DELETE", ["keys", _]) => ("key.revoke", None),
+        ("POST", ["users"]) => ("user.create", None),
+        ("PATCH", ["users", _]) => ("user.update", None),
+        ("DELETE", ["users", _]) => ("user.delete", None),
+        ("POST", ["auth", "login"]) => ("auth.login", None),
+        ("POST", ["auth", "logout"]) => ("auth.logout", None),
+        ("POST", ["drift", "repair"]) => ("drift.repair", None),
+        ("POST", ["repository", "check"]) => ("repository.check", None),
+        ("POST", ["repository", "gc"]) => ("repository.gc", None),
+        ("POST", ["offsite", "backups", id, _, "restore"]) => ("offsite.restore", Some(*id)),
+        // Routes without a name yet are still recorded
+        (_, ["stacks", id, ..]) => return (format!("{} {}", method, path), Some(id.to_string())),
+        _ => return (format!("{} {}", method, path), None),
+    };
+
+    (action.to_string(), stack_id.map(str::to_string))
+}
+
+fn job_id(response: &ServiceResponse<impl MessageBody>) -> Option<Uuid> {
+    let location = response.headers().get(header::LOCATION)?.to_str().ok()?;
+    location
+        .strip_prefix("/api/v1/jobs/")
+        .or_else(|| location.strip_prefix("/api/v2/jobs/"))
+        .and_then(|id| Uuid::parse_str(id).ok())
+}
+
+fn outcome(status_code: u16, job_id: Option<Uuid>) -> Outcome {
+    match status_code {
+        202 if job_id.is_some() => Outcome::Accepted,
+        401 | 403 => Outcome::Denied,
+        200..=299 => Outcome::Succeeded,
+        _ => Outcome::Failed,
+    }
+}
+
+/// Records every API request other than reads in the audit log.
+pub async fn record(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.call(req).await;
+    }
+
+    let audit = req
+        .app_data::<web::Data<AuditLog>>()
+        .cloned()
+        .expect("AuditLog is registered as app data");
+
+    // The body is read here for the log, then handed back to the route
+    let body = req.extract::<Bytes>().await?;
+    let params = params(&body);
+    let replay: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(stream::once(async move { Ok(body) }));
+    req.set_payload(Payload::from(replay));
+
+    let (action, stack_id) = describe(req.method(), req.path());
+    let source_ip = req.peer_addr().map(|addr| addr.ip().to_string());
+    let forwarded_for = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(str::to_string)
+        .filter(|addr| Some(addr) != source_ip.as_ref());
+
+    let result = next.call(req).await;
+
+    let (principal, status_code, job_id, error) = match &result {
+        Ok(response) => (
+            response.request().extensions().get::<Principal>().cloned(),
+            response.status().as_u16(),
+            job_id(response),
+            response
+                .response()
+                .error()
+                .map(|e| e.to_string())
+                .filter(|_| !response.status().is_success()),
+        ),
+        Err(e) => (
+            None,
+            e.as_response_error().status_code().as_u16(),
+            None,
+            Some(e.to_string()),
+        ),
+    };
+
+    audit
+        .append(AuditEntry {
+            id: Uuid::new_v4(),
+            timestamp: Utc::now(),
+            // Failed logins are worth knowing about, record who they tried to log in as
+            actor: match &principal {
+                Some(principal) => Some(principal.name.clone()),
+                None if action == "auth.login" => params["username"].as_str().map(str::to_string),
+                None => None,
+            },
+            user_id: principal.as_ref().and_then(|p| p.user_id),
+            key_id: principal.as_ref().and_then(|p| p.key_id),
+            source_ip,
+            forwarded_for,
+            action,
+            stack_id,
+            params,
+            outcome: outcome(status_code, job_id),
+            status_code: Some(status_code),
+            job_id,
+            error,
+        })
+        .await;
+
+    result
+}
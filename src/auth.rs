@@ -0,0 +1,569 @@
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::future::{ready, Ready};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::stacks::{self, StackMeta, StackRole};
+use crate::storage;
+use crate::users::UserStore;
+
+const TOKEN_PREFIX: &str = "mcs_";
+pub const SESSION_COOKIE: &str = "mc_stack_session";
+// Last-used timestamps are written to disk at most this often per key
+const LAST_USED_PERSIST_INTERVAL: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// List stacks, jobs and events
+    Read,
+    /// Everything `read` allows, plus creating stacks and operating them within the caller's roles
+    Manage,
+    /// Everything `manage` allows, on every stack, plus managing API keys, users and webhooks,
+    /// reading the audit log and maintaining the backup repository and off-site backups
+    Admin,
+}
+
+impl TryFrom<&str> for Scope {
+    type Error = String;
+
+    fn try_from(scope: &str) -> Result<Self, Self::Error> {
+        match scope.to_lowercase().as_str() {
+            "read" => Ok(Scope::Read),
+            "manage" => Ok(Scope::Manage),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(format!(
+                "Invalid scope: '{}'. Must be 'read', 'manage' or 'admin'",
+                scope
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Manage => write!(f, "manage"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ApiKey {
+    pub id: Uuid,
+    pub name: String,
+    /// Start of the token, enough to recognize it without revealing it
+    pub prefix: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    #[schema(ignore)]
+    pub hash: String,
+    pub scope: Scope,
+    /// User the key acts for, `None` for host keys created from the command line
+    #[serde(default)]
+    pub user_id: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiKey {
+    /// Copy safe to return through the API.
+    pub fn redacted(&self) -> ApiKey {
+        ApiKey {
+            hash: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Caller of an API route, stored in the request extensions by [`require_api_key`].
+#[derive(Debug, Clone)]
+pub struct Principal {
+    /// API key used, `None` for web UI sessions
+    pub key_id: Option<Uuid>,
+    /// Account acting, `None` for host keys
+    pub user_id: Option<Uuid>,
+    pub name: String,
+    pub scope: Scope,
+}
+
+impl Principal {
+    /// Host keys and administrators hold the `admin` role on every stack.
+    pub fn sees_all_stacks(&self) -> bool {
+        self.user_id.is_none() || self.scope == Scope::Admin
+    }
+
+    pub fn role_on(&self, meta: &StackMeta) -> Option<StackRole> {
+        if self.sees_all_stacks() {
+            return Some(StackRole::Admin);
+        }
+        self.user_id.and_then(|id| meta.role_of(&id))
+    }
+
+    /// Loads the record of a stack, checking the caller holds at least `required` on it.
+    ///
+    /// Stacks the caller has no role on are reported as missing.
+    pub fn authorize(
+        &self,
+        stack_id: &str,
+        required: StackRole,
+    ) -> Result<StackMeta, StackAccessError> {
+        let not_found = || StackAccessError::NotFound(format!("Stack {} does not exist", stack_id));
+
+        let stack_dir = stacks::stack_directory(stack_id)
+            .map_err(|e| StackAccessError::Io(format!("Failed to find stacks directory: {}", e)))?;
+        if !stack_dir.join("compose.yaml").exists() {
+            return Err(not_found());
+        }
+
+        let meta = StackMeta::load(stack_id)
+            .map_err(|e| StackAccessError::Io(format!("Failed to read stack.json: {}", e)))?;
+        let role = self.role_on(&meta).ok_or_else(not_found)?;
+        if role < required {
+            return Err(StackAccessError::Forbidden(format!(
+                "This operation requires the '{}' role on stack {}, '{}' has '{}'",
+                required, stack_id, self.name, role
+            )));
+        }
+
+        Ok(meta)
+    }
+
+    /// Checks the caller holds at least `required`, for operations the middleware
+    /// cannot tell apart by their method.
+    pub fn require(&self, required: Scope) -> Result<(), Error> {
+        if self.scope < required {
+            return Err(AuthError::insufficient_scope(self, required).into());
+        }
+        Ok(())
+    }
+}
+
+/// Caller of an administration route, rejected unless it holds the `admin` scope.
+///
+/// Scopes are checked on the matched route rather than on the path, which clients
+/// may percent-encode.
+pub struct Admin(pub Principal);
+
+impl FromRequest for Admin {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let Some(principal) = req.extensions().get::<Principal>().cloned() else {
+            return ready(Err(AuthError::MissingCredentials(
+                "Authentication required".to_string(),
+            )
+            .into()));
+        };
+        ready(principal.require(Scope::Admin).map(|()| Admin(principal)))
+    }
+}
+
+#[derive(Debug)]
+pub enum StackAccessError {
+    NotFound(String),
+    Forbidden(String),
+    Io(String),
+}
+
+impl FromRequest for Principal {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Principal>().cloned().ok_or_else(|| {
+            AuthError::MissingCredentials("Authentication required".to_string()).into()
+        }))
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// API keys, stored in `data/api_keys.json` as SHA-256 hashes of the tokens.
+pub struct KeyStore {
+    path: PathBuf,
+    keys: Mutex<Vec<ApiKey>>,
+    // Held from taking a snapshot of the keys until it is written, so writes land in order
+    persist_lock: tokio::sync::Mutex<()>,
+}
+
+impl KeyStore {
+    pub fn load() -> io::Result<Self> {
+        let path = storage::data_directory()?.join("api_keys.json");
+        let keys = storage::read_json(&path)?.unwrap_or_default();
+
+        Ok(Self {
+            path,
+            keys: Mutex::new(keys),
+            persist_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.lock().unwrap().is_empty()
+    }
+
+    pub fn list(&self) -> Vec<ApiKey> {
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(ApiKey::redacted)
+            .collect()
+    }
+
+    /// Creates a key and returns it with its token, which is not stored anywhere.
+    pub async fn create(
+        &self,
+        name: String,
+        scope: Scope,
+        user_id: Option<Uuid>,
+    ) -> io::Result<(ApiKey, String)> {
+        let token = format!(
+            "{}{}{}",
+            TOKEN_PREFIX,
+            Uuid::new_v4().simple(),
+            Uuid::new_v4().simple()
+        );
+
+        let key = ApiKey {
+            id: Uuid::new_v4(),
+            name,
+            prefix: token[..TOKEN_PREFIX.len() + 8].to_string(),
+            hash: hash_token(&token),
+            scope,
+            user_id,
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+
+        let _guard = self.persist_lock.lock().await;
+        let keys = {
+            let mut keys = self.keys.lock().unwrap();
+            keys.push(key.clone());
+            keys.clone()
+        };
+        storage::write_json(&self.path, &keys).await?;
+
+        Ok((key.redacted(), token))
+    }
+
+    /// Returns false when the key does not exist.
+    pub async fn revoke(&self, id: &Uuid) -> io::Result<bool> {
+        let _guard = self.persist_lock.lock().await;
+        let keys = {
+            let mut keys = self.keys.lock().unwrap();
+            let count = keys.len();
+            keys.retain(|key| key.id != *id);
+            if keys.len() == count {
+                return Ok(false);
+            }
+            keys.clone()
+        };
+        storage::write_json(&self.path, &keys).await?;
+
+        Ok(true)
+    }
+
+    pub async fn authenticate(&self, token: &str) -> Option<Principal> {
+        let hash = hash_token(token);
+        let now = Utc::now();
+
+        let (principal, persist) = {
+            let mut keys = self.keys.lock().unwrap();
+            let key = keys.iter_mut().find(|key| key.hash == hash)?;
+
+            let persist = key
+                .last_used_at
+                .map(|last| now - last > Duration::seconds(LAST_USED_PERSIST_INTERVAL))
+                .unwrap_or(true);
+            key.last_used_at = Some(now);
+
+            let principal = Principal {
+                key_id: Some(key.id),
+                user_id: key.user_id,
+                name: key.name.clone(),
+                scope: key.scope,
+            };
+            (principal, persist)
+        };
+
+        if persist {
+            // Snapshot taken under the lock, so a key revoked meanwhile is not written back
+            let _guard = self.persist_lock.lock().await;
+            let keys = self.keys.lock().unwrap().clone();
+            if let Err(e) = storage::write_json(&self.path, &keys).await {
+                log::warn!("Failed to record API key usage: {}", e);
+            }
+        }
+
+        Some(principal)
+    }
+}
+
+#[derive(Debug)]
+enum AuthError {
+    MissingCredentials(String),
+    InvalidCredentials(String),
+    InsufficientScope(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingCredentials(msg)
+            | Self::InvalidCredentials(msg)
+            | Self::InsufficientScope(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl AuthError {
+    fn insufficient_scope(principal: &Principal, required: Scope) -> Self {
+        AuthError::InsufficientScope(format!(
+            "'{}' has scope '{}', this operation requires '{}'",
+            principal.name, principal.scope, required
+        ))
+    }
+
+    // API v2 answers in its error envelope
+    fn into_error(self, req: &ServiceRequest) -> Error {
+        if !req.path().starts_with("/api/v2") {
+            return self.into();
+        }
+        let code = match self {
+            AuthError::MissingCredentials(_) | AuthError::InvalidCredentials(_) => {
+                ErrorCode::Unauthorized
+            }
+            AuthError::InsufficientScope(_) => ErrorCode::Forbidden,
+        };
+        ApiError::new(code, self.to_string()).into()
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            AuthError::MissingCredentials(_) | AuthError::InvalidCredentials(_) => {
+                actix_web::http::StatusCode::UNAUTHORIZED
+            }
+            AuthError::InsufficientScope(_) => actix_web::http::StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == actix_web::http::StatusCode::UNAUTHORIZED {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+// Reachable without credentials
+fn is_public(req: &ServiceRequest) -> bool {
+    matches!(
+        req.path(),
+        "/api/v1/auth/login" | "/api/v1/openapi.json" | "/api/v2/openapi.json"
+    )
+}
+
+// Administration routes also require the `admin` scope, through the `Admin` extractor
+fn required_scope(req: &ServiceRequest) -> Scope {
+    if matches!(*req.method(), Method::GET | Method::HEAD) {
+        Scope::Read
+    } else {
+        Scope::Manage
+    }
+}
+
+fn extract_token(req: &ServiceRequest) -> Option<String> {
+    if let Some(value) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+    {
+        return value
+            .strip_prefix("Bearer ")
+            .map(|token| token.trim().to_string());
+    }
+
+    if let Some(value) = req.headers().get("X-API-Key").and_then(|v| v.to_str().ok()) {
+        return Some(value.trim().to_string());
+    }
+
+    None
+}
+
+fn session_principal(req: &ServiceRequest, users: &UserStore) -> Option<Principal> {
+    let cookie = req.cookie(SESSION_COOKIE)?;
+    let user = users.session_user(cookie.value())?;
+
+    Some(Principal {
+        key_id: None,
+        user_id: Some(user.id),
+        name: user.username,
+        scope: if user.admin {
+            Scope::Admin
+        } else {
+            Scope::Manage
+        },
+    })
+}
+
+async fn key_principal(
+    req: &ServiceRequest,
+    keys: &KeyStore,
+    users: &UserStore,
+) -> Result<Principal, AuthError> {
+    let token = extract_token(req).ok_or_else(|| {
+        AuthError::MissingCredentials(
+            "Missing credentials, log in or send an API key as 'Authorization: Bearer <key>'"
+                .to_string(),
+        )
+    })?;
+
+    let invalid = || AuthError::InvalidCredentials("Invalid API key".to_string());
+    let mut principal = keys.authenticate(&token).await.ok_or_else(invalid)?;
+
+    // Keys die with their user and never exceed the user's own rights
+    if let Some(user_id) = principal.user_id {
+        let user = users.get(&user_id).ok_or_else(invalid)?;
+        if !user.admin {
+            principal.scope = principal.scope.min(Scope::Manage);
+        }
+    }
+
+    Ok(principal)
+}
+
+/// Rejects API requests without a valid session or key of sufficient scope.
+pub async fn require_api_key(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if is_public(&req) {
+        return next.call(req).await;
+    }
+
+    let keys = req
+        .app_data::<web::Data<KeyStore>>()
+        .cloned()
+        .expect("KeyStore is registered as app data");
+    let users = req
+        .app_data::<web::Data<UserStore>>()
+        .cloned()
+        .expect("UserStore is registered as app data");
+
+    let principal = match session_principal(&req, &users) {
+        Some(principal) => principal,
+        None => match key_principal(&req, &keys, &users).await {
+            Ok(principal) => principal,
+            Err(e) => return Err(e.into_error(&req)),
+        },
+    };
+
+    let required = required_scope(&req);
+    if principal.scope < required {
+        return Err(AuthError::insufficient_scope(&principal, required).into_error(&req));
+    }
+
+    req.extensions_mut().insert(principal);
+    next.call(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes;
+    use actix_web::{http::StatusCode, middleware::from_fn, test, App};
+
+    fn key_store() -> KeyStore {
+        let dir = std::env::temp_dir().join(format!("mc_stack-auth-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        KeyStore {
+            path: dir.join("api_keys.json"),
+            keys: Mutex::new(Vec::new()),
+            persist_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn admin_routes_reject_encoded_paths() {
+        let keys = web::Data::new(key_store());
+        let users = web::Data::new(UserStore::load().unwrap());
+        let (_, manage) = keys
+            .create("ops".to_string(), Scope::Manage, None)
+            .await
+            .unwrap();
+        let (_, admin) = keys
+            .create("root".to_string(), Scope::Admin, None)
+            .await
+            .unwrap();
+        let app = test::init_service(
+            App::new().app_data(keys.clone()).app_data(users).service(
+                web::scope("/api/v1")
+                    .wrap(from_fn(require_api_key))
+                    .service(routes::keys::create_key)
+                    .service(routes::users::update_user),
+            ),
+        )
+        .await;
+
+        let new_key = json!({ "name": "minted", "scope": "admin" });
+        for path in [
+            "/api/v1/keys",
+            "/api/v1/%6Beys",
+            "/api/v1/%6beys",
+            "/api/v1/k%65ys",
+        ] {
+            let request = test::TestRequest::post()
+                .uri(path)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", manage)))
+                .set_json(&new_key)
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN, "POST {}", path);
+        }
+
+        let path = format!("/api/v1/%75sers/{}", Uuid::new_v4());
+        let request = test::TestRequest::patch()
+            .uri(&path)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", manage)))
+            .set_json(json!({ "admin": true }))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "PATCH {}", path);
+
+        // The encoded path does reach the route, which admins may use
+        let request = test::TestRequest::post()
+            .uri("/api/v1/%6Beys")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", admin)))
+            .set_json(&new_key)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(keys.list().len(), 3);
+    }
+}
@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::jobs::JobStore;
+use crate::offsite::Offsite;
+use crate::routes::backups;
+use crate::stacks::{self, StackMeta};
+
+// Schedules have a precision of one minute
+const TICK: Duration = Duration::from_secs(20);
+
+/// Starts the backups of stack schedules in the background.
+///
+/// Runs missed while mc_stack was down are not caught up.
+pub fn start(jobs: Arc<JobStore>, offsite: Arc<Offsite>, host: String) {
+    tokio::spawn(async move {
+        let mut since = Utc::now();
+        let mut interval = tokio::time::interval(TICK);
+        loop {
+            interval.tick().await;
+            let now = Utc::now();
+            if let Err(e) = run_due(&jobs, &offsite, &host, since, now).await {
+                log::warn!("Failed to run scheduled backups: {}", e);
+            }
+            since = now;
+        }
+    });
+}
+
+/// Starts the backups of the stacks whose schedule has a run after `since` and up to `now`.
+async fn run_due(
+    jobs: &Arc<JobStore>,
+    offsite: &Arc<Offsite>,
+    host: &str,
+    since: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> std::io::Result<()> {
+    for stack_id in stacks::list_stack_ids()? {
+        let schedule = match StackMeta::load(&stack_id) {
+            Ok(meta) => meta.backup_schedule,
+            Err(e) => {
+                log::warn!("Stack {} has an unreadable stack.json: {}", stack_id, e);
+                continue;
+            }
+        };
+        let Some(schedule) = schedule else {
+            continue;
+        };
+        let cron = match schedule.parse() {
+            Ok(cron) => cron,
+            Err(e) => {
+                log::warn!("Stack {} has an invalid backup schedule: {}", stack_id, e);
+                continue;
+            }
+        };
+        if cron.after(&since).next().is_none_or(|run| run > now) {
+            continue;
+        }
+
+        // A backup still running from a previous run is not queued again
+        match backups::start_scheduled_backup(
+            jobs.clone(),
+            offsite.clone(),
+            host.to_string(),
+            stack_id.clone(),
+        )
+        .await
+        {
+            Ok(_) => log::info!("Started the scheduled backup of stack {}", stack_id),
+            Err(active) => log::warn!(
+                "Skipping the scheduled backup of stack {}, job {} is changing it",
+                stack_id,
+                active
+            ),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,757 @@
+use chrono::{DateTime, Datelike, Duration, Utc};
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::io::AsyncRead;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::repository;
+use crate::stacks::{self, StackMeta};
+use crate::storage;
+use crate::volumes;
+
+/// Held while backups are written or restored and while the repository is collected,
+/// one at a time as they are heavy on the disk.
+pub static LOCK: Mutex<()> = Mutex::const_new(());
+
+const PIPE_SIZE: usize = 256 * 1024;
+
+/// Settings of the stack when it was backed up, enough to create it again.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BackupConfig {
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    pub memory_mb: u32,
+    pub restart_policy: String,
+    pub idle_timeout_minutes: Option<u32>,
+}
+
+/// Why a backup was taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupKind {
+    /// Requested through the API
+    #[default]
+    Manual,
+    /// Taken by the stack's backup schedule, pruned by its retention
+    Scheduled,
+    /// The world a restore replaced, to undo it
+    PreRestore,
+}
+
+/// How the world of a backup is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupFormat {
+    /// A gzipped tarball of its own, as backups were before the repository
+    #[default]
+    Archive,
+    /// The chunks of a tarball in the deduplicated repository
+    Chunked,
+}
+
+/// Description of a backup, kept next to its archive or chunk list.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BackupManifest {
+    pub id: Uuid,
+    pub stack_id: String,
+    pub created_at: DateTime<Utc>,
+    #[serde(default)]
+    pub kind: BackupKind,
+    /// Minecraft version the stack ran, `latest` unless pinned
+    pub minecraft_version: String,
+    pub server_type: String,
+    /// Taken while the server ran, with world saving paused
+    pub online: bool,
+    #[serde(default)]
+    pub format: BackupFormat,
+    /// Size of the tarball, gzipped for the `archive` format
+    pub size_bytes: u64,
+    /// SHA-256 of the tarball, hex encoded
+    pub sha256: String,
+    /// Bytes the backup added to the repository, the rest was already stored
+    #[serde(default)]
+    pub stored_bytes: u64,
+    /// When the backup was last copied to the off-site target
+    #[serde(default)]
+    pub uploaded_at: Option<DateTime<Utc>>,
+    pub config: BackupConfig,
+}
+
+impl BackupManifest {
+    /// Manifest of a new backup of the stack, before its world is written.
+    pub fn new(stack_id: &str, kind: BackupKind, online: bool) -> io::Result<Self> {
+        let env = stacks::read_env(stack_id)?;
+        let meta = StackMeta::load(stack_id)?;
+
+        Ok(Self {
+            id: Uuid::new_v4(),
+            stack_id: stack_id.to_string(),
+            created_at: Utc::now(),
+            kind,
+            minecraft_version: stacks::minecraft_version(&env),
+            server_type: stacks::server_type(&env),
+            online,
+            format: BackupFormat::Chunked,
+            size_bytes: 0,
+            sha256: String::new(),
+            stored_bytes: 0,
+            uploaded_at: None,
+            config: BackupConfig {
+                name: meta.name,
+                tags: meta.tags,
+                memory_mb: stacks::memory_mb(stack_id)?,
+                restart_policy: stacks::restart_policy(&env),
+                idle_timeout_minutes: meta.idle_timeout_minutes,
+            },
+        })
+    }
+
+    pub fn archive_path(&self) -> io::Result<PathBuf> {
+        archive_path(&self.stack_id, &self.id)
+    }
+
+    /// Ids of the repository chunks of a `chunked` backup, in order.
+    pub fn chunks(&self) -> io::Result<Vec<String>> {
+        read_chunk_list(&chunk_list_path(&self.stack_id, &self.id)?)
+    }
+
+    /// Stores the content of the volume in the repository, before the manifest is saved.
+    pub async fn write(&mut self, volume: &str) -> io::Result<()> {
+        let snapshot = volumes::export_tar(volume, repository::store).await?;
+        self.size_bytes = snapshot.size_bytes;
+        self.sha256 = snapshot.sha256.clone();
+        self.record(snapshot).await
+    }
+
+    async fn record(&mut self, snapshot: repository::Snapshot) -> io::Result<()> {
+        let path = chunk_list_path(&self.stack_id, &self.id)?;
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, snapshot.chunks.join("\n") + "\n").await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+        self.stored_bytes = snapshot.stored_bytes;
+        Ok(())
+    }
+
+    /// Reads the stored world, the tarball a download sends.
+    pub async fn open(&self) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        match self.format {
+            BackupFormat::Archive => {
+                Ok(Box::new(tokio::fs::File::open(self.archive_path()?).await?))
+            }
+            BackupFormat::Chunked => {
+                let chunks = self.chunks()?;
+                let (writer, reader) = tokio::io::duplex(PIPE_SIZE);
+                // Fails with the reader dropped, nobody is left to tell
+                tokio::spawn(async move {
+                    let _ = repository::write(&chunks, writer).await;
+                });
+                Ok(Box::new(reader))
+            }
+        }
+    }
+
+    /// Stores a world read from elsewhere as this backup's, before the manifest is saved.
+    /// Fails without keeping anything when it is not the world the manifest describes.
+    pub async fn import(&mut self, mut reader: impl AsyncRead + Unpin) -> io::Result<()> {
+        let mismatch = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Backup {} does not match its checksum", self.id),
+            )
+        };
+        match self.format {
+            BackupFormat::Archive => {
+                let path = self.archive_path()?;
+                let tmp_path = path.with_extension("tmp");
+                let mut file = tokio::fs::File::create(&tmp_path).await?;
+                tokio::io::copy(&mut reader, &mut file).await?;
+                file.sync_all().await?;
+
+                let checked = tmp_path.clone();
+                let (sha256, size_bytes) = tokio::task::spawn_blocking(move || checksum(&checked))
+                    .await
+                    .map_err(io::Error::other)??;
+                if sha256 != self.sha256 || size_bytes != self.size_bytes {
+                    let _ = std::fs::remove_file(&tmp_path);
+                    return Err(mismatch());
+                }
+                tokio::fs::rename(&tmp_path, &path).await
+            }
+            BackupFormat::Chunked => {
+                let snapshot = repository::store(reader).await?;
+                if snapshot.sha256 != self.sha256 || snapshot.size_bytes != self.size_bytes {
+                    return Err(mismatch());
+                }
+                self.record(snapshot).await
+            }
+        }
+    }
+
+    /// Extracts the world of the backup into a new volume.
+    pub async fn restore(&self, volume: &str) -> io::Result<()> {
+        match self.format {
+            BackupFormat::Archive => volumes::import(volume, &self.archive_path()?).await,
+            BackupFormat::Chunked => {
+                let chunks = self.chunks()?;
+                volumes::import_tar(volume, |stdin| async move {
+                    repository::write(&chunks, stdin).await
+                })
+                .await
+            }
+        }
+    }
+
+    /// Checks the stored world is still the one the manifest describes.
+    pub fn verify(&self) -> io::Result<()> {
+        let (sha256, size_bytes) = match self.format {
+            BackupFormat::Archive => checksum(&self.archive_path()?)?,
+            BackupFormat::Chunked => {
+                let mut hasher = Sha256::new();
+                let mut size = 0;
+                for id in self.chunks()? {
+                    let data = repository::read_chunk(&id)?;
+                    hasher.update(&data);
+                    size += data.len() as u64;
+                }
+                (hex::encode(hasher.finalize()), size)
+            }
+        };
+        if sha256 != self.sha256 || size_bytes != self.size_bytes {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The archive of backup {} does not match its checksum",
+                    self.id
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn save(&self) -> io::Result<()> {
+        storage::write_json(&manifest_path(&self.stack_id, &self.id)?, self).await
+    }
+}
+
+/// How many scheduled backups to keep, a backup kept by any rule stays.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct Retention {
+    /// The newest backups
+    #[serde(default)]
+    pub keep_last: u32,
+    /// The newest backup of each of the last days
+    #[serde(default)]
+    pub keep_daily: u32,
+    /// The newest backup of each of the last weeks
+    #[serde(default)]
+    pub keep_weekly: u32,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            keep_last: 3,
+            keep_daily: 7,
+            keep_weekly: 4,
+        }
+    }
+}
+
+/// When mc_stack backs up a stack by itself.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BackupSchedule {
+    /// Cron expression of minute, hour, day of month, month and day of week, in UTC
+    #[schema(example = "0 4 * * *")]
+    pub cron: String,
+    #[serde(default)]
+    pub retention: Retention,
+}
+
+impl BackupSchedule {
+    pub fn parse(&self) -> Result<Schedule, String> {
+        parse_cron(&self.cron)
+    }
+}
+
+/// Parses a five field cron expression.
+///
+/// Days of the week must be named, cron implementations disagree on their numbers.
+pub fn parse_cron(expression: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    if fields.len() != 5 {
+        return Err(format!(
+            "'{}' must have 5 fields: minute, hour, day of month, month and day of week",
+            expression
+        ));
+    }
+    let day_of_week = fields[4]
+        .split(',')
+        .map(|item| item.split('/').next().unwrap_or_default());
+    if day_of_week.flat_map(str::chars).any(|c| c.is_ascii_digit()) {
+        return Err(format!(
+            "Days of the week of '{}' must be names such as MON-FRI",
+            expression
+        ));
+    }
+
+    // The parser also takes seconds, backups start on the minute
+    Schedule::from_str(&format!("0 {}", fields.join(" ")))
+        .map_err(|e| format!("Invalid cron expression '{}': {}", expression, e))
+}
+
+/// Outcome of the last scheduled backups of a stack.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct ScheduleStatus {
+    pub last_success_at: Option<DateTime<Utc>>,
+    pub last_backup_id: Option<Uuid>,
+    pub last_failure_at: Option<DateTime<Utc>>,
+    /// Error of the last failure, kept after later successes
+    pub last_error: Option<String>,
+}
+
+impl ScheduleStatus {
+    pub fn load(stack_id: &str) -> io::Result<Self> {
+        Ok(storage::read_json(&directory(stack_id)?.join(STATUS_FILE))?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, stack_id: &str) -> io::Result<()> {
+        storage::write_json(&directory(stack_id)?.join(STATUS_FILE), self).await
+    }
+}
+
+const STATUS_FILE: &str = "schedule.json";
+
+/// Backups of a stack are kept apart from the stack, so they outlive it.
+fn directory(stack_id: &str) -> io::Result<PathBuf> {
+    let dir = storage::data_subdirectory("backups")?.join(format!("stack_{}", stack_id));
+    if !dir.exists() {
+        std::fs::create_dir_all(&dir)?;
+    }
+    Ok(dir)
+}
+
+pub fn archive_path(stack_id: &str, id: &Uuid) -> io::Result<PathBuf> {
+    Ok(directory(stack_id)?.join(format!("{}.tar.gz", id)))
+}
+
+fn manifest_path(stack_id: &str, id: &Uuid) -> io::Result<PathBuf> {
+    Ok(directory(stack_id)?.join(format!("{}.json", id)))
+}
+
+fn chunk_list_path(stack_id: &str, id: &Uuid) -> io::Result<PathBuf> {
+    Ok(directory(stack_id)?.join(format!("{}.chunks", id)))
+}
+
+fn read_chunk_list(path: &Path) -> io::Result<Vec<String>> {
+    Ok(std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Ids of the stacks having backups, deleted stacks included.
+fn backed_up_stacks() -> io::Result<Vec<String>> {
+    let mut stack_ids = Vec::new();
+    for entry in std::fs::read_dir(storage::data_subdirectory("backups")?)? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if let Some(stack_id) = name.strip_prefix("stack_") {
+            stack_ids.push(stack_id.to_string());
+        }
+    }
+    Ok(stack_ids)
+}
+
+/// Backups of a stack, newest first. Archives still being written have no manifest yet.
+pub fn list(stack_id: &str) -> io::Result<Vec<BackupManifest>> {
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(directory(stack_id)?)? {
+        let path = entry?.path();
+        // Manifests are named after their backup, other files are not backups
+        let is_manifest = path.extension().and_then(|e| e.to_str()) == Some("json")
+            && path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .is_some_and(|stem| Uuid::parse_str(stem).is_ok());
+        if !is_manifest {
+            continue;
+        }
+        match storage::read_json::<BackupManifest>(&path) {
+            Ok(Some(manifest)) => backups.push(manifest),
+            Ok(None) => {}
+            Err(e) => log::warn!("Skipping backup manifest {}: {}", path.display(), e),
+        }
+    }
+
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_at));
+    Ok(backups)
+}
+
+pub fn get(stack_id: &str, id: &Uuid) -> io::Result<Option<BackupManifest>> {
+    storage::read_json(&manifest_path(stack_id, id)?)
+}
+
+/// Removes the manifest first, so a backup half deleted is no longer listed.
+///
+/// Chunks are left in the repository until it is collected.
+pub fn delete(stack_id: &str, id: &Uuid) -> io::Result<()> {
+    std::fs::remove_file(manifest_path(stack_id, id)?)?;
+    discard(stack_id, id)
+}
+
+/// Removes the archive or chunk list of a backup without a manifest.
+pub fn discard(stack_id: &str, id: &Uuid) -> io::Result<()> {
+    for path in [archive_path(stack_id, id)?, chunk_list_path(stack_id, id)?] {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Size of the repository and of the backups it holds.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RepositoryStats {
+    /// Backups in the repository, of every stack
+    pub backups: usize,
+    pub chunks: usize,
+    /// Bytes the chunks take on disk
+    pub stored_bytes: u64,
+    /// Bytes the backups would take as separate tarballs
+    pub logical_bytes: u64,
+}
+
+/// A backup the repository can no longer restore.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DamagedBackup {
+    pub stack_id: String,
+    pub backup_id: Uuid,
+    pub missing_chunks: usize,
+    pub corrupt_chunks: usize,
+}
+
+/// Outcome of checking every chunk of the repository.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CheckReport {
+    /// No chunk is missing or corrupt
+    pub ok: bool,
+    pub backups: usize,
+    pub chunks: usize,
+    /// Chunks no backup uses, removed by the next collection
+    pub unreferenced_chunks: usize,
+    pub corrupt_chunks: Vec<String>,
+    pub damaged_backups: Vec<DamagedBackup>,
+}
+
+/// Outcome of a collection of the repository.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GarbageReport {
+    pub removed_chunks: usize,
+    pub freed_bytes: u64,
+}
+
+/// Chunked backups of every stack with their chunk lists.
+fn chunked_backups() -> io::Result<Vec<(BackupManifest, Vec<String>)>> {
+    let mut backups = Vec::new();
+    for stack_id in backed_up_stacks()? {
+        for backup in list(&stack_id)? {
+            if backup.format != BackupFormat::Chunked {
+                continue;
+            }
+            // Any other error fails, a collection must not miss the chunks of a backup
+            match backup.chunks() {
+                Ok(chunks) => backups.push((backup, chunks)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    log::warn!("Skipping backup {} without its chunk list", backup.id)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(backups)
+}
+
+pub fn repository_stats() -> io::Result<RepositoryStats> {
+    let backups = chunked_backups()?;
+    let chunks = repository::list_chunks()?;
+    Ok(RepositoryStats {
+        backups: backups.len(),
+        chunks: chunks.len(),
+        stored_bytes: chunks.iter().map(|(_, size)| size).sum(),
+        logical_bytes: backups.iter().map(|(backup, _)| backup.size_bytes).sum(),
+    })
+}
+
+/// Reads every chunk of the repository and checks every backup has its chunks.
+pub fn check_repository() -> io::Result<CheckReport> {
+    let backups = chunked_backups()?;
+
+    let mut corrupt_chunks = Vec::new();
+    let mut present = HashSet::new();
+    for (id, _) in repository::list_chunks()? {
+        match repository::read_chunk(&id) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => corrupt_chunks.push(id.clone()),
+            Err(e) => return Err(e),
+        }
+        present.insert(id);
+    }
+    let corrupt: HashSet<&String> = corrupt_chunks.iter().collect();
+
+    let mut referenced = HashSet::new();
+    let mut damaged_backups = Vec::new();
+    for (backup, chunks) in &backups {
+        let missing_chunks = chunks.iter().filter(|id| !present.contains(*id)).count();
+        let corrupt_chunks = chunks.iter().filter(|id| corrupt.contains(id)).count();
+        if missing_chunks > 0 || corrupt_chunks > 0 {
+            damaged_backups.push(DamagedBackup {
+                stack_id: backup.stack_id.clone(),
+                backup_id: backup.id,
+                missing_chunks,
+                corrupt_chunks,
+            });
+        }
+        referenced.extend(chunks.iter());
+    }
+
+    Ok(CheckReport {
+        ok: corrupt_chunks.is_empty() && damaged_backups.is_empty(),
+        backups: backups.len(),
+        chunks: present.len(),
+        unreferenced_chunks: present.iter().filter(|id| !referenced.contains(id)).count(),
+        corrupt_chunks,
+        damaged_backups,
+    })
+}
+
+/// Removes the chunks no backup uses. Must run under `LOCK`, as a backup being written
+/// has stored chunks its chunk list does not name yet.
+pub fn collect_garbage() -> io::Result<GarbageReport> {
+    let referenced: HashSet<String> = chunked_backups()?
+        .into_iter()
+        .flat_map(|(_, chunks)| chunks)
+        .collect();
+    let (removed_chunks, freed_bytes) = repository::collect_garbage(&referenced)?;
+    Ok(GarbageReport {
+        removed_chunks,
+        freed_bytes,
+    })
+}
+
+/// Deletes the scheduled backups no retention rule keeps, returning their ids.
+///
+/// Manual and pre-restore backups are left alone.
+pub fn prune(stack_id: &str, retention: &Retention) -> io::Result<Vec<Uuid>> {
+    let scheduled: Vec<BackupManifest> = list(stack_id)?
+        .into_iter()
+        .filter(|backup| backup.kind == BackupKind::Scheduled)
+        .collect();
+
+    let expired = expired(&scheduled, retention, Utc::now());
+    for id in &expired {
+        delete(stack_id, id)?;
+    }
+    Ok(expired)
+}
+
+/// Ids of the scheduled backups, listed newest first, no retention rule keeps at `now`.
+fn expired(scheduled: &[BackupManifest], retention: &Retention, now: DateTime<Utc>) -> Vec<Uuid> {
+    let mut kept = HashSet::new();
+    kept.extend(
+        scheduled
+            .iter()
+            .take(retention.keep_last as usize)
+            .map(|backup| backup.id),
+    );
+    // Newest first, so the first backup seen of a day or week is its newest
+    let daily_since = now.date_naive() - Duration::days(i64::from(retention.keep_daily));
+    let mut days = HashSet::new();
+    let weekly_since = now - Duration::weeks(i64::from(retention.keep_weekly));
+    let mut weeks = HashSet::new();
+    for backup in scheduled {
+        let day = backup.created_at.date_naive();
+        if day > daily_since && days.insert(day) {
+            kept.insert(backup.id);
+        }
+        let week = backup.created_at.iso_week();
+        if backup.created_at > weekly_since && weeks.insert((week.year(), week.week())) {
+            kept.insert(backup.id);
+        }
+    }
+
+    scheduled
+        .iter()
+        .map(|backup| backup.id)
+        .filter(|id| !kept.contains(id))
+        .collect()
+}
+
+/// SHA-256 and size of a file.
+pub fn checksum(path: &Path) -> io::Result<(String, u64)> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((hex::encode(hasher.finalize()), size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, month, day, hour, 0, 0).unwrap()
+    }
+
+    fn scheduled(created_at: DateTime<Utc>) -> BackupManifest {
+        BackupManifest {
+            id: Uuid::new_v4(),
+            stack_id: "1".to_string(),
+            created_at,
+            kind: BackupKind::Scheduled,
+            minecraft_version: "latest".to_string(),
+            server_type: "vanilla".to_string(),
+            online: false,
+            format: BackupFormat::Chunked,
+            size_bytes: 0,
+            sha256: String::new(),
+            stored_bytes: 0,
+            uploaded_at: None,
+            config: BackupConfig {
+                name: None,
+                tags: Vec::new(),
+                memory_mb: 1024,
+                restart_policy: "unless-stopped".to_string(),
+                idle_timeout_minutes: None,
+            },
+        }
+    }
+
+    /// Backups every `hours` going back from `newest`, newest first as listed.
+    fn history(newest: DateTime<Utc>, hours: i64, count: i64) -> Vec<BackupManifest> {
+        (0..count)
+            .map(|i| scheduled(newest - Duration::hours(i * hours)))
+            .collect()
+    }
+
+    fn kept(
+        backups: &[BackupManifest],
+        retention: Retention,
+        now: DateTime<Utc>,
+    ) -> Vec<DateTime<Utc>> {
+        let expired = expired(backups, &retention, now);
+        backups
+            .iter()
+            .filter(|backup| !expired.contains(&backup.id))
+            .map(|backup| backup.created_at)
+            .collect()
+    }
+
+    fn rules(keep_last: u32, keep_daily: u32, keep_weekly: u32) -> Retention {
+        Retention {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+        }
+    }
+
+    // Wednesday
+    const NOW: (u32, u32, u32) = (11, 20, 12);
+
+    fn now() -> DateTime<Utc> {
+        at(NOW.0, NOW.1, NOW.2)
+    }
+
+    #[test]
+    fn keeps_the_last_backups() {
+        let backups = history(now(), 1, 5);
+        assert_eq!(
+            kept(&backups, rules(2, 0, 0), now()),
+            [at(11, 20, 12), at(11, 20, 11)]
+        );
+        assert!(kept(&backups, rules(0, 0, 0), now()).is_empty());
+    }
+
+    #[test]
+    fn keeps_the_newest_backup_of_each_day() {
+        let backups = history(at(11, 20, 8), 8, 15);
+        assert_eq!(
+            kept(&backups, rules(0, 3, 0), now()),
+            [at(11, 20, 8), at(11, 19, 16), at(11, 18, 16)]
+        );
+    }
+
+    #[test]
+    fn keeps_the_newest_backup_of_each_week() {
+        let backups = history(now(), 24, 40);
+        // The current week and the two before, back to Wednesday two weeks ago
+        assert_eq!(
+            kept(&backups, rules(0, 0, 2), now()),
+            [at(11, 20, 12), at(11, 17, 12), at(11, 10, 12)]
+        );
+    }
+
+    #[test]
+    fn keeps_backups_kept_by_any_rule() {
+        let backups = history(now(), 6, 240);
+        let kept = kept(&backups, Retention::default(), now());
+
+        // 3 last, 6 more days, and 3 more weeks as Sunday the 17th is kept daily
+        assert_eq!(kept.len(), 12);
+        assert_eq!(kept[..3], [at(11, 20, 12), at(11, 20, 6), at(11, 20, 0)]);
+        assert_eq!(kept[3], at(11, 19, 18));
+        assert_eq!(kept.last(), Some(&at(10, 27, 18)));
+    }
+
+    #[test]
+    fn parses_five_field_cron_expressions() {
+        let daily = parse_cron("0 4 * * *").unwrap();
+        assert_eq!(daily.after(&now()).next(), Some(at(11, 21, 4)));
+
+        let quarterly = parse_cron("*/15 * * * *").unwrap();
+        let runs: Vec<_> = quarterly.after(&now()).take(2).collect();
+        assert_eq!(
+            runs,
+            [now() + Duration::minutes(15), now() + Duration::minutes(30)]
+        );
+
+        // From Saturday to Monday
+        let weekdays = parse_cron("30 4 * * MON-FRI").unwrap();
+        assert_eq!(
+            weekdays.after(&at(11, 23, 12)).next(),
+            Some(at(11, 25, 4) + Duration::minutes(30))
+        );
+    }
+
+    #[test]
+    fn rejects_ambiguous_or_invalid_cron_expressions() {
+        for expression in [
+            "0 4 * * 1",
+            "0 4 * * MON,5",
+            "0 4 * *",
+            "0 0 4 * * *",
+            "61 * * * *",
+            "0 25 * * *",
+            "",
+        ] {
+            assert!(parse_cron(expression).is_err(), "{:?}", expression);
+        }
+    }
+}
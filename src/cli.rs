@@ -0,0 +1,163 @@
+use std::io::BufRead;
+use uuid::Uuid;
+
+use crate::auth::{KeyStore, Scope};
+use crate::users::{self, Quota, UserStore, UserStoreError};
+
+const USAGE: &str = "Usage:
+  mc_stack                                       Start the server
+  mc_stack keys list                             List API keys
+  mc_stack keys create <name> [read|manage|admin] Create an API key, 'admin' by default
+  mc_stack keys revoke <id>                      Revoke an API key
+  mc_stack users list                            List user accounts
+  mc_stack users create <username> [admin]       Create a user, reads the password from stdin
+  mc_stack users passwd <username>               Change a password, reads it from stdin
+  mc_stack users delete <username>               Delete a user";
+
+/// Runs the administration command given on the command line, if any.
+///
+/// Returns `None` when the server should start instead.
+pub async fn run(args: &[String]) -> Option<Result<(), String>> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => None,
+        ["keys", rest @ ..] => Some(keys(rest).await),
+        ["users", rest @ ..] => Some(users(rest).await),
+        _ => Some(Err(USAGE.to_string())),
+    }
+}
+
+async fn keys(args: &[&str]) -> Result<(), String> {
+    let keys = KeyStore::load().map_err(|e| format!("Failed to load API keys: {}", e))?;
+
+    match args {
+        ["list"] => {
+            for key in keys.list() {
+                println!(
+                    "{}  {:<8}  {}...  {}  last used: {}",
+                    key.id,
+                    key.scope.to_string(),
+                    key.prefix,
+                    key.name,
+                    key.last_used_at
+                        .map(|t| t.to_rfc3339())
+                        .unwrap_or_else(|| "never".to_string())
+                );
+            }
+            Ok(())
+        }
+        ["create", name] | ["create", name, _] => {
+            let scope = match args.get(2) {
+                Some(scope) => Scope::try_from(*scope)?,
+                None => Scope::Admin,
+            };
+            let (key, token) = keys
+                .create(name.to_string(), scope, None)
+                .await
+                .map_err(|e| format!("Failed to save API key: {}", e))?;
+
+            println!("Created {} key '{}' ({})", key.scope, key.name, key.id);
+            println!("{}", token);
+            println!("Store this token now, it cannot be shown again.");
+            Ok(())
+        }
+        ["revoke", id] => {
+            let id = Uuid::parse_str(id).map_err(|_| format!("Invalid key id '{}'", id))?;
+            match keys.revoke(&id).await {
+                Ok(true) => {
+                    println!("Revoked key {}", id);
+                    Ok(())
+                }
+                Ok(false) => Err(format!("API key {} does not exist", id)),
+                Err(e) => Err(format!("Failed to save API keys: {}", e)),
+            }
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn read_password() -> Result<String, String> {
+    eprintln!("Password:");
+    let mut password = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|e| format!("Failed to read password: {}", e))?;
+
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.chars().count() < 8 {
+        return Err("Password must be at least 8 characters long".to_string());
+    }
+    Ok(password)
+}
+
+fn store_error(e: UserStoreError) -> String {
+    match e {
+        UserStoreError::UsernameTaken(msg) | UserStoreError::UserNotFound(msg) => msg,
+        UserStoreError::Io(e) => format!("Failed to save users: {}", e),
+    }
+}
+
+async fn users(args: &[&str]) -> Result<(), String> {
+    let store = UserStore::load().map_err(|e| format!("Failed to load users: {}", e))?;
+    let find = |username: &str| {
+        store
+            .find_by_username(username)
+            .ok_or_else(|| format!("User {} does not exist", username))
+    };
+
+    match args {
+        ["list"] => {
+            for user in store.list() {
+                println!(
+                    "{}  {:<5}  {}  quota: {} stacks, {} MB",
+                    user.id,
+                    if user.admin { "admin" } else { "user" },
+                    user.username,
+                    user.quota.max_stacks,
+                    user.quota.max_memory_mb
+                );
+            }
+            Ok(())
+        }
+        ["create", username] | ["create", username, "admin"] => {
+            if !users::is_valid_username(username) {
+                return Err("Username must be made of letters, digits, '.', '_' or '-'".to_string());
+            }
+            let password_hash = users::hash_password(&read_password()?)
+                .map_err(|e| format!("Failed to hash password: {}", e))?;
+            let user = store
+                .create(
+                    username.to_string(),
+                    password_hash,
+                    args.len() == 3,
+                    Quota::default(),
+                )
+                .await
+                .map_err(store_error)?;
+
+            println!("Created user '{}' ({})", user.username, user.id);
+            Ok(())
+        }
+        ["passwd", username] => {
+            let user = find(username)?;
+            let password_hash = users::hash_password(&read_password()?)
+                .map_err(|e| format!("Failed to hash password: {}", e))?;
+            store
+                .update(&user.id, |user| user.password_hash = password_hash)
+                .await
+                .map_err(store_error)?;
+
+            println!("Changed password of '{}'", user.username);
+            Ok(())
+        }
+        ["delete", username] => {
+            let user = find(username)?;
+            store.delete(&user.id).await.map_err(store_error)?;
+
+            println!("Deleted user '{}'", user.username);
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
@@ -0,0 +1,336 @@
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_HTTP_PORT: u16 = 8080;
+const DEFAULT_HTTPS_PORT: u16 = 8443;
+// One year, as recommended for HSTS preloading
+const DEFAULT_HSTS_MAX_AGE: u64 = 31_536_000;
+const DEFAULT_WAN_TTL: u64 = 3600;
+const DEFAULT_STUN_SERVER: &str = "stun.l.google.com:19302";
+const DEFAULT_HTTP_PROBE_V4: &str = "https://api.ipify.org";
+const DEFAULT_HTTP_PROBE_V6: &str = "https://api6.ipify.org";
+const DEFAULT_IDLE_CHECK_INTERVAL: u64 = 60;
+const DEFAULT_STACK_HOST: &str = "127.0.0.1";
+const DEFAULT_DRIFT_INTERVAL: u64 = 300;
+const DEFAULT_STARTUP_STAGGER: u64 = 0;
+const DEFAULT_TRASH_RETENTION_DAYS: u64 = 7;
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_S3_PREFIX: &str = "mc_stack/";
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    pub https_port: u16,
+    /// Answer plain HTTP requests with a redirect to HTTPS instead of serving them
+    pub redirect_http: bool,
+    /// `max-age` of the `Strict-Transport-Security` header, `0` leaves the header out
+    pub hsts_max_age: u64,
+}
+
+/// Routing of Minecraft connections by the address players type, on a single port.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub port: u16,
+    /// Domain under which stacks are reached, any domain when unset
+    pub domain: Option<String>,
+    /// Host on which the stacks publish their ports, as seen from mc_stack
+    pub upstream_host: String,
+    /// Start stopped stacks when a player tries to join them
+    pub wake_on_join: bool,
+}
+
+/// Stopping of stacks left without players, for stacks having an idle timeout.
+#[derive(Debug, Clone)]
+pub struct IdleConfig {
+    /// Time between two player counts
+    pub check_interval: Duration,
+    /// Host on which the stacks publish their ports, as seen from mc_stack
+    pub host: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconcileConfig {
+    /// Bring stacks to their desired state when mc_stack starts
+    pub on_startup: bool,
+    /// Time between two stacks started, so they do not all boot at once
+    pub stagger: Duration,
+}
+
+/// A way of finding the host's public address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WanMethod {
+    /// Address of the network interface used to reach the internet, when public
+    Interface,
+    /// Address seen by a STUN server
+    Stun,
+    /// Address returned as plain text by an HTTP service
+    Http,
+}
+
+impl FromStr for WanMethod {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "interface" => Ok(WanMethod::Interface),
+            "stun" => Ok(WanMethod::Stun),
+            "http" => Ok(WanMethod::Http),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WanConfig {
+    /// Fixed public address, detection is skipped when set
+    pub address: Option<IpAddr>,
+    /// Name given to players instead of the address, such as a dynamic DNS name
+    pub hostname: Option<String>,
+    /// Look for an IPv6 address instead of an IPv4 one
+    pub ipv6: bool,
+    /// Detection methods, tried in order until one succeeds
+    pub methods: Vec<WanMethod>,
+    pub stun_server: String,
+    pub http_url: String,
+    /// How long a detected address is trusted before it is looked up again
+    pub ttl: Duration,
+}
+
+/// S3-compatible bucket backups are replicated to.
+#[derive(Debug, Clone)]
+pub struct OffsiteConfig {
+    /// Base URL of the S3 API, buckets are addressed in its path
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Prepended to every object key
+    pub prefix: String,
+    /// AES-256 key backups are encrypted with before they leave the host
+    pub encryption_key: Option<[u8; 32]>,
+}
+
+/// Server settings, read from `MC_STACK_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub http_port: u16,
+    /// HTTPS is served when both a certificate and a key are configured
+    pub tls: Option<TlsConfig>,
+    pub wan: WanConfig,
+    /// The proxy runs when a port is configured for it
+    pub proxy: Option<ProxyConfig>,
+    pub idle: IdleConfig,
+    /// Time between two background drift checks
+    pub drift_interval: Duration,
+    pub reconcile: ReconcileConfig,
+    /// Time deleted stacks stay restorable, `None` deletes them right away
+    pub trash_retention: Option<Duration>,
+    /// Backups stay on the host when no bucket is configured
+    pub offsite: Option<OffsiteConfig>,
+}
+
+fn var(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn invalid(name: &str, value: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid value for {}: '{}'", name, value),
+    )
+}
+
+fn parse<T: FromStr>(name: &str, default: T) -> io::Result<T> {
+    match var(name) {
+        Some(value) => value.parse().map_err(|_| invalid(name, &value)),
+        None => Ok(default),
+    }
+}
+
+fn parse_list<T: FromStr>(name: &str, default: &str) -> io::Result<Vec<T>> {
+    let value = var(name).unwrap_or_else(|| default.to_string());
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| item.parse().map_err(|_| invalid(name, item)))
+        .collect()
+}
+
+impl WanConfig {
+    fn from_env() -> io::Result<Self> {
+        let address = match var("MC_STACK_WAN_ADDRESS") {
+            Some(value) => Some(
+                value
+                    .parse()
+                    .map_err(|_| invalid("MC_STACK_WAN_ADDRESS", &value))?,
+            ),
+            None => None,
+        };
+        let ipv6 = parse("MC_STACK_WAN_IPV6", false)?;
+        let default_http_url = if ipv6 {
+            DEFAULT_HTTP_PROBE_V6
+        } else {
+            DEFAULT_HTTP_PROBE_V4
+        };
+
+        let ttl = parse("MC_STACK_WAN_TTL", DEFAULT_WAN_TTL)?;
+        if ttl == 0 {
+            return Err(invalid("MC_STACK_WAN_TTL", "0"));
+        }
+
+        Ok(Self {
+            address,
+            hostname: var("MC_STACK_WAN_HOSTNAME"),
+            ipv6,
+            methods: parse_list("MC_STACK_WAN_DETECT", "interface,stun,http")?,
+            stun_server: var("MC_STACK_WAN_STUN_SERVER")
+                .unwrap_or_else(|| DEFAULT_STUN_SERVER.to_string()),
+            http_url: var("MC_STACK_WAN_HTTP_URL").unwrap_or_else(|| default_http_url.to_string()),
+            ttl: Duration::from_secs(ttl),
+        })
+    }
+}
+
+impl ProxyConfig {
+    fn from_env() -> io::Result<Option<Self>> {
+        let Some(port) = var("MC_STACK_PROXY_PORT") else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self {
+            port: port
+                .parse()
+                .map_err(|_| invalid("MC_STACK_PROXY_PORT", &port))?,
+            domain: var("MC_STACK_PROXY_DOMAIN")
+                .map(|domain| domain.trim_matches('.').to_lowercase()),
+            upstream_host: var("MC_STACK_PROXY_UPSTREAM_HOST")
+                .unwrap_or_else(|| DEFAULT_STACK_HOST.to_string()),
+            wake_on_join: parse("MC_STACK_PROXY_WAKE_ON_JOIN", false)?,
+        }))
+    }
+}
+
+impl IdleConfig {
+    fn from_env() -> io::Result<Self> {
+        let check_interval = parse("MC_STACK_IDLE_CHECK_INTERVAL", DEFAULT_IDLE_CHECK_INTERVAL)?;
+        if check_interval == 0 {
+            return Err(invalid("MC_STACK_IDLE_CHECK_INTERVAL", "0"));
+        }
+
+        Ok(Self {
+            check_interval: Duration::from_secs(check_interval),
+            // Stacks are reached the same way as by the proxy
+            host: var("MC_STACK_IDLE_CHECK_HOST")
+                .or_else(|| var("MC_STACK_PROXY_UPSTREAM_HOST"))
+                .unwrap_or_else(|| DEFAULT_STACK_HOST.to_string()),
+        })
+    }
+}
+
+impl ReconcileConfig {
+    fn from_env() -> io::Result<Self> {
+        Ok(Self {
+            on_startup: parse("MC_STACK_RECONCILE_ON_STARTUP", true)?,
+            stagger: Duration::from_secs(parse(
+                "MC_STACK_STARTUP_STAGGER",
+                DEFAULT_STARTUP_STAGGER,
+            )?),
+        })
+    }
+}
+
+impl OffsiteConfig {
+    fn from_env() -> io::Result<Option<Self>> {
+        let Some(endpoint) = var("MC_STACK_S3_ENDPOINT") else {
+            return Ok(None);
+        };
+        reqwest::Url::parse(&endpoint).map_err(|_| invalid("MC_STACK_S3_ENDPOINT", &endpoint))?;
+        let required = |name: &str| {
+            var(name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} must be set along with MC_STACK_S3_ENDPOINT", name),
+                )
+            })
+        };
+
+        let encryption_key = match var("MC_STACK_S3_ENCRYPTION_KEY") {
+            Some(value) => Some(
+                hex::decode(&value)
+                    .ok()
+                    .and_then(|key| key.try_into().ok())
+                    // The key itself is not echoed back
+                    .ok_or_else(|| invalid("MC_STACK_S3_ENCRYPTION_KEY", "<64 hex digits>"))?,
+            ),
+            None => None,
+        };
+
+        let mut prefix = var("MC_STACK_S3_PREFIX").unwrap_or_else(|| DEFAULT_S3_PREFIX.to_string());
+        if !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+
+        Ok(Some(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket: required("MC_STACK_S3_BUCKET")?,
+            region: var("MC_STACK_S3_REGION").unwrap_or_else(|| DEFAULT_S3_REGION.to_string()),
+            access_key_id: required("MC_STACK_S3_ACCESS_KEY_ID")?,
+            secret_access_key: required("MC_STACK_S3_SECRET_ACCESS_KEY")?,
+            prefix: prefix.trim_start_matches('/').to_string(),
+            encryption_key,
+        }))
+    }
+}
+
+impl Config {
+    pub fn from_env() -> io::Result<Self> {
+        let tls = match (var("MC_STACK_TLS_CERT"), var("MC_STACK_TLS_KEY")) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+                https_port: parse("MC_STACK_HTTPS_PORT", DEFAULT_HTTPS_PORT)?,
+                redirect_http: parse("MC_STACK_HTTP_REDIRECT", false)?,
+                hsts_max_age: parse("MC_STACK_HSTS_MAX_AGE", DEFAULT_HSTS_MAX_AGE)?,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "MC_STACK_TLS_CERT and MC_STACK_TLS_KEY must be set together",
+                ))
+            }
+        };
+
+        let drift_interval = parse("MC_STACK_DRIFT_INTERVAL", DEFAULT_DRIFT_INTERVAL)?;
+        if drift_interval == 0 {
+            return Err(invalid("MC_STACK_DRIFT_INTERVAL", "0"));
+        }
+
+        let trash_retention_days = parse(
+            "MC_STACK_TRASH_RETENTION_DAYS",
+            DEFAULT_TRASH_RETENTION_DAYS,
+        )?;
+
+        Ok(Self {
+            http_port: parse("MC_STACK_HTTP_PORT", DEFAULT_HTTP_PORT)?,
+            tls,
+            wan: WanConfig::from_env()?,
+            proxy: ProxyConfig::from_env()?,
+            idle: IdleConfig::from_env()?,
+            drift_interval: Duration::from_secs(drift_interval),
+            reconcile: ReconcileConfig::from_env()?,
+            trash_retention: (trash_retention_days > 0)
+                .then(|| Duration::from_secs(trash_retention_days * 86_400)),
+            offsite: OffsiteConfig::from_env()?,
+        })
+    }
+}
@@ -0,0 +1,236 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use tokio::process::Command;
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::events::{EventBus, StackEvent};
+use crate::stacks;
+use crate::storage;
+
+const CRASHES_FILE: &str = "crashes.json";
+// Older records are dropped
+const MAX_RECORDS: usize = 20;
+const LOG_TAIL_LINES: usize = 100;
+const MAX_REPORTS_PER_CRASH: usize = 3;
+const MAX_REPORT_BYTES: usize = 64 * 1024;
+const CRASH_REPORTS_PATH: &str = "/minecraft_server/appdata/crash-reports";
+
+/// A file the Minecraft server wrote to `crash-reports/`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CrashReport {
+    pub name: String,
+    /// Content of the report, cut after 64 KiB
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CrashRecord {
+    pub id: Uuid,
+    /// `minecraft_server` or `sftp_server`
+    pub service: String,
+    pub exit_code: i32,
+    /// Restarts Docker made before this crash
+    pub restart_count: u32,
+    /// Docker's restart policy gave up, the service stays down until started again
+    pub gave_up: bool,
+    pub occurred_at: DateTime<Utc>,
+    /// Reports written since the previous crash
+    pub crash_reports: Vec<CrashReport>,
+    /// Last lines the container logged
+    pub log_tail: Vec<String>,
+}
+
+/// Crashes of a stack, oldest first.
+pub fn load(stack_id: &str) -> io::Result<Vec<CrashRecord>> {
+    Ok(
+        storage::read_json(&stacks::stack_directory(stack_id)?.join(CRASHES_FILE))?
+            .unwrap_or_default(),
+    )
+}
+
+async fn save(stack_id: &str, records: &[CrashRecord]) -> io::Result<()> {
+    storage::write_json(
+        &stacks::stack_directory(stack_id)?.join(CRASHES_FILE),
+        &records,
+    )
+    .await
+}
+
+/// Records every crash the monitor reports, with what the container left behind.
+pub fn start(events: &EventBus) {
+    let mut receiver = events.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Crash records missed {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let StackEvent::Crashed {
+                stack_id,
+                service,
+                exit_code,
+            } = event.payload
+            else {
+                continue;
+            };
+
+            // One at a time, crash loops must not write the file concurrently
+            if let Err(e) = record(&stack_id, &service, exit_code, event.timestamp).await {
+                log::warn!("Failed to record the crash of stack {}: {}", stack_id, e);
+            }
+        }
+    });
+}
+
+async fn record(
+    stack_id: &str,
+    service: &str,
+    exit_code: i32,
+    occurred_at: DateTime<Utc>,
+) -> io::Result<()> {
+    let container = format!("{}_{}", service, stack_id);
+    let mut records = load(stack_id)?;
+
+    let (restart_count, gave_up) = restart_state(&container).await.unwrap_or_else(|e| {
+        log::debug!("Failed to inspect {}: {}", container, e);
+        (0, false)
+    });
+
+    let crash_reports = if service == "minecraft_server" {
+        let known: HashSet<&str> = records
+            .iter()
+            .flat_map(|record| record.crash_reports.iter())
+            .map(|report| report.name.as_str())
+            .collect();
+        new_crash_reports(&container, &known)
+            .await
+            .unwrap_or_else(|e| {
+                log::debug!("No crash reports copied from {}: {}", container, e);
+                Vec::new()
+            })
+    } else {
+        Vec::new()
+    };
+
+    let record = CrashRecord {
+        id: Uuid::new_v4(),
+        service: service.to_string(),
+        exit_code,
+        restart_count,
+        gave_up,
+        occurred_at,
+        crash_reports,
+        log_tail: log_tail(&container).await.unwrap_or_default(),
+    };
+    if gave_up {
+        log::warn!(
+            "{} crashed {} times, Docker no longer restarts it",
+            container,
+            restart_count + 1
+        );
+    }
+
+    records.push(record);
+    if records.len() > MAX_RECORDS {
+        records.drain(..records.len() - MAX_RECORDS);
+    }
+    save(stack_id, &records).await
+}
+
+async fn docker(args: &[&str]) -> io::Result<std::process::Output> {
+    let output = Command::new("docker").args(args).output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(output)
+}
+
+// Restarts so far, and whether the restart policy allows no more
+async fn restart_state(container: &str) -> io::Result<(u32, bool)> {
+    let output = docker(&[
+        "inspect",
+        "--format",
+        "{{.RestartCount}} {{.HostConfig.RestartPolicy.Name}} {{.HostConfig.RestartPolicy.MaximumRetryCount}}",
+        container,
+    ])
+    .await?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let mut fields = output.split_whitespace();
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "unexpected inspect output");
+
+    let restart_count: u32 = fields
+        .next()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(invalid)?;
+    let policy = fields.next().unwrap_or("no");
+    let max_retries: u32 = fields.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+
+    let gave_up = match policy {
+        "always" | "unless-stopped" => false,
+        "on-failure" => max_retries > 0 && restart_count >= max_retries,
+        _ => true,
+    };
+    Ok((restart_count, gave_up))
+}
+
+async fn log_tail(container: &str) -> io::Result<Vec<String>> {
+    let output = docker(&["logs", "--tail", &LOG_TAIL_LINES.to_string(), container]).await?;
+
+    // The server logs to both streams, stderr usually holds the stack trace
+    let mut lines: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+        .map(str::to_string)
+        .collect();
+    if lines.len() > LOG_TAIL_LINES {
+        lines.drain(..lines.len() - LOG_TAIL_LINES);
+    }
+    Ok(lines)
+}
+
+// Stopped containers cannot run commands, their files are copied out instead
+async fn new_crash_reports(container: &str, known: &HashSet<&str>) -> io::Result<Vec<CrashReport>> {
+    let destination = storage::data_subdirectory("tmp")?.join(Uuid::new_v4().to_string());
+    let source = format!("{}:{}", container, CRASH_REPORTS_PATH);
+    let copied = docker(&["cp", &source, &destination.to_string_lossy()]).await;
+
+    let reports = copied.and_then(|_| read_reports(&destination, known));
+    let _ = std::fs::remove_dir_all(&destination);
+    reports
+}
+
+fn read_reports(directory: &Path, known: &HashSet<&str>) -> io::Result<Vec<CrashReport>> {
+    let mut names: Vec<String> = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !known.contains(name.as_str()))
+        .collect();
+    // Names carry the date of the crash, the newest come last
+    names.sort();
+    names.drain(..names.len().saturating_sub(MAX_REPORTS_PER_CRASH));
+
+    names
+        .into_iter()
+        .map(|name| {
+            let mut content = std::fs::read(directory.join(&name))?;
+            content.truncate(MAX_REPORT_BYTES);
+            Ok(CrashReport {
+                name,
+                content: String::from_utf8_lossy(&content).to_string(),
+            })
+        })
+        .collect()
+}
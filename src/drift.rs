@@ -0,0 +1,230 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::process::Command;
+use utoipa::ToSchema;
+
+use crate::jobs::JobStore;
+use crate::monitor::parse_container_name;
+use crate::routes::delete;
+use crate::stacks;
+
+const SERVICES: [&str; 2] = ["minecraft_server", "sftp_server"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftKind {
+    /// The world volume of a stack is gone
+    MissingVolume,
+    /// One service of a stack has a container, the other has none
+    MissingContainer,
+    /// Container of a stack mc_stack has no directory for
+    OrphanContainer,
+    OrphanVolume,
+    OrphanNetwork,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairAction {
+    /// Create the missing resource again, an empty world for a missing volume
+    Recreate,
+    /// Write the configuration of the orphan's stack, keeping its world
+    Adopt,
+    /// Delete the orphan resource
+    Remove,
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepairAction::Recreate => write!(f, "recreate"),
+            RepairAction::Adopt => write!(f, "adopt"),
+            RepairAction::Remove => write!(f, "remove"),
+        }
+    }
+}
+
+/// A difference between mc_stack's stacks and what Docker runs.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Drift {
+    /// Stable while the drift lasts, `<kind>:<resource>`
+    pub id: String,
+    pub kind: DriftKind,
+    pub stack_id: String,
+    /// Container, volume or network concerned
+    pub resource: String,
+    /// Repairs that apply
+    pub repairs: Vec<RepairAction>,
+}
+
+impl Drift {
+    fn new(kind: DriftKind, stack_id: &str, resource: String) -> Self {
+        let repairs = match kind {
+            DriftKind::MissingVolume | DriftKind::MissingContainer => vec![RepairAction::Recreate],
+            DriftKind::OrphanContainer | DriftKind::OrphanVolume | DriftKind::OrphanNetwork => {
+                vec![RepairAction::Adopt, RepairAction::Remove]
+            }
+        };
+        let kind_name = serde_json::to_value(kind)
+            .ok()
+            .and_then(|value| value.as_str().map(str::to_string))
+            .unwrap_or_default();
+
+        Self {
+            id: format!("{}:{}", kind_name, resource),
+            kind,
+            stack_id: stack_id.to_string(),
+            resource,
+            repairs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DriftReport {
+    pub checked_at: DateTime<Utc>,
+    pub drift: Vec<Drift>,
+}
+
+/// Names Docker lists for a kind of object, such as `volume`.
+async fn docker_names(args: &[&str]) -> io::Result<HashSet<String>> {
+    let output = Command::new("docker").args(args).output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Compares the stacks directory with Docker's containers, volumes and networks.
+///
+/// Stacks a job is changing, or being deleted, are skipped: their resources come and go.
+pub async fn detect(jobs: &JobStore) -> io::Result<Vec<Drift>> {
+    let containers = docker_names(&["ps", "-a", "--format", "{{.Names}}"]).await?;
+    let volumes = docker_names(&["volume", "ls", "--format", "{{.Name}}"]).await?;
+    let networks = docker_names(&["network", "ls", "--format", "{{.Name}}"]).await?;
+    let known: HashSet<String> = stacks::list_stack_ids()?.into_iter().collect();
+
+    let mut drift = Vec::new();
+    for stack_id in &known {
+        let volume = format!("minecraft_server_{}", stack_id);
+        if !volumes.contains(&volume) {
+            drift.push(Drift::new(DriftKind::MissingVolume, stack_id, volume));
+        }
+
+        // A stack stopped through the API has no containers at all
+        let present: Vec<String> = SERVICES
+            .iter()
+            .map(|service| format!("{}_{}", service, stack_id))
+            .filter(|container| containers.contains(container))
+            .collect();
+        if !present.is_empty() && present.len() < SERVICES.len() {
+            for service in SERVICES {
+                let container = format!("{}_{}", service, stack_id);
+                if !present.contains(&container) {
+                    drift.push(Drift::new(DriftKind::MissingContainer, stack_id, container));
+                }
+            }
+        }
+    }
+
+    let orphans = [
+        (DriftKind::OrphanContainer, &containers),
+        (DriftKind::OrphanVolume, &volumes),
+        (DriftKind::OrphanNetwork, &networks),
+    ];
+    for (kind, names) in orphans {
+        // Sorted, so reports list orphans in a stable order
+        let names: BTreeSet<&String> = names.iter().collect();
+        for name in names {
+            let Some((service, stack_id)) = parse_container_name(name) else {
+                continue;
+            };
+            // Only containers exist for the SFTP service
+            if kind != DriftKind::OrphanContainer && service != "minecraft_server" {
+                continue;
+            }
+            if !known.contains(&stack_id) {
+                drift.push(Drift::new(kind, &stack_id, name.clone()));
+            }
+        }
+    }
+
+    drift.retain(|drift| {
+        let deleting = stacks::stack_directory(&drift.stack_id)
+            .map(|dir| dir.join(delete::DELETION_FILE).exists())
+            .unwrap_or(false);
+        !deleting && jobs.changing(&drift.stack_id).is_none()
+    });
+
+    drift.sort_by_key(|drift| (stacks::parse_id(&drift.stack_id), drift.id.clone()));
+    Ok(drift)
+}
+
+/// Checks for drift in the background and keeps the last report.
+pub struct DriftMonitor {
+    report: RwLock<Option<DriftReport>>,
+    jobs: Arc<JobStore>,
+}
+
+impl DriftMonitor {
+    pub fn start(interval: Duration, jobs: Arc<JobStore>) -> Arc<Self> {
+        let monitor = Arc::new(Self {
+            report: RwLock::new(None),
+            jobs,
+        });
+
+        let checker = monitor.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = checker.check().await {
+                    log::warn!("Failed to check for drift: {}", e);
+                }
+            }
+        });
+
+        monitor
+    }
+
+    /// The last report, `None` until a first check succeeded.
+    pub fn report(&self) -> Option<DriftReport> {
+        self.report.read().unwrap().clone()
+    }
+
+    /// Checks now and keeps the new report.
+    pub async fn check(&self) -> io::Result<DriftReport> {
+        let drift = detect(&self.jobs).await?;
+        let report = DriftReport {
+            checked_at: Utc::now(),
+            drift,
+        };
+
+        let previous = self.report.write().unwrap().replace(report.clone());
+        let seen: HashSet<&str> = previous
+            .as_ref()
+            .map(|previous| previous.drift.iter().map(|d| d.id.as_str()).collect())
+            .unwrap_or_default();
+        for drift in report
+            .drift
+            .iter()
+            .filter(|d| !seen.contains(d.id.as_str()))
+        {
+            log::warn!("Drift detected: {:?} {}", drift.kind, drift.resource);
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,231 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::jobs::{JobKind, JobState};
+use crate::stacks::{self, StackMeta};
+
+// Events kept in memory so reconnecting clients can catch up
+const HISTORY_SIZE: usize = 1000;
+const CHANNEL_CAPACITY: usize = 256;
+
+pub const EVENT_TYPES: &[&str] = &[
+    "stack_created",
+    "stack_deleted",
+    "state_changed",
+    "player_joined",
+    "player_left",
+    "job_progress",
+    "crashed",
+    "idle_stopped",
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StackEvent {
+    StackCreated {
+        stack_id: String,
+    },
+    StackDeleted {
+        stack_id: String,
+    },
+    StateChanged {
+        stack_id: String,
+        service: String,
+        state: String,
+    },
+    PlayerJoined {
+        stack_id: String,
+        player: String,
+    },
+    PlayerLeft {
+        stack_id: String,
+        player: String,
+    },
+    JobProgress {
+        job_id: Uuid,
+        kind: JobKind,
+        stack_id: Option<String>,
+        state: JobState,
+        progress: u8,
+        message: Option<String>,
+    },
+    Crashed {
+        stack_id: String,
+        service: String,
+        exit_code: i32,
+    },
+    /// Stopped by mc_stack after a while without players
+    IdleStopped {
+        stack_id: String,
+        idle_minutes: u32,
+        job_id: Uuid,
+    },
+}
+
+impl StackEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StackEvent::StackCreated { .. } => "stack_created",
+            StackEvent::StackDeleted { .. } => "stack_deleted",
+            StackEvent::StateChanged { .. } => "state_changed",
+            StackEvent::PlayerJoined { .. } => "player_joined",
+            StackEvent::PlayerLeft { .. } => "player_left",
+            StackEvent::JobProgress { .. } => "job_progress",
+            StackEvent::Crashed { .. } => "crashed",
+            StackEvent::IdleStopped { .. } => "idle_stopped",
+        }
+    }
+
+    pub fn stack_id(&self) -> Option<&str> {
+        match self {
+            StackEvent::StackCreated { stack_id }
+            | StackEvent::StackDeleted { stack_id }
+            | StackEvent::StateChanged { stack_id, .. }
+            | StackEvent::PlayerJoined { stack_id, .. }
+            | StackEvent::PlayerLeft { stack_id, .. }
+            | StackEvent::Crashed { stack_id, .. }
+            | StackEvent::IdleStopped { stack_id, .. } => Some(stack_id),
+            StackEvent::JobProgress { stack_id, .. } => stack_id.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub payload: StackEvent,
+    /// Owner and collaborators of the stack when the event was published
+    #[serde(skip)]
+    pub audience: Arc<[Uuid]>,
+}
+
+struct History {
+    next_sequence: u64,
+    events: VecDeque<(u64, Event)>,
+}
+
+/// Fan-out of stack lifecycle events to SSE clients and other subscribers.
+///
+/// Event ids are `<boot>-<sequence>`, the boot part tells resuming clients
+/// whether their `Last-Event-ID` refers to this run of mc_stack.
+pub struct EventBus {
+    boot: String,
+    sender: broadcast::Sender<Event>,
+    history: Mutex<History>,
+    audiences: Mutex<HashMap<String, Arc<[Uuid]>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+
+        Self {
+            boot: Utc::now().timestamp_millis().to_string(),
+            sender,
+            history: Mutex::new(History {
+                next_sequence: 1,
+                events: VecDeque::with_capacity(HISTORY_SIZE),
+            }),
+            audiences: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn publish(&self, payload: StackEvent) -> Event {
+        let audience = payload
+            .stack_id()
+            .map(|stack_id| self.audience(stack_id))
+            .unwrap_or_default();
+
+        let mut history = self.history.lock().unwrap();
+
+        let sequence = history.next_sequence;
+        history.next_sequence += 1;
+
+        let event = Event {
+            id: format!("{}-{}", self.boot, sequence),
+            timestamp: Utc::now(),
+            payload,
+            audience,
+        };
+
+        if history.events.len() == HISTORY_SIZE {
+            history.events.pop_front();
+        }
+        history.events.push_back((sequence, event.clone()));
+
+        // No receivers is not an error, nobody is listening yet
+        let _ = self.sender.send(event.clone());
+
+        event
+    }
+
+    /// Users who may see the events of a stack, read from its record.
+    ///
+    /// Remembered for the events published once the stack is gone, its deletion among them.
+    fn audience(&self, stack_id: &str) -> Arc<[Uuid]> {
+        let meta = stacks::stack_directory(stack_id)
+            .ok()
+            .filter(|dir| dir.join("compose.yaml").exists())
+            .and_then(|_| StackMeta::load(stack_id).ok());
+
+        let mut audiences = self.audiences.lock().unwrap();
+        match meta {
+            Some(meta) => {
+                let audience: Arc<[Uuid]> = meta
+                    .owner
+                    .into_iter()
+                    .chain(
+                        meta.collaborators
+                            .iter()
+                            .map(|collaborator| collaborator.user_id),
+                    )
+                    .collect();
+                audiences.insert(stack_id.to_string(), audience.clone());
+                audience
+            }
+            None => audiences.get(stack_id).cloned().unwrap_or_default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Events published after `last_event_id`, and a receiver for the ones to come.
+    ///
+    /// Unknown ids or ids from a previous run replay the whole history.
+    pub fn subscribe_from(
+        &self,
+        last_event_id: Option<&str>,
+    ) -> (Vec<Event>, broadcast::Receiver<Event>) {
+        // Subscribing under the lock guarantees no event falls between backlog and receiver
+        let history = self.history.lock().unwrap();
+        let receiver = self.sender.subscribe();
+
+        let backlog = match last_event_id {
+            None => Vec::new(),
+            Some(id) => {
+                let after = id
+                    .split_once('-')
+                    .filter(|(boot, _)| *boot == self.boot)
+                    .and_then(|(_, sequence)| sequence.parse::<u64>().ok())
+                    .unwrap_or(0);
+
+                history
+                    .events
+                    .iter()
+                    .filter(|(sequence, _)| *sequence > after)
+                    .map(|(_, event)| event.clone())
+                    .collect()
+            }
+        };
+
+        (backlog, receiver)
+    }
+}
@@ -0,0 +1,159 @@
+use chrono::Utc;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+use crate::audit::{AuditEntry, AuditLog, Outcome};
+use crate::config::IdleConfig;
+use crate::events::{EventBus, StackEvent};
+use crate::jobs::JobStore;
+use crate::proxy::protocol;
+use crate::routes::status::{self, StackStatus};
+use crate::stacks::{self, StackMeta};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stops stacks that have had no players for longer than their idle timeout.
+///
+/// Players are counted with a server list ping, which needs no RCON password. A server
+/// that does not answer is stopped or starting, its idle time starts over once it answers.
+pub struct IdleWatcher {
+    config: IdleConfig,
+    events: Arc<EventBus>,
+    jobs: Arc<JobStore>,
+    audit: Arc<AuditLog>,
+    // When each stack was first seen without players, by stack id
+    empty_since: HashMap<String, Instant>,
+}
+
+impl IdleWatcher {
+    pub fn start(
+        config: IdleConfig,
+        events: Arc<EventBus>,
+        jobs: Arc<JobStore>,
+        audit: Arc<AuditLog>,
+    ) {
+        let mut watcher = Self {
+            config,
+            events,
+            jobs,
+            audit,
+            empty_since: HashMap::new(),
+        };
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(watcher.config.check_interval);
+            loop {
+                interval.tick().await;
+                watcher.check().await;
+            }
+        });
+    }
+
+    async fn check(&mut self) {
+        let stack_ids = match stacks::list_stack_ids() {
+            Ok(stack_ids) => stack_ids,
+            Err(e) => {
+                log::warn!("Failed to read stacks for idle checks: {}", e);
+                return;
+            }
+        };
+
+        // Stacks deleted, joined by players or left without a timeout fall out of the map
+        let mut previous = std::mem::take(&mut self.empty_since);
+        for stack_id in stack_ids {
+            let Some(timeout) = StackMeta::load(&stack_id)
+                .ok()
+                .and_then(|meta| meta.idle_timeout_minutes)
+            else {
+                continue;
+            };
+            let port = match stacks::ports(&stack_id) {
+                Ok(ports) => ports.minecraft_server,
+                Err(e) => {
+                    log::warn!("Stack {} cannot be checked for players: {}", stack_id, e);
+                    continue;
+                }
+            };
+
+            match self.player_count(port).await {
+                Ok(0) => {}
+                Ok(_) => continue,
+                Err(e) => {
+                    log::debug!("Stack {} did not answer the player count: {}", stack_id, e);
+                    continue;
+                }
+            }
+
+            let since = previous.remove(&stack_id).unwrap_or_else(Instant::now);
+            // A stop refused, e.g. while a backup runs, is tried again on the next check
+            let idle = since.elapsed() >= Duration::from_secs(u64::from(timeout) * 60);
+            if !idle || !self.stop(stack_id.clone(), timeout).await {
+                self.empty_since.insert(stack_id, since);
+            }
+        }
+    }
+
+    async fn player_count(&self, port: u16) -> io::Result<u32> {
+        tokio::time::timeout(QUERY_TIMEOUT, async {
+            let mut stream = TcpStream::connect((self.config.host.as_str(), port)).await?;
+            protocol::query_players(&mut stream, &self.config.host, port).await
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no status response"))?
+    }
+
+    /// Returns false when the stop could not be started.
+    async fn stop(&self, stack_id: String, idle_minutes: u32) -> bool {
+        match status::start_system_status_update(
+            self.jobs.clone(),
+            stack_id.clone(),
+            StackStatus::Stopped,
+            format!("No players for {} minutes", idle_minutes),
+        )
+        .await
+        {
+            Ok(job) => {
+                log::info!(
+                    "Stopping stack {} after {} minutes without players (job {})",
+                    stack_id,
+                    idle_minutes,
+                    job.id
+                );
+                // Recorded like a request, its outcome follows once the job finished
+                self.audit
+                    .append(AuditEntry {
+                        id: Uuid::new_v4(),
+                        timestamp: Utc::now(),
+                        actor: None,
+                        user_id: None,
+                        key_id: None,
+                        source_ip: None,
+                        forwarded_for: None,
+                        action: "stack.idle_stop".to_string(),
+                        stack_id: Some(stack_id.clone()),
+                        params: json!({ "idle_minutes": idle_minutes }),
+                        outcome: Outcome::Accepted,
+                        status_code: None,
+                        job_id: Some(job.id),
+                        error: None,
+                    })
+                    .await;
+                self.events.publish(StackEvent::IdleStopped {
+                    stack_id,
+                    idle_minutes,
+                    job_id: job.id,
+                });
+                true
+            }
+            Err(e) => {
+                log::warn!("Failed to stop idle stack {}: {}", stack_id, e);
+                false
+            }
+        }
+    }
+}
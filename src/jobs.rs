@@ -0,0 +1,446 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::events::{EventBus, StackEvent};
+use crate::storage;
+
+// Finished jobs kept on disk, older ones are pruned
+const MAX_JOB_HISTORY: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobKind {
+    CreateStack,
+    DeleteStack,
+    UpdateStackStatus,
+    RepairDrift,
+    RestoreStack,
+    CreateBackup,
+    RestoreBackup,
+    CheckRepository,
+    CollectGarbage,
+    UploadBackup,
+    RestoreOffsiteBackup,
+}
+
+impl JobKind {
+    /// Jobs of these kinds change their stack, only one of them runs on a stack at a time.
+    pub fn locks_stack(&self) -> bool {
+        matches!(
+            self,
+            JobKind::CreateStack
+                | JobKind::DeleteStack
+                | JobKind::UpdateStackStatus
+                | JobKind::RepairDrift
+                | JobKind::RestoreStack
+                | JobKind::CreateBackup
+                | JobKind::RestoreBackup
+                | JobKind::RestoreOffsiteBackup
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Succeeded | JobState::Failed)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JobStep {
+    pub timestamp: DateTime<Utc>,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: JobKind,
+    pub stack_id: Option<String>,
+    /// User who started the job, `None` for host keys
+    #[serde(default)]
+    pub user_id: Option<Uuid>,
+    pub state: JobState,
+    pub progress: u8,
+    pub steps: Vec<JobStep>,
+    /// Outcome of a succeeded job, depends on its kind
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// Keeps track of long-running stack operations and persists them under `data/jobs`.
+pub struct JobStore {
+    dir: PathBuf,
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    events: Arc<EventBus>,
+}
+
+fn active_on(jobs: &HashMap<Uuid, Job>, stack_id: &str) -> Option<Uuid> {
+    jobs.values()
+        .find(|job| {
+            job.kind.locks_stack()
+                && job.stack_id.as_deref() == Some(stack_id)
+                && !job.state.is_finished()
+        })
+        .map(|job| job.id)
+}
+
+impl JobStore {
+    pub fn load(events: Arc<EventBus>) -> io::Result<Self> {
+        let dir = storage::data_subdirectory("jobs")?;
+        let mut jobs = HashMap::new();
+
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+
+            let mut job: Job = match storage::read_json(&path) {
+                Ok(Some(job)) => job,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("Skipping unreadable job file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+
+            // Jobs cannot survive a restart of the process running them
+            if !job.state.is_finished() {
+                let now = Utc::now();
+                job.state = JobState::Failed;
+                job.error = Some("Interrupted by a restart of mc_stack".to_string());
+                job.updated_at = now;
+                job.finished_at = Some(now);
+                let content = serde_json::to_vec_pretty(&job)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                std::fs::write(&path, content)?;
+            }
+
+            jobs.insert(job.id, job);
+        }
+
+        Ok(Self {
+            dir,
+            jobs: Mutex::new(jobs),
+            events,
+        })
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Id of the job changing the stack, if one is queued or running.
+    pub fn changing(&self, stack_id: &str) -> Option<Uuid> {
+        active_on(&self.jobs.lock().unwrap(), stack_id)
+    }
+
+    /// All known jobs, most recent first.
+    pub fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|job| Reverse(job.created_at));
+        jobs
+    }
+
+    /// Registers a new job and runs `task` in the background.
+    pub async fn spawn<F, Fut, E>(
+        self: Arc<Self>,
+        kind: JobKind,
+        stack_id: Option<String>,
+        user_id: Option<Uuid>,
+        task: F,
+    ) -> Job
+    where
+        F: FnOnce(JobHandle) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Value, E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        let job = self
+            .register(kind, stack_id, user_id, false)
+            .expect("only exclusive jobs are refused");
+        self.run(job, task).await
+    }
+
+    /// Like [`JobStore::spawn`] for a job changing the stack, unless another such job is
+    /// queued or running on it, whose id is returned as the error instead.
+    pub async fn spawn_exclusive<F, Fut, E>(
+        self: Arc<Self>,
+        kind: JobKind,
+        stack_id: String,
+        user_id: Option<Uuid>,
+        task: F,
+    ) -> Result<Job, Uuid>
+    where
+        F: FnOnce(JobHandle) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Value, E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        let job = self.register(kind, Some(stack_id), user_id, true)?;
+        Ok(self.run(job, task).await)
+    }
+
+    // Checks and inserts under one lock, so two requests cannot both get through
+    fn register(
+        &self,
+        kind: JobKind,
+        stack_id: Option<String>,
+        user_id: Option<Uuid>,
+        exclusive: bool,
+    ) -> Result<Job, Uuid> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if exclusive {
+            debug_assert!(kind.locks_stack());
+            if let Some(active) = stack_id
+                .as_deref()
+                .and_then(|stack_id| active_on(&jobs, stack_id))
+            {
+                return Err(active);
+            }
+        }
+
+        let now = Utc::now();
+        let job = Job {
+            id: Uuid::new_v4(),
+            kind,
+            stack_id,
+            user_id,
+            state: JobState::Queued,
+            progress: 0,
+            steps: Vec::new(),
+            result: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+            finished_at: None,
+        };
+        jobs.insert(job.id, job.clone());
+        Ok(job)
+    }
+
+    async fn run<F, Fut, E>(self: Arc<Self>, job: Job, task: F) -> Job
+    where
+        F: FnOnce(JobHandle) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<Value, E>> + Send + 'static,
+        E: fmt::Display,
+    {
+        self.persist(&job).await;
+
+        let handle = JobHandle {
+            store: self.clone(),
+            id: job.id,
+        };
+
+        tokio::spawn(async move {
+            let store = handle.store.clone();
+            let id = handle.id;
+
+            store.update(&id, |job| job.state = JobState::Running).await;
+
+            let outcome = task(handle).await.map_err(|e| e.to_string());
+
+            store
+                .update(&id, |job| {
+                    job.finished_at = Some(Utc::now());
+                    match outcome {
+                        Ok(result) => {
+                            job.state = JobState::Succeeded;
+                            job.progress = 100;
+                            job.result = Some(result);
+                        }
+                        Err(e) => {
+                            job.state = JobState::Failed;
+                            job.error = Some(e);
+                        }
+                    }
+                })
+                .await;
+
+            store.prune().await;
+        });
+
+        job
+    }
+
+    async fn update<F>(&self, id: &Uuid, f: F) -> Option<Job>
+    where
+        F: FnOnce(&mut Job),
+    {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let job = jobs.get_mut(id)?;
+            f(job);
+            job.updated_at = Utc::now();
+            job.clone()
+        };
+
+        self.persist(&job).await;
+        self.events.publish(StackEvent::JobProgress {
+            job_id: job.id,
+            kind: job.kind,
+            stack_id: job.stack_id.clone(),
+            state: job.state,
+            progress: job.progress,
+            message: job.steps.last().map(|step| step.message.clone()),
+        });
+        Some(job)
+    }
+
+    async fn persist(&self, job: &Job) {
+        let path = self.dir.join(format!("{}.json", job.id));
+        if let Err(e) = storage::write_json(&path, job).await {
+            log::error!("Failed to persist job {}: {}", job.id, e);
+        }
+    }
+
+    async fn prune(&self) {
+        let expired: Vec<Uuid> = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let mut finished: Vec<&Job> = jobs.values().filter(|j| j.state.is_finished()).collect();
+            if finished.len() <= MAX_JOB_HISTORY {
+                return;
+            }
+
+            finished.sort_by_key(|job| Reverse(job.created_at));
+            let expired: Vec<Uuid> = finished[MAX_JOB_HISTORY..].iter().map(|j| j.id).collect();
+            for id in &expired {
+                jobs.remove(id);
+            }
+            expired
+        };
+
+        for id in expired {
+            let path = self.dir.join(format!("{}.json", id));
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                log::warn!("Failed to remove expired job {}: {}", id, e);
+            }
+        }
+    }
+}
+
+/// Given to a running job so it can report its progress.
+#[derive(Clone)]
+pub struct JobHandle {
+    store: Arc<JobStore>,
+    id: Uuid,
+}
+
+impl JobHandle {
+    pub async fn step(&self, progress: u8, message: impl Into<String>) {
+        let message = message.into();
+        self.store
+            .update(&self.id, |job| {
+                job.progress = progress.min(100);
+                job.steps.push(JobStep {
+                    timestamp: Utc::now(),
+                    message,
+                });
+            })
+            .await;
+    }
+
+    /// Records the stack a job works on once it is known, e.g. after allocation.
+    pub async fn set_stack_id(&self, stack_id: impl Into<String>) {
+        let stack_id = stack_id.into();
+        self.store
+            .update(&self.id, |job| job.stack_id = Some(stack_id))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn store() -> Arc<JobStore> {
+        let dir = std::env::temp_dir().join(format!("mc_stack-jobs-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Arc::new(JobStore {
+            dir,
+            jobs: Mutex::new(HashMap::new()),
+            events: Arc::new(EventBus::new()),
+        })
+    }
+
+    /// Starts a job on the stack that runs until the returned sender is used.
+    async fn hold(
+        store: &Arc<JobStore>,
+        kind: JobKind,
+        stack_id: &str,
+    ) -> Result<(Job, oneshot::Sender<()>), Uuid> {
+        let (release, released) = oneshot::channel::<()>();
+        let task = move |_| async move {
+            let _ = released.await;
+            Ok::<_, String>(Value::Null)
+        };
+        let job = if kind.locks_stack() {
+            store
+                .clone()
+                .spawn_exclusive(kind, stack_id.to_string(), None, task)
+                .await?
+        } else {
+            store
+                .clone()
+                .spawn(kind, Some(stack_id.to_string()), None, task)
+                .await
+        };
+        Ok((job, release))
+    }
+
+    async fn finished(store: &JobStore, id: &Uuid) {
+        for _ in 0..200 {
+            if store.get(id).is_some_and(|job| job.state.is_finished()) {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("job {} never finished", id);
+    }
+
+    #[tokio::test]
+    async fn one_job_changes_a_stack_at_a_time() {
+        let store = store();
+        let (delete, release) = hold(&store, JobKind::DeleteStack, "3").await.unwrap();
+
+        for kind in [
+            JobKind::DeleteStack,
+            JobKind::UpdateStackStatus,
+            JobKind::RestoreBackup,
+            JobKind::CreateBackup,
+        ] {
+            assert_eq!(hold(&store, kind, "3").await.unwrap_err(), delete.id);
+        }
+        // Other stacks, and jobs only reading the stack, are not held back
+        let (_, other) = hold(&store, JobKind::UpdateStackStatus, "4").await.unwrap();
+        let (_, upload) = hold(&store, JobKind::UploadBackup, "3").await.unwrap();
+
+        release.send(()).unwrap();
+        finished(&store, &delete.id).await;
+        let (status, _) = hold(&store, JobKind::UpdateStackStatus, "3").await.unwrap();
+        assert_eq!(status.state, JobState::Queued);
+        drop((other, upload));
+    }
+}
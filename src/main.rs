@@ -14,38 +14,232 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use actix_web::{
-    middleware::{Compress, Logger},
+    middleware::{from_fn, Compress, Logger},
     web, App, HttpServer,
 };
 use env_logger::Env;
 
+mod audit;
+mod auth;
+mod backup_schedule;
+mod backups;
+mod cli;
+mod config;
+mod crashes;
+mod drift;
+mod events;
+mod idle;
+mod jobs;
+mod monitor;
+mod offsite;
+mod openapi;
+mod proxy;
+mod rcon;
+mod reconcile;
+mod repository;
 mod routes;
+mod s3;
+mod stacks;
+mod storage;
+mod tls;
+mod trash;
+mod users;
+mod volumes;
+mod wan;
+mod webhooks;
 mod website;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(Env::default().default_filter_or("info"));
-    log::info!("Starting server at http://0.0.0.0:8080");
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = cli::run(&args).await {
+        if let Err(message) = result {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let config = config::Config::from_env()?;
+    let tls_config = config.tls.as_ref().map(tls::server_config).transpose()?;
+
+    log::info!("Starting server at http://0.0.0.0:{}", config.http_port);
+    if let Some(tls) = &config.tls {
+        log::info!("Serving HTTPS at https://0.0.0.0:{}", tls.https_port);
+    }
 
     let num_workers = num_cpus::get();
 
-    HttpServer::new(|| {
+    let events = web::Data::new(events::EventBus::new());
+    let jobs = web::Data::new(jobs::JobStore::load(events.clone().into_inner())?);
+
+    let keys = web::Data::new(auth::KeyStore::load()?);
+    let users = web::Data::new(users::UserStore::load()?);
+    if keys.is_empty() && users.list().is_empty() {
+        log::warn!(
+            "No API keys or users exist, create an administrator with `mc_stack users create <name> admin`"
+        );
+    }
+
+    let webhooks = webhooks::WebhookManager::load()?;
+    webhooks.start(&events);
+    let webhooks = web::Data::from(webhooks);
+
+    let audit = audit::AuditLog::load(jobs.clone().into_inner())?;
+    audit.start(&events);
+    let audit = web::Data::from(audit);
+
+    crashes::start(&events);
+
+    let monitor = web::Data::from(monitor::Monitor::start(events.clone().into_inner()));
+    let drift_monitor = web::Data::from(drift::DriftMonitor::start(
+        config.drift_interval,
+        jobs.clone().into_inner(),
+    ));
+    let wan = web::Data::from(wan::WanAddress::start(config.wan.clone())?);
+
+    if let Some(proxy_config) = config.proxy.clone() {
+        let port = proxy_config.port;
+        proxy::Proxy::start(proxy_config, &events, jobs.clone().into_inner()).await?;
+        log::info!("Proxying Minecraft connections on port {}", port);
+    }
+
+    idle::IdleWatcher::start(
+        config.idle.clone(),
+        events.clone().into_inner(),
+        jobs.clone().into_inner(),
+        audit.clone().into_inner(),
+    );
+
+    reconcile::start(config.reconcile.clone(), jobs.clone().into_inner());
+    let offsite = web::Data::from(offsite::Offsite::new(config.offsite.clone())?);
+    backup_schedule::start(
+        jobs.clone().into_inner(),
+        offsite.clone().into_inner(),
+        config.idle.host.clone(),
+    );
+    // Without retention, stacks left in the trash from before are purged too
+    trash::start_purge(config.trash_retention.unwrap_or_default());
+
+    let http_port = config.http_port;
+    let https_port = config.tls.as_ref().map(|tls| tls.https_port);
+    let config = web::Data::new(config);
+
+    let server = HttpServer::new(move || {
         App::new()
+            .app_data(config.clone())
+            .app_data(jobs.clone())
+            .app_data(events.clone())
+            .app_data(webhooks.clone())
+            .app_data(keys.clone())
+            .app_data(users.clone())
+            .app_data(audit.clone())
+            .app_data(monitor.clone())
+            .app_data(wan.clone())
+            .app_data(drift_monitor.clone())
+            .app_data(offsite.clone())
             .wrap(Compress::default())
             .wrap(Logger::default())
+            .wrap(from_fn(tls::enforce_https))
             // API routes
             .service(
                 web::scope("/api/v1")
+                    .wrap(from_fn(auth::require_api_key))
+                    // Outermost, so requests refused by authentication are recorded too
+                    .wrap(from_fn(audit::record))
                     .service(routes::create::create_stack)
                     .service(routes::delete::delete_stack)
+                    .service(routes::trash::restore_stack)
+                    .service(routes::trash::list_trash)
                     .service(routes::status::update_stack_status)
-                    .service(routes::list::list_stacks),
+                    .service(routes::idle::update_idle_timeout)
+                    .service(routes::restart_policy::update_restart_policy)
+                    .service(routes::crashes::list_crashes)
+                    .service(routes::backups::create_backup)
+                    .service(routes::backups::list_backups)
+                    .service(routes::backups::download_backup)
+                    .service(routes::backups::delete_backup)
+                    .service(routes::backups::restore_backup)
+                    .service(routes::backups::upload_backup)
+                    .service(routes::backups::get_schedule)
+                    .service(routes::backups::update_schedule)
+                    .service(routes::backups::delete_schedule)
+                    .service(routes::list::list_stacks)
+                    .service(routes::jobs::list_jobs)
+                    .service(routes::jobs::get_job)
+                    .service(routes::events::stream_events)
+                    .service(routes::webhooks::list_webhooks)
+                    .service(routes::webhooks::create_webhook)
+                    .service(routes::webhooks::get_webhook)
+                    .service(routes::webhooks::delete_webhook)
+                    .service(routes::webhooks::list_webhook_deliveries)
+                    .service(routes::webhooks::test_webhook)
+                    .service(routes::keys::list_keys)
+                    .service(routes::keys::create_key)
+                    .service(routes::keys::revoke_key)
+                    .service(routes::auth::login)
+                    .service(routes::auth::logout)
+                    .service(routes::auth::current_user)
+                    .service(routes::users::list_users)
+                    .service(routes::users::create_user)
+                    .service(routes::users::update_user)
+                    .service(routes::users::delete_user)
+                    .service(routes::collaborators::list_collaborators)
+                    .service(routes::collaborators::set_collaborator)
+                    .service(routes::collaborators::remove_collaborator)
+                    .service(routes::audit::list_audit)
+                    .service(routes::audit::export_audit)
+                    .service(routes::drift::get_drift)
+                    .service(routes::drift::repair_drift)
+                    .service(routes::repository::get_repository)
+                    .service(routes::repository::check_repository)
+                    .service(routes::repository::collect_repository)
+                    .service(routes::offsite::list_offsite_backups)
+                    .service(routes::offsite::restore_offsite_backup)
+                    .service(routes::openapi::openapi_json),
+            )
+            .service(
+                web::scope("/api/v2")
+                    .app_data(web::JsonConfig::default().error_handler(routes::error::json_error))
+                    .app_data(web::QueryConfig::default().error_handler(routes::error::query_error))
+                    .wrap(from_fn(auth::require_api_key))
+                    .wrap(from_fn(audit::record))
+                    .service(routes::v2::stacks::list_stacks)
+                    .service(routes::v2::stacks::get_stack)
+                    .service(routes::v2::stacks::create_stack)
+                    .service(routes::v2::stacks::delete_stack)
+                    .service(routes::v2::stacks::restore_stack)
+                    .service(routes::v2::stacks::list_trash)
+                    .service(routes::v2::stacks::update_stack_status)
+                    .service(routes::v2::stacks::update_idle_timeout)
+                    .service(routes::v2::stacks::update_restart_policy)
+                    .service(routes::v2::stacks::list_crashes)
+                    .service(routes::v2::backups::create_backup)
+                    .service(routes::v2::backups::list_backups)
+                    .service(routes::v2::backups::download_backup)
+                    .service(routes::v2::backups::delete_backup)
+                    .service(routes::v2::backups::restore_backup)
+                    .service(routes::v2::backups::upload_backup)
+                    .service(routes::v2::backups::get_schedule)
+                    .service(routes::v2::backups::update_schedule)
+                    .service(routes::v2::backups::delete_schedule)
+                    .service(routes::v2::jobs::list_jobs)
+                    .service(routes::v2::jobs::get_job)
+                    .service(routes::openapi::openapi_v2_json),
             )
             // Static web files
             .configure(website::config)
     })
-    .bind("0.0.0.0:8080")?
-    .workers(num_workers)
-    .run()
-    .await
+    .bind(("0.0.0.0", http_port))?;
+
+    let server = match (tls_config, https_port) {
+        (Some(tls_config), Some(https_port)) => {
+            server.bind_rustls_0_23(("0.0.0.0", https_port), tls_config)?
+        }
+        _ => server,
+    };
+
+    server.workers(num_workers).run().await
 }
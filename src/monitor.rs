@@ -0,0 +1,242 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+
+use crate::events::{EventBus, StackEvent};
+
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+struct DockerEvent {
+    #[serde(rename = "Action")]
+    action: String,
+    #[serde(rename = "Actor")]
+    actor: DockerActor,
+}
+
+#[derive(Debug, Deserialize)]
+struct DockerActor {
+    #[serde(rename = "Attributes", default)]
+    attributes: HashMap<String, String>,
+}
+
+/// Splits a container name such as `minecraft_server_3` into its service and stack id.
+pub fn parse_container_name(name: &str) -> Option<(&'static str, String)> {
+    for service in ["minecraft_server", "sftp_server"] {
+        if let Some(stack_id) = name
+            .strip_prefix(service)
+            .and_then(|rest| rest.strip_prefix('_'))
+        {
+            if !stack_id.is_empty() && stack_id.chars().all(|c| c.is_ascii_digit()) {
+                return Some((service, stack_id.to_string()));
+            }
+        }
+    }
+    None
+}
+
+/// Watches Docker for container state changes and Minecraft logs for players.
+pub struct Monitor {
+    events: Arc<EventBus>,
+    // Containers stopped on purpose, their exit is not a crash
+    killed: Mutex<HashSet<String>>,
+    // Minecraft containers whose logs are being followed
+    log_watchers: Mutex<HashSet<String>>,
+    // Players online, by stack id
+    players: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl Monitor {
+    pub fn start(events: Arc<EventBus>) -> Arc<Self> {
+        let monitor = Arc::new(Self {
+            events,
+            killed: Mutex::new(HashSet::new()),
+            log_watchers: Mutex::new(HashSet::new()),
+            players: Mutex::new(HashMap::new()),
+        });
+
+        let watcher = monitor.clone();
+        tokio::spawn(async move {
+            watcher.watch_running_containers().await;
+            loop {
+                if let Err(e) = watcher.watch_docker_events().await {
+                    log::warn!("Docker event stream failed: {}", e);
+                }
+                tokio::time::sleep(RESTART_DELAY).await;
+            }
+        });
+
+        monitor
+    }
+
+    /// Number of players online on a stack, as seen in its logs since mc_stack started.
+    pub fn player_count(&self, stack_id: &str) -> u32 {
+        self.players
+            .lock()
+            .unwrap()
+            .get(stack_id)
+            .map_or(0, |players| players.len() as u32)
+    }
+
+    async fn watch_running_containers(self: &Arc<Self>) {
+        let output = match Command::new("docker")
+            .args(["ps", "--format", "{{.Names}}"])
+            .output()
+            .await
+        {
+            Ok(output) if output.status.success() => output,
+            _ => return,
+        };
+
+        for name in String::from_utf8_lossy(&output.stdout).lines() {
+            if let Some(("minecraft_server", stack_id)) = parse_container_name(name) {
+                self.follow_logs(name.to_string(), stack_id);
+            }
+        }
+    }
+
+    async fn watch_docker_events(self: &Arc<Self>) -> std::io::Result<()> {
+        let mut child = Command::new("docker")
+            .args([
+                "events",
+                "--filter",
+                "type=container",
+                "--format",
+                "{{json .}}",
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut lines = BufReader::new(stdout).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            match serde_json::from_str::<DockerEvent>(&line) {
+                Ok(event) => self.handle_docker_event(event),
+                Err(e) => log::debug!("Ignoring unparsable docker event: {}", e),
+            }
+        }
+
+        child.wait().await?;
+        Ok(())
+    }
+
+    fn handle_docker_event(self: &Arc<Self>, event: DockerEvent) {
+        let Some(name) = event.actor.attributes.get("name") else {
+            return;
+        };
+        let Some((service, stack_id)) = parse_container_name(name) else {
+            return;
+        };
+
+        match event.action.as_str() {
+            "start" => {
+                self.killed.lock().unwrap().remove(name);
+                self.events.publish(StackEvent::StateChanged {
+                    stack_id: stack_id.clone(),
+                    service: service.to_string(),
+                    state: "running".to_string(),
+                });
+                if service == "minecraft_server" {
+                    self.follow_logs(name.clone(), stack_id);
+                }
+            }
+            "kill" => {
+                self.killed.lock().unwrap().insert(name.clone());
+            }
+            "die" => {
+                let exit_code = event
+                    .actor
+                    .attributes
+                    .get("exitCode")
+                    .and_then(|c| c.parse::<i32>().ok())
+                    .unwrap_or(0);
+                let was_killed = self.killed.lock().unwrap().remove(name);
+                if service == "minecraft_server" {
+                    self.players.lock().unwrap().remove(&stack_id);
+                }
+
+                if exit_code != 0 && !was_killed {
+                    self.events.publish(StackEvent::Crashed {
+                        stack_id: stack_id.clone(),
+                        service: service.to_string(),
+                        exit_code,
+                    });
+                }
+
+                self.events.publish(StackEvent::StateChanged {
+                    stack_id,
+                    service: service.to_string(),
+                    state: "stopped".to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn follow_logs(self: &Arc<Self>, container: String, stack_id: String) {
+        if !self.log_watchers.lock().unwrap().insert(container.clone()) {
+            return;
+        }
+
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = monitor.read_player_logs(&container, &stack_id).await {
+                log::warn!("Failed to follow logs of {}: {}", container, e);
+            }
+            monitor.log_watchers.lock().unwrap().remove(&container);
+        });
+    }
+
+    async fn read_player_logs(&self, container: &str, stack_id: &str) -> std::io::Result<()> {
+        static PLAYER_RE: OnceLock<Regex> = OnceLock::new();
+        let player_re =
+            PLAYER_RE.get_or_init(|| Regex::new(r"\]: (\w{1,16}) (joined|left) the game").unwrap());
+
+        // Ends by itself once the container stops
+        let mut child = Command::new("docker")
+            .args(["logs", "--follow", "--tail", "0", container])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut lines = BufReader::new(stdout).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let Some(captures) = player_re.captures(&line) else {
+                continue;
+            };
+
+            let player = captures[1].to_string();
+            let joined = &captures[2] == "joined";
+            {
+                let mut players = self.players.lock().unwrap();
+                let online = players.entry(stack_id.to_string()).or_default();
+                if joined {
+                    online.insert(player.clone());
+                } else {
+                    online.remove(&player);
+                }
+            }
+
+            let stack_id = stack_id.to_string();
+            self.events.publish(if joined {
+                StackEvent::PlayerJoined { stack_id, player }
+            } else {
+                StackEvent::PlayerLeft { stack_id, player }
+            });
+        }
+
+        child.wait().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,623 @@
+use chrono::Utc;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::backups::{BackupFormat, BackupManifest};
+use crate::config::OffsiteConfig;
+use crate::s3::Bucket;
+
+// S3 takes parts of 5 MiB and more, and at most 10,000 of them
+const PART_SIZE: usize = 16 * 1024 * 1024;
+const READ_BUFFER: usize = 256 * 1024;
+const PIPE_SIZE: usize = 256 * 1024;
+
+// Encrypted objects start with the magic and a random nonce prefix, followed by
+// segments sealed one by one so they can be streamed. The last segment is shorter
+// than the others and sealed as such, a truncated object fails to open.
+const MAGIC: &[u8; 8] = b"MCSENC1\0";
+const NONCE_PREFIX: usize = 8;
+const HEADER: usize = MAGIC.len() + NONCE_PREFIX;
+const SEGMENT: usize = 1024 * 1024;
+const TAG: usize = 16;
+
+fn nonce(prefix: &[u8; NONCE_PREFIX], counter: u32) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[..NONCE_PREFIX].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+fn next(counter: &mut u32) -> io::Result<u32> {
+    let current = *counter;
+    *counter = counter
+        .checked_add(1)
+        .ok_or_else(|| io::Error::other("Object too large to encrypt"))?;
+    Ok(current)
+}
+
+/// Encrypts a stream segment by segment.
+struct Sealer<'a> {
+    key: &'a LessSafeKey,
+    prefix: [u8; NONCE_PREFIX],
+    counter: u32,
+    plain: Vec<u8>,
+}
+
+impl<'a> Sealer<'a> {
+    /// The sealer and the header it writes first.
+    fn new(key: &'a LessSafeKey) -> io::Result<(Self, Vec<u8>)> {
+        let mut prefix = [0; NONCE_PREFIX];
+        SystemRandom::new()
+            .fill(&mut prefix)
+            .map_err(|_| io::Error::other("No randomness for the encryption nonce"))?;
+        let header = [MAGIC.as_slice(), prefix.as_slice()].concat();
+        let sealer = Self {
+            key,
+            prefix,
+            counter: 0,
+            plain: Vec::new(),
+        };
+        Ok((sealer, header))
+    }
+
+    fn seal(&mut self, mut segment: Vec<u8>, last: bool, out: &mut Vec<u8>) -> io::Result<()> {
+        let counter = next(&mut self.counter)?;
+        self.key
+            .seal_in_place_append_tag(
+                nonce(&self.prefix, counter),
+                Aad::from([last as u8]),
+                &mut segment,
+            )
+            .map_err(|_| io::Error::other("Failed to encrypt"))?;
+        out.extend_from_slice(&segment);
+        Ok(())
+    }
+
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        self.plain.extend_from_slice(data);
+        while self.plain.len() >= SEGMENT {
+            let segment = self.plain.drain(..SEGMENT).collect();
+            self.seal(segment, false, out)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let segment = std::mem::take(&mut self.plain);
+        self.seal(segment, true, out)
+    }
+}
+
+enum DecoderState {
+    /// Not enough read to tell whether the object is encrypted
+    Start(Vec<u8>),
+    Plain,
+    Sealed {
+        prefix: [u8; NONCE_PREFIX],
+        counter: u32,
+        sealed: Vec<u8>,
+    },
+}
+
+fn unencrypted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "The object is not encrypted while MC_STACK_S3_ENCRYPTION_KEY is set, it may have been forged",
+    )
+}
+
+/// Turns an object back into what was uploaded, decrypting it when it is encrypted.
+///
+/// With a key, only encrypted objects are read: anyone able to write to the bucket
+/// could otherwise replace a backup and the manifest vouching for its checksum.
+struct Decoder<'a> {
+    key: Option<&'a LessSafeKey>,
+    state: DecoderState,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(key: Option<&'a LessSafeKey>) -> Self {
+        Self {
+            key,
+            state: DecoderState::Start(Vec::new()),
+        }
+    }
+
+    fn open(&mut self, mut segment: Vec<u8>, last: bool, out: &mut Vec<u8>) -> io::Result<()> {
+        let DecoderState::Sealed {
+            prefix, counter, ..
+        } = &mut self.state
+        else {
+            unreachable!("only sealed objects have segments");
+        };
+        let counter = next(counter)?;
+        let key = self.key.expect("sealed objects are only read with a key");
+        let plain = key
+            .open_in_place(
+                nonce(prefix, counter),
+                Aad::from([last as u8]),
+                &mut segment,
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "The object does not decrypt, it is damaged or was encrypted with another key",
+                )
+            })?;
+        out.extend_from_slice(plain);
+        Ok(())
+    }
+
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        match &mut self.state {
+            DecoderState::Start(start) => {
+                start.extend_from_slice(data);
+                if start.len() < HEADER {
+                    return Ok(());
+                }
+                let start = std::mem::take(start);
+                if !start.starts_with(MAGIC) {
+                    if self.key.is_some() {
+                        return Err(unencrypted());
+                    }
+                    self.state = DecoderState::Plain;
+                    out.extend_from_slice(&start);
+                    return Ok(());
+                }
+                if self.key.is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "The object is encrypted, set MC_STACK_S3_ENCRYPTION_KEY to read it",
+                    ));
+                }
+                self.state = DecoderState::Sealed {
+                    prefix: start[MAGIC.len()..HEADER].try_into().unwrap(),
+                    counter: 0,
+                    sealed: Vec::new(),
+                };
+                self.push(&start[HEADER..], out)
+            }
+            DecoderState::Plain => {
+                out.extend_from_slice(data);
+                Ok(())
+            }
+            DecoderState::Sealed { sealed, .. } => {
+                sealed.extend_from_slice(data);
+                // Only the last segment is shorter than a full one
+                while let DecoderState::Sealed { sealed, .. } = &mut self.state {
+                    if sealed.len() < SEGMENT + TAG {
+                        break;
+                    }
+                    let segment = sealed.drain(..SEGMENT + TAG).collect();
+                    self.open(segment, false, out)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        match std::mem::replace(&mut self.state, DecoderState::Plain) {
+            DecoderState::Start(start) => {
+                if self.key.is_some() {
+                    return Err(unencrypted());
+                }
+                out.extend_from_slice(&start);
+                Ok(())
+            }
+            DecoderState::Plain => Ok(()),
+            DecoderState::Sealed {
+                prefix,
+                counter,
+                sealed,
+            } => {
+                if sealed.len() < TAG {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "The object is truncated",
+                    ));
+                }
+                self.state = DecoderState::Sealed {
+                    prefix,
+                    counter,
+                    sealed: Vec::new(),
+                };
+                self.open(sealed, true, out)
+            }
+        }
+    }
+}
+
+struct Target {
+    bucket: Bucket,
+    prefix: String,
+    key: Option<LessSafeKey>,
+}
+
+/// Replication of backups to an S3-compatible bucket, when one is configured.
+///
+/// A backup is stored as `<prefix>stack_<id>/<backup id>.tar` with its manifest
+/// next to it as `.json`, both encrypted when a key is configured. The manifest is
+/// uploaded last, so listed backups are complete.
+pub struct Offsite {
+    target: Option<Target>,
+}
+
+impl Offsite {
+    pub fn new(config: Option<OffsiteConfig>) -> io::Result<Arc<Self>> {
+        let target = match config {
+            Some(config) => {
+                let key = match &config.encryption_key {
+                    Some(key) => Some(LessSafeKey::new(
+                        UnboundKey::new(&AES_256_GCM, key)
+                            .map_err(|_| io::Error::other("Invalid encryption key"))?,
+                    )),
+                    None => None,
+                };
+                Some(Target {
+                    bucket: Bucket::new(&config)?,
+                    prefix: config.prefix,
+                    key,
+                })
+            }
+            None => None,
+        };
+        Ok(Arc::new(Self { target }))
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.target.is_some()
+    }
+
+    fn target(&self) -> io::Result<&Target> {
+        self.target
+            .as_ref()
+            .ok_or_else(|| io::Error::other("No off-site target is configured"))
+    }
+
+    /// Copies a backup to the bucket, recording when in its manifest.
+    pub async fn upload(&self, backup: &mut BackupManifest) -> io::Result<()> {
+        let target = self.target()?;
+        let stem = target.stem(&backup.stack_id, &backup.id);
+        let data_key = format!("{}{}", stem, data_extension(backup.format));
+
+        let reader = backup.open().await?;
+        let upload_id = target.bucket.start_upload(&data_key).await?;
+        if let Err(e) = target.upload_parts(&data_key, &upload_id, reader).await {
+            if let Err(abort) = target.bucket.abort_upload(&data_key, &upload_id).await {
+                log::warn!("Failed to abort the upload of {}: {}", data_key, abort);
+            }
+            return Err(e);
+        }
+
+        let mut uploaded = backup.clone();
+        uploaded.uploaded_at = Some(Utc::now());
+        let manifest = serde_json::to_vec_pretty(&uploaded).map_err(io::Error::other)?;
+        target
+            .bucket
+            .put(&format!("{}.json", stem), target.encode(&manifest)?)
+            .await?;
+        *backup = uploaded;
+        Ok(())
+    }
+
+    /// Manifests of the backups in the bucket, of one stack or of all, newest first.
+    pub async fn list(&self, stack_id: Option<&str>) -> io::Result<Vec<BackupManifest>> {
+        let target = self.target()?;
+        let prefix = match stack_id {
+            Some(stack_id) => format!("{}stack_{}/", target.prefix, stack_id),
+            None => format!("{}stack_", target.prefix),
+        };
+
+        let mut backups = Vec::new();
+        for key in target.bucket.list(&prefix).await? {
+            if !key.ends_with(".json") {
+                continue;
+            }
+            match target.manifest(&key).await {
+                Ok(Some(backup)) => backups.push(backup),
+                Ok(None) => {}
+                Err(e) => log::warn!("Skipping unreadable off-site manifest {}: {}", key, e),
+            }
+        }
+        backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_at));
+        Ok(backups)
+    }
+
+    /// Manifest of a backup in the bucket, `None` when it has none.
+    pub async fn get(&self, stack_id: &str, id: &Uuid) -> io::Result<Option<BackupManifest>> {
+        let target = self.target()?;
+        let Some(backup) = target
+            .manifest(&format!("{}.json", target.stem(stack_id, id)))
+            .await?
+        else {
+            return Ok(None);
+        };
+        // The key is all that ties a manifest to its stack
+        if backup.stack_id != stack_id || backup.id != *id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "The off-site manifest of backup {} describes another backup",
+                    id
+                ),
+            ));
+        }
+        Ok(Some(backup))
+    }
+
+    /// Downloads the world of a backup of the bucket into the local backups. Must
+    /// run under `backups::LOCK`, as the chunks it stores are not referenced until
+    /// the manifest is saved.
+    pub async fn fetch(&self, mut backup: BackupManifest) -> io::Result<BackupManifest> {
+        let target = self.target()?;
+        let data_key = format!(
+            "{}{}",
+            target.stem(&backup.stack_id, &backup.id),
+            data_extension(backup.format)
+        );
+        let response = target.bucket.get(&data_key).await?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("The off-site copy of backup {} has no data", backup.id),
+            )
+        })?;
+
+        let (writer, reader) = tokio::io::duplex(PIPE_SIZE);
+        let (downloaded, imported) =
+            tokio::join!(target.download(response, writer), backup.import(reader));
+        match (downloaded, imported) {
+            // The import stopping first breaks the pipe, its own error says why
+            (Err(e), _) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+            (_, Err(e)) | (Err(e), Ok(())) => return Err(e),
+            _ => {}
+        }
+
+        backup.save().await?;
+        Ok(backup)
+    }
+}
+
+fn data_extension(format: BackupFormat) -> &'static str {
+    match format {
+        BackupFormat::Archive => ".tar.gz",
+        BackupFormat::Chunked => ".tar",
+    }
+}
+
+impl Target {
+    fn stem(&self, stack_id: &str, id: &Uuid) -> String {
+        format!("{}stack_{}/{}", self.prefix, stack_id, id)
+    }
+
+    /// A whole small object as it is stored, encrypted when a key is configured.
+    fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let Some(key) = &self.key else {
+            return Ok(data.to_vec());
+        };
+        let (mut sealer, mut out) = Sealer::new(key)?;
+        sealer.push(data, &mut out)?;
+        sealer.finish(&mut out)?;
+        Ok(out)
+    }
+
+    async fn manifest(&self, key: &str) -> io::Result<Option<BackupManifest>> {
+        let Some(response) = self.bucket.get(key).await? else {
+            return Ok(None);
+        };
+        let data = response
+            .bytes()
+            .await
+            .map_err(|e| io::Error::other(format!("Failed to download {}: {}", key, e)))?;
+        let mut decoder = Decoder::new(self.key.as_ref());
+        let mut manifest = Vec::new();
+        decoder.push(&data, &mut manifest)?;
+        decoder.finish(&mut manifest)?;
+        serde_json::from_slice(&manifest)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Uploads the stream as parts of a started upload and completes it.
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> io::Result<()> {
+        let mut sealer = match &self.key {
+            Some(key) => Some(Sealer::new(key)?),
+            None => None,
+        };
+        let mut part = match &mut sealer {
+            Some((_, header)) => std::mem::take(header),
+            None => Vec::new(),
+        };
+        let mut etags = Vec::new();
+        let mut buffer = vec![0; READ_BUFFER];
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            match &mut sealer {
+                Some((sealer, _)) => sealer.push(&buffer[..read], &mut part)?,
+                None => part.extend_from_slice(&buffer[..read]),
+            }
+            if part.len() >= PART_SIZE {
+                let body = std::mem::take(&mut part);
+                etags.push(
+                    self.bucket
+                        .upload_part(key, upload_id, etags.len() + 1, body)
+                        .await?,
+                );
+            }
+        }
+        if let Some((sealer, _)) = sealer {
+            sealer.finish(&mut part)?;
+        }
+        // The last part may be small, an upload has at least one
+        if !part.is_empty() || etags.is_empty() {
+            etags.push(
+                self.bucket
+                    .upload_part(key, upload_id, etags.len() + 1, part)
+                    .await?,
+            );
+        }
+        self.bucket.complete_upload(key, upload_id, &etags).await
+    }
+
+    /// Writes the decoded body of an object to `writer`.
+    async fn download(
+        &self,
+        mut response: reqwest::Response,
+        mut writer: impl AsyncWrite + Unpin,
+    ) -> io::Result<()> {
+        let mut decoder = Decoder::new(self.key.as_ref());
+        let mut out = Vec::new();
+        while let Some(data) = response
+            .chunk()
+            .await
+            .map_err(|e| io::Error::other(format!("Failed to download the backup: {}", e)))?
+        {
+            decoder.push(&data, &mut out)?;
+            writer.write_all(&out).await?;
+            out.clear();
+        }
+        decoder.finish(&mut out)?;
+        writer.write_all(&out).await?;
+        writer.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> LessSafeKey {
+        LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &[7; 32]).unwrap())
+    }
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn seal(key: &LessSafeKey, data: &[u8], piece: usize) -> Vec<u8> {
+        let (mut sealer, mut out) = Sealer::new(key).unwrap();
+        for part in data.chunks(piece.max(1)) {
+            sealer.push(part, &mut out).unwrap();
+        }
+        sealer.finish(&mut out).unwrap();
+        out
+    }
+
+    fn decode(key: Option<&LessSafeKey>, object: &[u8], piece: usize) -> io::Result<Vec<u8>> {
+        let mut decoder = Decoder::new(key);
+        let mut out = Vec::new();
+        for part in object.chunks(piece.max(1)) {
+            decoder.push(part, &mut out)?;
+        }
+        decoder.finish(&mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trips() {
+        let key = key();
+        for length in [
+            0,
+            1,
+            100,
+            SEGMENT - 1,
+            SEGMENT,
+            SEGMENT + 1,
+            3 * SEGMENT + 17,
+        ] {
+            let plain = data(length);
+            let sealed = seal(&key, &plain, 65_536);
+            assert!(sealed.starts_with(MAGIC));
+            assert_eq!(sealed.len(), HEADER + length + (length / SEGMENT + 1) * TAG);
+            for piece in [7, 65_536, sealed.len()] {
+                assert_eq!(
+                    decode(Some(&key), &sealed, piece).unwrap(),
+                    plain,
+                    "{}",
+                    length
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn objects_are_sealed_with_fresh_nonces() {
+        let key = key();
+        let plain = data(1000);
+        assert_ne!(seal(&key, &plain, 1000), seal(&key, &plain, 1000));
+    }
+
+    #[test]
+    fn rejects_truncated_objects() {
+        let key = key();
+        let sealed = seal(&key, &data(2 * SEGMENT + 100), SEGMENT);
+
+        // Cut at a segment boundary, the last segment left was not sealed as the last
+        let at_boundary = &sealed[..HEADER + 2 * (SEGMENT + TAG)];
+        assert!(decode(Some(&key), at_boundary, SEGMENT).is_err());
+        assert!(decode(Some(&key), &sealed[..sealed.len() - 1], SEGMENT).is_err());
+        assert!(decode(Some(&key), &sealed[..HEADER + 5], SEGMENT).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_segments() {
+        let key = key();
+        let sealed = seal(&key, &data(3 * SEGMENT), SEGMENT);
+        let segment = |i: usize| &sealed[HEADER + i * (SEGMENT + TAG)..][..SEGMENT + TAG];
+
+        let swapped = [
+            &sealed[..HEADER],
+            segment(1),
+            segment(0),
+            &sealed[HEADER + 2 * (SEGMENT + TAG)..],
+        ]
+        .concat();
+        assert!(decode(Some(&key), &swapped, SEGMENT).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_objects() {
+        let key = key();
+        let sealed = seal(&key, &data(SEGMENT + 100), SEGMENT);
+        for at in [
+            HEADER - 1,
+            HEADER,
+            HEADER + SEGMENT + TAG + 3,
+            sealed.len() - 1,
+        ] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            assert!(decode(Some(&key), &tampered, SEGMENT).is_err(), "{}", at);
+        }
+
+        let other = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &[8; 32]).unwrap());
+        assert!(decode(Some(&other), &sealed, SEGMENT).is_err());
+    }
+
+    #[test]
+    fn reads_plain_objects_only_without_a_key() {
+        let key = key();
+        let plain = data(100);
+        assert_eq!(decode(None, &plain, 10).unwrap(), plain);
+        assert_eq!(decode(None, b"{}", 10).unwrap(), b"{}");
+
+        let error = decode(Some(&key), &plain, 10).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(decode(Some(&key), b"{}", 10).is_err());
+
+        let sealed = seal(&key, &plain, 100);
+        assert!(decode(None, &sealed, 100).is_err());
+    }
+}
@@ -0,0 +1,163 @@
+use serde::Serialize;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::auth::SESSION_COOKIE;
+use crate::backups::{CheckReport, GarbageReport};
+use crate::routes::{self, create::CreatedStack, error::ErrorEnvelope};
+
+/// Body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub message: String,
+}
+
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "API key created with `mc_stack keys create` or `POST /keys`",
+                    ))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                SESSION_COOKIE,
+                "Session cookie set by `POST /auth/login`",
+            ))),
+        );
+    }
+}
+
+/// The API description served at `/api/v1/openapi.json`, generated from the route definitions.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "mc_stack",
+        description = "Create and operate containerized Minecraft server stacks."
+    ),
+    servers((url = "/api/v1")),
+    security(("api_key" = []), ("session" = [])),
+    modifiers(&Security),
+    paths(
+        routes::list::list_stacks,
+        routes::create::create_stack,
+        routes::delete::delete_stack,
+        routes::trash::restore_stack,
+        routes::trash::list_trash,
+        routes::status::update_stack_status,
+        routes::idle::update_idle_timeout,
+        routes::restart_policy::update_restart_policy,
+        routes::crashes::list_crashes,
+        routes::backups::create_backup,
+        routes::backups::list_backups,
+        routes::backups::download_backup,
+        routes::backups::delete_backup,
+        routes::backups::restore_backup,
+        routes::backups::upload_backup,
+        routes::backups::get_schedule,
+        routes::backups::update_schedule,
+        routes::backups::delete_schedule,
+        routes::collaborators::list_collaborators,
+        routes::collaborators::set_collaborator,
+        routes::collaborators::remove_collaborator,
+        routes::jobs::list_jobs,
+        routes::jobs::get_job,
+        routes::events::stream_events,
+        routes::auth::login,
+        routes::auth::logout,
+        routes::auth::current_user,
+        routes::users::list_users,
+        routes::users::create_user,
+        routes::users::update_user,
+        routes::users::delete_user,
+        routes::keys::list_keys,
+        routes::keys::create_key,
+        routes::keys::revoke_key,
+        routes::webhooks::list_webhooks,
+        routes::webhooks::create_webhook,
+        routes::webhooks::get_webhook,
+        routes::webhooks::delete_webhook,
+        routes::webhooks::list_webhook_deliveries,
+        routes::webhooks::test_webhook,
+        routes::audit::list_audit,
+        routes::audit::export_audit,
+        routes::drift::get_drift,
+        routes::drift::repair_drift,
+        routes::repository::get_repository,
+        routes::repository::check_repository,
+        routes::repository::collect_repository,
+        routes::offsite::list_offsite_backups,
+        routes::offsite::restore_offsite_backup,
+    ),
+    components(schemas(ErrorResponse, CheckReport, GarbageReport)),
+    tags(
+        (name = "stacks", description = "Minecraft server stacks"),
+        (name = "collaborators", description = "Roles of other users on a stack"),
+        (name = "backups", description = "Snapshots of stack worlds, for stack admins"),
+        (name = "jobs", description = "Progress of long-running stack operations"),
+        (name = "events", description = "Live stack, player and job events"),
+        (name = "auth", description = "Web interface sessions"),
+        (name = "users", description = "User accounts and quotas, admin scope"),
+        (name = "keys", description = "API keys, admin scope"),
+        (name = "webhooks", description = "Event notifications, admin scope"),
+        (name = "audit", description = "Record of mutating requests, admin scope"),
+        (name = "drift", description = "Differences between the stacks and Docker, admin scope"),
+        (name = "repository", description = "Deduplicated storage of backups, admin scope"),
+        (name = "offsite", description = "Backups replicated to an S3-compatible bucket, admin scope"),
+    )
+)]
+pub struct ApiDoc;
+
+/// The API description served at `/api/v2/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "mc_stack",
+        description = "Create and operate containerized Minecraft server stacks. \
+            Errors are answered as `{\"error\": {\"code\", \"message\"}}`, \
+            codes are stable across releases."
+    ),
+    servers((url = "/api/v2")),
+    security(("api_key" = []), ("session" = [])),
+    modifiers(&Security),
+    paths(
+        routes::v2::stacks::list_stacks,
+        routes::v2::stacks::get_stack,
+        routes::v2::stacks::create_stack,
+        routes::v2::stacks::delete_stack,
+        routes::v2::stacks::restore_stack,
+        routes::v2::stacks::list_trash,
+        routes::v2::stacks::update_stack_status,
+        routes::v2::stacks::update_idle_timeout,
+        routes::v2::stacks::update_restart_policy,
+        routes::v2::stacks::list_crashes,
+        routes::v2::backups::create_backup,
+        routes::v2::backups::list_backups,
+        routes::v2::backups::download_backup,
+        routes::v2::backups::delete_backup,
+        routes::v2::backups::restore_backup,
+        routes::v2::backups::upload_backup,
+        routes::v2::backups::get_schedule,
+        routes::v2::backups::update_schedule,
+        routes::v2::backups::delete_schedule,
+        routes::v2::jobs::list_jobs,
+        routes::v2::jobs::get_job,
+    ),
+    components(schemas(ErrorEnvelope, CreatedStack)),
+    tags(
+        (name = "stacks", description = "Minecraft server stacks"),
+        (name = "backups", description = "Snapshots of stack worlds, for stack admins"),
+        (name = "jobs", description = "Progress of long-running stack operations"),
+    )
+)]
+pub struct ApiDocV2;
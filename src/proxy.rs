@@ -0,0 +1,305 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::ProxyConfig;
+use crate::events::{EventBus, StackEvent};
+use crate::jobs::JobStore;
+use crate::routes::status::{self, StackStatus};
+use crate::stacks::{self, StackMeta};
+
+pub mod protocol;
+
+use protocol::{Handshake, NextState};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a woken stack is reported as starting, it is not woken again meanwhile
+const WAKE_GRACE: Duration = Duration::from_secs(180);
+
+/// Where connections for a stack are forwarded.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub stack_id: String,
+    pub port: u16,
+}
+
+/// Subdomain of a stack name, `None` when nothing of the name fits in a DNS label.
+pub fn name_label(name: &str) -> Option<String> {
+    let mut label = String::new();
+    for c in name.to_lowercase().chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c);
+        } else if !label.is_empty() && !label.ends_with('-') {
+            label.push('-');
+        }
+    }
+    let label = label.trim_end_matches('-');
+    (!label.is_empty() && label.len() <= 63).then(|| label.to_string())
+}
+
+/// Forwards Minecraft connections to the stack named by the address the player typed.
+pub struct Proxy {
+    config: ProxyConfig,
+    // By subdomain, `stack-<id>` and the label of the stack name
+    routes: RwLock<HashMap<String, Route>>,
+    jobs: Arc<JobStore>,
+    // Stacks started by a joining player, by stack id
+    waking: Mutex<HashMap<String, Instant>>,
+}
+
+impl Proxy {
+    pub async fn start(
+        config: ProxyConfig,
+        events: &EventBus,
+        jobs: Arc<JobStore>,
+    ) -> io::Result<Arc<Self>> {
+        let listener = TcpListener::bind(("0.0.0.0", config.port)).await?;
+        let proxy = Arc::new(Self {
+            config,
+            routes: RwLock::new(HashMap::new()),
+            jobs,
+            waking: Mutex::new(HashMap::new()),
+        });
+        proxy.rebuild_routes();
+
+        // Routes follow the stacks as they are created and deleted
+        let mut receiver = events.subscribe();
+        let watcher = proxy.clone();
+        tokio::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => match event.payload {
+                        StackEvent::StackCreated { .. } | StackEvent::StackDeleted { .. } => {
+                            watcher.rebuild_routes()
+                        }
+                        // A stack stopping again is sleeping, no longer starting
+                        StackEvent::StateChanged {
+                            stack_id, state, ..
+                        } if state == "stopped" => {
+                            watcher.waking.lock().unwrap().remove(&stack_id);
+                        }
+                        _ => {}
+                    },
+                    Err(RecvError::Lagged(_)) => watcher.rebuild_routes(),
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+
+        let acceptor = proxy.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        log::warn!("Proxy failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let proxy = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = proxy.handle(stream, peer).await {
+                        log::debug!("Proxied connection from {} ended: {}", peer, e);
+                    }
+                });
+            }
+        });
+
+        Ok(proxy)
+    }
+
+    fn rebuild_routes(&self) {
+        let stack_ids = match stacks::list_stack_ids() {
+            Ok(stack_ids) => stack_ids,
+            Err(e) => {
+                log::warn!("Failed to read stacks for the proxy routes: {}", e);
+                return;
+            }
+        };
+
+        let mut routes = HashMap::new();
+        for stack_id in stack_ids {
+            let port = match stacks::ports(&stack_id) {
+                Ok(ports) => ports.minecraft_server,
+                Err(e) => {
+                    log::warn!("Stack {} has no proxy route: {}", stack_id, e);
+                    continue;
+                }
+            };
+            let route = Route {
+                stack_id: stack_id.clone(),
+                port,
+            };
+
+            let name = StackMeta::load(&stack_id)
+                .ok()
+                .and_then(|meta| meta.name)
+                .and_then(|name| name_label(&name));
+            if let Some(label) = name {
+                // Stacks are listed by id, the oldest keeps a shared name
+                match routes.entry(label) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(route.clone());
+                    }
+                    Entry::Occupied(_) => log::warn!(
+                        "Stack {} has the same name as another stack, reach it as stack-{}",
+                        stack_id,
+                        stack_id
+                    ),
+                }
+            }
+            routes.insert(format!("stack-{}", stack_id), route);
+        }
+
+        *self.routes.write().unwrap() = routes;
+    }
+
+    /// Route of the address a player typed, such as `survival.mc.example.com`.
+    pub fn route(&self, server_address: &str) -> Option<Route> {
+        let label = match self.config.domain.as_deref() {
+            Some(domain) => server_address.strip_suffix(domain)?.strip_suffix('.')?,
+            None => server_address.split('.').next()?,
+        };
+        self.routes.read().unwrap().get(label).cloned()
+    }
+
+    async fn handle(&self, mut client: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        client.set_nodelay(true)?;
+
+        let mut buffer = Vec::new();
+        let (packet, end) = tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            protocol::read_packet(&mut client, &mut buffer, 0),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no handshake"))??;
+        let handshake = protocol::parse_handshake(&packet)?;
+        let login = handshake.next_state != NextState::Status;
+
+        let Some(route) = self.route(&handshake.server_address) else {
+            log::debug!(
+                "{} asked for unknown server '{}'",
+                peer,
+                handshake.server_address
+            );
+            if login {
+                protocol::disconnect(
+                    &mut client,
+                    &format!("There is no server at {}", handshake.server_address),
+                )
+                .await?;
+            }
+            return Ok(());
+        };
+
+        let upstream = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect((self.config.upstream_host.as_str(), route.port)),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "stack did not accept"))
+        .and_then(|result| result);
+        let mut upstream = match upstream {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                log::debug!("Stack {} is not reachable: {}", route.stack_id, e);
+                return tokio::time::timeout(
+                    HANDSHAKE_TIMEOUT,
+                    self.answer_stopped(&mut client, &mut buffer, end, &handshake, &route),
+                )
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "client went silent"))?;
+            }
+        };
+        upstream.set_nodelay(true)?;
+
+        log::debug!(
+            "Proxying {} to stack {} (protocol {})",
+            peer,
+            route.stack_id,
+            handshake.protocol_version
+        );
+        tokio::io::AsyncWriteExt::write_all(&mut upstream, &buffer).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        Ok(())
+    }
+
+    fn is_waking(&self, stack_id: &str) -> bool {
+        let mut waking = self.waking.lock().unwrap();
+        waking.retain(|_, started| started.elapsed() < WAKE_GRACE);
+        waking.contains_key(stack_id)
+    }
+
+    // Answers in place of a stack whose server does not accept connections
+    async fn answer_stopped(
+        &self,
+        client: &mut TcpStream,
+        buffer: &mut Vec<u8>,
+        end: usize,
+        handshake: &Handshake,
+        route: &Route,
+    ) -> io::Result<()> {
+        let starting = self.is_waking(&route.stack_id);
+
+        if handshake.next_state == NextState::Status {
+            let (version, motd) = if starting {
+                ("Starting", "Starting, join again in a moment")
+            } else if self.config.wake_on_join {
+                ("Sleeping", "Sleeping, join to wake it up")
+            } else {
+                ("Stopped", "This server is stopped")
+            };
+            return protocol::answer_status(
+                client,
+                buffer,
+                end,
+                handshake.protocol_version,
+                version,
+                motd,
+            )
+            .await;
+        }
+
+        if !self.config.wake_on_join {
+            return protocol::disconnect(client, "This server is not running").await;
+        }
+
+        if !starting {
+            let player = protocol::read_packet(client, buffer, end)
+                .await
+                .and_then(|(packet, _)| protocol::parse_login_start(&packet))
+                .unwrap_or_else(|_| "A player".to_string());
+            self.wake(&route.stack_id, &player).await;
+        }
+
+        protocol::disconnect(client, "The server is starting, join again in a minute").await
+    }
+
+    async fn wake(&self, stack_id: &str, player: &str) {
+        self.waking
+            .lock()
+            .unwrap()
+            .insert(stack_id.to_string(), Instant::now());
+
+        match status::start_system_status_update(
+            self.jobs.clone(),
+            stack_id.to_string(),
+            StackStatus::Running,
+            format!("Woken by {} joining", player),
+        )
+        .await
+        {
+            Ok(job) => log::info!("{} woke stack {} (job {})", player, stack_id, job.id),
+            Err(e) => {
+                log::warn!("Failed to wake stack {}: {}", stack_id, e);
+                self.waking.lock().unwrap().remove(stack_id);
+            }
+        }
+    }
+}
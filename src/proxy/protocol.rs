@@ -0,0 +1,401 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// A handshake carries at most a 255 character address, of up to 4 bytes each
+const MAX_HANDSHAKE_LENGTH: usize = 1100;
+// Largest packet a server may send
+const MAX_PACKET_LENGTH: usize = 2_097_151;
+const LEGACY_PING: u8 = 0xFE;
+// Status queries do not depend on the version, any protocol number is accepted
+const STATUS_PROTOCOL_VERSION: i32 = -1;
+
+/// What the client asks for after the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextState {
+    /// Server list ping
+    Status,
+    Login,
+    /// Joining after a server transfer, handled like a login
+    Transfer,
+}
+
+/// First packet of every Minecraft connection.
+#[derive(Debug)]
+pub struct Handshake {
+    pub protocol_version: i32,
+    /// Address typed by the player, lowercased, without the suffixes of modded clients
+    pub server_address: String,
+    pub next_state: NextState,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Decodes a VarInt at the start of `bytes`, with the number of bytes it took.
+/// `None` when more bytes are needed.
+fn read_varint(bytes: &[u8]) -> io::Result<Option<(i32, usize)>> {
+    let mut value: u32 = 0;
+    for (i, byte) in bytes.iter().enumerate().take(5) {
+        value |= u32::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value as i32, i + 1)));
+        }
+    }
+    if bytes.len() >= 5 {
+        return Err(invalid("VarInt is too long"));
+    }
+    Ok(None)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, value: i32) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            buffer.push(value as u8);
+            return;
+        }
+        buffer.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+}
+
+fn write_string(buffer: &mut Vec<u8>, value: &str) {
+    write_varint(buffer, value.len() as i32);
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+/// Cursor over the fields of a packet.
+struct Fields<'a> {
+    bytes: &'a [u8],
+}
+
+impl Fields<'_> {
+    fn varint(&mut self) -> io::Result<i32> {
+        let (value, len) = read_varint(self.bytes)?.ok_or_else(|| invalid("truncated packet"))?;
+        self.bytes = &self.bytes[len..];
+        Ok(value)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = usize::try_from(self.varint()?).map_err(|_| invalid("negative length"))?;
+        let value = self
+            .bytes
+            .get(..len)
+            .ok_or_else(|| invalid("truncated packet"))?;
+        self.bytes = &self.bytes[len..];
+        String::from_utf8(value.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+    }
+}
+
+/// Reads the packet starting at `start` in `buffer`, returns its payload and where it ends.
+///
+/// Every byte read is kept in `buffer`: clients often send the next packet in the
+/// same segment, and the bytes must be replayed to the server as they are.
+pub async fn read_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    start: usize,
+) -> io::Result<(Vec<u8>, usize)> {
+    read_frame(reader, buffer, start, MAX_HANDSHAKE_LENGTH, true).await
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    start: usize,
+    max_length: usize,
+    from_client: bool,
+) -> io::Result<(Vec<u8>, usize)> {
+    loop {
+        if from_client && start == 0 && buffer.first() == Some(&LEGACY_PING) {
+            return Err(invalid("legacy server list ping"));
+        }
+        if let Some((len, header)) = read_varint(&buffer[start..])? {
+            let len = usize::try_from(len).map_err(|_| invalid("negative packet length"))?;
+            if len > max_length {
+                return Err(invalid("packet is too long"));
+            }
+            let end = start + header + len;
+            if buffer.len() >= end {
+                return Ok((buffer[start + header..end].to_vec(), end));
+            }
+        }
+
+        let mut chunk = [0u8; 1024];
+        let read = reader.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before a full packet",
+            ));
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+pub fn parse_handshake(packet: &[u8]) -> io::Result<Handshake> {
+    let mut fields = Fields { bytes: packet };
+    if fields.varint()? != 0x00 {
+        return Err(invalid("first packet is not a handshake"));
+    }
+
+    let protocol_version = fields.varint()?;
+    let address = fields.string()?;
+    // Port, unused since the proxy listens on a single one
+    fields.bytes = fields
+        .bytes
+        .get(2..)
+        .ok_or_else(|| invalid("truncated packet"))?;
+    let next_state = match fields.varint()? {
+        1 => NextState::Status,
+        2 => NextState::Login,
+        3 => NextState::Transfer,
+        _ => return Err(invalid("unknown next state")),
+    };
+
+    // Forge appends `\0FML\0`, some clients a trailing dot
+    let server_address = address
+        .split('\0')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches('.')
+        .to_lowercase();
+
+    Ok(Handshake {
+        protocol_version,
+        server_address,
+        next_state,
+    })
+}
+
+/// Name of the player in a Login Start packet.
+pub fn parse_login_start(packet: &[u8]) -> io::Result<String> {
+    let mut fields = Fields { bytes: packet };
+    if fields.varint()? != 0x00 {
+        return Err(invalid("packet is not a login start"));
+    }
+    fields.string()
+}
+
+async fn write_packet<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    packet_id: i32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut body = Vec::with_capacity(payload.len() + 1);
+    write_varint(&mut body, packet_id);
+    body.extend_from_slice(payload);
+
+    let mut packet = Vec::with_capacity(body.len() + 5);
+    write_varint(&mut packet, body.len() as i32);
+    packet.extend_from_slice(&body);
+    writer.write_all(&packet).await?;
+    writer.flush().await
+}
+
+/// Refuses a login with a message shown to the player.
+pub async fn disconnect<W: AsyncWrite + Unpin>(writer: &mut W, message: &str) -> io::Result<()> {
+    let mut payload = Vec::new();
+    write_string(
+        &mut payload,
+        &serde_json::json!({ "text": message }).to_string(),
+    );
+    write_packet(writer, 0x00, &payload).await
+}
+
+/// Answers a server list ping with a status of mc_stack's own, in place of the server.
+pub async fn answer_status<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    start: usize,
+    protocol_version: i32,
+    version_name: &str,
+    motd: &str,
+) -> io::Result<()> {
+    // Status Request, without fields
+    let (request, end) = read_packet(stream, buffer, start).await?;
+    if request.first() != Some(&0x00) {
+        return Err(invalid("expected a status request"));
+    }
+
+    let status = serde_json::json!({
+        "version": { "name": version_name, "protocol": protocol_version },
+        "players": { "max": 0, "online": 0 },
+        "description": { "text": motd, "color": "gray" },
+    });
+    let mut payload = Vec::new();
+    write_string(&mut payload, &status.to_string());
+    write_packet(stream, 0x00, &payload).await?;
+
+    // Ping Request, answered with the same payload so the client shows a latency
+    let (ping, _) = read_packet(stream, buffer, end).await?;
+    if ping.first() != Some(&0x01) {
+        return Err(invalid("expected a ping request"));
+    }
+    write_packet(stream, 0x01, &ping[1..]).await
+}
+
+/// Asks a server how many players are online, as the server list does.
+pub async fn query_players<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+) -> io::Result<u32> {
+    let mut handshake = Vec::new();
+    write_varint(&mut handshake, STATUS_PROTOCOL_VERSION);
+    write_string(&mut handshake, host);
+    handshake.extend_from_slice(&port.to_be_bytes());
+    write_varint(&mut handshake, 1);
+    write_packet(stream, 0x00, &handshake).await?;
+    write_packet(stream, 0x00, &[]).await?;
+
+    let mut buffer = Vec::new();
+    let (response, _) = read_frame(stream, &mut buffer, 0, MAX_PACKET_LENGTH, false).await?;
+    let mut fields = Fields { bytes: &response };
+    if fields.varint()? != 0x00 {
+        return Err(invalid("expected a status response"));
+    }
+    let status: serde_json::Value =
+        serde_json::from_str(&fields.string()?).map_err(|_| invalid("status is not JSON"))?;
+
+    status["players"]["online"]
+        .as_u64()
+        .and_then(|online| u32::try_from(online).ok())
+        .ok_or_else(|| invalid("status has no player count"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The examples of the protocol documentation
+    const VARINTS: [(i32, &[u8]); 10] = [
+        (0, &[0x00]),
+        (1, &[0x01]),
+        (127, &[0x7F]),
+        (128, &[0x80, 0x01]),
+        (255, &[0xFF, 0x01]),
+        (25565, &[0xDD, 0xC7, 0x01]),
+        (2_097_151, &[0xFF, 0xFF, 0x7F]),
+        (i32::MAX, &[0xFF, 0xFF, 0xFF, 0xFF, 0x07]),
+        (-1, &[0xFF, 0xFF, 0xFF, 0xFF, 0x0F]),
+        (i32::MIN, &[0x80, 0x80, 0x80, 0x80, 0x08]),
+    ];
+
+    fn handshake(address: &str, next_state: i32) -> Vec<u8> {
+        let mut packet = Vec::new();
+        write_varint(&mut packet, 0x00);
+        write_varint(&mut packet, 767);
+        write_string(&mut packet, address);
+        packet.extend_from_slice(&25565u16.to_be_bytes());
+        write_varint(&mut packet, next_state);
+        packet
+    }
+
+    fn frame(packet: &[u8]) -> Vec<u8> {
+        let mut framed = Vec::new();
+        write_varint(&mut framed, packet.len() as i32);
+        framed.extend_from_slice(packet);
+        framed
+    }
+
+    #[test]
+    fn decodes_varints() {
+        for (value, bytes) in VARINTS {
+            assert_eq!(read_varint(bytes).unwrap(), Some((value, bytes.len())));
+            let mut written = Vec::new();
+            write_varint(&mut written, value);
+            assert_eq!(written, bytes);
+        }
+        // Trailing bytes belong to the next field
+        assert_eq!(read_varint(&[0xAC, 0x02, 0x05]).unwrap(), Some((300, 2)));
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_varint() {
+        assert_eq!(read_varint(&[]).unwrap(), None);
+        assert_eq!(read_varint(&[0x80]).unwrap(), None);
+        assert_eq!(read_varint(&[0xFF, 0xFF, 0xFF, 0xFF]).unwrap(), None);
+        assert!(read_varint(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn parses_handshakes() {
+        let parsed = parse_handshake(&handshake("Survival.MC.example.com", 2)).unwrap();
+        assert_eq!(parsed.protocol_version, 767);
+        assert_eq!(parsed.server_address, "survival.mc.example.com");
+        assert_eq!(parsed.next_state, NextState::Login);
+
+        let status = parse_handshake(&handshake("stack-3.mc.example.com", 1)).unwrap();
+        assert_eq!(status.next_state, NextState::Status);
+        let transfer = parse_handshake(&handshake("stack-3.mc.example.com", 3)).unwrap();
+        assert_eq!(transfer.next_state, NextState::Transfer);
+    }
+
+    #[test]
+    fn strips_modded_client_suffixes() {
+        for address in [
+            "survival.mc.example.com\0FML\0",
+            "survival.mc.example.com\0FML3\0",
+            "survival.mc.example.com.",
+            "survival.mc.example.com.\0FML2\0",
+        ] {
+            let parsed = parse_handshake(&handshake(address, 2)).unwrap();
+            assert_eq!(
+                parsed.server_address, "survival.mc.example.com",
+                "{:?}",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_handshakes() {
+        assert!(parse_handshake(&handshake("example.com", 4)).is_err());
+        let mut not_handshake = handshake("example.com", 2);
+        not_handshake[0] = 0x01;
+        assert!(parse_handshake(&not_handshake).is_err());
+        let full = handshake("example.com", 2);
+        assert!(parse_handshake(&full[..full.len() - 2]).is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_bytes_after_a_packet() {
+        let first = handshake("example.com", 2);
+        let mut login = Vec::new();
+        write_varint(&mut login, 0x00);
+        write_string(&mut login, "Steve");
+        let sent = [frame(&first), frame(&login)].concat();
+
+        let mut reader = sent.as_slice();
+        let mut buffer = Vec::new();
+        let (packet, end) = read_packet(&mut reader, &mut buffer, 0).await.unwrap();
+        assert_eq!(packet, first);
+        let (packet, _) = read_packet(&mut reader, &mut buffer, end).await.unwrap();
+        assert_eq!(parse_login_start(&packet).unwrap(), "Steve");
+        assert_eq!(buffer, sent);
+    }
+
+    #[tokio::test]
+    async fn refuses_legacy_pings_and_long_packets() {
+        let mut buffer = Vec::new();
+        let error = read_packet(&mut [0xFE, 0x01, 0xFA].as_slice(), &mut buffer, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "legacy server list ping");
+
+        let mut long = Vec::new();
+        write_varint(&mut long, MAX_HANDSHAKE_LENGTH as i32 + 1);
+        let mut buffer = Vec::new();
+        assert!(read_packet(&mut long.as_slice(), &mut buffer, 0)
+            .await
+            .is_err());
+
+        let mut buffer = Vec::new();
+        let error = read_packet(&mut [0x05, 0x00].as_slice(), &mut buffer, 0)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,104 @@
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const AUTH: i32 = 3;
+const COMMAND: i32 = 2;
+// Responses of the server are at most 4096 bytes of body
+const MAX_PACKET_LENGTH: i32 = 4110;
+
+/// Connection to a Minecraft server's remote console.
+pub struct Rcon {
+    stream: TcpStream,
+    next_id: i32,
+}
+
+impl Rcon {
+    pub async fn connect(host: &str, port: u16, password: &str) -> io::Result<Self> {
+        let stream = tokio::time::timeout(TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "RCON connection timed out"))??;
+        let mut rcon = Self { stream, next_id: 1 };
+
+        let id = rcon.send(AUTH, password).await?;
+        // A failed login is answered with the id -1
+        let (response_id, _) = rcon.receive().await?;
+        if response_id != id {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "RCON password rejected",
+            ));
+        }
+        Ok(rcon)
+    }
+
+    /// Runs a console command and returns its output.
+    pub async fn command(&mut self, command: &str) -> io::Result<String> {
+        let id = self.send(COMMAND, command).await?;
+        loop {
+            let (response_id, body) = self.receive().await?;
+            if response_id == id {
+                return Ok(body);
+            }
+        }
+    }
+
+    async fn send(&mut self, kind: i32, body: &str) -> io::Result<i32> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let length = i32::try_from(body.len() + 10)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "RCON command too long"))?;
+        let mut packet = Vec::with_capacity(body.len() + 14);
+        packet.extend_from_slice(&length.to_le_bytes());
+        packet.extend_from_slice(&id.to_le_bytes());
+        packet.extend_from_slice(&kind.to_le_bytes());
+        packet.extend_from_slice(body.as_bytes());
+        packet.extend_from_slice(&[0, 0]);
+
+        tokio::time::timeout(TIMEOUT, self.stream.write_all(&packet))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "RCON write timed out"))??;
+        Ok(id)
+    }
+
+    async fn receive(&mut self) -> io::Result<(i32, String)> {
+        tokio::time::timeout(TIMEOUT, async {
+            let length = self.stream.read_i32_le().await?;
+            if !(10..=MAX_PACKET_LENGTH).contains(&length) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid RCON packet length {}", length),
+                ));
+            }
+
+            let mut packet = vec![0; length as usize];
+            self.stream.read_exact(&mut packet).await?;
+            let id = i32::from_le_bytes([packet[0], packet[1], packet[2], packet[3]]);
+            // The body ends with two null bytes
+            let body = String::from_utf8_lossy(&packet[8..packet.len() - 2]).to_string();
+            Ok((id, body))
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "RCON response timed out"))?
+    }
+}
+
+/// Reads `enable-rcon` and `rcon.password` from a `server.properties` file.
+pub fn password_from_properties(properties: &str) -> Option<String> {
+    let value = |key: &str| {
+        properties
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .find(|(name, _)| name.trim() == key)
+            .map(|(_, value)| value.trim().to_string())
+    };
+
+    if value("enable-rcon").as_deref() != Some("true") {
+        return None;
+    }
+    value("rcon.password").filter(|password| !password.is_empty())
+}
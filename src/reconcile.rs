@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::ReconcileConfig;
+use crate::jobs::JobStore;
+use crate::routes::list::{get_container_states, ServiceState};
+use crate::routes::status::{self, StackStatus};
+use crate::stacks::{self, DesiredState, StackMeta};
+
+// Docker may come up after mc_stack when the host boots
+const DOCKER_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const DOCKER_ATTEMPTS: u32 = 30;
+
+/// Starts the stacks that should run and stops the ones that should not, in the background.
+///
+/// Stacks without a desired state are left as Docker has them.
+pub fn start(config: ReconcileConfig, jobs: Arc<JobStore>) {
+    if !config.on_startup {
+        return;
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = reconcile(&config, jobs).await {
+            log::warn!("Failed to restore the desired state of stacks: {}", e);
+        }
+    });
+}
+
+async fn reconcile(config: &ReconcileConfig, jobs: Arc<JobStore>) -> Result<(), String> {
+    let mut attempt = 1;
+    let containers = loop {
+        match get_container_states().await {
+            Ok(containers) => break containers,
+            Err(e) if attempt < DOCKER_ATTEMPTS => {
+                log::debug!("Docker is not available yet: {}", e);
+                attempt += 1;
+                tokio::time::sleep(DOCKER_RETRY_INTERVAL).await;
+            }
+            Err(e) => return Err(e.message),
+        }
+    };
+
+    let mut to_start = Vec::new();
+    let mut to_stop = Vec::new();
+    for stack_id in stacks::list_stack_ids().map_err(|e| e.to_string())? {
+        let desired_state = match StackMeta::load(&stack_id) {
+            Ok(meta) => meta.desired_state,
+            Err(e) => {
+                log::warn!("Stack {} has an unreadable stack.json: {}", stack_id, e);
+                continue;
+            }
+        };
+        let running = containers.get(&format!("minecraft_server_{}", stack_id))
+            == Some(&ServiceState::Running);
+        // Containers left after a crash are removed too
+        let present = ["minecraft_server", "sftp_server"]
+            .iter()
+            .any(|service| containers.contains_key(&format!("{}_{}", service, stack_id)));
+
+        match desired_state {
+            Some(DesiredState::Running) if !running => to_start.push(stack_id),
+            Some(DesiredState::Stopped) if present => to_stop.push(stack_id),
+            _ => {}
+        }
+    }
+
+    if to_start.is_empty() && to_stop.is_empty() {
+        log::info!("All stacks are in their desired state");
+        return Ok(());
+    }
+
+    for stack_id in to_stop {
+        update(&jobs, stack_id, StackStatus::Stopped).await;
+    }
+    for (index, stack_id) in to_start.into_iter().enumerate() {
+        if index > 0 && !config.stagger.is_zero() {
+            tokio::time::sleep(config.stagger).await;
+        }
+        update(&jobs, stack_id, StackStatus::Running).await;
+    }
+    Ok(())
+}
+
+async fn update(jobs: &Arc<JobStore>, stack_id: String, status: StackStatus) {
+    let reason = format!("Restoring its desired state, {}", status);
+    match status::start_system_status_update(jobs.clone(), stack_id.clone(), status, reason).await {
+        Ok(job) => log::info!(
+            "Setting stack {} to {}, its desired state (job {})",
+            stack_id,
+            status,
+            job.id
+        ),
+        Err(e) => log::warn!("Failed to set stack {} to {}: {}", stack_id, status, e),
+    }
+}
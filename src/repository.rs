@@ -0,0 +1,357 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::storage;
+
+// Chunks end where the rolling hash of the last 64 bytes has its top bits clear, so
+// changing part of a file only changes the chunks around the change
+const MIN_CHUNK: usize = 64 * 1024;
+const MAX_CHUNK: usize = 1024 * 1024;
+// 18 bits, chunks average 256 KiB past the minimum
+const CUT_MASK: u64 = !(u64::MAX >> 18);
+const READ_BUFFER: usize = 256 * 1024;
+// Extension of zstd compressed chunks, chunks stored by earlier versions have none
+const COMPRESSED: &str = "zst";
+
+// Changing the table changes every chunk boundary, and with it deduplication
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 from a fixed seed
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Splits a stream into content-defined chunks.
+#[derive(Default)]
+struct Chunker {
+    hash: u64,
+    chunk: Vec<u8>,
+}
+
+impl Chunker {
+    /// Adds data, returning the chunks it completes.
+    fn push(&mut self, mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut complete = Vec::new();
+        while !data.is_empty() {
+            let mut cut = None;
+            for (i, byte) in data.iter().enumerate() {
+                self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+                let length = self.chunk.len() + i + 1;
+                if length >= MAX_CHUNK || (length >= MIN_CHUNK && self.hash & CUT_MASK == 0) {
+                    cut = Some(i + 1);
+                    break;
+                }
+            }
+
+            let Some(cut) = cut else {
+                self.chunk.extend_from_slice(data);
+                break;
+            };
+            self.chunk.extend_from_slice(&data[..cut]);
+            complete.push(std::mem::take(&mut self.chunk));
+            self.hash = 0;
+            data = &data[cut..];
+        }
+        complete
+    }
+
+    fn finish(self) -> Option<Vec<u8>> {
+        (!self.chunk.is_empty()).then_some(self.chunk)
+    }
+}
+
+/// A stream stored in the repository.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    /// Ids of the chunks, in stream order
+    pub chunks: Vec<String>,
+    pub size_bytes: u64,
+    /// SHA-256 of the whole stream, hex encoded
+    pub sha256: String,
+    /// Compressed bytes of the chunks the repository did not have yet
+    pub stored_bytes: u64,
+}
+
+fn directory() -> io::Result<PathBuf> {
+    let dir = storage::data_subdirectory("backups")?.join("repository");
+    if !dir.exists() {
+        std::fs::create_dir_all(&dir)?;
+    }
+    Ok(dir)
+}
+
+/// Chunks are named after the SHA-256 of their content and spread over 256 directories.
+/// The extension of the file tells how it is compressed.
+fn chunk_path(id: &str) -> io::Result<PathBuf> {
+    if id.len() != 64 || !id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid chunk id '{}'", id),
+        ));
+    }
+    Ok(directory()?.join(&id[..2]).join(id))
+}
+
+/// Stores a stream, writing only the chunks the repository does not have.
+pub async fn store(mut reader: impl AsyncRead + Unpin) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot::default();
+    let mut chunker = Chunker::default();
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; READ_BUFFER];
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        snapshot.size_bytes += read as u64;
+        for chunk in chunker.push(&buffer[..read]) {
+            store_chunk(chunk, &mut snapshot).await?;
+        }
+    }
+    if let Some(chunk) = chunker.finish() {
+        store_chunk(chunk, &mut snapshot).await?;
+    }
+
+    snapshot.sha256 = hex::encode(hasher.finalize());
+    Ok(snapshot)
+}
+
+async fn store_chunk(data: Vec<u8>, snapshot: &mut Snapshot) -> io::Result<()> {
+    let id = hex::encode(Sha256::digest(&data));
+    let path = chunk_path(&id)?;
+    let compressed_path = path.with_extension(COMPRESSED);
+    if !tokio::fs::try_exists(&compressed_path).await? && !tokio::fs::try_exists(&path).await? {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let compressed = tokio::task::spawn_blocking(move || compress(&data))
+            .await
+            .map_err(io::Error::other)??;
+        // Renamed into place, a chunk is never half written
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, &compressed).await?;
+        tokio::fs::rename(&tmp_path, &compressed_path).await?;
+        snapshot.stored_bytes += compressed.len() as u64;
+    }
+    snapshot.chunks.push(id);
+    Ok(())
+}
+
+fn compress(data: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)
+}
+
+fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    // No chunk is larger, a corrupt one cannot make us allocate more
+    zstd::bulk::decompress(data, MAX_CHUNK)
+}
+
+/// Content of a chunk, checked against its id. Reads and decompresses on the calling
+/// thread, see [`load_chunk`] for async code.
+pub fn read_chunk(id: &str) -> io::Result<Vec<u8>> {
+    let path = chunk_path(id)?;
+    let corrupt = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Chunk {} is corrupt", id),
+        )
+    };
+
+    let data = match std::fs::read(path.with_extension(COMPRESSED)) {
+        Ok(compressed) => decompress(&compressed).map_err(|_| corrupt())?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            std::fs::read(&path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => {
+                    io::Error::new(io::ErrorKind::NotFound, format!("Chunk {} is missing", id))
+                }
+                _ => e,
+            })?
+        }
+        Err(e) => return Err(e),
+    };
+    if hex::encode(Sha256::digest(&data)) != id {
+        return Err(corrupt());
+    }
+    Ok(data)
+}
+
+/// [`read_chunk`] on the blocking thread pool.
+pub async fn load_chunk(id: String) -> io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || read_chunk(&id))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Writes a stored stream back out.
+pub async fn write(chunks: &[String], mut writer: impl AsyncWrite + Unpin) -> io::Result<()> {
+    for id in chunks {
+        writer.write_all(&load_chunk(id.clone()).await?).await?;
+    }
+    writer.shutdown().await
+}
+
+fn chunk_id(file_name: &str) -> String {
+    file_name
+        .strip_suffix(&format!(".{}", COMPRESSED))
+        .unwrap_or(file_name)
+        .to_string()
+}
+
+/// Ids and sizes on disk of the stored chunks.
+pub fn list_chunks() -> io::Result<Vec<(String, u64)>> {
+    let mut chunks = Vec::new();
+    for prefix in std::fs::read_dir(directory()?)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(prefix.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Left over from an interrupted write
+            if name.ends_with(".tmp") {
+                continue;
+            }
+            chunks.push((chunk_id(&name), entry.metadata()?.len()));
+        }
+    }
+    Ok(chunks)
+}
+
+/// Removes the chunks not in `referenced` and interrupted writes, returning how many
+/// chunks were removed and the bytes freed.
+pub fn collect_garbage(referenced: &HashSet<String>) -> io::Result<(usize, u64)> {
+    let mut removed = 0;
+    let mut freed = 0;
+    for prefix in std::fs::read_dir(directory()?)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(prefix.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let is_tmp = name.ends_with(".tmp");
+            if !is_tmp && referenced.contains(&chunk_id(&name)) {
+                continue;
+            }
+            let size = entry.metadata()?.len();
+            std::fs::remove_file(entry.path())?;
+            if !is_tmp {
+                removed += 1;
+                freed += size;
+            }
+        }
+    }
+    Ok((removed, freed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift64, enough for incompressible test data
+    fn random_bytes(length: usize, mut state: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(length + 8);
+        while data.len() < length {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            data.extend_from_slice(&state.to_le_bytes());
+        }
+        data.truncate(length);
+        data
+    }
+
+    fn chunk(data: &[u8], piece: usize) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::default();
+        let mut chunks = Vec::new();
+        for part in data.chunks(piece) {
+            chunks.extend(chunker.push(part));
+        }
+        chunks.extend(chunker.finish());
+        chunks
+    }
+
+    #[test]
+    fn chunks_respect_size_bounds() {
+        let data = random_bytes(8 * 1024 * 1024, 1);
+        let chunks = chunk(&data, READ_BUFFER);
+
+        assert_eq!(chunks.concat(), data);
+        let (last, full) = chunks.split_last().unwrap();
+        assert!(last.len() <= MAX_CHUNK);
+        for chunk in full {
+            assert!(
+                (MIN_CHUNK..=MAX_CHUNK).contains(&chunk.len()),
+                "{}",
+                chunk.len()
+            );
+        }
+        // Random data is cut by content, well before the maximum
+        assert!(full.iter().any(|chunk| chunk.len() < MAX_CHUNK));
+    }
+
+    #[test]
+    fn uniform_data_is_cut_at_the_maximum() {
+        let data = vec![0; 3 * MAX_CHUNK + 10];
+        let lengths: Vec<usize> = chunk(&data, READ_BUFFER).iter().map(Vec::len).collect();
+        assert_eq!(lengths, [MAX_CHUNK, MAX_CHUNK, MAX_CHUNK, 10]);
+    }
+
+    #[test]
+    fn boundaries_do_not_depend_on_reads() {
+        let data = random_bytes(4 * 1024 * 1024, 2);
+        assert_eq!(chunk(&data, READ_BUFFER), chunk(&data, 4093));
+        assert_eq!(chunk(&data, READ_BUFFER), chunk(&data, data.len()));
+    }
+
+    #[test]
+    fn boundaries_are_stable_after_an_insert() {
+        let data = random_bytes(8 * 1024 * 1024, 3);
+        let mut edited = data.clone();
+        let at = 3 * 1024 * 1024 + 12_345;
+        edited.splice(at..at, random_bytes(100, 4));
+
+        let before = chunk(&data, READ_BUFFER);
+        let after = chunk(&edited, READ_BUFFER);
+        let changed = before.iter().filter(|chunk| !after.contains(chunk)).count();
+
+        // Only the chunks around the insert differ, everything else deduplicates
+        assert!(before.len() > 10);
+        assert!(
+            changed <= 2,
+            "{} of {} chunks changed",
+            changed,
+            before.len()
+        );
+        let prefix = before.iter().zip(&after).take_while(|(a, b)| a == b);
+        assert!(prefix.map(|(a, _)| a.len()).sum::<usize>() + MAX_CHUNK > at);
+    }
+
+    #[test]
+    fn compressed_chunks_round_trip() {
+        let repetitive = b"minecraft:stone ".repeat(MAX_CHUNK / 16);
+        let compressed = compress(&repetitive).unwrap();
+        assert!(compressed.len() < repetitive.len() / 10);
+        assert_eq!(decompress(&compressed).unwrap(), repetitive);
+
+        let random = random_bytes(MAX_CHUNK, 3);
+        assert_eq!(decompress(&compress(&random).unwrap()).unwrap(), random);
+    }
+}
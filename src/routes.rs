@@ -1,4 +1,23 @@
+pub mod audit;
+pub mod auth;
+pub mod backups;
+pub mod collaborators;
+pub mod crashes;
 pub mod create;
 pub mod delete;
+pub mod drift;
+pub mod error;
+pub mod events;
+pub mod idle;
+pub mod jobs;
+pub mod keys;
 pub mod list;
+pub mod offsite;
+pub mod openapi;
+pub mod repository;
+pub mod restart_policy;
 pub mod status;
+pub mod trash;
+pub mod users;
+pub mod v2;
+pub mod webhooks;
@@ -0,0 +1,193 @@
+use actix_web::{get, http::header, web, Error, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::audit::{AuditEntry, AuditLog, Outcome};
+use crate::auth::Admin;
+use crate::openapi::ErrorResponse;
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug)]
+enum AuditError {
+    InvalidQuery(String),
+    FileSystemError(String),
+}
+
+impl fmt::Display for AuditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidQuery(msg) | Self::FileSystemError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ResponseError for AuditError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            AuditError::InvalidQuery(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            AuditError::FileSystemError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct AuditQuery {
+    /// User or API key name, case insensitive
+    actor: Option<String>,
+    user_id: Option<Uuid>,
+    /// Exact action, or a prefix when ending with `.` such as `stack.`
+    action: Option<String>,
+    stack_id: Option<String>,
+    /// `succeeded`, `accepted`, `denied` or `failed`
+    outcome: Option<String>,
+    /// Entries recorded at or after this time
+    since: Option<DateTime<Utc>>,
+    /// Entries recorded before this time
+    until: Option<DateTime<Utc>>,
+    /// Entries returned by the list, 100 by default and at most 1000, ignored by the export
+    limit: Option<usize>,
+}
+
+struct Filter {
+    query: AuditQuery,
+    outcome: Option<Outcome>,
+}
+
+impl Filter {
+    fn new(query: AuditQuery) -> Result<Self, AuditError> {
+        let outcome = query
+            .outcome
+            .as_deref()
+            .map(Outcome::try_from)
+            .transpose()
+            .map_err(AuditError::InvalidQuery)?;
+
+        Ok(Self { query, outcome })
+    }
+
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        let query = &self.query;
+
+        let action = match &query.action {
+            Some(action) if action.ends_with('.') => entry.action.starts_with(action.as_str()),
+            Some(action) => entry.action == *action,
+            None => true,
+        };
+
+        action
+            && query.actor.as_ref().is_none_or(|actor| {
+                entry
+                    .actor
+                    .as_ref()
+                    .is_some_and(|name| name.eq_ignore_ascii_case(actor))
+            })
+            && query.user_id.is_none_or(|id| entry.user_id == Some(id))
+            && query
+                .stack_id
+                .as_ref()
+                .is_none_or(|id| entry.stack_id.as_ref() == Some(id))
+            && self.outcome.is_none_or(|outcome| entry.outcome == outcome)
+            && query.since.is_none_or(|since| entry.timestamp >= since)
+            && query.until.is_none_or(|until| entry.timestamp < until)
+    }
+}
+
+async fn filtered_entries(
+    audit: &AuditLog,
+    filter: &Filter,
+) -> Result<Vec<AuditEntry>, AuditError> {
+    let entries = audit
+        .entries()
+        .await
+        .map_err(|e| AuditError::FileSystemError(format!("Failed to read audit log: {}", e)))?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| filter.matches(entry))
+        .collect())
+}
+
+async fn list_audit_impl(audit: &AuditLog, query: AuditQuery) -> Result<HttpResponse, Error> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let filter = Filter::new(query)?;
+
+    let entries: Vec<AuditEntry> = filtered_entries(audit, &filter)
+        .await?
+        .into_iter()
+        .rev()
+        .take(limit)
+        .collect();
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+async fn export_audit_impl(audit: &AuditLog, query: AuditQuery) -> Result<HttpResponse, Error> {
+    let filter = Filter::new(query)?;
+
+    let mut body = String::new();
+    for entry in filtered_entries(audit, &filter).await? {
+        let line = serde_json::to_string(&entry).map_err(|e| {
+            AuditError::FileSystemError(format!("Failed to serialize audit entry: {}", e))
+        })?;
+        body.push_str(&line);
+        body.push('\n');
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"mc_stack_audit_{}.jsonl\"",
+                Utc::now().format("%Y%m%d%H%M%S")
+            ),
+        ))
+        .body(body))
+}
+
+#[utoipa::path(
+    tag = "audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Matching entries, newest first", body = [AuditEntry]),
+        (status = 400, description = "Invalid filter", body = ErrorResponse),
+    )
+)]
+#[get("/audit")]
+pub async fn list_audit(
+    _admin: Admin,
+    audit: web::Data<AuditLog>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, Error> {
+    list_audit_impl(&audit, query.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Every matching entry, oldest first, one JSON object per line",
+            content_type = "application/x-ndjson", body = String),
+        (status = 400, description = "Invalid filter", body = ErrorResponse),
+    )
+)]
+#[get("/audit/export")]
+pub async fn export_audit(
+    _admin: Admin,
+    audit: web::Data<AuditLog>,
+    query: web::Query<AuditQuery>,
+) -> Result<HttpResponse, Error> {
+    export_audit_impl(&audit, query.into_inner()).await
+}
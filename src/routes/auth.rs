@@ -0,0 +1,177 @@
+use actix_web::{
+    cookie::{time::Duration, Cookie, SameSite},
+    get, post, web, Error, HttpRequest, HttpResponse, ResponseError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use utoipa::ToSchema;
+
+use crate::auth::{Principal, Scope, SESSION_COOKIE};
+use crate::openapi::ErrorResponse;
+use crate::stacks;
+use crate::users::{self, Usage, User, UserStore};
+
+#[derive(Debug)]
+enum LoginError {
+    InvalidCredentials(String),
+    InternalError(String),
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCredentials(msg) | Self::InternalError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ResponseError for LoginError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            LoginError::InvalidCredentials(_) => actix_web::http::StatusCode::UNAUTHORIZED,
+            LoginError::InternalError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Credentials {
+    username: String,
+    #[schema(format = Password)]
+    password: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CurrentUser {
+    /// Username, or name of the API key
+    name: String,
+    scope: Scope,
+    /// `None` for host keys
+    user: Option<User>,
+    usage: Option<Usage>,
+}
+
+fn session_cookie(value: String, max_age: Duration, secure: bool) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, value)
+        .path("/")
+        .http_only(true)
+        .secure(secure)
+        .same_site(SameSite::Strict)
+        .max_age(max_age)
+        .finish()
+}
+
+async fn login_impl(
+    users: &UserStore,
+    credentials: Credentials,
+    secure: bool,
+) -> Result<HttpResponse, Error> {
+    let invalid = || LoginError::InvalidCredentials("Invalid username or password".to_string());
+
+    // Unknown users are checked against a dummy hash, so they take as long to refuse
+    let user = users.find_by_username(&credentials.username);
+    let password_hash = user
+        .as_ref()
+        .map_or(users::DUMMY_PASSWORD_HASH.to_string(), |user| {
+            user.password_hash.clone()
+        });
+    let valid = web::block(move || users::verify_password(&credentials.password, &password_hash))
+        .await
+        .map_err(|e| LoginError::InternalError(format!("Failed to verify password: {}", e)))?;
+    let user = match user {
+        Some(user) if valid => user,
+        _ => return Err(invalid())?,
+    };
+
+    let token = users
+        .create_session(user.id)
+        .await
+        .map_err(|e| LoginError::InternalError(format!("Failed to save session: {}", e)))?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(session_cookie(token, Duration::days(7), secure))
+        .json(user.redacted()))
+}
+
+async fn current_user_impl(users: &UserStore, principal: Principal) -> Result<HttpResponse, Error> {
+    let user = principal.user_id.and_then(|id| users.get(&id));
+
+    let usage = match &user {
+        Some(user) => {
+            let (stacks, memory_mb) = stacks::owned_usage(&user.id)
+                .map_err(|e| LoginError::InternalError(format!("Failed to read stacks: {}", e)))?;
+            Some(Usage { stacks, memory_mb })
+        }
+        None => None,
+    };
+
+    Ok(HttpResponse::Ok().json(CurrentUser {
+        name: principal.name,
+        scope: principal.scope,
+        user: user.map(|user| user.redacted()),
+        usage,
+    }))
+}
+
+#[utoipa::path(
+    tag = "auth",
+    request_body = Credentials,
+    security(()),
+    responses(
+        (status = 200, description = "Signed in, the session cookie is set", body = User,
+            headers(("Set-Cookie" = String, description = "`mc_stack_session`, valid for 7 days"))),
+        (status = 401, description = "Invalid username or password", body = ErrorResponse),
+    )
+)]
+#[post("/auth/login")]
+pub async fn login(
+    req: HttpRequest,
+    users: web::Data<UserStore>,
+    credentials: web::Json<Credentials>,
+) -> Result<HttpResponse, Error> {
+    // Cookies set over HTTPS are never sent back over plain HTTP
+    let secure = req.app_config().secure();
+    login_impl(&users, credentials.into_inner(), secure).await
+}
+
+#[utoipa::path(
+    tag = "auth",
+    responses(
+        (status = 204, description = "The session was closed and its cookie cleared"),
+    )
+)]
+#[post("/auth/logout")]
+pub async fn logout(req: HttpRequest, users: web::Data<UserStore>) -> Result<HttpResponse, Error> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        users.end_session(cookie.value()).await;
+    }
+
+    Ok(HttpResponse::NoContent()
+        .cookie(session_cookie(
+            String::new(),
+            Duration::ZERO,
+            req.app_config().secure(),
+        ))
+        .finish())
+}
+
+#[utoipa::path(
+    tag = "auth",
+    responses(
+        (status = 200, description = "The authenticated user or API key", body = CurrentUser),
+    )
+)]
+#[get("/auth/me")]
+pub async fn current_user(
+    users: web::Data<UserStore>,
+    principal: Principal,
+) -> Result<HttpResponse, Error> {
+    current_user_impl(&users, principal).await
+}
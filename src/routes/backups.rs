@@ -0,0 +1,886 @@
+use actix_files::NamedFile;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{delete, get, post, put, web, Error, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures_util::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::process::Command;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::backups::{
+    self, BackupFormat, BackupKind, BackupManifest, BackupSchedule, ScheduleStatus,
+};
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::jobs::{Job, JobHandle, JobKind, JobStore};
+use crate::offsite::Offsite;
+use crate::openapi::ErrorResponse;
+use crate::rcon::{self, Rcon};
+use crate::repository;
+use crate::routes::create;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::jobs::{accepted, JobReference};
+use crate::routes::list::{get_container_states, ServiceState};
+use crate::stacks::{self, StackMeta, StackRole};
+use crate::users::UserStore;
+use crate::volumes;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct RestoreQuery {
+    /// Restore into a new stack owned by the caller, leaving this one as it is
+    #[serde(default)]
+    pub new_stack: bool,
+}
+
+/// Backup schedule of a stack and the outcome of its last runs.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BackupScheduleReport {
+    /// `null` when the stack is not backed up on a schedule
+    pub schedule: Option<BackupSchedule>,
+    pub next_run_at: Option<DateTime<Utc>>,
+    #[serde(flatten)]
+    pub status: ScheduleStatus,
+}
+
+fn backup_not_found(stack_id: &str, backup_id: &str) -> ApiError {
+    ApiError::new(
+        ErrorCode::BackupNotFound,
+        format!("Stack {} has no backup {}", stack_id, backup_id),
+    )
+}
+
+/// Pauses world saving of a running server, so the archived files do not change underneath.
+async fn pause_saving(stack_id: &str, host: &str) -> Result<Rcon, String> {
+    let volume = format!("minecraft_server_{}", stack_id);
+    let properties = volumes::read_file(&volume, "server.properties")
+        .await
+        .map_err(|e| format!("Failed to read server.properties: {}", e))?
+        .ok_or("The server has no server.properties yet")?;
+    let password = rcon::password_from_properties(&String::from_utf8_lossy(&properties))
+        .ok_or("RCON is not enabled in server.properties")?;
+    let port = stacks::ports(stack_id).map_err(|e| e.to_string())?.rcon;
+
+    let mut rcon = Rcon::connect(host, port, &password)
+        .await
+        .map_err(|e| format!("Failed to connect to RCON: {}", e))?;
+    rcon.command("save-off")
+        .await
+        .map_err(|e| format!("save-off failed: {}", e))?;
+    // Flushing waits until every chunk is written
+    if let Err(e) = rcon.command("save-all flush").await {
+        let _ = rcon.command("save-on").await;
+        return Err(format!("save-all failed: {}", e));
+    }
+    Ok(rcon)
+}
+
+async fn take_backup(
+    stack_id: &str,
+    host: &str,
+    kind: BackupKind,
+    job: &JobHandle,
+) -> Result<BackupManifest, ApiError> {
+    let _guard = backups::LOCK.lock().await;
+
+    let containers = get_container_states().await?;
+    let online =
+        containers.get(&format!("minecraft_server_{}", stack_id)) == Some(&ServiceState::Running);
+
+    let mut manifest = BackupManifest::new(stack_id, kind, online)
+        .map_err(|e| ApiError::internal(format!("Failed to read stack {}: {}", stack_id, e)))?;
+
+    let mut rcon = None;
+    if online {
+        job.step(10, "Pausing world saving").await;
+        rcon = Some(
+            pause_saving(stack_id, host)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to pause world saving: {}", e)))?,
+        );
+    }
+
+    job.step(30, "Storing world volume in the repository").await;
+    let volume = format!("minecraft_server_{}", stack_id);
+    let written = manifest.write(&volume).await;
+
+    // Saving resumes whatever happened to the backup
+    if let Some(mut rcon) = rcon {
+        job.step(80, "Resuming world saving").await;
+        if let Err(e) = rcon.command("save-on").await {
+            log::warn!("Failed to resume saving on stack {}: {}", stack_id, e);
+        }
+    }
+    if let Err(e) = written {
+        let _ = backups::discard(stack_id, &manifest.id);
+        return Err(ApiError::internal(e.to_string()));
+    }
+
+    commit(&manifest).await?;
+    job.step(
+        90,
+        format!(
+            "Stored {} of {} bytes, the rest was already in the repository",
+            manifest.stored_bytes, manifest.size_bytes
+        ),
+    )
+    .await;
+    Ok(manifest)
+}
+
+/// Copies a new backup off-site when a target is configured. A backup whose copy
+/// failed stays on the host only, it can be uploaded again.
+async fn replicate(offsite: &Offsite, manifest: &mut BackupManifest, job: &JobHandle) {
+    if !offsite.is_configured() {
+        return;
+    }
+    job.step(96, "Uploading the backup off-site").await;
+    match offsite.upload(manifest).await {
+        Ok(()) => {
+            if let Err(e) = manifest.save().await {
+                log::warn!(
+                    "Failed to record the upload of backup {}: {}",
+                    manifest.id,
+                    e
+                );
+            }
+        }
+        Err(e) => {
+            log::warn!("Failed to upload backup {} off-site: {}", manifest.id, e);
+            job.step(99, format!("Failed to upload the backup off-site: {}", e))
+                .await;
+        }
+    }
+}
+
+async fn create_backup_impl(
+    stack_id: String,
+    host: String,
+    offsite: Arc<Offsite>,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    let mut manifest = take_backup(&stack_id, &host, BackupKind::Manual, &job).await?;
+    replicate(&offsite, &mut manifest, &job).await;
+    serde_json::to_value(&manifest)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize job result: {}", e)))
+}
+
+/// Takes a backup for the stack's schedule, records how it went and prunes expired backups.
+async fn scheduled_backup_impl(
+    stack_id: String,
+    host: String,
+    offsite: Arc<Offsite>,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    let result = take_backup(&stack_id, &host, BackupKind::Scheduled, &job).await;
+
+    let mut status = ScheduleStatus::load(&stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to read the schedule status: {}", e)))?;
+    match &result {
+        Ok(manifest) => {
+            status.last_success_at = Some(manifest.created_at);
+            status.last_backup_id = Some(manifest.id);
+        }
+        Err(e) => {
+            status.last_failure_at = Some(Utc::now());
+            status.last_error = Some(e.message.clone());
+        }
+    }
+    if let Err(e) = status.save(&stack_id).await {
+        log::warn!(
+            "Failed to write the schedule status of stack {}: {}",
+            stack_id,
+            e
+        );
+    }
+    let mut manifest = result?;
+
+    // The schedule may have been removed while the backup ran
+    if let Some(schedule) = StackMeta::load(&stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to read stack.json: {}", e)))?
+        .backup_schedule
+    {
+        let pruned = backups::prune(&stack_id, &schedule.retention)
+            .map_err(|e| ApiError::internal(format!("Failed to prune backups: {}", e)))?;
+        if !pruned.is_empty() {
+            job.step(95, format!("Pruned {} expired backups", pruned.len()))
+                .await;
+            let garbage = collect_garbage().await?;
+            job.step(
+                98,
+                format!(
+                    "Removed {} chunks of {} bytes from the repository",
+                    garbage.removed_chunks, garbage.freed_bytes
+                ),
+            )
+            .await;
+        }
+    }
+
+    replicate(&offsite, &mut manifest, &job).await;
+    serde_json::to_value(&manifest)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize job result: {}", e)))
+}
+
+/// Saves the manifest of a written backup, which is only listed from then on.
+async fn commit(manifest: &BackupManifest) -> Result<(), ApiError> {
+    if let Err(e) = manifest.save().await {
+        let _ = backups::discard(&manifest.stack_id, &manifest.id);
+        return Err(ApiError::internal(format!(
+            "Failed to write the manifest: {}",
+            e
+        )));
+    }
+    Ok(())
+}
+
+/// Removes the repository chunks no backup uses, waiting for running backups.
+pub async fn collect_garbage() -> Result<backups::GarbageReport, ApiError> {
+    let _guard = backups::LOCK.lock().await;
+    tokio::task::spawn_blocking(backups::collect_garbage)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .map_err(|e| ApiError::internal(format!("Failed to collect the repository: {}", e)))
+}
+
+async fn compose(compose_file: &Path, args: &[&str]) -> Result<(), ApiError> {
+    let output = Command::new("docker")
+        .args(["compose", "-f", compose_file.to_str().unwrap()])
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| {
+            ApiError::new(
+                ErrorCode::DockerUnavailable,
+                format!("Failed to execute docker compose: {}", e),
+            )
+        })?;
+    if !output.status.success() {
+        return Err(ApiError::internal(format!(
+            "docker compose {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Replaces the world of a stopped stack with the backup's, after saving the current one.
+///
+/// Returns the backup of the replaced world, `None` when the stack had no world.
+async fn replace_world(
+    stack_id: &str,
+    backup: &BackupManifest,
+    job: &JobHandle,
+) -> Result<Option<BackupManifest>, ApiError> {
+    let volume = format!("minecraft_server_{}", stack_id);
+    let exists = volumes::exists(&volume).await.map_err(|e| {
+        ApiError::new(
+            ErrorCode::DockerUnavailable,
+            format!("Failed to execute docker volume inspect: {}", e),
+        )
+    })?;
+    let mut safety = None;
+    if exists {
+        job.step(30, "Saving the current world").await;
+        let mut manifest = BackupManifest::new(stack_id, BackupKind::PreRestore, false)
+            .map_err(|e| ApiError::internal(format!("Failed to read stack {}: {}", stack_id, e)))?;
+        if let Err(e) = manifest.write(&volume).await {
+            let _ = backups::discard(stack_id, &manifest.id);
+            return Err(ApiError::internal(e.to_string()));
+        }
+        commit(&manifest).await?;
+        safety = Some(manifest);
+
+        volumes::remove(&volume).await.map_err(|e| {
+            ApiError::internal(format!("Failed to remove volume {}: {}", volume, e))
+        })?;
+    }
+
+    job.step(50, "Restoring the world of the backup").await;
+    if let Err(e) = backup.restore(&volume).await {
+        let Some(safety) = &safety else {
+            return Err(ApiError::internal(e.to_string()));
+        };
+        // The import removed the volume again, the replaced world goes back in
+        return Err(match safety.restore(&volume).await {
+            Ok(()) => ApiError::internal(format!("{}. The previous world was put back", e)),
+            Err(undo) => ApiError::internal(format!(
+                "{}. Putting back the previous world failed too, it is kept as backup {}: {}",
+                e, safety.id, undo
+            )),
+        });
+    }
+    Ok(safety)
+}
+
+/// Applies the settings recorded in a backup to the stack.
+async fn restore_config(stack_id: &str, backup: &BackupManifest) -> Result<(), ApiError> {
+    let config = &backup.config;
+    let env_error =
+        |e: std::io::Error| ApiError::internal(format!("Failed to write .env file: {}", e));
+    // Stacks whose memory could not be read are left with their own
+    if config.memory_mb > 0 {
+        let memory = format!("{}M", config.memory_mb);
+        stacks::write_env(stack_id, "JAVA_XMS", &memory)
+            .await
+            .map_err(env_error)?;
+        stacks::write_env(stack_id, "JAVA_XMX", &memory)
+            .await
+            .map_err(env_error)?;
+    }
+    stacks::write_env(stack_id, "MINECRAFT_VERSION", &backup.minecraft_version)
+        .await
+        .map_err(env_error)?;
+    stacks::write_env(stack_id, "RESTART_POLICY", &config.restart_policy)
+        .await
+        .map_err(env_error)?;
+
+    StackMeta::update(stack_id, |meta| {
+        meta.name = config.name.clone();
+        meta.tags = config.tags.clone();
+        meta.idle_timeout_minutes = config.idle_timeout_minutes;
+    })
+    .await
+    .map(|_| ())
+    .map_err(|e| ApiError::internal(format!("Failed to update stack.json: {}", e)))
+}
+
+pub async fn restore_backup_impl(
+    stack_id: String,
+    backup: BackupManifest,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    let _guard = backups::LOCK.lock().await;
+
+    // Nothing is touched unless the archive is intact
+    job.step(5, "Verifying the backup").await;
+    let manifest = backup.clone();
+    tokio::task::spawn_blocking(move || manifest.verify())
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .map_err(|e| ApiError::internal(format!("Failed to verify the backup: {}", e)))?;
+
+    let compose_file = stacks::stack_directory(&stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to find stack directory: {}", e)))?
+        .join("compose.yaml");
+    let containers = get_container_states().await?;
+    let running =
+        containers.get(&format!("minecraft_server_{}", stack_id)) == Some(&ServiceState::Running);
+
+    // The server saves its world when stopped
+    job.step(10, "Stopping the stack").await;
+    compose(&compose_file, &["down"]).await?;
+
+    let replaced = match replace_world(&stack_id, &backup, &job).await {
+        Ok(safety) => {
+            job.step(70, "Restoring the configuration").await;
+            restore_config(&stack_id, &backup).await.map(|()| safety)
+        }
+        Err(e) => Err(e),
+    };
+
+    // Started again whether or not the restore went through
+    if running {
+        job.step(80, "Starting the stack").await;
+        let started = compose(&compose_file, &["up", "-d"]).await;
+        if let (Ok(_), Err(e)) = (&replaced, started) {
+            return Err(e);
+        }
+    }
+    let safety = replaced?;
+
+    Ok(json!({
+        "stack_id": stack_id,
+        "backup_id": backup.id,
+        "safety_backup_id": safety.map(|safety| safety.id),
+    }))
+}
+
+/// Checks the caller is an admin of the stack and starts restoring the backup,
+/// into the stack itself or into a new one.
+pub async fn start_restore_backup(
+    jobs: Arc<JobStore>,
+    events: Arc<EventBus>,
+    users: &UserStore,
+    principal: &Principal,
+    stack_id: String,
+    backup_id: &str,
+    new_stack: bool,
+) -> Result<Job, ApiError> {
+    principal.authorize(&stack_id, StackRole::Admin)?;
+
+    let backup = get_stack_backup(principal, &stack_id, backup_id)?;
+
+    if new_stack {
+        return create::start_create_from_backup(jobs, events, users, principal, backup).await;
+    }
+    jobs.spawn_exclusive(
+        JobKind::RestoreBackup,
+        stack_id.clone(),
+        principal.user_id,
+        move |job| restore_backup_impl(stack_id, backup, job),
+    )
+    .await
+    .map_err(ApiError::operation_in_progress)
+}
+
+/// Checks the caller is an admin of the stack and starts the backup job.
+pub async fn start_create_backup(
+    jobs: Arc<JobStore>,
+    offsite: Arc<Offsite>,
+    config: &Config,
+    principal: &Principal,
+    stack_id: String,
+) -> Result<Job, ApiError> {
+    principal.authorize(&stack_id, StackRole::Admin)?;
+
+    // Stacks are reached the same way as for idle checks
+    let host = config.idle.host.clone();
+    jobs.spawn_exclusive(
+        JobKind::CreateBackup,
+        stack_id.clone(),
+        principal.user_id,
+        move |job| create_backup_impl(stack_id, host, offsite, job),
+    )
+    .await
+    .map_err(ApiError::operation_in_progress)
+}
+
+/// Starts a backup on behalf of the stack's schedule, unless the job returned as the
+/// error is changing the stack.
+pub async fn start_scheduled_backup(
+    jobs: Arc<JobStore>,
+    offsite: Arc<Offsite>,
+    host: String,
+    stack_id: String,
+) -> Result<Job, Uuid> {
+    jobs.spawn_exclusive(JobKind::CreateBackup, stack_id.clone(), None, move |job| {
+        scheduled_backup_impl(stack_id, host, offsite, job)
+    })
+    .await
+}
+
+pub fn offsite_not_configured() -> ApiError {
+    ApiError::new(
+        ErrorCode::ValidationFailed,
+        "No off-site target is configured, set MC_STACK_S3_ENDPOINT",
+    )
+}
+
+async fn upload_backup_impl(
+    offsite: Arc<Offsite>,
+    mut backup: BackupManifest,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    job.step(10, "Uploading the backup off-site").await;
+    offsite
+        .upload(&mut backup)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to upload the backup: {}", e)))?;
+    backup
+        .save()
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to write the manifest: {}", e)))?;
+
+    serde_json::to_value(&backup)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize job result: {}", e)))
+}
+
+/// Checks the caller is an admin of the stack and starts copying the backup off-site.
+pub async fn start_upload_backup(
+    jobs: Arc<JobStore>,
+    offsite: Arc<Offsite>,
+    principal: &Principal,
+    stack_id: String,
+    backup_id: &str,
+) -> Result<Job, ApiError> {
+    if !offsite.is_configured() {
+        return Err(offsite_not_configured());
+    }
+    let backup = get_stack_backup(principal, &stack_id, backup_id)?;
+
+    Ok(jobs
+        .spawn(
+            JobKind::UploadBackup,
+            Some(stack_id),
+            principal.user_id,
+            move |job| upload_backup_impl(offsite, backup, job),
+        )
+        .await)
+}
+
+fn schedule_report(stack_id: &str, meta: StackMeta) -> Result<BackupScheduleReport, ApiError> {
+    let status = ScheduleStatus::load(stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to read the schedule status: {}", e)))?;
+    let next_run_at = meta
+        .backup_schedule
+        .as_ref()
+        .and_then(|schedule| schedule.parse().ok())
+        .and_then(|schedule| schedule.upcoming(Utc).next());
+
+    Ok(BackupScheduleReport {
+        schedule: meta.backup_schedule,
+        next_run_at,
+        status,
+    })
+}
+
+pub fn get_backup_schedule(
+    principal: &Principal,
+    stack_id: &str,
+) -> Result<BackupScheduleReport, ApiError> {
+    let meta = principal.authorize(stack_id, StackRole::Admin)?;
+    schedule_report(stack_id, meta)
+}
+
+/// Sets or, with `None`, removes the backup schedule of a stack, for its admins.
+pub async fn set_backup_schedule(
+    principal: &Principal,
+    stack_id: &str,
+    schedule: Option<BackupSchedule>,
+) -> Result<BackupScheduleReport, ApiError> {
+    if let Some(schedule) = &schedule {
+        schedule
+            .parse()
+            .map_err(|e| ApiError::new(ErrorCode::ValidationFailed, e))?;
+        let retention = &schedule.retention;
+        if retention.keep_last == 0 && retention.keep_daily == 0 && retention.keep_weekly == 0 {
+            return Err(ApiError::new(
+                ErrorCode::ValidationFailed,
+                "Retention must keep at least one backup",
+            ));
+        }
+    }
+
+    let _guard = StackMeta::lock(stack_id).await;
+    let mut meta = principal.authorize(stack_id, StackRole::Admin)?;
+    meta.backup_schedule = schedule;
+    meta.save(stack_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to write stack.json: {}", e)))?;
+
+    schedule_report(stack_id, meta)
+}
+
+/// Backups of a stack, newest first, for its admins.
+pub fn list_stack_backups(
+    principal: &Principal,
+    stack_id: &str,
+) -> Result<Vec<BackupManifest>, ApiError> {
+    principal.authorize(stack_id, StackRole::Admin)?;
+
+    backups::list(stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to read backups: {}", e)))
+}
+
+/// Manifest of a backup, for the stack's admins.
+pub fn get_stack_backup(
+    principal: &Principal,
+    stack_id: &str,
+    backup_id: &str,
+) -> Result<BackupManifest, ApiError> {
+    principal.authorize(stack_id, StackRole::Admin)?;
+
+    let id = Uuid::parse_str(backup_id).map_err(|_| backup_not_found(stack_id, backup_id))?;
+    backups::get(stack_id, &id)
+        .map_err(|e| ApiError::internal(format!("Failed to read backup: {}", e)))?
+        .ok_or_else(|| backup_not_found(stack_id, backup_id))
+}
+
+pub fn delete_stack_backup(
+    principal: &Principal,
+    stack_id: &str,
+    backup_id: &str,
+) -> Result<(), ApiError> {
+    principal.authorize(stack_id, StackRole::Admin)?;
+
+    let id = Uuid::parse_str(backup_id).map_err(|_| backup_not_found(stack_id, backup_id))?;
+    match backups::delete(stack_id, &id) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(backup_not_found(stack_id, backup_id))
+        }
+        Err(e) => Err(ApiError::internal(format!(
+            "Failed to delete backup: {}",
+            e
+        ))),
+    }
+}
+
+/// Streams the world of a backup as a tarball attachment, gzipped for the `archive` format.
+pub async fn download(req: &HttpRequest, backup: BackupManifest) -> Result<HttpResponse, ApiError> {
+    let name = format!(
+        "stack_{}-{}",
+        backup.stack_id,
+        backup.created_at.format("%Y%m%d-%H%M%S")
+    );
+    let disposition = |filename: String| ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename)],
+    };
+
+    match backup.format {
+        BackupFormat::Archive => {
+            let path = backup.archive_path().map_err(|e| {
+                ApiError::internal(format!("Failed to find backups directory: {}", e))
+            })?;
+            let file = NamedFile::open_async(&path)
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to open the archive: {}", e)))?
+                .set_content_type("application/gzip".parse().unwrap())
+                .set_content_disposition(disposition(format!("{}.tar.gz", name)));
+            Ok(file.into_response(req))
+        }
+        BackupFormat::Chunked => {
+            let chunks = backup
+                .chunks()
+                .map_err(|e| ApiError::internal(format!("Failed to read the chunk list: {}", e)))?;
+            // A chunk that cannot be read cuts the download short
+            let body = stream::iter(chunks)
+                .then(|id| async move { repository::load_chunk(id).await.map(web::Bytes::from) });
+            Ok(HttpResponse::Ok()
+                .content_type("application/x-tar")
+                .insert_header(disposition(format!("{}.tar", name)))
+                .no_chunking(backup.size_bytes)
+                .streaming(body))
+        }
+    }
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    responses(
+        (status = 202, description = "Backup started, the job result holds its manifest", body = JobReference),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+        (status = 409, description = "Another job is changing the stack", body = ErrorResponse),
+    )
+)]
+#[post("/stacks/{stack_id}/backups")]
+pub async fn create_backup(
+    jobs: web::Data<JobStore>,
+    offsite: web::Data<Offsite>,
+    config: web::Data<Config>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let job = start_create_backup(
+        jobs.into_inner(),
+        offsite.into_inner(),
+        &config,
+        &principal,
+        stack_id.into_inner(),
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "Backups of the stack, newest first", body = [BackupManifest]),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[get("/stacks/{stack_id}/backups")]
+pub async fn list_backups(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let backups = list_stack_backups(&principal, &stack_id).map_err(ApiError::into_legacy)?;
+    Ok(HttpResponse::Ok().json(backups))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = String, Path, description = "Stack id"),
+        ("backup_id" = String, Path, description = "Backup id"),
+    ),
+    responses(
+        (status = 200, description = "The tarball of the world, gzipped for the `archive` format", content_type = "application/x-tar"),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack or backup", body = ErrorResponse),
+    )
+)]
+#[get("/stacks/{stack_id}/backups/{backup_id}")]
+pub async fn download_backup(
+    req: HttpRequest,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (stack_id, backup_id) = path.into_inner();
+    let backup =
+        get_stack_backup(&principal, &stack_id, &backup_id).map_err(ApiError::into_legacy)?;
+
+    Ok(download(&req, backup)
+        .await
+        .map_err(ApiError::into_legacy)?)
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = String, Path, description = "Stack id"),
+        ("backup_id" = String, Path, description = "Backup id"),
+        RestoreQuery,
+    ),
+    responses(
+        (status = 202, description = "Restore started", body = JobReference),
+        (status = 403, description = "The caller is not an admin of the stack, or its quota would be exceeded by a new stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack or backup", body = ErrorResponse),
+        (status = 409, description = "Another job is changing the stack, for a restore into it", body = ErrorResponse),
+    )
+)]
+#[post("/stacks/{stack_id}/backups/{backup_id}/restore")]
+pub async fn restore_backup(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    users: web::Data<UserStore>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+    query: web::Query<RestoreQuery>,
+) -> Result<HttpResponse, Error> {
+    let (stack_id, backup_id) = path.into_inner();
+    let job = start_restore_backup(
+        jobs.into_inner(),
+        events.into_inner(),
+        &users,
+        &principal,
+        stack_id,
+        &backup_id,
+        query.new_stack,
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = String, Path, description = "Stack id"),
+        ("backup_id" = String, Path, description = "Backup id"),
+    ),
+    responses(
+        (status = 202, description = "Upload started, the job result holds the manifest", body = JobReference),
+        (status = 400, description = "No off-site target is configured", body = ErrorResponse),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack or backup", body = ErrorResponse),
+    )
+)]
+#[post("/stacks/{stack_id}/backups/{backup_id}/upload")]
+pub async fn upload_backup(
+    jobs: web::Data<JobStore>,
+    offsite: web::Data<Offsite>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (stack_id, backup_id) = path.into_inner();
+    let job = start_upload_backup(
+        jobs.into_inner(),
+        offsite.into_inner(),
+        &principal,
+        stack_id,
+        &backup_id,
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = String, Path, description = "Stack id"),
+        ("backup_id" = String, Path, description = "Backup id"),
+    ),
+    responses(
+        (status = 204, description = "Backup deleted"),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack or backup", body = ErrorResponse),
+    )
+)]
+#[delete("/stacks/{stack_id}/backups/{backup_id}")]
+pub async fn delete_backup(
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (stack_id, backup_id) = path.into_inner();
+    delete_stack_backup(&principal, &stack_id, &backup_id).map_err(ApiError::into_legacy)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "Backup schedule of the stack", body = BackupScheduleReport),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[get("/stacks/{stack_id}/backup_schedule")]
+pub async fn get_schedule(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let report = get_backup_schedule(&principal, &stack_id).map_err(ApiError::into_legacy)?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    request_body = BackupSchedule,
+    responses(
+        (status = 200, description = "Backup schedule changed", body = BackupScheduleReport),
+        (status = 400, description = "Invalid cron expression or retention", body = ErrorResponse),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[put("/stacks/{stack_id}/backup_schedule")]
+pub async fn update_schedule(
+    principal: Principal,
+    stack_id: web::Path<String>,
+    schedule: web::Json<BackupSchedule>,
+) -> Result<HttpResponse, Error> {
+    let report = set_backup_schedule(&principal, &stack_id, Some(schedule.into_inner()))
+        .await
+        .map_err(ApiError::into_legacy)?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    responses(
+        (status = 204, description = "Backup schedule removed, existing backups are kept"),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[delete("/stacks/{stack_id}/backup_schedule")]
+pub async fn delete_schedule(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    set_backup_schedule(&principal, &stack_id, None)
+        .await
+        .map_err(ApiError::into_legacy)?;
+    Ok(HttpResponse::NoContent().finish())
+}
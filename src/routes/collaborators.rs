@@ -0,0 +1,257 @@
+use actix_web::{delete, get, put, web, Error, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::{Principal, StackAccessError};
+use crate::openapi::ErrorResponse;
+use crate::stacks::{Collaborator, StackMeta, StackRole};
+use crate::users::UserStore;
+
+#[derive(Debug)]
+enum CollaboratorError {
+    NotFound(String),
+    Forbidden(String),
+    InvalidRequest(String),
+    FileSystemError(String),
+}
+
+impl fmt::Display for CollaboratorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(msg)
+            | Self::Forbidden(msg)
+            | Self::InvalidRequest(msg)
+            | Self::FileSystemError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ResponseError for CollaboratorError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            CollaboratorError::NotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            CollaboratorError::Forbidden(_) => actix_web::http::StatusCode::FORBIDDEN,
+            CollaboratorError::InvalidRequest(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            CollaboratorError::FileSystemError(_) => {
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+impl From<StackAccessError> for CollaboratorError {
+    fn from(e: StackAccessError) -> Self {
+        match e {
+            StackAccessError::NotFound(msg) => CollaboratorError::NotFound(msg),
+            StackAccessError::Forbidden(msg) => CollaboratorError::Forbidden(msg),
+            StackAccessError::Io(msg) => CollaboratorError::FileSystemError(msg),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RoleUpdate {
+    /// `viewer`, `operator` or `admin`
+    #[schema(example = "operator")]
+    role: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StackOwner {
+    user_id: Uuid,
+    username: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StackCollaborator {
+    user_id: Uuid,
+    username: String,
+    role: StackRole,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CollaboratorList {
+    /// `None` for stacks created by host keys
+    owner: Option<StackOwner>,
+    collaborators: Vec<StackCollaborator>,
+}
+
+fn collaborators(users: &UserStore, meta: &StackMeta) -> CollaboratorList {
+    let owner = meta
+        .owner
+        .and_then(|id| users.get(&id))
+        .map(|user| StackOwner {
+            user_id: user.id,
+            username: user.username,
+        });
+
+    let collaborators = meta
+        .collaborators
+        .iter()
+        .filter_map(|collaborator| {
+            users
+                .get(&collaborator.user_id)
+                .map(|user| StackCollaborator {
+                    user_id: user.id,
+                    username: user.username,
+                    role: collaborator.role,
+                })
+        })
+        .collect();
+
+    CollaboratorList {
+        owner,
+        collaborators,
+    }
+}
+
+async fn save(meta: &StackMeta, stack_id: &str) -> Result<(), CollaboratorError> {
+    meta.save(stack_id).await.map_err(|e| {
+        CollaboratorError::FileSystemError(format!("Failed to write stack.json: {}", e))
+    })
+}
+
+async fn set_collaborator_impl(
+    users: &UserStore,
+    principal: Principal,
+    stack_id: String,
+    username: String,
+    update: RoleUpdate,
+) -> Result<HttpResponse, Error> {
+    let _guard = StackMeta::lock(&stack_id).await;
+    let mut meta = principal
+        .authorize(&stack_id, StackRole::Admin)
+        .map_err(CollaboratorError::from)?;
+
+    let role =
+        StackRole::try_from(update.role.as_str()).map_err(CollaboratorError::InvalidRequest)?;
+    let user = users
+        .find_by_username(&username)
+        .ok_or_else(|| CollaboratorError::NotFound(format!("User {} does not exist", username)))?;
+
+    if meta.owner == Some(user.id) {
+        return Err(CollaboratorError::InvalidRequest(format!(
+            "{} owns stack {}, their role cannot change",
+            user.username, stack_id
+        )))?;
+    }
+
+    meta.collaborators
+        .retain(|collaborator| collaborator.user_id != user.id);
+    meta.collaborators.push(Collaborator {
+        user_id: user.id,
+        role,
+    });
+    save(&meta, &stack_id).await?;
+
+    Ok(HttpResponse::Ok().json(collaborators(users, &meta)))
+}
+
+async fn remove_collaborator_impl(
+    users: &UserStore,
+    principal: Principal,
+    stack_id: String,
+    username: String,
+) -> Result<HttpResponse, Error> {
+    let _guard = StackMeta::lock(&stack_id).await;
+    let mut meta = principal
+        .authorize(&stack_id, StackRole::Admin)
+        .map_err(CollaboratorError::from)?;
+
+    let not_collaborator = || {
+        CollaboratorError::NotFound(format!(
+            "{} is not a collaborator of stack {}",
+            username, stack_id
+        ))
+    };
+    let user = users
+        .find_by_username(&username)
+        .ok_or_else(not_collaborator)?;
+
+    let count = meta.collaborators.len();
+    meta.collaborators
+        .retain(|collaborator| collaborator.user_id != user.id);
+    if meta.collaborators.len() == count {
+        return Err(not_collaborator())?;
+    }
+    save(&meta, &stack_id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    tag = "collaborators",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "Owner and collaborators of the stack", body = CollaboratorList),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[get("/stacks/{stack_id}/collaborators")]
+pub async fn list_collaborators(
+    users: web::Data<UserStore>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let meta = principal
+        .authorize(&stack_id, StackRole::Viewer)
+        .map_err(CollaboratorError::from)?;
+
+    Ok(HttpResponse::Ok().json(collaborators(&users, &meta)))
+}
+
+#[utoipa::path(
+    tag = "collaborators",
+    params(
+        ("stack_id" = String, Path, description = "Stack id"),
+        ("username" = String, Path, description = "User given the role"),
+    ),
+    request_body = RoleUpdate,
+    responses(
+        (status = 200, description = "The role was set, returns the updated list", body = CollaboratorList),
+        (status = 400, description = "Invalid role, or the user owns the stack", body = ErrorResponse),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack or user", body = ErrorResponse),
+    )
+)]
+#[put("/stacks/{stack_id}/collaborators/{username}")]
+pub async fn set_collaborator(
+    users: web::Data<UserStore>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+    update: web::Json<RoleUpdate>,
+) -> Result<HttpResponse, Error> {
+    let (stack_id, username) = path.into_inner();
+    set_collaborator_impl(&users, principal, stack_id, username, update.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "collaborators",
+    params(
+        ("stack_id" = String, Path, description = "Stack id"),
+        ("username" = String, Path, description = "Collaborator to remove"),
+    ),
+    responses(
+        (status = 204, description = "The collaborator was removed"),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the user is not a collaborator", body = ErrorResponse),
+    )
+)]
+#[delete("/stacks/{stack_id}/collaborators/{username}")]
+pub async fn remove_collaborator(
+    users: web::Data<UserStore>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (stack_id, username) = path.into_inner();
+    remove_collaborator_impl(&users, principal, stack_id, username).await
+}
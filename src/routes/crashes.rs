@@ -0,0 +1,34 @@
+use actix_web::{get, web, Error, HttpResponse};
+
+use crate::auth::Principal;
+use crate::crashes::{self, CrashRecord};
+use crate::openapi::ErrorResponse;
+use crate::routes::error::ApiError;
+use crate::stacks::StackRole;
+
+/// Crashes of a stack, most recent first, for its viewers.
+pub fn crash_records(principal: &Principal, stack_id: &str) -> Result<Vec<CrashRecord>, ApiError> {
+    principal.authorize(stack_id, StackRole::Viewer)?;
+
+    let mut records = crashes::load(stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to read crashes.json: {}", e)))?;
+    records.reverse();
+    Ok(records)
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "The last 20 crashes of the stack, most recent first", body = [CrashRecord]),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[get("/stacks/{stack_id}/crashes")]
+pub async fn list_crashes(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let records = crash_records(&principal, &stack_id).map_err(ApiError::into_legacy)?;
+    Ok(HttpResponse::Ok().json(records))
+}
@@ -1,66 +1,98 @@
-use actix_web::{post, Error, HttpResponse, ResponseError};
+mod transaction;
+
+use actix_web::{http::StatusCode, post, web, Error, HttpResponse};
+use chrono::Utc;
 use num_cpus;
 use regex::Regex;
-use serde_json::json;
-use std::fmt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::fs;
-use std::path::PathBuf;
-use tokio::process::Command;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Mutex, MutexGuard};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::{Principal, Scope};
+use crate::backups::{self, BackupManifest};
+use crate::events::{EventBus, StackEvent};
+use crate::jobs::{Job, JobHandle, JobKind, JobStore};
+use crate::openapi::ErrorResponse;
+use crate::routes::error::{ApiError, ErrorCode, LegacyError};
+use crate::routes::jobs::{accepted, JobReference};
+use crate::stacks::{self, DesiredState, StackMeta, StackPorts};
+use crate::trash;
+use crate::users::{Quota, UserStore};
+
+use transaction::{CreateStep, CreateTransaction, StepFailure};
 
-const INCREMENT: i32 = 3;
+const INCREMENT: u32 = 3;
 const ENV_TEMPLATE: &str = include_str!("../../template/.env");
 const COMPOSE_TEMPLATE: &str = include_str!("../../template/compose.yaml");
+const DEFAULT_MEMORY_MB: u32 = 1024;
+const MIN_MEMORY_MB: u32 = 512;
+const MAX_NAME_LENGTH: usize = 64;
+const MAX_TAGS: usize = 16;
+// One week
+pub const MAX_IDLE_TIMEOUT_MINUTES: u32 = 10_080;
 
-#[derive(Debug)]
-enum CreateStackError {
-    Validation(String),
-    FileSystem(String),
-    Docker(String),
-}
+// Serializes stack number allocation between concurrent create jobs
+static CREATE_LOCK: Mutex<()> = Mutex::const_new(());
 
-impl fmt::Display for CreateStackError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::Validation(msg) | Self::FileSystem(msg) | Self::Docker(msg) => {
-                write!(f, "{}", msg)
-            }
-        }
-    }
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct NewStack {
+    /// Memory of the Minecraft server, 1024 MB by default
+    #[schema(minimum = 512)]
+    memory_mb: Option<u32>,
+    /// Display name, up to 64 characters
+    name: Option<String>,
+    /// Labels to filter stacks by, lowercase letters, digits, `-` and `_`
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Minecraft version to run, `latest` by default
+    #[schema(example = "1.21.1")]
+    version: Option<String>,
+    /// Stop the stack after this many minutes without players, never by default
+    #[schema(minimum = 1, maximum = 10080)]
+    idle_timeout_minutes: Option<u32>,
 }
 
-impl ResponseError for CreateStackError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
-        match self {
-            CreateStackError::Validation(_) => actix_web::http::StatusCode::FORBIDDEN,
-            CreateStackError::FileSystem(_) | CreateStackError::Docker(_) => {
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
-            }
-        }
-    }
+/// Result of a create job.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedStack {
+    pub stack_id: u32,
+    pub ports: StackPorts,
+}
 
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .content_type("application/json")
-            .json(json!({ "message": self.to_string() }))
-    }
+/// What a create job needs to know about the request.
+struct CreateRequest {
+    owner: Option<Uuid>,
+    // Quota to enforce, `None` for administrators and host keys
+    quota: Option<Quota>,
+    memory_mb: u32,
+    name: Option<String>,
+    tags: Vec<String>,
+    version: Option<String>,
+    idle_timeout_minutes: Option<u32>,
+    restart_policy: Option<String>,
+    // Backup whose world the stack starts with, instead of a new one
+    backup: Option<BackupManifest>,
 }
 
 struct EnvConfig {
-    server_port: i32,
-    rcon_port: i32,
-    sftp_port: i32,
+    server_port: u16,
+    rcon_port: u16,
+    sftp_port: u16,
 }
 
-fn parse_env_template() -> Result<EnvConfig, CreateStackError> {
-    let get_port = |var_name: &str| -> Result<i32, CreateStackError> {
+fn parse_env_template() -> Result<EnvConfig, ApiError> {
+    let get_port = |var_name: &str| -> Result<u16, ApiError> {
         let re = Regex::new(&format!(r"^{}=(\d+)", var_name)).unwrap();
         ENV_TEMPLATE
             .lines()
             .find_map(|line| re.captures(line))
             .and_then(|cap| cap[1].parse().ok())
-            .ok_or_else(|| {
-                CreateStackError::Validation(format!("{} not found in env template", var_name))
-            })
+            .ok_or_else(|| ApiError::internal(format!("{} not found in env template", var_name)))
     };
 
     Ok(EnvConfig {
@@ -70,35 +102,88 @@ fn parse_env_template() -> Result<EnvConfig, CreateStackError> {
     })
 }
 
-async fn get_stacks_directory() -> Result<PathBuf, CreateStackError> {
+/// `.env` file of a stack and the ports it publishes, derived from its number.
+fn render_env(
+    stack_id: u32,
+    memory_mb: u32,
+    version: Option<&str>,
+    restart_policy: Option<&str>,
+) -> Result<(String, StackPorts), ApiError> {
+    let env_config = parse_env_template()?;
+
+    let port = |base: u16| {
+        u16::try_from(u32::from(base) + stack_id * INCREMENT)
+            .map_err(|_| ApiError::internal("No ports left for a new stack"))
+    };
+    let ports = StackPorts {
+        minecraft_server: port(env_config.server_port)?,
+        rcon: port(env_config.rcon_port)?,
+        sftp_server: port(env_config.sftp_port)?,
+    };
+
+    let content = ENV_TEMPLATE
+        .lines()
+        .map(|line| {
+            if line.starts_with('#') || line.trim().is_empty() {
+                line.to_string()
+            } else {
+                match line.split('=').next() {
+                    Some("JAVA_XMS") => format!("JAVA_XMS={}M", memory_mb),
+                    Some("JAVA_XMX") => format!("JAVA_XMX={}M", memory_mb),
+                    Some("MINECRAFT_VERSION") => match version {
+                        Some(version) => format!("MINECRAFT_VERSION={}", version),
+                        None => line.to_string(),
+                    },
+                    Some("RESTART_POLICY") => match restart_policy {
+                        Some(policy) => format!("RESTART_POLICY={}", policy),
+                        None => line.to_string(),
+                    },
+                    Some("SERVER_PORT") => format!("SERVER_PORT={}", ports.minecraft_server),
+                    Some("RCON_PORT") => format!("RCON_PORT={}", ports.rcon),
+                    Some("SFTP_SERVER_PORT") => format!("SFTP_SERVER_PORT={}", ports.sftp_server),
+                    Some("MINECRAFT_SERVER_SERVICE") => {
+                        format!("MINECRAFT_SERVER_SERVICE=minecraft_server_{}", stack_id)
+                    }
+                    Some("MINECRAFT_SERVER_VOLUME") => {
+                        format!("MINECRAFT_SERVER_VOLUME=minecraft_server_{}", stack_id)
+                    }
+                    Some("MINECRAFT_SERVER_NETWORK") => {
+                        format!("MINECRAFT_SERVER_NETWORK=minecraft_server_{}", stack_id)
+                    }
+                    Some("SFTP_SERVER_SERVICE") => {
+                        format!("SFTP_SERVER_SERVICE=sftp_server_{}", stack_id)
+                    }
+                    _ => line.to_string(),
+                }
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    Ok((content, ports))
+}
+
+async fn get_stacks_directory() -> Result<PathBuf, ApiError> {
     let current_exe = std::env::current_exe()
-        .map_err(|e| CreateStackError::FileSystem(format!("Failed to get current path: {}", e)))?;
+        .map_err(|e| ApiError::internal(format!("Failed to get current path: {}", e)))?;
 
     let stacks_dir = current_exe
         .parent()
-        .ok_or_else(|| {
-            CreateStackError::FileSystem("Failed to find executable directory".to_string())
-        })?
+        .ok_or_else(|| ApiError::internal("Failed to find executable directory".to_string()))?
         .join("stacks");
 
     if !stacks_dir.exists() {
-        fs::create_dir_all(&stacks_dir).map_err(|e| {
-            CreateStackError::FileSystem(format!("Failed to create stacks directory: {}", e))
-        })?;
+        fs::create_dir_all(&stacks_dir)
+            .map_err(|e| ApiError::internal(format!("Failed to create stacks directory: {}", e)))?;
     }
 
     Ok(stacks_dir)
 }
 
-async fn create_stack_impl() -> Result<HttpResponse, Error> {
-    let stacks_dir = get_stacks_directory().await?;
-
-    // Check maximum stacks limit
+fn check_stack_limit(stacks_dir: &Path) -> Result<(), ApiError> {
     let max_stacks = num_cpus::get();
-    let stack_count = fs::read_dir(&stacks_dir)
-        .map_err(|e| {
-            CreateStackError::FileSystem(format!("Failed to read stacks directory: {}", e))
-        })?
+    let stack_count = fs::read_dir(stacks_dir)
+        .map_err(|e| ApiError::internal(format!("Failed to read stacks directory: {}", e)))?
         .filter(|entry| {
             entry
                 .as_ref()
@@ -108,115 +193,422 @@ async fn create_stack_impl() -> Result<HttpResponse, Error> {
         .count();
 
     if stack_count >= max_stacks {
-        return Err(CreateStackError::Validation(format!(
-            "Maximum number of stacks ({}) reached",
-            max_stacks
-        )))?;
+        return Err(ApiError::new(
+            ErrorCode::StackLimitReached,
+            format!("Maximum number of stacks ({}) reached", max_stacks),
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_name(name: Option<String>) -> Result<Option<String>, ApiError> {
+    let Some(name) = name.map(|name| name.trim().to_string()) else {
+        return Ok(None);
+    };
+    if name.is_empty()
+        || name.chars().count() > MAX_NAME_LENGTH
+        || name.chars().any(char::is_control)
+    {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("Name must be 1 to {} printable characters", MAX_NAME_LENGTH),
+        ));
+    }
+    Ok(Some(name))
+}
+
+fn validate_tags(mut tags: Vec<String>) -> Result<Vec<String>, ApiError> {
+    let tag_re = Regex::new(r"^[a-z0-9][a-z0-9_-]{0,31}$").unwrap();
+    if let Some(tag) = tags.iter().find(|tag| !tag_re.is_match(tag)) {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!(
+                "Invalid tag: '{}'. Tags are up to 32 lowercase letters, digits, '-' and '_'",
+                tag
+            ),
+        ));
+    }
+
+    tags.sort();
+    tags.dedup();
+    if tags.len() > MAX_TAGS {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("A stack has at most {} tags", MAX_TAGS),
+        ));
+    }
+    Ok(tags)
+}
+
+fn validate_version(version: Option<String>) -> Result<Option<String>, ApiError> {
+    let version_re = Regex::new(r"^[A-Za-z0-9._-]{1,32}$").unwrap();
+    match version {
+        Some(version) if !version_re.is_match(&version) => Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("Invalid Minecraft version: '{}'", version),
+        )),
+        version => Ok(version),
+    }
+}
+
+pub fn validate_idle_timeout(minutes: Option<u32>) -> Result<Option<u32>, ApiError> {
+    match minutes {
+        Some(minutes) if minutes == 0 || minutes > MAX_IDLE_TIMEOUT_MINUTES => Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!(
+                "Idle timeout must be between 1 and {} minutes",
+                MAX_IDLE_TIMEOUT_MINUTES
+            ),
+        )),
+        minutes => Ok(minutes),
+    }
+}
+
+fn check_quota(request: &CreateRequest) -> Result<(), ApiError> {
+    let (Some(owner), Some(quota)) = (request.owner, request.quota) else {
+        return Ok(());
+    };
+
+    let (stacks, memory_mb) = stacks::owned_usage(&owner)
+        .map_err(|e| ApiError::internal(format!("Failed to read stacks: {}", e)))?;
+
+    if stacks >= quota.max_stacks {
+        return Err(ApiError::new(
+            ErrorCode::QuotaExceeded,
+            format!(
+                "Stack quota reached ({} of {} stacks)",
+                stacks, quota.max_stacks
+            ),
+        ));
+    }
+
+    if memory_mb + request.memory_mb > quota.max_memory_mb {
+        return Err(ApiError::new(
+            ErrorCode::QuotaExceeded,
+            format!(
+                "Memory quota exceeded ({} MB used, {} MB requested, {} MB allowed)",
+                memory_mb, request.memory_mb, quota.max_memory_mb
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn build_stack(
+    transaction: &mut CreateTransaction,
+    env: &str,
+    meta: &StackMeta,
+    world: Option<(&BackupManifest, MutexGuard<'static, ()>)>,
+    job: &JobHandle,
+) -> Result<(), StepFailure> {
+    transaction.create_directory()?;
+    transaction.write_file(CreateStep::WriteEnv, ".env", env)?;
+    transaction.write_meta(meta).await?;
+    // Written last, the stack only counts as existing once it is complete
+    transaction.write_file(CreateStep::WriteCompose, "compose.yaml", COMPOSE_TEMPLATE)?;
+
+    job.step(20, "Wrote stack configuration").await;
+    if let Some((backup, backup_guard)) = world {
+        job.step(25, "Restoring the world of the backup").await;
+        transaction.import_world(backup).await?;
+        drop(backup_guard);
     }
+    job.step(
+        30,
+        "Starting containers, this may take a while if images must be pulled",
+    )
+    .await;
+
+    transaction.start_containers().await
+}
+
+async fn create_stack_impl(
+    events: Arc<EventBus>,
+    request: CreateRequest,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    let _guard = CREATE_LOCK.lock().await;
+
+    let stacks_dir = get_stacks_directory().await?;
+
+    // Held until the world is imported, so garbage collection leaves its chunks alone
+    let backup_guard = match &request.backup {
+        Some(backup) => {
+            let guard = backups::LOCK.lock().await;
+            let manifest = backup.clone();
+            tokio::task::spawn_blocking(move || manifest.verify())
+                .await
+                .map_err(|e| ApiError::internal(e.to_string()))?
+                .map_err(|e| ApiError::internal(format!("Failed to verify the backup: {}", e)))?;
+            Some(guard)
+        }
+        None => None,
+    };
+
+    // Check limits again, other jobs may have run since the request
+    check_stack_limit(&stacks_dir)?;
+    check_quota(&request)?;
 
     // Find highest existing stack number
     let mut highest_number = 0;
-    for entry in fs::read_dir(&stacks_dir).map_err(|e| {
-        CreateStackError::FileSystem(format!("Failed to read stacks directory: {}", e))
-    })? {
-        let entry = entry.map_err(|e| {
-            CreateStackError::FileSystem(format!("Failed to read directory entry: {}", e))
-        })?;
+    for entry in fs::read_dir(&stacks_dir)
+        .map_err(|e| ApiError::internal(format!("Failed to read stacks directory: {}", e)))?
+    {
+        let entry = entry
+            .map_err(|e| ApiError::internal(format!("Failed to read directory entry: {}", e)))?;
         if let Some(num_str) = entry.file_name().to_string_lossy().strip_prefix("stack_") {
-            if let Ok(num) = num_str.parse::<i32>() {
+            if let Ok(num) = num_str.parse::<u32>() {
                 highest_number = highest_number.max(num);
             }
         }
     }
 
+    // Numbers of stacks in the trash stay taken, so they can be restored
+    let trashed = trash::stack_numbers()
+        .map_err(|e| ApiError::internal(format!("Failed to read the trash: {}", e)))?;
+    highest_number = trashed.into_iter().fold(highest_number, u32::max);
+
     let new_stack_id = highest_number + 1;
     let new_stack_dir = stacks_dir.join(format!("stack_{}", new_stack_id));
 
-    let env_config = parse_env_template()?;
+    let (new_content, ports) = render_env(
+        new_stack_id,
+        request.memory_mb,
+        request.version.as_deref(),
+        request.restart_policy.as_deref(),
+    )?;
 
-    let new_server_port = env_config.server_port + new_stack_id * INCREMENT;
-    let new_rcon_port = env_config.rcon_port + new_stack_id * INCREMENT;
-    let new_sftp_port = env_config.sftp_port + new_stack_id * INCREMENT;
+    job.set_stack_id(new_stack_id.to_string()).await;
+    job.step(10, format!("Allocated stack {}", new_stack_id))
+        .await;
 
-    // Create new stack directory
-    fs::create_dir_all(&new_stack_dir).map_err(|e| {
-        CreateStackError::FileSystem(format!("Failed to create stack directory: {}", e))
-    })?;
+    let mut transaction = CreateTransaction::new(new_stack_id, new_stack_dir);
+    let meta = StackMeta {
+        owner: request.owner,
+        collaborators: Vec::new(),
+        created_at: Some(Utc::now()),
+        name: request.name,
+        tags: request.tags,
+        idle_timeout_minutes: request.idle_timeout_minutes,
+        desired_state: Some(DesiredState::Running),
+        backup_schedule: None,
+    };
+    if let Err(failure) = build_stack(
+        &mut transaction,
+        &new_content,
+        &meta,
+        request.backup.as_ref().zip(backup_guard),
+        &job,
+    )
+    .await
+    {
+        return Err(transaction.rollback(failure, &job).await);
+    }
 
-    // Create env file with updated values
-    let new_content = ENV_TEMPLATE
-        .lines()
-        .map(|line| {
-            if line.starts_with('#') || line.trim().is_empty() {
-                line.to_string()
-            } else {
-                match line.split('=').next() {
-                    Some("SERVER_PORT") => format!("SERVER_PORT={}", new_server_port),
-                    Some("RCON_PORT") => format!("RCON_PORT={}", new_rcon_port),
-                    Some("SFTP_SERVER_PORT") => format!("SFTP_SERVER_PORT={}", new_sftp_port),
-                    Some("MINECRAFT_SERVER_SERVICE") => {
-                        format!("MINECRAFT_SERVER_SERVICE=minecraft_server_{}", new_stack_id)
-                    }
-                    Some("MINECRAFT_SERVER_VOLUME") => {
-                        format!("MINECRAFT_SERVER_VOLUME=minecraft_server_{}", new_stack_id)
-                    }
-                    Some("MINECRAFT_SERVER_NETWORK") => {
-                        format!("MINECRAFT_SERVER_NETWORK=minecraft_server_{}", new_stack_id)
-                    }
-                    Some("SFTP_SERVER_SERVICE") => {
-                        format!("SFTP_SERVER_SERVICE=sftp_server_{}", new_stack_id)
-                    }
-                    _ => line.to_string(),
-                }
-            }
+    job.step(90, "Containers started").await;
+
+    events.publish(StackEvent::StackCreated {
+        stack_id: new_stack_id.to_string(),
+    });
+
+    serde_json::to_value(CreatedStack {
+        stack_id: new_stack_id,
+        ports,
+    })
+    .map_err(|e| ApiError::internal(format!("Failed to serialize job result: {}", e)))
+}
+
+/// Writes the configuration of a stack whose Docker resources exist without it.
+///
+/// The stack gets the default settings and no owner, its world volume is kept.
+pub async fn adopt_stack(stack_id: u32) -> Result<StackPorts, ApiError> {
+    let _guard = CREATE_LOCK.lock().await;
+
+    let stack_dir = get_stacks_directory()
+        .await?
+        .join(format!("stack_{}", stack_id));
+    if stack_dir.join("compose.yaml").exists() {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("Stack {} already exists", stack_id),
+        ));
+    }
+
+    let (env, ports) = render_env(stack_id, DEFAULT_MEMORY_MB, None, None)?;
+    fs::create_dir_all(&stack_dir)
+        .map_err(|e| ApiError::internal(format!("Failed to create stack directory: {}", e)))?;
+    fs::write(stack_dir.join(".env"), env)
+        .map_err(|e| ApiError::internal(format!("Failed to write .env file: {}", e)))?;
+
+    StackMeta {
+        created_at: Some(Utc::now()),
+        ..StackMeta::default()
+    }
+    .save(&stack_id.to_string())
+    .await
+    .map_err(|e| ApiError::internal(format!("Failed to write stack.json: {}", e)))?;
+
+    // Written last, the stack only counts as existing once it is complete
+    fs::write(stack_dir.join("compose.yaml"), COMPOSE_TEMPLATE)
+        .map_err(|e| ApiError::internal(format!("Failed to write compose.yaml: {}", e)))?;
+
+    Ok(ports)
+}
+
+/// Checks the request against the limits and starts the create job.
+pub async fn start_create_stack(
+    jobs: Arc<JobStore>,
+    events: Arc<EventBus>,
+    users: &UserStore,
+    principal: &Principal,
+    new_stack: NewStack,
+) -> Result<Job, ApiError> {
+    let memory_mb = new_stack.memory_mb.unwrap_or(DEFAULT_MEMORY_MB);
+    if memory_mb < MIN_MEMORY_MB {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("Memory must be at least {} MB", MIN_MEMORY_MB),
+        ));
+    }
+
+    let quota = match principal.user_id {
+        Some(user_id) if principal.scope != Scope::Admin => users.get(&user_id).map(|u| u.quota),
+        _ => None,
+    };
+    let request = CreateRequest {
+        owner: principal.user_id,
+        quota,
+        memory_mb,
+        name: validate_name(new_stack.name)?,
+        tags: validate_tags(new_stack.tags)?,
+        version: validate_version(new_stack.version)?,
+        idle_timeout_minutes: validate_idle_timeout(new_stack.idle_timeout_minutes)?,
+        restart_policy: None,
+        backup: None,
+    };
+
+    // Fail fast on the limits so clients get an immediate answer
+    check_stack_limit(&get_stacks_directory().await?)?;
+    check_quota(&request)?;
+
+    Ok(jobs
+        .spawn(JobKind::CreateStack, None, principal.user_id, move |job| {
+            create_stack_impl(events, request, job)
         })
-        .collect::<Vec<String>>()
-        .join("\n");
+        .await)
+}
 
-    // Write files
-    fs::write(new_stack_dir.join(".env"), new_content)
-        .map_err(|e| CreateStackError::FileSystem(format!("Failed to write .env file: {}", e)))?;
+fn backup_request(
+    owner: Option<Uuid>,
+    quota: Option<Quota>,
+    backup: BackupManifest,
+) -> Result<CreateRequest, ApiError> {
+    let config = &backup.config;
+    Ok(CreateRequest {
+        owner,
+        quota,
+        memory_mb: config.memory_mb.max(MIN_MEMORY_MB),
+        name: validate_name(config.name.clone())?,
+        tags: validate_tags(config.tags.clone())?,
+        version: validate_version(Some(backup.minecraft_version.clone()))?,
+        idle_timeout_minutes: validate_idle_timeout(config.idle_timeout_minutes)?,
+        restart_policy: Some(config.restart_policy.clone()),
+        backup: Some(backup),
+    })
+}
 
-    fs::write(new_stack_dir.join("compose.yaml"), COMPOSE_TEMPLATE).map_err(|e| {
-        CreateStackError::FileSystem(format!("Failed to write compose.yaml: {}", e))
-    })?;
+/// Starts a job creating a stack for the caller with the settings and world of a backup.
+pub async fn start_create_from_backup(
+    jobs: Arc<JobStore>,
+    events: Arc<EventBus>,
+    users: &UserStore,
+    principal: &Principal,
+    backup: BackupManifest,
+) -> Result<Job, ApiError> {
+    let quota = match principal.user_id {
+        Some(user_id) if principal.scope != Scope::Admin => users.get(&user_id).map(|u| u.quota),
+        _ => None,
+    };
+    let request = backup_request(principal.user_id, quota, backup)?;
 
-    // Start the containers
-    let output = Command::new("docker")
-        .args([
-            "compose",
-            "-f",
-            new_stack_dir.join("compose.yaml").to_str().unwrap(),
-            "up",
-            "-d",
-        ])
-        .output()
-        .await
-        .map_err(|e| {
-            CreateStackError::Docker(format!("Failed to execute docker compose: {}", e))
-        })?;
-
-    if !output.status.success() {
-        fs::remove_dir_all(&new_stack_dir).map_err(|e| {
-            CreateStackError::FileSystem(format!("Failed to cleanup failed stack: {}", e))
-        })?;
-
-        return Err(CreateStackError::Docker(
-            "Failed to start containers. Stack creation rolled back".to_string(),
-        ))?;
-    }
-
-    Ok(HttpResponse::Created().json(json!({
-        "stack_id": new_stack_id.to_string(),
-        "ports": {
-            "minecraft_server": new_server_port.to_string(),
-            "rcon": new_rcon_port.to_string(),
-            "sftp_server": new_sftp_port.to_string()
-        }
-    })))
+    check_stack_limit(&get_stacks_directory().await?)?;
+    check_quota(&request)?;
+
+    Ok(jobs
+        .spawn(
+            JobKind::RestoreBackup,
+            None,
+            principal.user_id,
+            move |job| create_stack_impl(events, request, job),
+        )
+        .await)
 }
 
+/// Creates a stack from a backup within a running job, for admin-scope callers who
+/// have no quota.
+pub async fn create_from_backup(
+    events: Arc<EventBus>,
+    owner: Option<Uuid>,
+    backup: BackupManifest,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    let request = backup_request(owner, None, backup)?;
+    create_stack_impl(events, request, job).await
+}
+
+/// Reads the body of a creation, which is optional but must be valid when present.
+pub fn parse_new_stack(body: &[u8]) -> Result<NewStack, ApiError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(NewStack::default());
+    }
+    serde_json::from_slice(body).map_err(|e| {
+        let code = if e.is_data() {
+            ErrorCode::ValidationFailed
+        } else {
+            ErrorCode::MalformedRequest
+        };
+        ApiError::new(code, format!("Invalid request body: {}", e))
+    })
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    request_body(content = Option<NewStack>, description = "Optional, every field has a default"),
+    responses(
+        (status = 202, description = "Creation started, the job result holds the stack id and ports", body = JobReference),
+        (status = 400, description = "The body is not a valid stack", body = ErrorResponse),
+        (status = 403, description = "Invalid memory, stack limit or quota reached", body = ErrorResponse),
+        (status = 500, description = "The stacks directory could not be read", body = ErrorResponse),
+    )
+)]
 #[post("/stacks")]
-pub async fn create_stack() -> Result<HttpResponse, Error> {
-    create_stack_impl().await
+pub async fn create_stack(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    users: web::Data<UserStore>,
+    principal: Principal,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let new_stack = parse_new_stack(&body).map_err(ApiError::into_legacy)?;
+
+    let job = start_create_stack(
+        jobs.into_inner(),
+        events.into_inner(),
+        &users,
+        &principal,
+        new_stack,
+    )
+    .await
+    .map_err(|e| match e.code {
+        // API v1 refused every creation it could not honor with 403
+        ErrorCode::ValidationFailed => LegacyError {
+            status: StatusCode::FORBIDDEN,
+            message: e.message,
+        },
+        _ => e.into_legacy(),
+    })?;
+
+    Ok(accepted(&job))
 }
@@ -0,0 +1,297 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::backups::BackupManifest;
+use crate::jobs::JobHandle;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::stacks::StackMeta;
+use crate::volumes;
+
+/// Steps of a stack creation, in order.
+#[derive(Debug, Clone, Copy)]
+pub enum CreateStep {
+    CreateDirectory,
+    WriteEnv,
+    WriteMeta,
+    WriteCompose,
+    ImportWorld,
+    StartContainers,
+}
+
+impl fmt::Display for CreateStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreateStep::CreateDirectory => write!(f, "creating the stack directory"),
+            CreateStep::WriteEnv => write!(f, "writing .env"),
+            CreateStep::WriteMeta => write!(f, "writing stack.json"),
+            CreateStep::WriteCompose => write!(f, "writing compose.yaml"),
+            CreateStep::ImportWorld => write!(f, "restoring the world"),
+            CreateStep::StartContainers => write!(f, "starting containers"),
+        }
+    }
+}
+
+/// A step that failed, with the error it gave.
+#[derive(Debug)]
+pub struct StepFailure {
+    step: CreateStep,
+    code: ErrorCode,
+    message: String,
+}
+
+impl StepFailure {
+    fn new(step: CreateStep, message: impl Into<String>) -> Self {
+        Self {
+            step,
+            code: ErrorCode::InternalError,
+            message: message.into(),
+        }
+    }
+}
+
+/// Something a creation made, removed again when it rolls back.
+#[derive(Debug)]
+enum Resource {
+    Directory(PathBuf),
+    Container(String),
+    Volume(String),
+    Network(String),
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Directory(path) => write!(f, "directory {}", path.display()),
+            Resource::Container(name) => write!(f, "container {}", name),
+            Resource::Volume(name) => write!(f, "volume {}", name),
+            Resource::Network(name) => write!(f, "network {}", name),
+        }
+    }
+}
+
+impl Resource {
+    // Arguments of the inspect command telling whether it exists
+    fn inspect_args(&self) -> Option<[&str; 3]> {
+        match self {
+            Resource::Directory(_) => None,
+            Resource::Container(name) => Some(["container", "inspect", name]),
+            Resource::Volume(name) => Some(["volume", "inspect", name]),
+            Resource::Network(name) => Some(["network", "inspect", name]),
+        }
+    }
+
+    async fn exists(&self) -> bool {
+        match self.inspect_args() {
+            Some(args) => Command::new("docker")
+                .args(args)
+                .output()
+                .await
+                .map(|output| output.status.success())
+                .unwrap_or(false),
+            None => false,
+        }
+    }
+
+    async fn remove(&self) -> Result<(), String> {
+        let args: Vec<&str> = match self {
+            Resource::Directory(path) => {
+                return fs::remove_dir_all(path).map_err(|e| e.to_string());
+            }
+            Resource::Container(name) => vec!["rm", "-f", name],
+            Resource::Volume(name) => vec!["volume", "rm", name],
+            Resource::Network(name) => vec!["network", "rm", name],
+        };
+
+        let output = Command::new("docker")
+            .args(&args)
+            .output()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Creation of a stack, recording every resource made so a failure undoes all of them.
+pub struct CreateTransaction {
+    stack_id: u32,
+    directory: PathBuf,
+    created: Vec<Resource>,
+}
+
+impl CreateTransaction {
+    pub fn new(stack_id: u32, directory: PathBuf) -> Self {
+        Self {
+            stack_id,
+            directory,
+            created: Vec::new(),
+        }
+    }
+
+    pub fn create_directory(&mut self) -> Result<(), StepFailure> {
+        let step = CreateStep::CreateDirectory;
+        if self.directory.exists() {
+            return Err(StepFailure::new(
+                step,
+                format!("{} already exists", self.directory.display()),
+            ));
+        }
+
+        fs::create_dir_all(&self.directory).map_err(|e| StepFailure::new(step, e.to_string()))?;
+        self.created
+            .push(Resource::Directory(self.directory.clone()));
+        Ok(())
+    }
+
+    /// Writes a file of the stack directory, through a temporary file so it is never half written.
+    pub fn write_file(
+        &mut self,
+        step: CreateStep,
+        name: &str,
+        content: &str,
+    ) -> Result<(), StepFailure> {
+        write_atomically(&self.directory.join(name), content)
+            .map_err(|e| StepFailure::new(step, e.to_string()))
+    }
+
+    pub async fn write_meta(&mut self, meta: &StackMeta) -> Result<(), StepFailure> {
+        meta.save(&self.stack_id.to_string())
+            .await
+            .map_err(|e| StepFailure::new(CreateStep::WriteMeta, e.to_string()))
+    }
+
+    /// Fills the stack's world volume from a backup before its containers start.
+    pub async fn import_world(&mut self, backup: &BackupManifest) -> Result<(), StepFailure> {
+        let step = CreateStep::ImportWorld;
+        let volume = format!("minecraft_server_{}", self.stack_id);
+
+        // An orphan volume holds a world of its own, it is not overwritten
+        let exists = volumes::exists(&volume).await.map_err(|e| StepFailure {
+            step,
+            code: ErrorCode::DockerUnavailable,
+            message: format!("Failed to execute docker volume inspect: {}", e),
+        })?;
+        if exists {
+            return Err(StepFailure {
+                step,
+                code: ErrorCode::ValidationFailed,
+                message: format!("Volume {} already exists", volume),
+            });
+        }
+
+        // A failed import removes the volume itself
+        backup
+            .restore(&volume)
+            .await
+            .map_err(|e| StepFailure::new(step, e.to_string()))?;
+        self.created.push(Resource::Volume(volume));
+        Ok(())
+    }
+
+    /// Runs `docker compose up -d`, recording what it created even when it fails.
+    pub async fn start_containers(&mut self) -> Result<(), StepFailure> {
+        let step = CreateStep::StartContainers;
+
+        // Resources existing beforehand, such as an orphan volume, are not the creation's to remove
+        let mut expected = Vec::new();
+        for resource in self.docker_resources() {
+            if !resource.exists().await {
+                expected.push(resource);
+            }
+        }
+
+        let output = Command::new("docker")
+            .args([
+                "compose",
+                "-f",
+                self.directory.join("compose.yaml").to_str().unwrap(),
+                "up",
+                "-d",
+            ])
+            .output()
+            .await;
+
+        for resource in expected {
+            if resource.exists().await {
+                self.created.push(resource);
+            }
+        }
+
+        let output = output.map_err(|e| StepFailure {
+            step,
+            code: ErrorCode::DockerUnavailable,
+            message: format!("Failed to execute docker compose: {}", e),
+        })?;
+        if !output.status.success() {
+            return Err(StepFailure::new(
+                step,
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Names the compose file gives the stack's Docker resources, see `render_env`
+    fn docker_resources(&self) -> Vec<Resource> {
+        let name = |prefix: &str| format!("{}_{}", prefix, self.stack_id);
+        vec![
+            Resource::Container(name("minecraft_server")),
+            Resource::Container(name("sftp_server")),
+            Resource::Network(name("minecraft_server")),
+            Resource::Volume(name("minecraft_server")),
+        ]
+    }
+
+    /// Removes what was created and describes the failure.
+    pub async fn rollback(mut self, failure: StepFailure, job: &JobHandle) -> ApiError {
+        job.step(90, format!("Failed while {}, rolling back", failure.step))
+            .await;
+
+        let mut removed = Vec::new();
+        let mut left = Vec::new();
+        // Containers go before the network and volume they use, the directory last
+        self.created.sort_by_key(|resource| match resource {
+            Resource::Container(_) => 0,
+            Resource::Network(_) | Resource::Volume(_) => 1,
+            Resource::Directory(_) => 2,
+        });
+        for resource in &self.created {
+            match resource.remove().await {
+                Ok(()) => {
+                    job.step(90, format!("Removed {}", resource)).await;
+                    removed.push(resource.to_string());
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to remove {} after a failed creation: {}",
+                        resource,
+                        e
+                    );
+                    left.push(format!("{} ({})", resource, e));
+                }
+            }
+        }
+
+        let mut message = format!(
+            "Stack creation failed while {}: {}",
+            failure.step, failure.message
+        );
+        if !removed.is_empty() {
+            message.push_str(&format!(". Rolled back: {}", removed.join(", ")));
+        }
+        if !left.is_empty() {
+            message.push_str(&format!(". Could not remove: {}", left.join(", ")));
+        }
+        ApiError::new(failure.code, message)
+    }
+}
+
+fn write_atomically(path: &Path, content: &str) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content)?;
+    fs::rename(&tmp_path, path)
+}
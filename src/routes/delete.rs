@@ -1,119 +1,261 @@
-use actix_web::{delete, web, Error, HttpResponse, ResponseError};
-use serde_json::json;
-use std::fmt;
+use actix_web::{delete, web, Error, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::fs;
 use tokio::process::Command;
+use utoipa::IntoParams;
+use uuid::Uuid;
 
-#[derive(Debug)]
-enum DeleteStackError {
-    StackNotFound(String),
-    DockerError(String),
-    FileSystemError(String),
-}
+use crate::auth::Principal;
+use crate::config::Config;
+use crate::events::{EventBus, StackEvent};
+use crate::jobs::{Job, JobHandle, JobKind, JobStore};
+use crate::openapi::ErrorResponse;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::jobs::{accepted, JobReference};
+use crate::stacks::{self, StackMeta, StackRole};
+use crate::storage;
+use crate::trash::{self, TrashEntry};
+use crate::volumes;
 
-impl fmt::Display for DeleteStackError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::StackNotFound(msg) | Self::DockerError(msg) | Self::FileSystemError(msg) => {
-                write!(f, "{}", msg)
-            }
-        }
-    }
-}
+// Left in the stack directory while it is being deleted
+pub const DELETION_FILE: &str = "deletion.json";
 
-impl ResponseError for DeleteStackError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
-        match self {
-            DeleteStackError::StackNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
-            DeleteStackError::DockerError(_) | DeleteStackError::FileSystemError(_) => {
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
-            }
-        }
-    }
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct DeleteQuery {
+    /// Delete for good instead of moving the stack to the trash
+    #[serde(default)]
+    pub permanent: bool,
+}
 
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .content_type("application/json")
-            .json(json!({ "message": self.to_string() }))
-    }
+#[derive(Debug, Serialize, Deserialize)]
+struct Deletion {
+    started_at: DateTime<Utc>,
+    /// Trash entry receiving the stack, `None` for a permanent deletion
+    trash_id: Option<Uuid>,
 }
 
-async fn get_compose_file_path(stack_id: &str) -> Result<PathBuf, DeleteStackError> {
-    let current_exe = std::env::current_exe().map_err(|e| {
-        DeleteStackError::FileSystemError(format!("Failed to get current path: {}", e))
-    })?;
+async fn get_compose_file_path(stack_id: &str) -> Result<PathBuf, ApiError> {
+    let current_exe = std::env::current_exe()
+        .map_err(|e| ApiError::internal(format!("Failed to get current path: {}", e)))?;
 
     let stack_dir = current_exe
         .parent() // bin directory
-        .ok_or_else(|| {
-            DeleteStackError::FileSystemError("Failed to find executable directory".to_string())
-        })?
+        .ok_or_else(|| ApiError::internal("Failed to find executable directory".to_string()))?
         .join("stacks")
         .join(format!("stack_{}", stack_id))
         .join("compose.yaml");
 
     if !stack_dir.exists() {
-        return Err(DeleteStackError::StackNotFound(format!(
-            "Stack {} does not exist",
-            stack_id
-        )));
+        return Err(ApiError::new(
+            ErrorCode::StackNotFound,
+            format!("Stack {} does not exist", stack_id),
+        ));
     }
 
     Ok(stack_dir)
 }
 
-async fn delete_stack_impl(stack_id: String) -> Result<HttpResponse, Error> {
+async fn delete_stack_impl(
+    stack_id: String,
+    number: u32,
+    trash: bool,
+    events: Arc<EventBus>,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
     // Get compose file path and stack directory
     let compose_file = get_compose_file_path(&stack_id).await?;
-    let stack_dir = compose_file.parent().ok_or_else(|| {
-        DeleteStackError::FileSystemError("Failed to get stack directory".to_string())
-    })?;
+    let stack_dir = compose_file
+        .parent()
+        .ok_or_else(|| ApiError::internal("Failed to get stack directory".to_string()))?
+        .to_path_buf();
+
+    // A deletion that failed continues the way it started when retried
+    let marker = stack_dir.join(DELETION_FILE);
+    let deletion = match storage::read_json::<Deletion>(&marker)
+        .map_err(|e| ApiError::internal(format!("Failed to read {}: {}", DELETION_FILE, e)))?
+    {
+        Some(deletion) => {
+            job.step(
+                5,
+                format!("Resuming the deletion started at {}", deletion.started_at),
+            )
+            .await;
+            deletion
+        }
+        None => {
+            let deletion = Deletion {
+                started_at: Utc::now(),
+                trash_id: trash.then(Uuid::new_v4),
+            };
+            storage::write_json(&marker, &deletion).await.map_err(|e| {
+                ApiError::internal(format!("Failed to write {}: {}", DELETION_FILE, e))
+            })?;
+            deletion
+        }
+    };
 
     // Step 1: Stop the stack using docker compose down
+    job.step(10, "Stopping containers").await;
     let output = Command::new("docker")
         .args(["compose", "-f", compose_file.to_str().unwrap(), "down"])
         .output()
         .await
         .map_err(|e| {
-            DeleteStackError::DockerError(format!("Failed to execute docker compose down: {}", e))
+            ApiError::new(
+                ErrorCode::DockerUnavailable,
+                format!("Failed to execute docker compose down: {}", e),
+            )
         })?;
 
     if !output.status.success() {
         let error_msg = String::from_utf8_lossy(&output.stderr);
-        return Err(DeleteStackError::DockerError(format!(
+        return Err(ApiError::internal(format!(
             "Failed to stop stack {}: {}",
             stack_id, error_msg
-        )))?;
+        )));
     }
 
-    // Step 2: Remove the Docker volume
+    // Step 2: Snapshot the world into the trash
     let volume_name = format!("minecraft_server_{}", stack_id);
-    let output = Command::new("docker")
-        .args(["volume", "rm", &volume_name])
-        .output()
-        .await
-        .map_err(|e| {
-            DeleteStackError::DockerError(format!("Failed to remove docker volume: {}", e))
-        })?;
+    let mut world = false;
+    if let Some(trash_id) = deletion.trash_id {
+        let entry_dir = trash::entry_directory(&trash_id)
+            .map_err(|e| ApiError::internal(format!("Failed to find trash directory: {}", e)))?;
+        fs::create_dir_all(&entry_dir)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to create trash entry: {}", e)))?;
 
-    if !output.status.success() {
-        let error_msg = String::from_utf8_lossy(&output.stderr);
-        return Err(DeleteStackError::DockerError(format!(
-            "Failed to remove minecraft server volume: {}",
-            error_msg
-        )))?;
+        // Already snapshotted when a previous attempt removed the volume
+        let archive = entry_dir.join(trash::WORLD_ARCHIVE);
+        if volume_exists(&volume_name).await? {
+            job.step(30, "Saving world volume to the trash").await;
+            volumes::export(&volume_name, &archive)
+                .await
+                .map_err(|e| ApiError::internal(e.to_string()))?;
+        }
+        world = archive.exists();
+    }
+
+    // Step 3: Remove the Docker volume
+    job.step(50, "Removing world volume").await;
+    volumes::remove(&volume_name).await.map_err(|e| {
+        ApiError::internal(format!("Failed to remove minecraft server volume: {}", e))
+    })?;
+
+    // Step 4: Move the stack directory to the trash, or remove it
+    match deletion.trash_id {
+        Some(trash_id) => {
+            job.step(80, "Moving stack directory to the trash").await;
+            let entry = TrashEntry {
+                id: trash_id,
+                stack_id: stack_id.clone(),
+                name: StackMeta::load(&stack_id).ok().and_then(|meta| meta.name),
+                deleted_at: Utc::now(),
+                world,
+            };
+            let trashed_dir = entry
+                .directory()
+                .map_err(|e| ApiError::internal(format!("Failed to find trash entry: {}", e)))?
+                .join(trash::STACK_DIRECTORY);
+            entry
+                .save()
+                .await
+                .map_err(|e| ApiError::internal(format!("Failed to write trash entry: {}", e)))?;
+            fs::rename(&stack_dir, &trashed_dir).await.map_err(|e| {
+                ApiError::internal(format!("Failed to move stack directory: {}", e))
+            })?;
+            let _ = fs::remove_file(trashed_dir.join(DELETION_FILE)).await;
+        }
+        None => {
+            job.step(80, "Removing stack directory").await;
+            fs::remove_dir_all(&stack_dir).await.map_err(|e| {
+                ApiError::internal(format!("Failed to remove stack directory: {}", e))
+            })?;
+        }
     }
 
-    // Step 3: Remove the stack directory
-    fs::remove_dir_all(stack_dir).await.map_err(|e| {
-        DeleteStackError::FileSystemError(format!("Failed to remove stack directory: {}", e))
+    events.publish(StackEvent::StackDeleted {
+        stack_id: stack_id.clone(),
+    });
+
+    Ok(json!({ "stack_id": number, "trash_id": deletion.trash_id }))
+}
+
+async fn volume_exists(volume: &str) -> Result<bool, ApiError> {
+    volumes::exists(volume).await.map_err(|e| {
+        ApiError::new(
+            ErrorCode::DockerUnavailable,
+            format!("Failed to inspect volume {}: {}", volume, e),
+        )
+    })
+}
+
+/// Checks the caller may delete the stack and starts the delete job.
+///
+/// The stack goes to the trash unless `permanent` is set or the trash is disabled.
+pub async fn start_delete_stack(
+    jobs: Arc<JobStore>,
+    events: Arc<EventBus>,
+    config: &Config,
+    principal: &Principal,
+    stack_id: String,
+    permanent: bool,
+) -> Result<Job, ApiError> {
+    // Unknown stacks are rejected right away rather than through a failed job
+    get_compose_file_path(&stack_id).await?;
+    let number = stacks::parse_id(&stack_id).ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::StackNotFound,
+            format!("Stack {} does not exist", stack_id),
+        )
     })?;
 
-    Ok(HttpResponse::NoContent().finish())
+    principal.authorize(&stack_id, StackRole::Admin)?;
+
+    let trash = config.trash_retention.is_some() && !permanent;
+    jobs.spawn_exclusive(
+        JobKind::DeleteStack,
+        stack_id.clone(),
+        principal.user_id,
+        move |job| delete_stack_impl(stack_id, number, trash, events, job),
+    )
+    .await
+    .map_err(ApiError::operation_in_progress)
 }
 
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = String, Path, description = "Stack id"), DeleteQuery),
+    responses(
+        (status = 202, description = "Deletion started", body = JobReference),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+        (status = 409, description = "Another job is changing the stack", body = ErrorResponse),
+    )
+)]
 #[delete("/stacks/{stack_id}")]
-pub async fn delete_stack(stack_id: web::Path<String>) -> Result<HttpResponse, Error> {
-    delete_stack_impl(stack_id.into_inner()).await
+pub async fn delete_stack(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    config: web::Data<Config>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+) -> Result<HttpResponse, Error> {
+    let job = start_delete_stack(
+        jobs.into_inner(),
+        events.into_inner(),
+        &config,
+        &principal,
+        stack_id.into_inner(),
+        query.permanent,
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
 }
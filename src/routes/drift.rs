@@ -0,0 +1,222 @@
+use actix_web::{get, post, web, Error, HttpResponse};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::process::Command;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::{Admin, Principal};
+use crate::drift::{Drift, DriftKind, DriftMonitor, DriftReport, RepairAction};
+use crate::events::{EventBus, StackEvent};
+use crate::jobs::{Job, JobHandle, JobKind, JobStore};
+use crate::openapi::ErrorResponse;
+use crate::routes::create;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::jobs::{accepted, JobReference};
+use crate::stacks;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct DriftQuery {
+    /// Check now instead of returning the last background check
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DriftRepair {
+    /// Id of the drift, from the report
+    #[schema(example = "orphan_volume:minecraft_server_7")]
+    id: String,
+    action: RepairAction,
+}
+
+fn docker_error(e: std::io::Error) -> ApiError {
+    ApiError::new(
+        ErrorCode::DockerUnavailable,
+        format!("Failed to check for drift: {}", e),
+    )
+}
+
+async fn docker(args: &[&str]) -> Result<(), ApiError> {
+    let output = Command::new("docker")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| {
+            ApiError::new(
+                ErrorCode::DockerUnavailable,
+                format!("Failed to execute docker: {}", e),
+            )
+        })?;
+
+    if !output.status.success() {
+        return Err(ApiError::internal(format!(
+            "docker {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+async fn repair_impl(
+    drift_monitor: Arc<DriftMonitor>,
+    events: Arc<EventBus>,
+    drift: Drift,
+    action: RepairAction,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    job.step(10, format!("Repairing {} with {}", drift.id, action))
+        .await;
+
+    match (action, drift.kind) {
+        (RepairAction::Recreate, DriftKind::MissingVolume) => {
+            docker(&["volume", "create", &drift.resource]).await?;
+        }
+        (RepairAction::Recreate, _) => {
+            let compose_file = stacks::stack_directory(&drift.stack_id)
+                .map_err(|e| ApiError::internal(format!("Failed to find the stack: {}", e)))?
+                .join("compose.yaml");
+            docker(&[
+                "compose",
+                "-f",
+                &compose_file.to_string_lossy(),
+                "up",
+                "-d",
+                "--no-recreate",
+            ])
+            .await?;
+        }
+        (RepairAction::Adopt, _) => {
+            let number = stacks::parse_id(&drift.stack_id)
+                .ok_or_else(|| ApiError::internal("Invalid stack id"))?;
+            create::adopt_stack(number).await?;
+            events.publish(StackEvent::StackCreated {
+                stack_id: drift.stack_id.clone(),
+            });
+        }
+        (RepairAction::Remove, DriftKind::OrphanContainer) => {
+            docker(&["rm", "-f", &drift.resource]).await?;
+        }
+        (RepairAction::Remove, DriftKind::OrphanVolume) => {
+            docker(&["volume", "rm", &drift.resource]).await?;
+        }
+        (RepairAction::Remove, _) => {
+            docker(&["network", "rm", &drift.resource]).await?;
+        }
+    }
+
+    job.step(90, "Checking for drift again").await;
+    if let Err(e) = drift_monitor.check().await {
+        log::warn!("Failed to check for drift after a repair: {}", e);
+    }
+
+    Ok(json!({
+        "id": drift.id,
+        "action": action,
+    }))
+}
+
+/// Checks that the drift still exists and starts the repair job.
+pub async fn start_repair(
+    jobs: Arc<JobStore>,
+    drift_monitor: Arc<DriftMonitor>,
+    events: Arc<EventBus>,
+    principal: &Principal,
+    repair: DriftRepair,
+) -> Result<Job, ApiError> {
+    // Repairs act on the current state, not on a report that may be minutes old
+    let report = drift_monitor.check().await.map_err(docker_error)?;
+    let drift = report
+        .drift
+        .into_iter()
+        .find(|drift| drift.id == repair.id)
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::ValidationFailed,
+                format!("No drift '{}', it may have been repaired", repair.id),
+            )
+        })?;
+    if !drift.repairs.contains(&repair.action) {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!(
+                "'{}' does not repair {}, use {}",
+                repair.action,
+                drift.id,
+                drift
+                    .repairs
+                    .iter()
+                    .map(|action| format!("'{}'", action))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ),
+        ));
+    }
+
+    let action = repair.action;
+    jobs.spawn_exclusive(
+        JobKind::RepairDrift,
+        drift.stack_id.clone(),
+        principal.user_id,
+        move |job| repair_impl(drift_monitor, events, drift, action, job),
+    )
+    .await
+    .map_err(ApiError::operation_in_progress)
+}
+
+#[utoipa::path(
+    tag = "drift",
+    params(DriftQuery),
+    responses(
+        (status = 200, description = "Differences between the stacks and Docker's resources", body = DriftReport),
+        (status = 500, description = "Docker could not be reached", body = ErrorResponse),
+    )
+)]
+#[get("/drift")]
+pub async fn get_drift(
+    _admin: Admin,
+    drift_monitor: web::Data<DriftMonitor>,
+    query: web::Query<DriftQuery>,
+) -> Result<HttpResponse, Error> {
+    let report = match drift_monitor.report() {
+        Some(report) if !query.refresh => report,
+        _ => drift_monitor
+            .check()
+            .await
+            .map_err(|e| docker_error(e).into_legacy())?,
+    };
+
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[utoipa::path(
+    tag = "drift",
+    request_body = DriftRepair,
+    responses(
+        (status = 202, description = "Repair started", body = JobReference),
+        (status = 400, description = "Unknown drift, or an action that does not repair it", body = ErrorResponse),
+        (status = 409, description = "Another job is changing the stack", body = ErrorResponse),
+        (status = 500, description = "Docker could not be reached", body = ErrorResponse),
+    )
+)]
+#[post("/drift/repair")]
+pub async fn repair_drift(
+    jobs: web::Data<JobStore>,
+    drift_monitor: web::Data<DriftMonitor>,
+    events: web::Data<EventBus>,
+    Admin(principal): Admin,
+    repair: web::Json<DriftRepair>,
+) -> Result<HttpResponse, Error> {
+    let job = start_repair(
+        jobs.into_inner(),
+        drift_monitor.into_inner(),
+        events.into_inner(),
+        &principal,
+        repair.into_inner(),
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
+}
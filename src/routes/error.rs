@@ -0,0 +1,195 @@
+use actix_web::{
+    error::{JsonPayloadError, QueryPayloadError},
+    http::{header, StatusCode},
+    HttpRequest, HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::StackAccessError;
+
+/// Machine-readable error codes of API v2, clients may rely on them across releases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The body or query could not be parsed
+    MalformedRequest,
+    /// Missing or invalid session or API key
+    Unauthorized,
+    /// The API key scope or the stack role is too narrow
+    Forbidden,
+    StackNotFound,
+    JobNotFound,
+    BackupNotFound,
+    /// The host runs as many stacks as it has CPU cores
+    StackLimitReached,
+    /// The owner's stack or memory quota would be exceeded
+    QuotaExceeded,
+    /// The request is well-formed but its values are not acceptable
+    ValidationFailed,
+    /// Another job is already changing the stack
+    OperationInProgress,
+    /// Docker could not be reached
+    DockerUnavailable,
+    InternalError,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::MalformedRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::StackNotFound | ErrorCode::JobNotFound | ErrorCode::BackupNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::StackLimitReached
+            | ErrorCode::QuotaExceeded
+            | ErrorCode::OperationInProgress => StatusCode::CONFLICT,
+            ErrorCode::ValidationFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::DockerUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Status API v1 answered with, before codes existed.
+    fn legacy_status(&self) -> StatusCode {
+        match self {
+            ErrorCode::ValidationFailed => StatusCode::BAD_REQUEST,
+            ErrorCode::StackLimitReached | ErrorCode::QuotaExceeded => StatusCode::FORBIDDEN,
+            ErrorCode::DockerUnavailable => StatusCode::INTERNAL_SERVER_ERROR,
+            code => code.status(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// Body of every API v2 error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorEnvelope {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InternalError, message)
+    }
+
+    /// A job was refused because the job `active` changes the same stack.
+    pub fn operation_in_progress(active: Uuid) -> Self {
+        Self::new(
+            ErrorCode::OperationInProgress,
+            format!(
+                "Job {} is already changing the stack, try again once it finishes",
+                active
+            ),
+        )
+    }
+
+    /// The same error in the shape of API v1.
+    pub fn into_legacy(self) -> LegacyError {
+        LegacyError {
+            status: self.code.legacy_status(),
+            message: self.message,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.code == ErrorCode::Unauthorized {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorEnvelope {
+            error: ErrorDetail {
+                code: self.code,
+                message: self.message.clone(),
+            },
+        })
+    }
+}
+
+impl From<StackAccessError> for ApiError {
+    fn from(e: StackAccessError) -> Self {
+        match e {
+            StackAccessError::NotFound(msg) => ApiError::new(ErrorCode::StackNotFound, msg),
+            StackAccessError::Forbidden(msg) => ApiError::new(ErrorCode::Forbidden, msg),
+            StackAccessError::Io(msg) => ApiError::internal(msg),
+        }
+    }
+}
+
+/// Error of API v1, a bare message.
+#[derive(Debug)]
+pub struct LegacyError {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl fmt::Display for LegacyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for LegacyError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+/// Answers unparseable JSON bodies of API v2 in the error envelope.
+pub fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let code = match &err {
+        JsonPayloadError::Deserialize(e) if e.is_data() => ErrorCode::ValidationFailed,
+        _ => ErrorCode::MalformedRequest,
+    };
+    ApiError::new(code, format!("Invalid request body: {}", err)).into()
+}
+
+/// Answers unparseable query strings of API v2 in the error envelope.
+pub fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::new(
+        ErrorCode::MalformedRequest,
+        format!("Invalid query string: {}", err),
+    )
+    .into()
+}
@@ -0,0 +1,121 @@
+use actix_web::{
+    get,
+    http::header::{self, ContentEncoding},
+    web,
+    web::Bytes,
+    Error, HttpRequest, HttpResponse,
+};
+use futures_util::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::IntoParams;
+
+use crate::auth::Principal;
+use crate::events::{Event, EventBus};
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct EventQuery {
+    /// Only stream the events of this stack
+    stack_id: Option<String>,
+}
+
+fn format_event(event: &Event) -> Bytes {
+    let data = serde_json::to_string(event).unwrap_or_else(|_| "{}".to_string());
+    Bytes::from(format!(
+        "id: {}\nevent: {}\ndata: {}\n\n",
+        event.id,
+        event.payload.name(),
+        data
+    ))
+}
+
+fn matches(event: &Event, stack_id: &Option<String>, principal: &Principal) -> bool {
+    if let Some(stack_id) = stack_id {
+        if event.payload.stack_id() != Some(stack_id.as_str()) {
+            return false;
+        }
+    }
+
+    // Events of stacks the caller cannot view, and those of no stack, are for administrators.
+    // Every role on a stack views it, its audience holds all users having one.
+    principal.sees_all_stacks()
+        || principal
+            .user_id
+            .is_some_and(|user_id| event.audience.contains(&user_id))
+}
+
+async fn stream_events_impl(
+    events: &EventBus,
+    principal: Principal,
+    last_event_id: Option<String>,
+    query: EventQuery,
+) -> Result<HttpResponse, Error> {
+    let (backlog, receiver) = events.subscribe_from(last_event_id.as_deref());
+    let stack_id = query.stack_id;
+
+    let backlog: Vec<Result<Bytes, Error>> = backlog
+        .iter()
+        .filter(|event| matches(event, &stack_id, &principal))
+        .map(|event| Ok(format_event(event)))
+        .collect();
+
+    let live = stream::unfold(
+        (receiver, stack_id, principal),
+        |(mut receiver, stack_id, principal)| async move {
+            loop {
+                match tokio::time::timeout(KEEP_ALIVE_INTERVAL, receiver.recv()).await {
+                    Ok(Ok(event)) if matches(&event, &stack_id, &principal) => {
+                        return Some((Ok(format_event(&event)), (receiver, stack_id, principal)));
+                    }
+                    Ok(Ok(_)) => continue,
+                    // Slow client, it reconnects with `Last-Event-ID` and gets the missed
+                    // events from the history
+                    Ok(Err(RecvError::Lagged(_) | RecvError::Closed)) => return None,
+                    Err(_) => {
+                        return Some((
+                            Ok(Bytes::from(": keep-alive\n\n")),
+                            (receiver, stack_id, principal),
+                        ));
+                    }
+                }
+            }
+        },
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .insert_header(("X-Accel-Buffering", "no"))
+        // Keeps the compression middleware from buffering events
+        .insert_header(ContentEncoding::Identity)
+        .streaming(stream::iter(backlog).chain(live)))
+}
+
+#[utoipa::path(
+    tag = "events",
+    params(
+        EventQuery,
+        ("Last-Event-ID" = Option<String>, Header, description = "Resume after this event, replaying the ones still buffered"),
+    ),
+    responses(
+        (status = 200, description = "Server-Sent Events stream, `data` holds the event as JSON", content_type = "text/event-stream", body = String),
+    )
+)]
+#[get("/events")]
+pub async fn stream_events(
+    req: HttpRequest,
+    events: web::Data<EventBus>,
+    principal: Principal,
+    query: web::Query<EventQuery>,
+) -> Result<HttpResponse, Error> {
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    stream_events_impl(&events, principal, last_event_id, query.into_inner()).await
+}
@@ -0,0 +1,61 @@
+use actix_web::{put, web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::Principal;
+use crate::openapi::ErrorResponse;
+use crate::routes::create::validate_idle_timeout;
+use crate::routes::error::ApiError;
+use crate::stacks::{StackMeta, StackRole};
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct IdleTimeout {
+    /// Stop the stack after this many minutes without players, `null` never stops it
+    #[schema(minimum = 1, maximum = 10080, example = 30)]
+    #[serde(default)]
+    pub idle_timeout_minutes: Option<u32>,
+}
+
+/// Sets the idle timeout of a stack, for its admins.
+pub async fn set_idle_timeout(
+    principal: &Principal,
+    stack_id: &str,
+    update: IdleTimeout,
+) -> Result<IdleTimeout, ApiError> {
+    let idle_timeout_minutes = validate_idle_timeout(update.idle_timeout_minutes)?;
+
+    let _guard = StackMeta::lock(stack_id).await;
+    let mut meta = principal.authorize(stack_id, StackRole::Admin)?;
+    meta.idle_timeout_minutes = idle_timeout_minutes;
+    meta.save(stack_id)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to write stack.json: {}", e)))?;
+
+    Ok(IdleTimeout {
+        idle_timeout_minutes,
+    })
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    request_body = IdleTimeout,
+    responses(
+        (status = 200, description = "Idle timeout changed", body = IdleTimeout),
+        (status = 400, description = "Invalid timeout", body = ErrorResponse),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[put("/stacks/{stack_id}/idle_timeout")]
+pub async fn update_idle_timeout(
+    principal: Principal,
+    stack_id: web::Path<String>,
+    update: web::Json<IdleTimeout>,
+) -> Result<HttpResponse, Error> {
+    let idle_timeout = set_idle_timeout(&principal, &stack_id, update.into_inner())
+        .await
+        .map_err(ApiError::into_legacy)?;
+
+    Ok(HttpResponse::Ok().json(idle_timeout))
+}
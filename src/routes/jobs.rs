@@ -0,0 +1,195 @@
+use actix_web::{get, http::header, web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::jobs::{Job, JobState, JobStore};
+use crate::openapi::ErrorResponse;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::stacks::StackRole;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct JobQuery {
+    /// `active`, `finished`, `queued`, `running`, `succeeded` or `failed`
+    state: Option<String>,
+    stack_id: Option<String>,
+}
+
+/// Body of `202 Accepted` responses, the job tracks the operation.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct JobReference {
+    pub job_id: Uuid,
+    pub status_url: String,
+}
+
+/// `202 Accepted` response pointing the client at the job tracking the operation.
+pub fn accepted(job: &Job) -> HttpResponse {
+    accepted_at("/api/v1", job)
+}
+
+/// `202 Accepted` response pointing at the job under the given API base path.
+pub fn accepted_at(base: &str, job: &Job) -> HttpResponse {
+    let status_url = format!("{}/jobs/{}", base, job.id);
+
+    HttpResponse::Accepted()
+        .insert_header((header::LOCATION, status_url.clone()))
+        .json(JobReference {
+            job_id: job.id,
+            status_url,
+        })
+}
+
+/// Jobs are visible to whoever started them and to the viewers of their stack.
+fn is_visible(principal: &Principal, job: &Job) -> bool {
+    if principal.sees_all_stacks() || (job.user_id.is_some() && job.user_id == principal.user_id) {
+        return true;
+    }
+    job.stack_id
+        .as_deref()
+        .map(|stack_id| principal.authorize(stack_id, StackRole::Viewer).is_ok())
+        .unwrap_or(false)
+}
+
+fn matches_state(job: &Job, state: &str) -> Result<bool, ApiError> {
+    Ok(match state {
+        "active" => !job.state.is_finished(),
+        "finished" => job.state.is_finished(),
+        "queued" => job.state == JobState::Queued,
+        "running" => job.state == JobState::Running,
+        "succeeded" => job.state == JobState::Succeeded,
+        "failed" => job.state == JobState::Failed,
+        _ => {
+            return Err(ApiError::new(
+                ErrorCode::MalformedRequest,
+                format!(
+                    "Invalid state filter: '{}'. Must be one of 'active', 'finished', 'queued', 'running', 'succeeded' or 'failed'",
+                    state
+                ),
+            ))
+        }
+    })
+}
+
+/// Jobs the caller may see, most recent first.
+pub fn visible_jobs(
+    jobs: &JobStore,
+    principal: &Principal,
+    state: Option<&str>,
+    stack_id: Option<&str>,
+) -> Result<Vec<Job>, ApiError> {
+    let mut filtered = Vec::new();
+    for job in jobs.list() {
+        if let Some(state) = state {
+            if !matches_state(&job, state)? {
+                continue;
+            }
+        }
+        if stack_id.is_some() && job.stack_id.as_deref() != stack_id {
+            continue;
+        }
+        if !is_visible(principal, &job) {
+            continue;
+        }
+        filtered.push(job);
+    }
+
+    Ok(filtered)
+}
+
+/// A job, if the caller may see it.
+pub fn visible_job(jobs: &JobStore, principal: &Principal, job_id: &str) -> Result<Job, ApiError> {
+    Uuid::parse_str(job_id)
+        .ok()
+        .and_then(|id| jobs.get(&id))
+        .filter(|job| is_visible(principal, job))
+        .ok_or_else(|| {
+            ApiError::new(
+                ErrorCode::JobNotFound,
+                format!("Job {} does not exist", job_id),
+            )
+        })
+}
+
+/// API v1 reported stack ids and ports in job results as strings.
+fn legacy(mut job: Job) -> Job {
+    fn stringify(value: &mut Value) {
+        if let Value::Number(number) = value {
+            *value = Value::String(number.to_string());
+        }
+    }
+
+    if let Some(Value::Object(result)) = &mut job.result {
+        if let Some(stack_id) = result.get_mut("stack_id") {
+            stringify(stack_id);
+        }
+        if let Some(Value::Object(ports)) = result.get_mut("ports") {
+            ports.values_mut().for_each(stringify);
+        }
+    }
+    job
+}
+
+async fn list_jobs_impl(
+    jobs: &JobStore,
+    principal: Principal,
+    query: JobQuery,
+) -> Result<HttpResponse, Error> {
+    let filtered: Vec<Job> = visible_jobs(
+        jobs,
+        &principal,
+        query.state.as_deref(),
+        query.stack_id.as_deref(),
+    )
+    .map_err(ApiError::into_legacy)?
+    .into_iter()
+    .map(legacy)
+    .collect();
+
+    Ok(HttpResponse::Ok().json(filtered))
+}
+
+async fn get_job_impl(
+    jobs: &JobStore,
+    principal: Principal,
+    job_id: String,
+) -> Result<HttpResponse, Error> {
+    let job = visible_job(jobs, &principal, &job_id).map_err(ApiError::into_legacy)?;
+
+    Ok(HttpResponse::Ok().json(legacy(job)))
+}
+
+#[utoipa::path(
+    tag = "jobs",
+    params(JobQuery),
+    responses(
+        (status = 200, description = "Jobs visible to the caller, most recent first", body = [Job]),
+        (status = 400, description = "Invalid state filter", body = ErrorResponse),
+    )
+)]
+#[get("/jobs")]
+pub async fn list_jobs(
+    jobs: web::Data<JobStore>,
+    principal: Principal,
+    query: web::Query<JobQuery>,
+) -> Result<HttpResponse, Error> {
+    list_jobs_impl(&jobs, principal, query.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "jobs",
+    params(("job_id" = Uuid, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 404, description = "Unknown job, or started by someone else on a stack the caller cannot view", body = ErrorResponse),
+    )
+)]
+#[get("/jobs/{job_id}")]
+pub async fn get_job(
+    jobs: web::Data<JobStore>,
+    principal: Principal,
+    job_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    get_job_impl(&jobs, principal, job_id.into_inner()).await
+}
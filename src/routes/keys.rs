@@ -0,0 +1,161 @@
+use actix_web::{delete, get, post, web, Error, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::{Admin, ApiKey, KeyStore, Principal, Scope};
+use crate::openapi::ErrorResponse;
+
+#[derive(Debug)]
+enum KeyError {
+    KeyNotFound(String),
+    InvalidKey(String),
+    FileSystemError(String),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyNotFound(msg) | Self::InvalidKey(msg) | Self::FileSystemError(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
+    }
+}
+
+impl ResponseError for KeyError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            KeyError::KeyNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            KeyError::InvalidKey(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            KeyError::FileSystemError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewKey {
+    name: String,
+    /// `read`, `manage` or `admin`
+    #[schema(example = "read")]
+    scope: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    /// The bearer token, only revealed once
+    token: String,
+}
+
+async fn create_key_impl(
+    keys: &KeyStore,
+    caller: Principal,
+    new_key: NewKey,
+) -> Result<HttpResponse, Error> {
+    caller.require(Scope::Admin)?;
+
+    let name = new_key.name.trim().to_string();
+    if name.is_empty() {
+        return Err(KeyError::InvalidKey(
+            "Key name must not be empty".to_string(),
+        ))?;
+    }
+    let scope = Scope::try_from(new_key.scope.as_str()).map_err(KeyError::InvalidKey)?;
+    if scope > caller.scope {
+        return Err(KeyError::InvalidKey(format!(
+            "Cannot create a key with scope '{}' above your own '{}'",
+            scope, caller.scope
+        )))?;
+    }
+
+    let (key, token) = keys
+        .create(name, scope, caller.user_id)
+        .await
+        .map_err(|e| KeyError::FileSystemError(format!("Failed to save API key: {}", e)))?;
+
+    Ok(HttpResponse::Created().json(CreatedKey { key, token }))
+}
+
+async fn revoke_key_impl(
+    keys: &KeyStore,
+    caller: Principal,
+    key_id: String,
+) -> Result<HttpResponse, Error> {
+    caller.require(Scope::Admin)?;
+
+    let not_found = || KeyError::KeyNotFound(format!("API key {} does not exist", key_id));
+    let id = Uuid::parse_str(&key_id).map_err(|_| not_found())?;
+
+    // Revoking the key in use could lock the last administrator out
+    if caller.key_id == Some(id) {
+        return Err(KeyError::InvalidKey(
+            "Cannot revoke the API key used for this request".to_string(),
+        ))?;
+    }
+
+    let revoked = keys
+        .revoke(&id)
+        .await
+        .map_err(|e| KeyError::FileSystemError(format!("Failed to save API keys: {}", e)))?;
+    if !revoked {
+        return Err(not_found())?;
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    tag = "keys",
+    responses(
+        (status = 200, description = "Every API key, without their tokens", body = [ApiKey]),
+    )
+)]
+#[get("/keys")]
+pub async fn list_keys(_admin: Admin, keys: web::Data<KeyStore>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(keys.list()))
+}
+
+#[utoipa::path(
+    tag = "keys",
+    request_body = NewKey,
+    responses(
+        (status = 201, description = "The key was created", body = CreatedKey),
+        (status = 400, description = "Empty name, or a scope invalid or above the caller's", body = ErrorResponse),
+    )
+)]
+#[post("/keys")]
+pub async fn create_key(
+    keys: web::Data<KeyStore>,
+    Admin(caller): Admin,
+    new_key: web::Json<NewKey>,
+) -> Result<HttpResponse, Error> {
+    create_key_impl(&keys, caller, new_key.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "keys",
+    params(("key_id" = Uuid, Path, description = "API key id")),
+    responses(
+        (status = 204, description = "The key was revoked"),
+        (status = 400, description = "The key is the one authenticating the request", body = ErrorResponse),
+        (status = 404, description = "Unknown key", body = ErrorResponse),
+    )
+)]
+#[delete("/keys/{key_id}")]
+pub async fn revoke_key(
+    keys: web::Data<KeyStore>,
+    Admin(caller): Admin,
+    key_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    revoke_key_impl(&keys, caller, key_id.into_inner()).await
+}
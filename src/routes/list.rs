@@ -1,212 +1,357 @@
-use actix_web::{get, Error, HttpResponse, ResponseError};
-use serde_json::{json, Value};
+pub mod query;
+
+use actix_web::{get, web, Error, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 use std::collections::HashMap;
-use std::fmt;
-use std::fs;
-use std::path::PathBuf;
 use tokio::process::Command;
-use walkdir::WalkDir;
+use utoipa::ToSchema;
+
+use crate::auth::Principal;
+use crate::monitor::Monitor;
+use crate::openapi::ErrorResponse;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::stacks::{self, DesiredState, StackMeta, StackPorts, StackRole};
+use crate::users::UserStore;
+use crate::wan::{WanAddress, WanSnapshot};
+
+use query::StackQuery;
 
-#[derive(Debug)]
-enum ListStackError {
-    FileSystemError(String),
-    DockerError(String),
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceState {
+    Running,
+    Stopped,
+    /// Exited with an error and not restarted, see the stack's crashes
+    Crashed,
 }
 
-impl fmt::Display for ListStackError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl ServiceState {
+    fn as_str(&self) -> &'static str {
         match self {
-            Self::FileSystemError(msg) | Self::DockerError(msg) => {
-                write!(f, "{}", msg)
-            }
+            ServiceState::Running => "running",
+            ServiceState::Stopped => "stopped",
+            ServiceState::Crashed => "crashed",
         }
     }
 }
 
-impl ResponseError for ListStackError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
-        match self {
-            ListStackError::FileSystemError(_) | ListStackError::DockerError(_) => {
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
-            }
-        }
-    }
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StackServices {
+    pub minecraft_server: ServiceState,
+    pub sftp_server: ServiceState,
+}
 
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .content_type("application/json")
-            .json(json!({ "message": self.to_string() }))
-    }
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StackSummary {
+    pub stack_id: u32,
+    pub name: Option<String>,
+    /// Username of the owner, `None` for stacks created by host keys
+    pub owner: Option<String>,
+    /// Role of the caller on the stack
+    pub role: StackRole,
+    /// Public address of the host, `None` until detected
+    pub wan_ip: Option<String>,
+    /// Name to connect to instead of the address, when configured
+    pub wan_hostname: Option<String>,
+    pub server_type: String,
+    /// Minecraft version, `latest` unless pinned at creation
+    pub version: String,
+    pub tags: Vec<String>,
+    pub memory_mb: u32,
+    pub ports: StackPorts,
+    pub services: StackServices,
+    /// Players online
+    pub players: u32,
+    /// Minutes without players after which the stack is stopped
+    pub idle_timeout_minutes: Option<u32>,
+    /// Docker restart policy of the Minecraft server
+    pub restart_policy: String,
+    /// State mc_stack restores when it starts, `None` when left as Docker has it
+    pub desired_state: Option<DesiredState>,
+    pub created_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone)]
-struct ServiceStatus {
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = ServiceStatus)]
+pub struct LegacyServiceStatus {
+    /// `running`, `stopped` or `crashed`
     status: String,
+    /// Published host port, while running
     port: Option<String>,
 }
 
-// Maybe don't automatically create on dir on dir not found
-async fn get_stacks_directory() -> Result<PathBuf, ListStackError> {
-    let current_exe = std::env::current_exe().map_err(|e| {
-        ListStackError::FileSystemError(format!("Failed to get current path: {}", e))
-    })?;
-
-    let stacks_dir = current_exe
-        .parent()
-        .ok_or_else(|| {
-            ListStackError::FileSystemError("Failed to find executable directory".to_string())
-        })?
-        .join("stacks");
-
-    if !stacks_dir.exists() {
-        fs::create_dir_all(&stacks_dir).map_err(|e| {
-            ListStackError::FileSystemError(format!("Failed to create stacks directory: {}", e))
-        })?;
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = StackServices)]
+pub struct LegacyStackServices {
+    sftp_server: LegacyServiceStatus,
+    minecraft_server: LegacyServiceStatus,
+}
+
+/// A stack in the shape of API v1, with ports as strings.
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = StackSummary)]
+pub struct LegacyStackSummary {
+    stack_id: String,
+    name: Option<String>,
+    /// Username of the owner, `None` for stacks created by host keys
+    owner: Option<String>,
+    /// Role of the caller on the stack
+    role: StackRole,
+    /// Public hostname or address of the host, empty until detected
+    wan_ip: String,
+    server_type: String,
+    version: String,
+    tags: Vec<String>,
+    services: LegacyStackServices,
+    /// Players online
+    players: u32,
+    /// Minutes without players after which the stack is stopped
+    idle_timeout_minutes: Option<u32>,
+    /// Docker restart policy of the Minecraft server
+    restart_policy: String,
+    /// State mc_stack restores when it starts, `None` when left as Docker has it
+    desired_state: Option<DesiredState>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+impl From<StackSummary> for LegacyStackSummary {
+    fn from(summary: StackSummary) -> Self {
+        let service = |state: ServiceState, port: u16| LegacyServiceStatus {
+            status: state.as_str().to_string(),
+            port: (state == ServiceState::Running).then(|| port.to_string()),
+        };
+
+        Self {
+            stack_id: summary.stack_id.to_string(),
+            name: summary.name,
+            owner: summary.owner,
+            role: summary.role,
+            wan_ip: summary.wan_hostname.or(summary.wan_ip).unwrap_or_default(),
+            server_type: summary.server_type,
+            version: summary.version,
+            tags: summary.tags,
+            players: summary.players,
+            idle_timeout_minutes: summary.idle_timeout_minutes,
+            restart_policy: summary.restart_policy,
+            desired_state: summary.desired_state,
+            created_at: summary.created_at,
+            services: LegacyStackServices {
+                sftp_server: service(summary.services.sftp_server, summary.ports.sftp_server),
+                minecraft_server: service(
+                    summary.services.minecraft_server,
+                    summary.ports.minecraft_server,
+                ),
+            },
+        }
     }
+}
 
-    Ok(stacks_dir)
+// State of a container from `docker ps -a`, such as `exited` and `Exited (1) 2 minutes ago`
+fn container_state(state: &str, status: &str) -> ServiceState {
+    match state {
+        "running" | "restarting" => ServiceState::Running,
+        "exited" | "dead" => {
+            let exit_code = status
+                .split_once('(')
+                .and_then(|(_, rest)| rest.split_once(')'))
+                .and_then(|(code, _)| code.parse::<i32>().ok())
+                .unwrap_or(0);
+            if exit_code == 0 {
+                ServiceState::Stopped
+            } else {
+                ServiceState::Crashed
+            }
+        }
+        _ => ServiceState::Stopped,
+    }
 }
 
-async fn get_running_containers() -> Result<HashMap<String, ServiceStatus>, ListStackError> {
+/// State of every container, stopped through the API means removed.
+pub async fn get_container_states() -> Result<HashMap<String, ServiceState>, ApiError> {
+    let unavailable = |message: String| ApiError::new(ErrorCode::DockerUnavailable, message);
+
     let output = Command::new("docker")
-        .args(["ps", "--format", "{{.Names}}|{{.Ports}}"])
+        .args([
+            "ps",
+            "-a",
+            "--format",
+            "{{.Names}}\t{{.State}}\t{{.Status}}",
+        ])
         .output()
         .await
-        .map_err(|e| ListStackError::DockerError(format!("Failed to execute docker ps: {}", e)))?;
+        .map_err(|e| unavailable(format!("Failed to execute docker ps: {}", e)))?;
 
     if !output.status.success() {
-        return Err(ListStackError::DockerError(
+        return Err(unavailable(
             "Failed to get container information".to_string(),
         ));
     }
 
-    let containers_str = String::from_utf8_lossy(&output.stdout);
-    let mut container_map = HashMap::new();
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let name = fields.next().filter(|name| !name.is_empty())?;
+            let state = fields.next().unwrap_or_default();
+            let status = fields.next().unwrap_or_default();
+            Some((name.to_string(), container_state(state, status)))
+        })
+        .collect())
+}
 
-    for line in containers_str.lines() {
-        if line.is_empty() {
-            continue;
-        }
+fn summarize(
+    users: &UserStore,
+    monitor: &Monitor,
+    wan: &WanSnapshot,
+    containers: &HashMap<String, ServiceState>,
+    stack_id: &str,
+    meta: StackMeta,
+    role: StackRole,
+) -> Result<StackSummary, ApiError> {
+    let read_error =
+        |e: std::io::Error| ApiError::internal(format!("Failed to read stack {}: {}", stack_id, e));
+    let state = |container: String| {
+        containers
+            .get(&container)
+            .copied()
+            .unwrap_or(ServiceState::Stopped)
+    };
 
-        let parts: Vec<&str> = line.split('|').collect();
-        if parts.len() != 2 {
-            continue;
-        }
+    let env = stacks::read_env(stack_id).map_err(read_error)?;
 
-        let name = parts[0];
-        let ports = parts[1];
-
-        let port = ports
-            .split(',')
-            .filter_map(|p| p.trim().split(':').nth(1))
-            .filter_map(|p| p.split('-').next())
-            .next()
-            .map(|p| p.to_string());
-
-        container_map.insert(
-            name.to_string(),
-            ServiceStatus {
-                status: "running".to_string(),
-                port,
-            },
-        );
-    }
-
-    Ok(container_map)
+    Ok(StackSummary {
+        stack_id: stacks::parse_id(stack_id)
+            .ok_or_else(|| ApiError::internal(format!("Invalid stack id {}", stack_id)))?,
+        name: meta.name,
+        owner: meta
+            .owner
+            .and_then(|id| users.get(&id))
+            .map(|user| user.username),
+        role,
+        wan_ip: wan.ip.map(|ip| ip.to_string()),
+        wan_hostname: wan.hostname.clone(),
+        server_type: stacks::server_type(&env),
+        version: stacks::minecraft_version(&env),
+        tags: meta.tags,
+        memory_mb: stacks::memory_mb(stack_id).map_err(read_error)?,
+        ports: stacks::ports(stack_id).map_err(read_error)?,
+        services: StackServices {
+            minecraft_server: state(format!("minecraft_server_{}", stack_id)),
+            sftp_server: state(format!("sftp_server_{}", stack_id)),
+        },
+        players: monitor.player_count(stack_id),
+        idle_timeout_minutes: meta.idle_timeout_minutes,
+        restart_policy: stacks::restart_policy(&env),
+        desired_state: meta.desired_state,
+        created_at: meta.created_at,
+    })
 }
 
-async fn list_stacks_impl() -> Result<HttpResponse, Error> {
-    let stacks_dir = get_stacks_directory().await?;
+/// Stacks the caller holds a role on, in ascending id order.
+pub async fn stack_summaries(
+    users: &UserStore,
+    monitor: &Monitor,
+    wan: &WanAddress,
+    principal: &Principal,
+) -> Result<Vec<StackSummary>, ApiError> {
+    let stack_ids = stacks::list_stack_ids()
+        .map_err(|e| ApiError::internal(format!("Failed to read stacks directory: {}", e)))?;
 
-    // Get all compose files
-    let mut stacks = Vec::new();
-    for entry in WalkDir::new(&stacks_dir)
-        .min_depth(2)
-        .max_depth(2)
+    let mut visible = Vec::new();
+    for stack_id in stack_ids {
+        let meta = StackMeta::load(&stack_id)
+            .map_err(|e| ApiError::internal(format!("Failed to read stack.json: {}", e)))?;
+        if let Some(role) = principal.role_on(&meta) {
+            visible.push((stack_id, meta, role));
+        }
+    }
+    if visible.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let containers = get_container_states().await?;
+    let wan = wan.current();
+    visible
         .into_iter()
-        .filter_entry(|e| {
-            e.file_name()
-                .to_str()
-                .map(|s| s == "compose.yaml")
-                .unwrap_or(false)
+        .map(|(stack_id, meta, role)| {
+            summarize(users, monitor, &wan, &containers, &stack_id, meta, role)
         })
-    {
-        let entry = entry.map_err(|e| {
-            ListStackError::FileSystemError(format!("Failed to read directory entry: {}", e))
-        })?;
-
-        let stack_dir = entry.path().parent().unwrap();
-        let stack_id = stack_dir
-            .file_name()
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .split('_')
-            .nth(1)
-            .unwrap()
-            .to_string();
-
-        stacks.push(stack_id);
-    }
+        .collect()
+}
+
+/// A single stack, for its viewers.
+pub async fn stack_summary(
+    users: &UserStore,
+    monitor: &Monitor,
+    wan: &WanAddress,
+    principal: &Principal,
+    stack_id: &str,
+) -> Result<StackSummary, ApiError> {
+    let meta = principal.authorize(stack_id, StackRole::Viewer)?;
+    let role = principal.role_on(&meta).unwrap_or(StackRole::Viewer);
+
+    let containers = get_container_states().await?;
+    summarize(
+        users,
+        monitor,
+        &wan.current(),
+        &containers,
+        stack_id,
+        meta,
+        role,
+    )
+}
+
+async fn list_stacks_impl(
+    req: &HttpRequest,
+    users: &UserStore,
+    monitor: &Monitor,
+    wan: &WanAddress,
+    principal: Principal,
+    query: &StackQuery,
+) -> Result<HttpResponse, ApiError> {
+    let summaries = stack_summaries(users, monitor, wan, &principal).await?;
+    let page = query::apply(summaries, query)?;
 
-    if stacks.is_empty() {
+    if page.stacks.is_empty() {
         return Ok(HttpResponse::NoContent().finish());
     }
 
-    // TODO: Hardcoded WAN IP for now or performance reasons,
-    // must find way to get it from the running dockers themselves to save some loading time,
-    // currently it takes ~200ms to get the WAN IP by fetching it from the web,
-    // objective is to get it under 20ms
-    let wan_ip = "24.48.49.227".to_string();
-
-    // Get running containers
-    let containers = get_running_containers().await?;
-
-    // Build stacks status
-    let stack_statuses: Vec<Value> = stacks
-        .iter()
-        .map(|stack_id| {
-            let sftp_name = format!("sftp_server_{}", stack_id);
-            let minecraft_name = format!("minecraft_server_{}", stack_id);
-
-            let sftp_status = containers
-                .get(&sftp_name)
-                .cloned()
-                .unwrap_or(ServiceStatus {
-                    status: "stopped".to_string(),
-                    port: None,
-                });
-
-            let minecraft_status =
-                containers
-                    .get(&minecraft_name)
-                    .cloned()
-                    .unwrap_or(ServiceStatus {
-                        status: "stopped".to_string(),
-                        port: None,
-                    });
-
-            json!({
-                "stack_id": stack_id,
-                "wan_ip": wan_ip,
-                "services": {
-                    "sftp_server": {
-                        "status": sftp_status.status,
-                        "port": sftp_status.port
-                    },
-                    "minecraft_server": {
-                        "status": minecraft_status.status,
-                        "port": minecraft_status.port
-                    }
-                }
-            })
-        })
+    let stack_statuses: Vec<LegacyStackSummary> = page
+        .stacks
+        .into_iter()
+        .map(LegacyStackSummary::from)
         .collect();
+    let stack_statuses = query::select_fields(stack_statuses, query.fields())?;
 
-    Ok(HttpResponse::Ok().json(stack_statuses))
+    Ok(query::page_response(
+        req,
+        stack_statuses,
+        page.next_cursor.as_deref(),
+    ))
 }
 
+#[utoipa::path(
+    tag = "stacks",
+    params(StackQuery),
+    responses(
+        (status = 200, description = "Stacks the caller has a role on, the `Link` header points to the next page", body = [LegacyStackSummary]),
+        (status = 204, description = "No stack matches"),
+        (status = 400, description = "Invalid filter, sort, cursor or field", body = ErrorResponse),
+        (status = 500, description = "Docker or the stacks directory could not be read", body = ErrorResponse),
+    )
+)]
 #[get("/stacks")]
-pub async fn list_stacks() -> Result<HttpResponse, Error> {
-    list_stacks_impl().await
+pub async fn list_stacks(
+    req: HttpRequest,
+    users: web::Data<UserStore>,
+    monitor: web::Data<Monitor>,
+    wan: web::Data<WanAddress>,
+    principal: Principal,
+    query: web::Query<StackQuery>,
+) -> Result<HttpResponse, Error> {
+    list_stacks_impl(&req, &users, &monitor, &wan, principal, &query)
+        .await
+        .map_err(|e| e.into_legacy().into())
 }
@@ -0,0 +1,535 @@
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+use utoipa::IntoParams;
+
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::list::{ServiceState, StackSummary};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct StackQuery {
+    /// `running`, `stopped` or `crashed`, state of the Minecraft server
+    state: Option<String>,
+    /// Username of the owner
+    owner: Option<String>,
+    /// Server software, such as `vanilla`
+    server_type: Option<String>,
+    /// Minecraft version, such as `latest` or `1.21.1`
+    version: Option<String>,
+    /// Comma-separated tags, stacks must carry all of them
+    tag: Option<String>,
+    /// `id`, `name`, `created` or `players`, prefixed with `-` for descending order, `id` by default
+    sort: Option<String>,
+    /// Stacks per page, up to 100, every stack unless `limit` or `cursor` is given
+    limit: Option<usize>,
+    /// Cursor of the next page, from the `Link` header of the previous one
+    cursor: Option<String>,
+    /// Comma-separated fields to return, all by default
+    fields: Option<String>,
+}
+
+impl StackQuery {
+    pub fn fields(&self) -> Option<&str> {
+        self.fields.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortField {
+    Id,
+    Name,
+    Created,
+    Players,
+}
+
+struct Sort {
+    field: SortField,
+    descending: bool,
+}
+
+impl Sort {
+    fn parse(sort: Option<&str>) -> Result<Self, ApiError> {
+        let sort = sort.unwrap_or("id");
+        let (descending, name) = match sort.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, sort),
+        };
+        let field = match name {
+            "id" => SortField::Id,
+            "name" => SortField::Name,
+            "created" => SortField::Created,
+            "players" => SortField::Players,
+            _ => {
+                return Err(ApiError::new(
+                    ErrorCode::ValidationFailed,
+                    format!(
+                        "Invalid sort: '{}'. Must be 'id', 'name', 'created' or 'players', optionally prefixed with '-'",
+                        sort
+                    ),
+                ))
+            }
+        };
+
+        Ok(Self { field, descending })
+    }
+
+    fn key(&self, stack: &StackSummary) -> SortKey {
+        match self.field {
+            SortField::Id => SortKey::Number(u64::from(stack.stack_id)),
+            SortField::Name => SortKey::Text(stack.name.as_ref().map(|name| name.to_lowercase())),
+            SortField::Created => SortKey::Time(stack.created_at),
+            SortField::Players => SortKey::Number(u64::from(stack.players)),
+        }
+    }
+
+    // Stack ids break ties, so every stack has a distinct position
+    fn compare(&self, a: (&SortKey, u32), b: (&SortKey, u32)) -> Ordering {
+        let ordering = a.0.cmp(b.0).then(a.1.cmp(&b.1));
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+enum SortKey {
+    Number(u64),
+    Text(Option<String>),
+    Time(Option<DateTime<Utc>>),
+}
+
+/// Position after the last stack of a page, handed to clients hex-encoded.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    key: SortKey,
+    stack_id: u32,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).expect("cursors serialize to JSON"))
+    }
+
+    fn decode(cursor: &str) -> Result<Self, ApiError> {
+        hex::decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| ApiError::new(ErrorCode::ValidationFailed, "Invalid cursor"))
+    }
+}
+
+/// A page of stacks, with the cursor of the next one.
+pub struct StackPage {
+    pub stacks: Vec<StackSummary>,
+    pub next_cursor: Option<String>,
+}
+
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn parse_state(state: Option<&str>) -> Result<Option<ServiceState>, ApiError> {
+    match state {
+        None => Ok(None),
+        Some("running") => Ok(Some(ServiceState::Running)),
+        Some("stopped") => Ok(Some(ServiceState::Stopped)),
+        Some("crashed") => Ok(Some(ServiceState::Crashed)),
+        Some(state) => Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!(
+                "Invalid state filter: '{}'. Must be 'running', 'stopped' or 'crashed'",
+                state
+            ),
+        )),
+    }
+}
+
+fn matches(stack: &StackSummary, state: Option<ServiceState>, query: &StackQuery) -> bool {
+    let eq = |value: &str, filter: &Option<String>| {
+        filter
+            .as_deref()
+            .is_none_or(|filter| value.eq_ignore_ascii_case(filter))
+    };
+
+    state.is_none_or(|state| stack.services.minecraft_server == state)
+        && query
+            .owner
+            .as_deref()
+            .is_none_or(|owner| stack.owner.as_deref() == Some(owner))
+        && eq(&stack.server_type, &query.server_type)
+        && eq(&stack.version, &query.version)
+        && query.tag.as_deref().is_none_or(|tags| {
+            split_list(tags).all(|tag| stack.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+        })
+}
+
+/// Filters, sorts and paginates the stacks as the query asks.
+pub fn apply(stacks: Vec<StackSummary>, query: &StackQuery) -> Result<StackPage, ApiError> {
+    let sort = Sort::parse(query.sort.as_deref())?;
+    let sort_name = query.sort.clone().unwrap_or_else(|| "id".to_string());
+    let state = parse_state(query.state.as_deref())?;
+
+    let mut keyed: Vec<(SortKey, StackSummary)> = stacks
+        .into_iter()
+        .filter(|stack| matches(stack, state, query))
+        .map(|stack| (sort.key(&stack), stack))
+        .collect();
+    keyed.sort_by(|(a_key, a), (b_key, b)| sort.compare((a_key, a.stack_id), (b_key, b.stack_id)));
+
+    if query.limit.is_none() && query.cursor.is_none() {
+        return Ok(StackPage {
+            stacks: keyed.into_iter().map(|(_, stack)| stack).collect(),
+            next_cursor: None,
+        });
+    }
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("Limit must be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+
+    if let Some(cursor) = query.cursor.as_deref() {
+        let cursor = Cursor::decode(cursor)?;
+        if cursor.sort != sort_name {
+            return Err(ApiError::new(
+                ErrorCode::ValidationFailed,
+                "The cursor belongs to a different sort order",
+            ));
+        }
+        keyed.retain(|(key, stack)| {
+            sort.compare((key, stack.stack_id), (&cursor.key, cursor.stack_id)) == Ordering::Greater
+        });
+    }
+
+    let has_more = keyed.len() > limit;
+    keyed.truncate(limit);
+    let next_cursor = match keyed.last() {
+        Some((key, stack)) if has_more => Some(
+            Cursor {
+                sort: sort_name,
+                key: key.clone(),
+                stack_id: stack.stack_id,
+            }
+            .encode(),
+        ),
+        _ => None,
+    };
+
+    Ok(StackPage {
+        stacks: keyed.into_iter().map(|(_, stack)| stack).collect(),
+        next_cursor,
+    })
+}
+
+/// Keeps only the requested fields of each stack.
+pub fn select_fields<T: Serialize>(
+    stacks: Vec<T>,
+    fields: Option<&str>,
+) -> Result<Vec<Value>, ApiError> {
+    let stacks = stacks
+        .into_iter()
+        .map(|stack| serde_json::to_value(stack))
+        .collect::<Result<Vec<Value>, _>>()
+        .map_err(|e| ApiError::internal(format!("Failed to serialize stacks: {}", e)))?;
+
+    let Some(fields) = fields else {
+        return Ok(stacks);
+    };
+    let fields: Vec<&str> = split_list(fields).collect();
+
+    stacks
+        .into_iter()
+        .map(|stack| {
+            let Value::Object(mut object) = stack else {
+                return Ok(stack);
+            };
+            if let Some(field) = fields.iter().find(|field| !object.contains_key(**field)) {
+                return Err(ApiError::new(
+                    ErrorCode::ValidationFailed,
+                    format!(
+                        "Unknown field: '{}'. Must be one of {}",
+                        field,
+                        object
+                            .keys()
+                            .map(|key| format!("'{}'", key))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                ));
+            }
+            object.retain(|key, _| fields.contains(&key.as_str()));
+            Ok(Value::Object(object))
+        })
+        .collect()
+}
+
+/// Answers a page of stacks, pointing to the next one with a `Link` header.
+pub fn page_response(
+    req: &HttpRequest,
+    stacks: Vec<Value>,
+    next_cursor: Option<&str>,
+) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if let Some(cursor) = next_cursor {
+        // Same query, with the cursor of the next page
+        let mut params: Vec<String> = req
+            .query_string()
+            .split('&')
+            .filter(|param| !param.is_empty() && !param.starts_with("cursor="))
+            .map(str::to_string)
+            .collect();
+        params.push(format!("cursor={}", cursor));
+        response.insert_header((
+            header::LINK,
+            format!("<{}?{}>; rel=\"next\"", req.path(), params.join("&")),
+        ));
+    }
+    response.json(stacks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::routes::list::StackServices;
+    use crate::stacks::{StackPorts, StackRole};
+    use chrono::TimeZone;
+
+    fn stack(stack_id: u32, name: Option<&str>, players: u32) -> StackSummary {
+        StackSummary {
+            stack_id,
+            name: name.map(str::to_string),
+            owner: None,
+            role: StackRole::Admin,
+            wan_ip: None,
+            wan_hostname: None,
+            server_type: "vanilla".to_string(),
+            version: "latest".to_string(),
+            tags: Vec::new(),
+            memory_mb: 1024,
+            ports: StackPorts {
+                minecraft_server: 25565,
+                rcon: 25575,
+                sftp_server: 2222,
+            },
+            services: StackServices {
+                minecraft_server: ServiceState::Running,
+                sftp_server: ServiceState::Running,
+            },
+            players,
+            idle_timeout_minutes: None,
+            restart_policy: "unless-stopped".to_string(),
+            desired_state: None,
+            created_at: (!stack_id.is_multiple_of(3)).then(|| {
+                Utc.timestamp_opt(1_700_000_000 - i64::from(stack_id) * 60, 0)
+                    .unwrap()
+            }),
+        }
+    }
+
+    // Names, player counts and creation times collide, so ties must be broken by id
+    fn stacks() -> Vec<StackSummary> {
+        vec![
+            stack(4, Some("Survival"), 3),
+            stack(1, Some("creative"), 0),
+            stack(7, None, 3),
+            stack(2, Some("survival"), 12),
+            stack(9, Some("Creative"), 0),
+            stack(3, None, 3),
+            stack(5, Some("Anarchy"), 0),
+        ]
+    }
+
+    fn ids(stacks: &[StackSummary]) -> Vec<u32> {
+        stacks.iter().map(|stack| stack.stack_id).collect()
+    }
+
+    fn error(result: Result<StackPage, ApiError>) -> ApiError {
+        match result {
+            Ok(_) => panic!("the query should have failed"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for key in [
+            SortKey::Number(u64::MAX),
+            SortKey::Text(Some("Ünïcode \"name\"".to_string())),
+            SortKey::Text(None),
+            SortKey::Time(Some(Utc.timestamp_opt(1_700_000_000, 123).unwrap())),
+            SortKey::Time(None),
+        ] {
+            let cursor = Cursor {
+                sort: "-name".to_string(),
+                key: key.clone(),
+                stack_id: 42,
+            };
+            let encoded = cursor.encode();
+            assert!(encoded.bytes().all(|byte| byte.is_ascii_hexdigit()));
+
+            let decoded = Cursor::decode(&encoded).unwrap();
+            assert_eq!(decoded.sort, "-name");
+            assert_eq!(decoded.key, key);
+            assert_eq!(decoded.stack_id, 42);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_cursors() {
+        let valid = Cursor {
+            sort: "id".to_string(),
+            key: SortKey::Number(1),
+            stack_id: 1,
+        }
+        .encode();
+        for cursor in [
+            "",
+            "not hex",
+            &valid[1..],
+            &hex::encode("{\"sort\":\"id\"}"),
+            &hex::encode("garbage"),
+        ] {
+            let e = Cursor::decode(cursor).expect_err(cursor);
+            assert_eq!(e.code, ErrorCode::ValidationFailed);
+        }
+    }
+
+    #[test]
+    fn pages_visit_every_stack_once_in_order() {
+        for sort in [
+            "id", "-id", "name", "-name", "created", "-created", "players", "-players",
+        ] {
+            let all = StackQuery {
+                sort: Some(sort.to_string()),
+                ..Default::default()
+            };
+            let all = apply(stacks(), &all).unwrap();
+            assert!(all.next_cursor.is_none());
+            assert_eq!(all.stacks.len(), 7);
+
+            let mut paged = Vec::new();
+            let mut cursor = None;
+            loop {
+                let query = StackQuery {
+                    sort: Some(sort.to_string()),
+                    limit: Some(3),
+                    cursor: cursor.take(),
+                    ..Default::default()
+                };
+                let page = apply(stacks(), &query).unwrap();
+                assert!(page.stacks.len() <= 3);
+                paged.extend(ids(&page.stacks));
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => break,
+                }
+            }
+            assert_eq!(paged, ids(&all.stacks), "sort {}", sort);
+        }
+    }
+
+    #[test]
+    fn sorts_names_without_case_and_breaks_ties_by_id() {
+        let query = StackQuery {
+            sort: Some("name".to_string()),
+            ..Default::default()
+        };
+        let page = apply(stacks(), &query).unwrap();
+        assert_eq!(ids(&page.stacks), [3, 7, 5, 1, 9, 2, 4]);
+
+        let query = StackQuery {
+            sort: Some("-players".to_string()),
+            ..Default::default()
+        };
+        let page = apply(stacks(), &query).unwrap();
+        assert_eq!(ids(&page.stacks), [2, 7, 4, 3, 9, 5, 1]);
+    }
+
+    #[test]
+    fn cursors_survive_the_removal_of_their_stack() {
+        let query = StackQuery {
+            sort: Some("-players".to_string()),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = apply(stacks(), &query).unwrap();
+        assert_eq!(ids(&first.stacks), [2, 7]);
+
+        let remaining: Vec<_> = stacks()
+            .into_iter()
+            .filter(|stack| stack.stack_id != 7)
+            .collect();
+        let query = StackQuery {
+            cursor: first.next_cursor,
+            ..query
+        };
+        let second = apply(remaining, &query).unwrap();
+        assert_eq!(ids(&second.stacks), [4, 3]);
+    }
+
+    #[test]
+    fn last_page_has_no_cursor() {
+        let query = StackQuery {
+            limit: Some(7),
+            ..Default::default()
+        };
+        assert!(apply(stacks(), &query).unwrap().next_cursor.is_none());
+
+        let query = StackQuery {
+            limit: Some(6),
+            ..Default::default()
+        };
+        let page = apply(stacks(), &query).unwrap();
+        let query = StackQuery {
+            limit: Some(6),
+            cursor: page.next_cursor,
+            ..Default::default()
+        };
+        let last = apply(stacks(), &query).unwrap();
+        assert_eq!(ids(&last.stacks), [9]);
+        assert!(last.next_cursor.is_none());
+    }
+
+    #[test]
+    fn rejects_cursors_of_another_sort_and_invalid_limits() {
+        let query = StackQuery {
+            limit: Some(2),
+            ..Default::default()
+        };
+        let cursor = apply(stacks(), &query).unwrap().next_cursor;
+        let query = StackQuery {
+            sort: Some("-id".to_string()),
+            cursor,
+            ..Default::default()
+        };
+        assert_eq!(
+            error(apply(stacks(), &query)).code,
+            ErrorCode::ValidationFailed
+        );
+
+        for limit in [0, MAX_PAGE_SIZE + 1] {
+            let query = StackQuery {
+                limit: Some(limit),
+                ..Default::default()
+            };
+            assert_eq!(
+                error(apply(stacks(), &query)).code,
+                ErrorCode::ValidationFailed
+            );
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use actix_web::{get, post, web, Error, HttpResponse};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::auth::{Admin, Principal};
+use crate::backups::{self, BackupManifest};
+use crate::events::EventBus;
+use crate::jobs::{Job, JobHandle, JobKind, JobStore};
+use crate::offsite::Offsite;
+use crate::openapi::ErrorResponse;
+use crate::routes::backups::{offsite_not_configured, restore_backup_impl, RestoreQuery};
+use crate::routes::create;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::jobs::{accepted, JobReference};
+use crate::stacks::StackRole;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct OffsiteQuery {
+    /// Only the backups of this stack, which may no longer exist
+    stack_id: Option<String>,
+}
+
+fn offsite_error(e: std::io::Error) -> ApiError {
+    ApiError::internal(format!("Failed to reach the off-site target: {}", e))
+}
+
+async fn restore_impl(
+    offsite: Arc<Offsite>,
+    events: Arc<EventBus>,
+    owner: Option<Uuid>,
+    backup: BackupManifest,
+    new_stack: bool,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    job.step(2, "Downloading the backup from the off-site target")
+        .await;
+    let backup = {
+        let _guard = backups::LOCK.lock().await;
+        offsite
+            .fetch(backup)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to download the backup: {}", e)))?
+    };
+
+    if new_stack {
+        return create::create_from_backup(events, owner, backup, job).await;
+    }
+    restore_backup_impl(backup.stack_id.clone(), backup, job).await
+}
+
+/// Starts restoring a backup of the bucket into its stack or into a new one.
+pub async fn start_restore(
+    jobs: Arc<JobStore>,
+    offsite: Arc<Offsite>,
+    events: Arc<EventBus>,
+    principal: &Principal,
+    stack_id: String,
+    backup_id: &str,
+    new_stack: bool,
+) -> Result<Job, ApiError> {
+    if !offsite.is_configured() {
+        return Err(offsite_not_configured());
+    }
+    if !new_stack {
+        principal.authorize(&stack_id, StackRole::Admin)?;
+    }
+
+    let not_found = || {
+        ApiError::new(
+            ErrorCode::BackupNotFound,
+            format!("Stack {} has no off-site backup {}", stack_id, backup_id),
+        )
+    };
+    let id = Uuid::parse_str(backup_id).map_err(|_| not_found())?;
+    let backup = offsite
+        .get(&stack_id, &id)
+        .await
+        .map_err(offsite_error)?
+        .ok_or_else(not_found)?;
+
+    let owner = principal.user_id;
+    let task = move |job| restore_impl(offsite, events, owner, backup, new_stack, job);
+    if new_stack {
+        return Ok(jobs
+            .spawn(JobKind::RestoreOffsiteBackup, None, owner, task)
+            .await);
+    }
+    jobs.spawn_exclusive(JobKind::RestoreOffsiteBackup, stack_id, owner, task)
+        .await
+        .map_err(ApiError::operation_in_progress)
+}
+
+#[utoipa::path(
+    tag = "offsite",
+    params(OffsiteQuery),
+    responses(
+        (status = 200, description = "Manifests of the backups in the bucket, newest first", body = [BackupManifest]),
+        (status = 400, description = "No off-site target is configured", body = ErrorResponse),
+        (status = 500, description = "The bucket could not be listed", body = ErrorResponse),
+    )
+)]
+#[get("/offsite/backups")]
+pub async fn list_offsite_backups(
+    _admin: Admin,
+    offsite: web::Data<Offsite>,
+    query: web::Query<OffsiteQuery>,
+) -> Result<HttpResponse, Error> {
+    if !offsite.is_configured() {
+        return Err(offsite_not_configured().into_legacy().into());
+    }
+    let backups = offsite
+        .list(query.stack_id.as_deref())
+        .await
+        .map_err(|e| offsite_error(e).into_legacy())?;
+
+    Ok(HttpResponse::Ok().json(backups))
+}
+
+#[utoipa::path(
+    tag = "offsite",
+    params(
+        ("stack_id" = String, Path, description = "Id of the stack the backup was taken of"),
+        ("backup_id" = String, Path, description = "Backup id"),
+        RestoreQuery,
+    ),
+    responses(
+        (status = 202, description = "Restore started", body = JobReference),
+        (status = 400, description = "No off-site target is configured", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, for a restore into it, or backup", body = ErrorResponse),
+        (status = 409, description = "Another job is changing the stack, for a restore into it", body = ErrorResponse),
+    )
+)]
+#[post("/offsite/backups/{stack_id}/{backup_id}/restore")]
+pub async fn restore_offsite_backup(
+    jobs: web::Data<JobStore>,
+    offsite: web::Data<Offsite>,
+    events: web::Data<EventBus>,
+    Admin(principal): Admin,
+    path: web::Path<(String, String)>,
+    query: web::Query<RestoreQuery>,
+) -> Result<HttpResponse, Error> {
+    let (stack_id, backup_id) = path.into_inner();
+    let job = start_restore(
+        jobs.into_inner(),
+        offsite.into_inner(),
+        events.into_inner(),
+        &principal,
+        stack_id,
+        &backup_id,
+        query.new_stack,
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
+}
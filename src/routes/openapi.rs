@@ -0,0 +1,32 @@
+use actix_web::{get, HttpResponse};
+use std::sync::LazyLock;
+use utoipa::OpenApi;
+
+use crate::openapi::{ApiDoc, ApiDocV2};
+
+// The document only depends on the code, it is generated once
+static DOCUMENT: LazyLock<String> = LazyLock::new(|| {
+    ApiDoc::openapi()
+        .to_json()
+        .expect("OpenAPI document serializes to JSON")
+});
+
+static DOCUMENT_V2: LazyLock<String> = LazyLock::new(|| {
+    ApiDocV2::openapi()
+        .to_json()
+        .expect("OpenAPI document serializes to JSON")
+});
+
+#[get("/openapi.json")]
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(DOCUMENT.as_str())
+}
+
+#[get("/openapi.json")]
+pub async fn openapi_v2_json() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(DOCUMENT_V2.as_str())
+}
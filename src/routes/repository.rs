@@ -0,0 +1,99 @@
+use actix_web::{get, post, web, Error, HttpResponse};
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::auth::Admin;
+use crate::backups::{self, RepositoryStats};
+use crate::jobs::{JobHandle, JobKind, JobStore};
+use crate::openapi::ErrorResponse;
+use crate::routes::backups::collect_garbage;
+use crate::routes::error::ApiError;
+use crate::routes::jobs::{accepted, JobReference};
+
+fn to_value(report: impl serde::Serialize) -> Result<Value, ApiError> {
+    serde_json::to_value(report)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize job result: {}", e)))
+}
+
+async fn check_impl(job: JobHandle) -> Result<Value, ApiError> {
+    // Backups are not written while the chunks are read
+    let _guard = backups::LOCK.lock().await;
+    job.step(10, "Reading every chunk of the repository").await;
+    let report = tokio::task::spawn_blocking(backups::check_repository)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .map_err(|e| ApiError::internal(format!("Failed to check the repository: {}", e)))?;
+    to_value(report)
+}
+
+async fn collect_garbage_impl(job: JobHandle) -> Result<Value, ApiError> {
+    job.step(10, "Removing the chunks no backup uses").await;
+    to_value(collect_garbage().await?)
+}
+
+#[utoipa::path(
+    tag = "repository",
+    responses(
+        (status = 200, description = "Size of the backup repository", body = RepositoryStats),
+        (status = 500, description = "The repository could not be read", body = ErrorResponse),
+    )
+)]
+#[get("/repository")]
+pub async fn get_repository(_admin: Admin) -> Result<HttpResponse, Error> {
+    let stats = tokio::task::spawn_blocking(backups::repository_stats)
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()).into_legacy())?
+        .map_err(|e| {
+            ApiError::internal(format!("Failed to read the repository: {}", e)).into_legacy()
+        })?;
+
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+#[utoipa::path(
+    tag = "repository",
+    responses(
+        (status = 202, description = "Check started, its result is a CheckReport", body = JobReference),
+    )
+)]
+#[post("/repository/check")]
+pub async fn check_repository(
+    jobs: web::Data<JobStore>,
+    Admin(principal): Admin,
+) -> Result<HttpResponse, Error> {
+    let jobs: Arc<JobStore> = jobs.into_inner();
+    let job = jobs
+        .spawn(
+            JobKind::CheckRepository,
+            None,
+            principal.user_id,
+            check_impl,
+        )
+        .await;
+
+    Ok(accepted(&job))
+}
+
+#[utoipa::path(
+    tag = "repository",
+    responses(
+        (status = 202, description = "Collection started, its result is a GarbageReport", body = JobReference),
+    )
+)]
+#[post("/repository/gc")]
+pub async fn collect_repository(
+    jobs: web::Data<JobStore>,
+    Admin(principal): Admin,
+) -> Result<HttpResponse, Error> {
+    let jobs: Arc<JobStore> = jobs.into_inner();
+    let job = jobs
+        .spawn(
+            JobKind::CollectGarbage,
+            None,
+            principal.user_id,
+            collect_garbage_impl,
+        )
+        .await;
+
+    Ok(accepted(&job))
+}
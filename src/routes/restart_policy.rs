@@ -0,0 +1,100 @@
+use actix_web::{put, web, Error, HttpResponse};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use utoipa::ToSchema;
+
+use crate::auth::Principal;
+use crate::openapi::ErrorResponse;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::stacks::{self, StackRole};
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
+pub struct RestartPolicy {
+    /// `no`, `always`, `unless-stopped`, `on-failure` or `on-failure:<max retries>`
+    #[schema(example = "on-failure:5")]
+    pub restart_policy: String,
+}
+
+fn validate_restart_policy(policy: &str) -> Result<(), ApiError> {
+    let policy_re =
+        Regex::new(r"^(no|always|unless-stopped|on-failure(:[1-9][0-9]{0,2})?)$").unwrap();
+    if !policy_re.is_match(policy) {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!(
+                "Invalid restart policy: '{}'. Must be 'no', 'always', 'unless-stopped', 'on-failure' or 'on-failure:<1 to 999>'",
+                policy
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Changes the restart policy of a stack's Minecraft server, for its admins.
+///
+/// The policy is kept in the stack's `.env` file and applied to the running container.
+pub async fn set_restart_policy(
+    principal: &Principal,
+    stack_id: &str,
+    update: RestartPolicy,
+) -> Result<RestartPolicy, ApiError> {
+    // Callers without a role learn nothing of the stack, not even that their policy is invalid
+    principal.authorize(stack_id, StackRole::Admin)?;
+    validate_restart_policy(&update.restart_policy)?;
+
+    stacks::write_env(stack_id, "RESTART_POLICY", &update.restart_policy)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to write .env file: {}", e)))?;
+
+    let output = Command::new("docker")
+        .args([
+            "update",
+            "--restart",
+            &update.restart_policy,
+            &format!("minecraft_server_{}", stack_id),
+        ])
+        .output()
+        .await
+        .map_err(|e| {
+            ApiError::new(
+                ErrorCode::DockerUnavailable,
+                format!("Failed to execute docker update: {}", e),
+            )
+        })?;
+
+    // Stopped stacks have no container, they get the policy when started
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() && !stderr.contains("No such container") {
+        return Err(ApiError::internal(format!(
+            "Failed to update the restart policy of stack {}: {}",
+            stack_id, stderr
+        )));
+    }
+
+    Ok(update)
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    request_body = RestartPolicy,
+    responses(
+        (status = 200, description = "Restart policy changed", body = RestartPolicy),
+        (status = 400, description = "Invalid restart policy", body = ErrorResponse),
+        (status = 403, description = "The caller is not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+    )
+)]
+#[put("/stacks/{stack_id}/restart_policy")]
+pub async fn update_restart_policy(
+    principal: Principal,
+    stack_id: web::Path<String>,
+    update: web::Json<RestartPolicy>,
+) -> Result<HttpResponse, Error> {
+    let policy = set_restart_policy(&principal, &stack_id, update.into_inner())
+        .await
+        .map_err(ApiError::into_legacy)?;
+
+    Ok(HttpResponse::Ok().json(policy))
+}
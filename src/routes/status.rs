@@ -1,129 +1,228 @@
-use actix_web::{patch, web, Error, HttpResponse, ResponseError};
+use actix_web::{patch, web, Error, HttpResponse};
 use serde::{Deserialize, Serialize};
-use serde_json::json;
+use serde_json::{json, Value};
 use std::fmt;
 use std::path::PathBuf;
+use std::sync::Arc;
 use tokio::process::Command;
+use utoipa::ToSchema;
 
-#[derive(Debug, Deserialize, Serialize)]
+use crate::auth::Principal;
+use crate::jobs::{Job, JobHandle, JobKind, JobStore};
+use crate::openapi::ErrorResponse;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::jobs::{accepted, JobReference};
+use crate::stacks::{self, DesiredState, StackMeta, StackRole};
+
+#[derive(Debug, Deserialize, Serialize, ToSchema)]
 pub struct StatusUpdate {
-    status: String,
+    /// `running` or `stopped`
+    #[schema(example = "running")]
+    pub status: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub enum StackStatus {
     Running,
     Stopped,
 }
 
-#[derive(Debug)]
-pub enum StackError {
-    // Made public
-    StackNotFound(String),
-    DockerError(String),
-    InvalidStatus(String),
-}
-
 impl TryFrom<String> for StackStatus {
-    type Error = StackError;
+    type Error = ApiError;
 
     fn try_from(status: String) -> Result<Self, Self::Error> {
         match status.to_lowercase().as_str() {
             "running" => Ok(StackStatus::Running),
             "stopped" => Ok(StackStatus::Stopped),
-            _ => Err(StackError::InvalidStatus(format!(
-                "Invalid status value: '{}'. Must be 'running' or 'stopped'",
-                status
-            ))),
+            _ => Err(ApiError::new(
+                ErrorCode::ValidationFailed,
+                format!(
+                    "Invalid status value: '{}'. Must be 'running' or 'stopped'",
+                    status
+                ),
+            )),
         }
     }
 }
 
-impl fmt::Display for StackError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::StackNotFound(msg) | Self::DockerError(msg) | Self::InvalidStatus(msg) => {
-                write!(f, "{}", msg)
-            }
+impl From<StackStatus> for DesiredState {
+    fn from(status: StackStatus) -> Self {
+        match status {
+            StackStatus::Running => DesiredState::Running,
+            StackStatus::Stopped => DesiredState::Stopped,
         }
     }
 }
 
-impl ResponseError for StackError {
-    fn status_code(&self) -> actix_web::http::StatusCode {
-        match self {
-            StackError::StackNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
-            StackError::DockerError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-            StackError::InvalidStatus(_) => actix_web::http::StatusCode::BAD_REQUEST,
-        }
-    }
-
-    fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .content_type("application/json")
-            .json(json!({ "message": self.to_string() }))
-    }
-}
-
-async fn get_compose_file_path(stack_id: &str) -> Result<PathBuf, StackError> {
+async fn get_compose_file_path(stack_id: &str) -> Result<PathBuf, ApiError> {
     let current_exe = std::env::current_exe()
-        .map_err(|e| StackError::DockerError(format!("Failed to get current path: {}", e)))?;
+        .map_err(|e| ApiError::internal(format!("Failed to get current path: {}", e)))?;
 
     let stack_dir = current_exe
         .parent()
-        .ok_or_else(|| StackError::DockerError("Failed to find executable directory".to_string()))?
+        .ok_or_else(|| ApiError::internal("Failed to find executable directory"))?
         .join("stacks")
         .join(format!("stack_{}", stack_id))
         .join("compose.yaml");
 
     if !stack_dir.exists() {
-        return Err(StackError::StackNotFound(format!(
-            "Stack {} does not exist",
-            stack_id
-        )));
+        return Err(ApiError::new(
+            ErrorCode::StackNotFound,
+            format!("Stack {} does not exist", stack_id),
+        ));
     }
 
     Ok(stack_dir)
 }
 
+impl fmt::Display for StackStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackStatus::Running => write!(f, "running"),
+            StackStatus::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
 async fn update_stack_status_impl(
     stack_id: String,
-    status_update: StatusUpdate,
-) -> Result<HttpResponse, Error> {
-    // Convert and validate status
-    let status = StackStatus::try_from(status_update.status)?;
-
+    number: u32,
+    status: StackStatus,
+    reason: Option<String>,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
     let compose_file = get_compose_file_path(&stack_id).await?;
+    if let Some(reason) = &reason {
+        job.step(5, reason.clone()).await;
+    }
 
     let docker_command = match status {
         StackStatus::Running => vec!["up", "-d"],
         StackStatus::Stopped => vec!["down"],
     };
 
+    // Recorded first, a stack that failed to start should still be started on the next boot
+    StackMeta::update(&stack_id, |meta| meta.desired_state = Some(status.into()))
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to update stack.json: {}", e)))?;
+
+    job.step(10, format!("Setting stack {} to {}", stack_id, status))
+        .await;
+
     let mut cmd = Command::new("docker");
     cmd.args(["compose", "-f", compose_file.to_str().unwrap()]);
     cmd.args(&docker_command);
 
-    let output = cmd
-        .output()
-        .await
-        .map_err(|e| StackError::DockerError(format!("Failed to execute docker compose: {}", e)))?;
+    let output = cmd.output().await.map_err(|e| {
+        ApiError::new(
+            ErrorCode::DockerUnavailable,
+            format!("Failed to execute docker compose: {}", e),
+        )
+    })?;
 
     if !output.status.success() {
         let error_msg = String::from_utf8_lossy(&output.stderr);
-        return Err(StackError::DockerError(format!(
+        return Err(ApiError::internal(format!(
             "Failed to update stack {} status: {}",
             stack_id, error_msg
-        )))?;
+        )));
+    }
+
+    let mut result = json!({
+        "stack_id": number,
+        "status": status.to_string()
+    });
+    if let Some(reason) = reason {
+        result["reason"] = json!(reason);
     }
+    Ok(result)
+}
 
-    Ok(HttpResponse::NoContent().finish())
+/// Checks the status and the caller's role, then starts the status job.
+pub async fn start_status_update(
+    jobs: Arc<JobStore>,
+    principal: &Principal,
+    stack_id: String,
+    update: StatusUpdate,
+) -> Result<Job, ApiError> {
+    let status = StackStatus::try_from(update.status)?;
+
+    // Unknown stacks are rejected right away rather than through a failed job
+    get_compose_file_path(&stack_id).await?;
+    let number = stacks::parse_id(&stack_id).ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::StackNotFound,
+            format!("Stack {} does not exist", stack_id),
+        )
+    })?;
+
+    principal.authorize(&stack_id, StackRole::Operator)?;
+
+    jobs.spawn_exclusive(
+        JobKind::UpdateStackStatus,
+        stack_id.clone(),
+        principal.user_id,
+        move |job| update_stack_status_impl(stack_id, number, status, None, job),
+    )
+    .await
+    .map_err(ApiError::operation_in_progress)
+}
+
+/// Starts the status job on behalf of mc_stack itself, such as when a player wakes a stack.
+///
+/// Refused as requests are while another job changes the stack, callers try again later.
+/// The `reason` is kept in the job's steps and result.
+pub async fn start_system_status_update(
+    jobs: Arc<JobStore>,
+    stack_id: String,
+    status: StackStatus,
+    reason: String,
+) -> Result<Job, ApiError> {
+    get_compose_file_path(&stack_id).await?;
+    let number = stacks::parse_id(&stack_id).ok_or_else(|| {
+        ApiError::new(
+            ErrorCode::StackNotFound,
+            format!("Stack {} does not exist", stack_id),
+        )
+    })?;
+
+    jobs.spawn_exclusive(
+        JobKind::UpdateStackStatus,
+        stack_id.clone(),
+        None,
+        move |job| update_stack_status_impl(stack_id, number, status, Some(reason), job),
+    )
+    .await
+    .map_err(ApiError::operation_in_progress)
 }
 
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = String, Path, description = "Stack id")),
+    request_body = StatusUpdate,
+    responses(
+        (status = 202, description = "Status change started", body = JobReference),
+        (status = 400, description = "Invalid status", body = ErrorResponse),
+        (status = 403, description = "The caller is not an operator of the stack", body = ErrorResponse),
+        (status = 404, description = "Unknown stack, or the caller has no role on it", body = ErrorResponse),
+        (status = 409, description = "Another job is changing the stack", body = ErrorResponse),
+    )
+)]
 #[patch("/stacks/{stack_id}/status")]
 pub async fn update_stack_status(
+    jobs: web::Data<JobStore>,
+    principal: Principal,
     stack_id: web::Path<String>,
     status: web::Json<StatusUpdate>,
 ) -> Result<HttpResponse, Error> {
-    update_stack_status_impl(stack_id.into_inner(), status.into_inner()).await
+    let job = start_status_update(
+        jobs.into_inner(),
+        &principal,
+        stack_id.into_inner(),
+        status.into_inner(),
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
 }
@@ -0,0 +1,230 @@
+use actix_web::{get, post, web, Error, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::fs;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::config::Config;
+use crate::events::{EventBus, StackEvent};
+use crate::jobs::{Job, JobHandle, JobKind, JobStore};
+use crate::openapi::ErrorResponse;
+use crate::routes::error::{ApiError, ErrorCode};
+use crate::routes::jobs::{accepted, JobReference};
+use crate::stacks::{self, DesiredState, StackMeta, StackRole};
+use crate::trash::{self, TrashEntry};
+use crate::volumes;
+
+/// A deleted stack that can be restored.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TrashedStack {
+    pub trash_id: Uuid,
+    pub stack_id: String,
+    pub name: Option<String>,
+    pub deleted_at: DateTime<Utc>,
+    /// When the stack is removed for good
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The world was saved, otherwise the stack comes back with an empty one
+    pub world: bool,
+}
+
+/// Stacks in the trash the caller is an admin of, newest deletion first.
+pub fn trashed_stacks(
+    config: &Config,
+    principal: &Principal,
+) -> Result<Vec<TrashedStack>, ApiError> {
+    let entries = trash::list()
+        .map_err(|e| ApiError::internal(format!("Failed to read the trash: {}", e)))?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|entry| {
+            entry
+                .meta()
+                .is_ok_and(|meta| principal.role_on(&meta) == Some(StackRole::Admin))
+        })
+        .map(|entry| TrashedStack {
+            trash_id: entry.id,
+            stack_id: entry.stack_id,
+            name: entry.name,
+            deleted_at: entry.deleted_at,
+            expires_at: config
+                .trash_retention
+                .and_then(|retention| chrono::Duration::from_std(retention).ok())
+                .and_then(|retention| entry.deleted_at.checked_add_signed(retention)),
+            world: entry.world,
+        })
+        .collect())
+}
+
+async fn restore_stack_impl(
+    entry: TrashEntry,
+    number: u32,
+    events: Arc<EventBus>,
+    job: JobHandle,
+) -> Result<Value, ApiError> {
+    let stack_id = entry.stack_id.clone();
+    let entry_dir = entry
+        .directory()
+        .map_err(|e| ApiError::internal(format!("Failed to find trash entry: {}", e)))?;
+    let volume_name = format!("minecraft_server_{}", stack_id);
+
+    if entry.world {
+        let exists = volumes::exists(&volume_name).await.map_err(|e| {
+            ApiError::new(
+                ErrorCode::DockerUnavailable,
+                format!("Failed to inspect volume {}: {}", volume_name, e),
+            )
+        })?;
+        if exists {
+            return Err(ApiError::new(
+                ErrorCode::ValidationFailed,
+                format!(
+                    "Volume {} already exists, adopt or remove it first",
+                    volume_name
+                ),
+            ));
+        }
+
+        job.step(20, "Restoring world volume").await;
+        volumes::import(&volume_name, &entry_dir.join(trash::WORLD_ARCHIVE))
+            .await
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+    }
+
+    job.step(70, "Restoring stack directory").await;
+    let stack_dir = stacks::stack_directory(&stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to find stacks directory: {}", e)))?;
+    if let Err(e) = fs::rename(entry_dir.join(trash::STACK_DIRECTORY), &stack_dir).await {
+        // The trash entry stays complete, so restoring can be tried again
+        if entry.world {
+            let _ = volumes::remove(&volume_name).await;
+        }
+        return Err(ApiError::internal(format!(
+            "Failed to restore stack directory: {}",
+            e
+        )));
+    }
+
+    // Restored stacks stay stopped until started
+    StackMeta::update(&stack_id, |meta| {
+        meta.desired_state = Some(DesiredState::Stopped)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Failed to update stack.json: {}", e)))?;
+
+    if let Err(e) = fs::remove_dir_all(&entry_dir).await {
+        log::warn!("Failed to remove trash entry {}: {}", entry.id, e);
+    }
+
+    events.publish(StackEvent::StackCreated {
+        stack_id: stack_id.clone(),
+    });
+
+    Ok(json!({ "stack_id": number, "trash_id": entry.id }))
+}
+
+/// Checks the caller was an admin of the deleted stack and starts the restore job.
+pub async fn start_restore_stack(
+    jobs: Arc<JobStore>,
+    events: Arc<EventBus>,
+    principal: &Principal,
+    stack_id: String,
+) -> Result<Job, ApiError> {
+    let not_found = || {
+        ApiError::new(
+            ErrorCode::StackNotFound,
+            format!("Stack {} is not in the trash", stack_id),
+        )
+    };
+    let number = stacks::parse_id(&stack_id).ok_or_else(not_found)?;
+    let entry = trash::latest(&stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to read the trash: {}", e)))?
+        .ok_or_else(not_found)?;
+
+    let meta = entry
+        .meta()
+        .map_err(|e| ApiError::internal(format!("Failed to read stack.json: {}", e)))?;
+    let role = principal.role_on(&meta).ok_or_else(not_found)?;
+    if role < StackRole::Admin {
+        return Err(ApiError::new(
+            ErrorCode::Forbidden,
+            format!(
+                "This operation requires the '{}' role on stack {}, '{}' has '{}'",
+                StackRole::Admin,
+                stack_id,
+                principal.name,
+                role
+            ),
+        ));
+    }
+
+    let exists = stacks::stack_directory(&stack_id)
+        .map_err(|e| ApiError::internal(format!("Failed to find stacks directory: {}", e)))?
+        .exists();
+    if exists {
+        return Err(ApiError::new(
+            ErrorCode::ValidationFailed,
+            format!("Stack {} exists, it cannot be restored", stack_id),
+        ));
+    }
+
+    // Two restores would import into the same volume, and the one failing would remove it
+    jobs.spawn_exclusive(
+        JobKind::RestoreStack,
+        stack_id,
+        principal.user_id,
+        move |job| restore_stack_impl(entry, number, events, job),
+    )
+    .await
+    .map_err(ApiError::operation_in_progress)
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    responses(
+        (status = 200, description = "Stacks in the trash the caller is an admin of", body = [TrashedStack]),
+    )
+)]
+#[get("/trash")]
+pub async fn list_trash(
+    config: web::Data<Config>,
+    principal: Principal,
+) -> Result<HttpResponse, Error> {
+    let stacks = trashed_stacks(&config, &principal).map_err(ApiError::into_legacy)?;
+
+    Ok(HttpResponse::Ok().json(stacks))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = String, Path, description = "Id of the deleted stack")),
+    responses(
+        (status = 202, description = "Restore started", body = JobReference),
+        (status = 400, description = "A stack with this id exists", body = ErrorResponse),
+        (status = 403, description = "The caller was not an admin of the stack", body = ErrorResponse),
+        (status = 404, description = "The stack is not in the trash, or the caller had no role on it", body = ErrorResponse),
+        (status = 409, description = "Another job is restoring the stack", body = ErrorResponse),
+    )
+)]
+#[post("/stacks/{stack_id}/restore")]
+pub async fn restore_stack(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let job = start_restore_stack(
+        jobs.into_inner(),
+        events.into_inner(),
+        &principal,
+        stack_id.into_inner(),
+    )
+    .await
+    .map_err(ApiError::into_legacy)?;
+
+    Ok(accepted(&job))
+}
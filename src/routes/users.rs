@@ -0,0 +1,287 @@
+use actix_web::{delete, get, patch, post, web, Error, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::{Admin, Principal, Scope};
+use crate::openapi::ErrorResponse;
+use crate::stacks;
+use crate::users::{self, Quota, Usage, User, UserStore, UserStoreError};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug)]
+enum UserError {
+    UserNotFound(String),
+    InvalidUser(String),
+    Conflict(String),
+    InternalError(String),
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UserNotFound(msg)
+            | Self::InvalidUser(msg)
+            | Self::Conflict(msg)
+            | Self::InternalError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl ResponseError for UserError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            UserError::UserNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            UserError::InvalidUser(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            UserError::Conflict(_) => actix_web::http::StatusCode::CONFLICT,
+            UserError::InternalError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+impl From<UserStoreError> for UserError {
+    fn from(e: UserStoreError) -> Self {
+        match e {
+            UserStoreError::UsernameTaken(msg) => UserError::Conflict(msg),
+            UserStoreError::UserNotFound(msg) => UserError::UserNotFound(msg),
+            UserStoreError::Io(e) => {
+                UserError::InternalError(format!("Failed to save users: {}", e))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewUser {
+    username: String,
+    #[schema(min_length = 8, format = Password)]
+    password: String,
+    #[serde(default)]
+    admin: bool,
+    max_stacks: Option<u32>,
+    max_memory_mb: Option<u32>,
+}
+
+/// Fields left out are kept.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UserUpdate {
+    #[schema(min_length = 8, format = Password)]
+    password: Option<String>,
+    admin: Option<bool>,
+    max_stacks: Option<u32>,
+    max_memory_mb: Option<u32>,
+}
+
+fn validate_password(password: &str) -> Result<(), UserError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(UserError::InvalidUser(format!(
+            "Password must be at least {} characters long",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+async fn hash_password(password: String) -> Result<String, UserError> {
+    web::block(move || users::hash_password(&password))
+        .await
+        .map_err(|e| UserError::InternalError(format!("Failed to hash password: {}", e)))?
+        .map_err(|e| UserError::InternalError(format!("Failed to hash password: {}", e)))
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct UserWithUsage {
+    #[serde(flatten)]
+    user: User,
+    usage: Usage,
+}
+
+fn with_usage(user: User) -> Result<UserWithUsage, UserError> {
+    let (stacks, memory_mb) = stacks::owned_usage(&user.id)
+        .map_err(|e| UserError::InternalError(format!("Failed to read stacks: {}", e)))?;
+
+    Ok(UserWithUsage {
+        user: user.redacted(),
+        usage: Usage { stacks, memory_mb },
+    })
+}
+
+fn parse_user_id(user_id: &str) -> Result<Uuid, UserError> {
+    Uuid::parse_str(user_id)
+        .map_err(|_| UserError::UserNotFound(format!("User {} does not exist", user_id)))
+}
+
+async fn create_user_impl(
+    users: &UserStore,
+    caller: Principal,
+    new_user: NewUser,
+) -> Result<HttpResponse, Error> {
+    caller.require(Scope::Admin)?;
+
+    let username = new_user.username.trim().to_string();
+    if !users::is_valid_username(&username) {
+        return Err(UserError::InvalidUser(
+            "Username must be made of letters, digits, '.', '_' or '-'".to_string(),
+        ))?;
+    }
+    validate_password(&new_user.password)?;
+
+    let defaults = Quota::default();
+    let quota = Quota {
+        max_stacks: new_user.max_stacks.unwrap_or(defaults.max_stacks),
+        max_memory_mb: new_user.max_memory_mb.unwrap_or(defaults.max_memory_mb),
+    };
+
+    let password_hash = hash_password(new_user.password).await?;
+    let user = users
+        .create(username, password_hash, new_user.admin, quota)
+        .await
+        .map_err(UserError::from)?;
+
+    Ok(HttpResponse::Created().json(with_usage(user)?))
+}
+
+async fn update_user_impl(
+    users: &UserStore,
+    caller: Principal,
+    user_id: String,
+    update: UserUpdate,
+) -> Result<HttpResponse, Error> {
+    caller.require(Scope::Admin)?;
+    let id = parse_user_id(&user_id)?;
+
+    if caller.user_id == Some(id) && update.admin == Some(false) {
+        return Err(UserError::InvalidUser(
+            "Cannot remove your own administrator rights".to_string(),
+        ))?;
+    }
+
+    let password_hash = match update.password {
+        Some(password) => {
+            validate_password(&password)?;
+            Some(hash_password(password).await?)
+        }
+        None => None,
+    };
+
+    let user = users
+        .update(&id, |user| {
+            if let Some(password_hash) = password_hash {
+                user.password_hash = password_hash;
+            }
+            if let Some(admin) = update.admin {
+                user.admin = admin;
+            }
+            if let Some(max_stacks) = update.max_stacks {
+                user.quota.max_stacks = max_stacks;
+            }
+            if let Some(max_memory_mb) = update.max_memory_mb {
+                user.quota.max_memory_mb = max_memory_mb;
+            }
+        })
+        .await
+        .map_err(UserError::from)?;
+
+    Ok(HttpResponse::Ok().json(with_usage(user)?))
+}
+
+async fn delete_user_impl(
+    users: &UserStore,
+    caller: Principal,
+    user_id: String,
+) -> Result<HttpResponse, Error> {
+    caller.require(Scope::Admin)?;
+    let id = parse_user_id(&user_id)?;
+
+    if caller.user_id == Some(id) {
+        return Err(UserError::InvalidUser(
+            "Cannot delete your own account".to_string(),
+        ))?;
+    }
+
+    users.delete(&id).await.map_err(UserError::from)?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    tag = "users",
+    responses(
+        (status = 200, description = "Every account with its quota usage", body = [UserWithUsage]),
+    )
+)]
+#[get("/users")]
+pub async fn list_users(_admin: Admin, users: web::Data<UserStore>) -> Result<HttpResponse, Error> {
+    let list = users
+        .list()
+        .into_iter()
+        .map(with_usage)
+        .collect::<Result<Vec<UserWithUsage>, UserError>>()?;
+
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[utoipa::path(
+    tag = "users",
+    request_body = NewUser,
+    responses(
+        (status = 201, description = "The account was created", body = UserWithUsage),
+        (status = 400, description = "Invalid username or password", body = ErrorResponse),
+        (status = 409, description = "The username is taken", body = ErrorResponse),
+    )
+)]
+#[post("/users")]
+pub async fn create_user(
+    users: web::Data<UserStore>,
+    Admin(caller): Admin,
+    new_user: web::Json<NewUser>,
+) -> Result<HttpResponse, Error> {
+    create_user_impl(&users, caller, new_user.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "users",
+    params(("user_id" = Uuid, Path, description = "User id")),
+    request_body = UserUpdate,
+    responses(
+        (status = 200, description = "The updated account", body = UserWithUsage),
+        (status = 400, description = "Invalid password, or the caller would lose their own administrator rights", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+#[patch("/users/{user_id}")]
+pub async fn update_user(
+    users: web::Data<UserStore>,
+    Admin(caller): Admin,
+    user_id: web::Path<String>,
+    update: web::Json<UserUpdate>,
+) -> Result<HttpResponse, Error> {
+    update_user_impl(&users, caller, user_id.into_inner(), update.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "users",
+    params(("user_id" = Uuid, Path, description = "User id")),
+    responses(
+        (status = 204, description = "The account and its sessions were removed"),
+        (status = 400, description = "The caller cannot delete their own account", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+#[delete("/users/{user_id}")]
+pub async fn delete_user(
+    users: web::Data<UserStore>,
+    Admin(caller): Admin,
+    user_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    delete_user_impl(&users, caller, user_id.into_inner()).await
+}
@@ -0,0 +1,7 @@
+// API v2 serves stacks, their backups and jobs with typed responses and
+// coded errors, everything else is only served by v1
+pub mod backups;
+pub mod jobs;
+pub mod stacks;
+
+pub const BASE_PATH: &str = "/api/v2";
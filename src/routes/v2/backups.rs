@@ -0,0 +1,233 @@
+use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
+
+use crate::auth::Principal;
+use crate::backups::{BackupManifest, BackupSchedule};
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::jobs::JobStore;
+use crate::offsite::Offsite;
+use crate::routes::backups::{self, BackupScheduleReport, RestoreQuery};
+use crate::routes::error::{ApiError, ErrorEnvelope};
+use crate::routes::jobs::{accepted_at, JobReference};
+use crate::routes::v2::BASE_PATH;
+use crate::users::UserStore;
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    responses(
+        (status = 202, description = "Backup started, the job result holds its manifest", body = JobReference),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+        (status = 409, description = "`operation_in_progress`, another job is changing the stack", body = ErrorEnvelope),
+    )
+)]
+#[post("/stacks/{stack_id}/backups")]
+pub async fn create_backup(
+    jobs: web::Data<JobStore>,
+    offsite: web::Data<Offsite>,
+    config: web::Data<Config>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let job = backups::start_create_backup(
+        jobs.into_inner(),
+        offsite.into_inner(),
+        &config,
+        &principal,
+        stack_id.into_inner(),
+    )
+    .await?;
+
+    Ok(accepted_at(BASE_PATH, &job))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "Backups of the stack, newest first", body = [BackupManifest]),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+    )
+)]
+#[get("/stacks/{stack_id}/backups")]
+pub async fn list_backups(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(backups::list_stack_backups(&principal, &stack_id)?))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = u32, Path, description = "Stack id"),
+        ("backup_id" = uuid::Uuid, Path, description = "Backup id"),
+    ),
+    responses(
+        (status = 200, description = "The tarball of the world, gzipped for the `archive` format", content_type = "application/x-tar"),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found` or `backup_not_found`", body = ErrorEnvelope),
+    )
+)]
+#[get("/stacks/{stack_id}/backups/{backup_id}")]
+pub async fn download_backup(
+    req: HttpRequest,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (stack_id, backup_id) = path.into_inner();
+    let backup = backups::get_stack_backup(&principal, &stack_id, &backup_id)?;
+    backups::download(&req, backup).await
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = u32, Path, description = "Stack id"),
+        ("backup_id" = uuid::Uuid, Path, description = "Backup id"),
+    ),
+    responses(
+        (status = 204, description = "Backup deleted"),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found` or `backup_not_found`", body = ErrorEnvelope),
+    )
+)]
+#[delete("/stacks/{stack_id}/backups/{backup_id}")]
+pub async fn delete_backup(
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (stack_id, backup_id) = path.into_inner();
+    backups::delete_stack_backup(&principal, &stack_id, &backup_id)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = u32, Path, description = "Stack id"),
+        ("backup_id" = uuid::Uuid, Path, description = "Backup id"),
+        RestoreQuery,
+    ),
+    responses(
+        (status = 202, description = "Restore started", body = JobReference),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found` or `backup_not_found`", body = ErrorEnvelope),
+        (status = 409, description = "`operation_in_progress`, another job is changing the stack, or `stack_limit_reached` or `quota_exceeded`, for a new stack", body = ErrorEnvelope),
+    )
+)]
+#[post("/stacks/{stack_id}/backups/{backup_id}/restore")]
+pub async fn restore_backup(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    users: web::Data<UserStore>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+    query: web::Query<RestoreQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let (stack_id, backup_id) = path.into_inner();
+    let job = backups::start_restore_backup(
+        jobs.into_inner(),
+        events.into_inner(),
+        &users,
+        &principal,
+        stack_id,
+        &backup_id,
+        query.new_stack,
+    )
+    .await?;
+
+    Ok(accepted_at(BASE_PATH, &job))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(
+        ("stack_id" = u32, Path, description = "Stack id"),
+        ("backup_id" = uuid::Uuid, Path, description = "Backup id"),
+    ),
+    responses(
+        (status = 202, description = "Upload started, the job result holds the manifest", body = JobReference),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found` or `backup_not_found`", body = ErrorEnvelope),
+        (status = 422, description = "`validation_failed`, no off-site target is configured", body = ErrorEnvelope),
+    )
+)]
+#[post("/stacks/{stack_id}/backups/{backup_id}/upload")]
+pub async fn upload_backup(
+    jobs: web::Data<JobStore>,
+    offsite: web::Data<Offsite>,
+    principal: Principal,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ApiError> {
+    let (stack_id, backup_id) = path.into_inner();
+    let job = backups::start_upload_backup(
+        jobs.into_inner(),
+        offsite.into_inner(),
+        &principal,
+        stack_id,
+        &backup_id,
+    )
+    .await?;
+
+    Ok(accepted_at(BASE_PATH, &job))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "Backup schedule of the stack", body = BackupScheduleReport),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+    )
+)]
+#[get("/stacks/{stack_id}/backup_schedule")]
+pub async fn get_schedule(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(backups::get_backup_schedule(&principal, &stack_id)?))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    request_body = BackupSchedule,
+    responses(
+        (status = 200, description = "Backup schedule changed", body = BackupScheduleReport),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+        (status = 422, description = "`validation_failed`, invalid cron expression or retention", body = ErrorEnvelope),
+    )
+)]
+#[put("/stacks/{stack_id}/backup_schedule")]
+pub async fn update_schedule(
+    principal: Principal,
+    stack_id: web::Path<String>,
+    schedule: web::Json<BackupSchedule>,
+) -> Result<HttpResponse, ApiError> {
+    let report =
+        backups::set_backup_schedule(&principal, &stack_id, Some(schedule.into_inner())).await?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[utoipa::path(
+    tag = "backups",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    responses(
+        (status = 204, description = "Backup schedule removed, existing backups are kept"),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+    )
+)]
+#[delete("/stacks/{stack_id}/backup_schedule")]
+pub async fn delete_schedule(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    backups::set_backup_schedule(&principal, &stack_id, None).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
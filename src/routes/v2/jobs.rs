@@ -0,0 +1,104 @@
+use actix_web::{get, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::auth::Principal;
+use crate::jobs::{Job, JobKind, JobState, JobStep, JobStore};
+use crate::routes::error::{ApiError, ErrorEnvelope};
+use crate::routes::jobs::{visible_job, visible_jobs};
+use crate::stacks;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct JobQuery {
+    /// `active`, `finished`, `queued`, `running`, `succeeded` or `failed`
+    state: Option<String>,
+    stack_id: Option<u32>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[schema(as = Job)]
+pub struct JobResponse {
+    id: Uuid,
+    kind: JobKind,
+    /// Stack the job works on, known once a creation allocated it
+    stack_id: Option<u32>,
+    /// User who started the job, `None` for host keys
+    user_id: Option<Uuid>,
+    state: JobState,
+    progress: u8,
+    steps: Vec<JobStep>,
+    /// Outcome of a succeeded job, `{stack_id, ports}` for creations
+    result: Option<Value>,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+}
+
+impl From<Job> for JobResponse {
+    fn from(job: Job) -> Self {
+        Self {
+            id: job.id,
+            kind: job.kind,
+            stack_id: job.stack_id.as_deref().and_then(stacks::parse_id),
+            user_id: job.user_id,
+            state: job.state,
+            progress: job.progress,
+            steps: job.steps,
+            result: job.result,
+            error: job.error,
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+            finished_at: job.finished_at,
+        }
+    }
+}
+
+#[utoipa::path(
+    tag = "jobs",
+    params(JobQuery),
+    responses(
+        (status = 200, description = "Jobs visible to the caller, most recent first", body = [JobResponse]),
+        (status = 400, description = "`malformed_request`, invalid state filter", body = ErrorEnvelope),
+    )
+)]
+#[get("/jobs")]
+pub async fn list_jobs(
+    jobs: web::Data<JobStore>,
+    principal: Principal,
+    query: web::Query<JobQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let stack_id = query.stack_id.map(|id| id.to_string());
+    let list: Vec<JobResponse> = visible_jobs(
+        &jobs,
+        &principal,
+        query.state.as_deref(),
+        stack_id.as_deref(),
+    )?
+    .into_iter()
+    .map(JobResponse::from)
+    .collect();
+
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[utoipa::path(
+    tag = "jobs",
+    params(("job_id" = Uuid, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job", body = JobResponse),
+        (status = 404, description = "`job_not_found`, also for jobs the caller cannot see", body = ErrorEnvelope),
+    )
+)]
+#[get("/jobs/{job_id}")]
+pub async fn get_job(
+    jobs: web::Data<JobStore>,
+    principal: Principal,
+    job_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let job = visible_job(&jobs, &principal, &job_id)?;
+    Ok(HttpResponse::Ok().json(JobResponse::from(job)))
+}
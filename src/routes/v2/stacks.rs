@@ -0,0 +1,268 @@
+use actix_web::{delete, get, patch, post, put, web, HttpRequest, HttpResponse};
+
+use crate::auth::Principal;
+use crate::config::Config;
+use crate::crashes::CrashRecord;
+use crate::events::EventBus;
+use crate::jobs::JobStore;
+use crate::monitor::Monitor;
+use crate::routes::crashes;
+use crate::routes::create::{self, NewStack};
+use crate::routes::delete::{self, DeleteQuery};
+use crate::routes::error::{ApiError, ErrorEnvelope};
+use crate::routes::idle::{self, IdleTimeout};
+use crate::routes::jobs::{accepted_at, JobReference};
+use crate::routes::list::query::{self, StackQuery};
+use crate::routes::list::{self, StackSummary};
+use crate::routes::restart_policy::{self, RestartPolicy};
+use crate::routes::status::{self, StatusUpdate};
+use crate::routes::trash::{self, TrashedStack};
+use crate::routes::v2::BASE_PATH;
+use crate::users::UserStore;
+use crate::wan::WanAddress;
+
+#[utoipa::path(
+    tag = "stacks",
+    params(StackQuery),
+    responses(
+        (status = 200, description = "Stacks the caller has a role on, the `Link` header points to the next page", body = [StackSummary]),
+        (status = 422, description = "`validation_failed`, invalid filter, sort, cursor or field", body = ErrorEnvelope),
+        (status = 503, description = "Docker is unavailable", body = ErrorEnvelope),
+    )
+)]
+#[get("/stacks")]
+pub async fn list_stacks(
+    req: HttpRequest,
+    users: web::Data<UserStore>,
+    monitor: web::Data<Monitor>,
+    wan: web::Data<WanAddress>,
+    principal: Principal,
+    query: web::Query<StackQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let summaries = list::stack_summaries(&users, &monitor, &wan, &principal).await?;
+    let page = query::apply(summaries, &query)?;
+    let stacks = query::select_fields(page.stacks, query.fields())?;
+
+    Ok(query::page_response(
+        &req,
+        stacks,
+        page.next_cursor.as_deref(),
+    ))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "The stack", body = StackSummary),
+        (status = 404, description = "`stack_not_found`, also when the caller has no role on it", body = ErrorEnvelope),
+        (status = 503, description = "Docker is unavailable", body = ErrorEnvelope),
+    )
+)]
+#[get("/stacks/{stack_id}")]
+pub async fn get_stack(
+    users: web::Data<UserStore>,
+    monitor: web::Data<Monitor>,
+    wan: web::Data<WanAddress>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let summary = list::stack_summary(&users, &monitor, &wan, &principal, &stack_id).await?;
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    request_body(content = Option<NewStack>, description = "Optional, every field has a default"),
+    responses(
+        (status = 202, description = "Creation started, the job result holds the stack id and ports", body = JobReference),
+        (status = 400, description = "`malformed_request`", body = ErrorEnvelope),
+        (status = 409, description = "`stack_limit_reached` or `quota_exceeded`", body = ErrorEnvelope),
+        (status = 422, description = "`validation_failed`, e.g. too little memory", body = ErrorEnvelope),
+    )
+)]
+#[post("/stacks")]
+pub async fn create_stack(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    users: web::Data<UserStore>,
+    principal: Principal,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let new_stack = create::parse_new_stack(&body)?;
+    let job = create::start_create_stack(
+        jobs.into_inner(),
+        events.into_inner(),
+        &users,
+        &principal,
+        new_stack,
+    )
+    .await?;
+
+    Ok(accepted_at(BASE_PATH, &job))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = u32, Path, description = "Stack id"), DeleteQuery),
+    responses(
+        (status = 202, description = "Deletion started", body = JobReference),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+        (status = 409, description = "`operation_in_progress`, another job is changing the stack", body = ErrorEnvelope),
+    )
+)]
+#[delete("/stacks/{stack_id}")]
+pub async fn delete_stack(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    config: web::Data<Config>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+    query: web::Query<DeleteQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let job = delete::start_delete_stack(
+        jobs.into_inner(),
+        events.into_inner(),
+        &config,
+        &principal,
+        stack_id.into_inner(),
+        query.permanent,
+    )
+    .await?;
+
+    Ok(accepted_at(BASE_PATH, &job))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = u32, Path, description = "Id of the deleted stack")),
+    responses(
+        (status = 202, description = "Restore started", body = JobReference),
+        (status = 403, description = "`forbidden`, the caller was not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`, the stack is not in the trash", body = ErrorEnvelope),
+        (status = 409, description = "`operation_in_progress`, another job is restoring the stack", body = ErrorEnvelope),
+        (status = 422, description = "`validation_failed`, a stack with this id exists", body = ErrorEnvelope),
+    )
+)]
+#[post("/stacks/{stack_id}/restore")]
+pub async fn restore_stack(
+    jobs: web::Data<JobStore>,
+    events: web::Data<EventBus>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let job = trash::start_restore_stack(
+        jobs.into_inner(),
+        events.into_inner(),
+        &principal,
+        stack_id.into_inner(),
+    )
+    .await?;
+
+    Ok(accepted_at(BASE_PATH, &job))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    responses(
+        (status = 200, description = "Stacks in the trash the caller is an admin of", body = [TrashedStack]),
+    )
+)]
+#[get("/trash")]
+pub async fn list_trash(
+    config: web::Data<Config>,
+    principal: Principal,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(trash::trashed_stacks(&config, &principal)?))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    request_body = StatusUpdate,
+    responses(
+        (status = 202, description = "Status change started", body = JobReference),
+        (status = 403, description = "`forbidden`, the caller is not an operator of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+        (status = 409, description = "`operation_in_progress`, another job is changing the stack", body = ErrorEnvelope),
+        (status = 422, description = "`validation_failed`, unknown status", body = ErrorEnvelope),
+    )
+)]
+#[patch("/stacks/{stack_id}/status")]
+pub async fn update_stack_status(
+    jobs: web::Data<JobStore>,
+    principal: Principal,
+    stack_id: web::Path<String>,
+    update: web::Json<StatusUpdate>,
+) -> Result<HttpResponse, ApiError> {
+    let job = status::start_status_update(
+        jobs.into_inner(),
+        &principal,
+        stack_id.into_inner(),
+        update.into_inner(),
+    )
+    .await?;
+
+    Ok(accepted_at(BASE_PATH, &job))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    request_body = IdleTimeout,
+    responses(
+        (status = 200, description = "Idle timeout changed", body = IdleTimeout),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+        (status = 422, description = "`validation_failed`, timeout out of range", body = ErrorEnvelope),
+    )
+)]
+#[put("/stacks/{stack_id}/idle_timeout")]
+pub async fn update_idle_timeout(
+    principal: Principal,
+    stack_id: web::Path<String>,
+    update: web::Json<IdleTimeout>,
+) -> Result<HttpResponse, ApiError> {
+    let idle_timeout = idle::set_idle_timeout(&principal, &stack_id, update.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(idle_timeout))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    request_body = RestartPolicy,
+    responses(
+        (status = 200, description = "Restart policy changed", body = RestartPolicy),
+        (status = 403, description = "`forbidden`, the caller is not an admin of the stack", body = ErrorEnvelope),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+        (status = 422, description = "`validation_failed`, unknown policy", body = ErrorEnvelope),
+    )
+)]
+#[put("/stacks/{stack_id}/restart_policy")]
+pub async fn update_restart_policy(
+    principal: Principal,
+    stack_id: web::Path<String>,
+    update: web::Json<RestartPolicy>,
+) -> Result<HttpResponse, ApiError> {
+    let policy =
+        restart_policy::set_restart_policy(&principal, &stack_id, update.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+#[utoipa::path(
+    tag = "stacks",
+    params(("stack_id" = u32, Path, description = "Stack id")),
+    responses(
+        (status = 200, description = "The last 20 crashes of the stack, most recent first", body = [CrashRecord]),
+        (status = 404, description = "`stack_not_found`", body = ErrorEnvelope),
+    )
+)]
+#[get("/stacks/{stack_id}/crashes")]
+pub async fn list_crashes(
+    principal: Principal,
+    stack_id: web::Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let records = crashes::crash_records(&principal, &stack_id)?;
+    Ok(HttpResponse::Ok().json(records))
+}
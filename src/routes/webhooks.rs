@@ -0,0 +1,254 @@
+use actix_web::{delete, get, post, web, Error, HttpResponse, ResponseError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::auth::Admin;
+use crate::events::EVENT_TYPES;
+use crate::openapi::ErrorResponse;
+use crate::webhooks::{Delivery, Webhook, WebhookManager};
+
+#[derive(Debug)]
+enum WebhookError {
+    WebhookNotFound(String),
+    InvalidWebhook(String),
+    FileSystemError(String),
+}
+
+impl fmt::Display for WebhookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WebhookNotFound(msg) | Self::InvalidWebhook(msg) | Self::FileSystemError(msg) => {
+                write!(f, "{}", msg)
+            }
+        }
+    }
+}
+
+impl ResponseError for WebhookError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            WebhookError::WebhookNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
+            WebhookError::InvalidWebhook(_) => actix_web::http::StatusCode::BAD_REQUEST,
+            WebhookError::FileSystemError(_) => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .content_type("application/json")
+            .json(json!({ "message": self.to_string() }))
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct NewWebhook {
+    /// http or https URL receiving the events
+    url: String,
+    /// Event types to deliver, all of them when empty
+    #[serde(default)]
+    events: Vec<String>,
+    /// Signing secret, generated when left out
+    secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookSummary {
+    id: Uuid,
+    url: String,
+    events: Vec<String>,
+    created_at: DateTime<Utc>,
+    /// Signing secret, only revealed when the webhook is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+// The secret is only revealed when the webhook is created
+fn summary(webhook: &Webhook) -> WebhookSummary {
+    WebhookSummary {
+        id: webhook.id,
+        url: webhook.url.clone(),
+        events: webhook.events.clone(),
+        created_at: webhook.created_at,
+        secret: None,
+    }
+}
+
+fn find_webhook(webhooks: &WebhookManager, webhook_id: &str) -> Result<Webhook, WebhookError> {
+    Uuid::parse_str(webhook_id)
+        .ok()
+        .and_then(|id| webhooks.get(&id))
+        .ok_or_else(|| {
+            WebhookError::WebhookNotFound(format!("Webhook {} does not exist", webhook_id))
+        })
+}
+
+fn validate(new_webhook: &NewWebhook) -> Result<(), WebhookError> {
+    let url = reqwest::Url::parse(&new_webhook.url).map_err(|e| {
+        WebhookError::InvalidWebhook(format!("Invalid url '{}': {}", new_webhook.url, e))
+    })?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(WebhookError::InvalidWebhook(format!(
+            "Invalid url '{}': must use http or https",
+            new_webhook.url
+        )));
+    }
+
+    if let Some(unknown) = new_webhook
+        .events
+        .iter()
+        .find(|event| !EVENT_TYPES.contains(&event.as_str()))
+    {
+        return Err(WebhookError::InvalidWebhook(format!(
+            "Unknown event type '{}'. Must be one of: {}",
+            unknown,
+            EVENT_TYPES.join(", ")
+        )));
+    }
+
+    if matches!(&new_webhook.secret, Some(secret) if secret.is_empty()) {
+        return Err(WebhookError::InvalidWebhook(
+            "Secret must not be empty".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn create_webhook_impl(
+    webhooks: &WebhookManager,
+    new_webhook: NewWebhook,
+) -> Result<HttpResponse, Error> {
+    validate(&new_webhook)?;
+
+    let webhook = webhooks
+        .create(new_webhook.url, new_webhook.events, new_webhook.secret)
+        .await
+        .map_err(|e| WebhookError::FileSystemError(format!("Failed to save webhook: {}", e)))?;
+
+    Ok(HttpResponse::Created().json(WebhookSummary {
+        secret: Some(webhook.secret.clone()),
+        ..summary(&webhook)
+    }))
+}
+
+async fn delete_webhook_impl(
+    webhooks: &WebhookManager,
+    webhook_id: String,
+) -> Result<HttpResponse, Error> {
+    let webhook = find_webhook(webhooks, &webhook_id)?;
+
+    webhooks
+        .delete(&webhook.id)
+        .await
+        .map_err(|e| WebhookError::FileSystemError(format!("Failed to save webhooks: {}", e)))?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    responses(
+        (status = 200, description = "Every webhook, without their secrets", body = [WebhookSummary]),
+    )
+)]
+#[get("/webhooks")]
+pub async fn list_webhooks(
+    _admin: Admin,
+    webhooks: web::Data<WebhookManager>,
+) -> Result<HttpResponse, Error> {
+    let list: Vec<WebhookSummary> = webhooks.list().iter().map(summary).collect();
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    request_body = NewWebhook,
+    responses(
+        (status = 201, description = "The webhook was registered, with its secret", body = WebhookSummary),
+        (status = 400, description = "Invalid url, event type or secret", body = ErrorResponse),
+    )
+)]
+#[post("/webhooks")]
+pub async fn create_webhook(
+    _admin: Admin,
+    webhooks: web::Data<WebhookManager>,
+    new_webhook: web::Json<NewWebhook>,
+) -> Result<HttpResponse, Error> {
+    create_webhook_impl(&webhooks, new_webhook.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    params(("webhook_id" = Uuid, Path, description = "Webhook id")),
+    responses(
+        (status = 200, description = "The webhook", body = WebhookSummary),
+        (status = 404, description = "Unknown webhook", body = ErrorResponse),
+    )
+)]
+#[get("/webhooks/{webhook_id}")]
+pub async fn get_webhook(
+    _admin: Admin,
+    webhooks: web::Data<WebhookManager>,
+    webhook_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let webhook = find_webhook(&webhooks, &webhook_id)?;
+    Ok(HttpResponse::Ok().json(summary(&webhook)))
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    params(("webhook_id" = Uuid, Path, description = "Webhook id")),
+    responses(
+        (status = 204, description = "The webhook was removed"),
+        (status = 404, description = "Unknown webhook", body = ErrorResponse),
+    )
+)]
+#[delete("/webhooks/{webhook_id}")]
+pub async fn delete_webhook(
+    _admin: Admin,
+    webhooks: web::Data<WebhookManager>,
+    webhook_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    delete_webhook_impl(&webhooks, webhook_id.into_inner()).await
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    params(("webhook_id" = Uuid, Path, description = "Webhook id")),
+    responses(
+        (status = 200, description = "Recent deliveries to the webhook, most recent first", body = [Delivery]),
+        (status = 404, description = "Unknown webhook", body = ErrorResponse),
+    )
+)]
+#[get("/webhooks/{webhook_id}/deliveries")]
+pub async fn list_webhook_deliveries(
+    _admin: Admin,
+    webhooks: web::Data<WebhookManager>,
+    webhook_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let webhook = find_webhook(&webhooks, &webhook_id)?;
+    Ok(HttpResponse::Ok().json(webhooks.deliveries(&webhook.id)))
+}
+
+#[utoipa::path(
+    tag = "webhooks",
+    params(("webhook_id" = Uuid, Path, description = "Webhook id")),
+    responses(
+        (status = 202, description = "A `ping` event is being delivered", body = Delivery),
+        (status = 404, description = "Unknown webhook", body = ErrorResponse),
+    )
+)]
+#[post("/webhooks/{webhook_id}/test")]
+pub async fn test_webhook(
+    _admin: Admin,
+    webhooks: web::Data<WebhookManager>,
+    webhook_id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let webhook = find_webhook(&webhooks, &webhook_id)?;
+    let delivery = webhooks.into_inner().test(webhook).await;
+    Ok(HttpResponse::Accepted().json(delivery))
+}
@@ -0,0 +1,316 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::OwnedMutexGuard;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::backups::BackupSchedule;
+use crate::storage;
+
+const META_FILE: &str = "stack.json";
+const DEFAULT_RESTART_POLICY: &str = "on-failure:5";
+
+// Held while stack.json is read, changed and written, one lock per stack
+static META_LOCKS: LazyLock<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+pub fn stacks_directory() -> io::Result<PathBuf> {
+    let current_exe = std::env::current_exe()?;
+
+    let stacks_dir = current_exe
+        .parent()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "Failed to find executable directory",
+            )
+        })?
+        .join("stacks");
+
+    if !stacks_dir.exists() {
+        std::fs::create_dir_all(&stacks_dir)?;
+    }
+
+    Ok(stacks_dir)
+}
+
+/// Number of a stack, `None` for ids no stack can have.
+pub fn parse_id(stack_id: &str) -> Option<u32> {
+    stack_id.parse().ok()
+}
+
+pub fn stack_directory(stack_id: &str) -> io::Result<PathBuf> {
+    Ok(stacks_directory()?.join(format!("stack_{}", stack_id)))
+}
+
+/// Ids of the stacks having a compose file, in ascending order.
+pub fn list_stack_ids() -> io::Result<Vec<String>> {
+    let mut ids: Vec<u32> = Vec::new();
+    for entry in std::fs::read_dir(stacks_directory()?)? {
+        let entry = entry?;
+        if !entry.path().join("compose.yaml").exists() {
+            continue;
+        }
+        if let Some(id) = entry
+            .file_name()
+            .to_string_lossy()
+            .strip_prefix("stack_")
+            .and_then(|id| id.parse().ok())
+        {
+            ids.push(id);
+        }
+    }
+
+    ids.sort_unstable();
+    Ok(ids.into_iter().map(|id| id.to_string()).collect())
+}
+
+/// Variables of the stack's `.env` file.
+pub fn read_env(stack_id: &str) -> io::Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(stack_directory(stack_id)?.join(".env"))?;
+
+    Ok(content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.trim().to_string(),
+                value.trim().trim_matches('"').to_string(),
+            )
+        })
+        .collect())
+}
+
+/// Sets a variable of the stack's `.env` file, appending it when missing.
+pub async fn write_env(stack_id: &str, key: &str, value: &str) -> io::Result<()> {
+    let path = stack_directory(stack_id)?.join(".env");
+    let content = tokio::fs::read_to_string(&path).await?;
+
+    let mut found = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((name, _)) if !line.starts_with('#') && name.trim() == key => {
+                found = true;
+                format!("{}={}", key, value)
+            }
+            _ => line.to_string(),
+        })
+        .collect();
+    if !found {
+        lines.push(format!("{}={}", key, value));
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, lines.join("\n") + "\n").await?;
+    tokio::fs::rename(&tmp_path, &path).await
+}
+
+/// Parses a Java heap size such as `1024M` or `2G` into megabytes.
+pub fn parse_memory_mb(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let (number, unit) = value.split_at(value.find(|c: char| !c.is_ascii_digit())?);
+    let number: u32 = number.parse().ok()?;
+
+    match unit.to_ascii_uppercase().as_str() {
+        "M" => Some(number),
+        "G" => number.checked_mul(1024),
+        _ => None,
+    }
+}
+
+/// Host ports published by a stack.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct StackPorts {
+    pub minecraft_server: u16,
+    pub rcon: u16,
+    pub sftp_server: u16,
+}
+
+/// Ports of the stack, as configured in its `.env` file.
+pub fn ports(stack_id: &str) -> io::Result<StackPorts> {
+    let env = read_env(stack_id)?;
+    let port = |name: &str| {
+        env.get(name)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Stack {} has no valid {}", stack_id, name),
+                )
+            })
+    };
+
+    Ok(StackPorts {
+        minecraft_server: port("SERVER_PORT")?,
+        rcon: port("RCON_PORT")?,
+        sftp_server: port("SFTP_SERVER_PORT")?,
+    })
+}
+
+/// Memory given to the stack's Minecraft server, in megabytes.
+pub fn memory_mb(stack_id: &str) -> io::Result<u32> {
+    Ok(read_env(stack_id)?
+        .get("JAVA_XMX")
+        .and_then(|value| parse_memory_mb(value))
+        .unwrap_or(0))
+}
+
+/// Minecraft version the stack runs, `latest` unless pinned at creation.
+pub fn minecraft_version(env: &HashMap<String, String>) -> String {
+    env.get("MINECRAFT_VERSION")
+        .cloned()
+        .unwrap_or_else(|| "latest".to_string())
+}
+
+/// Server software of the stack, `vanilla` unless its `.env` says otherwise.
+pub fn server_type(env: &HashMap<String, String>) -> String {
+    env.get("SERVER_TYPE")
+        .map(|value| value.to_lowercase())
+        .unwrap_or_else(|| "vanilla".to_string())
+}
+
+/// Docker restart policy of the stack's Minecraft server, `on-failure:5` unless changed.
+pub fn restart_policy(env: &HashMap<String, String>) -> String {
+    env.get("RESTART_POLICY")
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_RESTART_POLICY.to_string())
+}
+
+/// Rights of a user on a single stack, each role includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StackRole {
+    /// See the stack, its status, logs and jobs
+    Viewer,
+    /// Start and stop the stack, use its console and whitelist
+    Operator,
+    /// Change the stack's configuration, backups and collaborators, delete it
+    Admin,
+}
+
+impl TryFrom<&str> for StackRole {
+    type Error = String;
+
+    fn try_from(role: &str) -> Result<Self, Self::Error> {
+        match role.to_lowercase().as_str() {
+            "viewer" => Ok(StackRole::Viewer),
+            "operator" => Ok(StackRole::Operator),
+            "admin" => Ok(StackRole::Admin),
+            _ => Err(format!(
+                "Invalid role: '{}'. Must be 'viewer', 'operator' or 'admin'",
+                role
+            )),
+        }
+    }
+}
+
+impl fmt::Display for StackRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackRole::Viewer => write!(f, "viewer"),
+            StackRole::Operator => write!(f, "operator"),
+            StackRole::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Collaborator {
+    pub user_id: Uuid,
+    pub role: StackRole,
+}
+
+/// Whether a stack should run, restored when mc_stack starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DesiredState {
+    Running,
+    Stopped,
+}
+
+/// mc_stack's own record of a stack, kept next to its compose file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StackMeta {
+    /// User owning the stack, `None` for stacks created by host keys or before accounts existed
+    pub owner: Option<Uuid>,
+    #[serde(default)]
+    pub collaborators: Vec<Collaborator>,
+    pub created_at: Option<DateTime<Utc>>,
+    /// Display name given at creation
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Minutes without players after which the stack is stopped, never when unset
+    #[serde(default)]
+    pub idle_timeout_minutes: Option<u32>,
+    /// Set by every start and stop, `None` for stacks not started or stopped since upgrading
+    #[serde(default)]
+    pub desired_state: Option<DesiredState>,
+    /// Backups taken by mc_stack itself, none when unset
+    #[serde(default)]
+    pub backup_schedule: Option<BackupSchedule>,
+}
+
+impl StackMeta {
+    /// Role of a user on the stack, the owner is its admin.
+    pub fn role_of(&self, user_id: &Uuid) -> Option<StackRole> {
+        if self.owner.as_ref() == Some(user_id) {
+            return Some(StackRole::Admin);
+        }
+        self.collaborators
+            .iter()
+            .find(|collaborator| collaborator.user_id == *user_id)
+            .map(|collaborator| collaborator.role)
+    }
+
+    pub fn load(stack_id: &str) -> io::Result<Self> {
+        Ok(storage::read_json(&stack_directory(stack_id)?.join(META_FILE))?.unwrap_or_default())
+    }
+
+    pub async fn save(&self, stack_id: &str) -> io::Result<()> {
+        storage::write_json(&stack_directory(stack_id)?.join(META_FILE), self).await
+    }
+
+    /// Waits until no one else changes the record of the stack, until the guard is dropped.
+    ///
+    /// Taken before loading a record that will be saved, so no change is lost.
+    pub async fn lock(stack_id: &str) -> OwnedMutexGuard<()> {
+        let lock = META_LOCKS
+            .lock()
+            .unwrap()
+            .entry(stack_id.to_string())
+            .or_default()
+            .clone();
+        lock.lock_owned().await
+    }
+
+    /// Loads, changes and saves the record of the stack under its lock.
+    pub async fn update(stack_id: &str, change: impl FnOnce(&mut Self)) -> io::Result<Self> {
+        let _guard = Self::lock(stack_id).await;
+        let mut meta = Self::load(stack_id)?;
+        change(&mut meta);
+        meta.save(stack_id).await?;
+        Ok(meta)
+    }
+}
+
+/// Number of stacks and total memory in megabytes owned by a user.
+pub fn owned_usage(user_id: &Uuid) -> io::Result<(u32, u32)> {
+    let mut stacks = 0;
+    let mut memory = 0;
+    for stack_id in list_stack_ids()? {
+        if StackMeta::load(&stack_id)?.owner.as_ref() == Some(user_id) {
+            stacks += 1;
+            memory += memory_mb(&stack_id)?;
+        }
+    }
+    Ok((stacks, memory))
+}
@@ -0,0 +1,86 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use uuid::Uuid;
+
+/// Directory holding mc_stack's own state (jobs, settings, ...), next to the executable.
+pub fn data_directory() -> io::Result<PathBuf> {
+    let current_exe = std::env::current_exe()?;
+
+    let data_dir = current_exe
+        .parent()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "Failed to find executable directory",
+            )
+        })?
+        .join("data");
+
+    if !data_dir.exists() {
+        std::fs::create_dir_all(&data_dir)?;
+    }
+
+    Ok(data_dir)
+}
+
+/// Returns a subdirectory of the data directory, creating it if needed.
+pub fn data_subdirectory(name: &str) -> io::Result<PathBuf> {
+    let dir = data_directory()?.join(name);
+
+    if !dir.exists() {
+        std::fs::create_dir_all(&dir)?;
+    }
+
+    Ok(dir)
+}
+
+pub fn read_json<T: DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    match std::fs::read(path) {
+        Ok(content) => serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the value through a temporary file so readers never see a partial document.
+///
+/// Concurrent writes of a file each use their own temporary file, the last rename wins.
+/// Callers keep their writes in order themselves.
+pub async fn write_json<T: Serialize>(path: &Path, value: &T) -> io::Result<()> {
+    let content = serde_json::to_vec_pretty(value)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let tmp_path = path.with_extension(format!("json.{}.tmp", Uuid::new_v4().simple()));
+    fs::write(&tmp_path, content).await?;
+    fs::rename(&tmp_path, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_writes_leave_a_whole_document() {
+        let dir = std::env::temp_dir().join(format!("mc_stack-storage-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("values.json");
+
+        let writes = (0..32u32).map(|i| {
+            let path = path.clone();
+            tokio::spawn(async move { write_json(&path, &vec![i; 10_000]).await })
+        });
+        for write in writes.collect::<Vec<_>>() {
+            write.await.unwrap().unwrap();
+        }
+
+        let values: Vec<u32> = read_json(&path).unwrap().unwrap();
+        assert_eq!(values.len(), 10_000);
+        assert!(values.iter().all(|value| *value == values[0]));
+        // No temporary file left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderValue},
+    middleware::Next,
+    web, Error, HttpResponse,
+};
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{Config, TlsConfig};
+
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Failed to load {}: {}", path.display(), e),
+    )
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| invalid(cert_path, e))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, "no certificate found"));
+    }
+
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid(key_path, e))?;
+    let signing_key = ring::sign::any_supported_type(&key).map_err(|e| invalid(key_path, e))?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Serves the configured certificate and swaps it when its files change on disk.
+#[derive(Debug)]
+struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCert {
+    /// Checks the files periodically, a broken renewal keeps the previous certificate.
+    fn watch(self: Arc<Self>) {
+        let mut last_modified = (modified(&self.cert_path), modified(&self.key_path));
+
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+
+                let modified_now = (modified(&self.cert_path), modified(&self.key_path));
+                if modified_now == last_modified {
+                    continue;
+                }
+                last_modified = modified_now;
+
+                match load_certified_key(&self.cert_path, &self.key_path) {
+                    Ok(key) => {
+                        *self.current.write().unwrap() = Arc::new(key);
+                        log::info!("Reloaded TLS certificate {}", self.cert_path.display());
+                    }
+                    // Files are often replaced one at a time, the next change retries
+                    Err(e) => log::error!("Keeping the previous TLS certificate: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Builds the rustls configuration and starts watching the certificate files.
+pub fn server_config(tls: &TlsConfig) -> io::Result<ServerConfig> {
+    let resolver = Arc::new(ReloadingCert {
+        cert_path: tls.cert_path.clone(),
+        key_path: tls.key_path.clone(),
+        current: RwLock::new(Arc::new(load_certified_key(&tls.cert_path, &tls.key_path)?)),
+    });
+    resolver.clone().watch();
+
+    Ok(
+        ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver),
+    )
+}
+
+/// Redirects plain HTTP requests to HTTPS when enabled, and adds HSTS to HTTPS responses.
+pub async fn enforce_https(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let Some(tls) = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.tls.clone())
+    else {
+        return Ok(next.call(req).await?.map_into_left_body());
+    };
+    let secure = req.app_config().secure();
+
+    if !secure && tls.redirect_http {
+        let host = req.connection_info().host().to_string();
+        // Strip the HTTP port, keeping bracketed IPv6 addresses intact
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if port.parse::<u16>().is_ok() => name,
+            _ => host.as_str(),
+        };
+        let authority = match tls.https_port {
+            443 => host.to_string(),
+            port => format!("{}:{}", host, port),
+        };
+        let path = req
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+
+        let response = HttpResponse::PermanentRedirect()
+            .insert_header((header::LOCATION, format!("https://{}{}", authority, path)))
+            .finish();
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+
+    let mut response = next.call(req).await?;
+    if secure && tls.hsts_max_age > 0 {
+        if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", tls.hsts_max_age)) {
+            response
+                .headers_mut()
+                .insert(header::STRICT_TRANSPORT_SECURITY, value);
+        }
+    }
+    Ok(response.map_into_left_body())
+}
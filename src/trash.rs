@@ -0,0 +1,115 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::stacks::{self, StackMeta};
+use crate::storage;
+
+const ENTRY_FILE: &str = "trash.json";
+/// Directory of the deleted stack inside an entry
+pub const STACK_DIRECTORY: &str = "stack";
+/// Snapshot of the world volume inside an entry
+pub const WORLD_ARCHIVE: &str = "world.tar.gz";
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// A deleted stack, kept until the trash retention has passed.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct TrashEntry {
+    pub id: Uuid,
+    pub stack_id: String,
+    pub name: Option<String>,
+    pub deleted_at: DateTime<Utc>,
+    /// The world volume was snapshotted, a stack deleted without one comes back with an empty world
+    pub world: bool,
+}
+
+impl TrashEntry {
+    pub fn directory(&self) -> io::Result<PathBuf> {
+        entry_directory(&self.id)
+    }
+
+    /// Record of the stack as it was when deleted.
+    pub fn meta(&self) -> io::Result<StackMeta> {
+        Ok(
+            storage::read_json(&self.directory()?.join(STACK_DIRECTORY).join("stack.json"))?
+                .unwrap_or_default(),
+        )
+    }
+
+    pub async fn save(&self) -> io::Result<()> {
+        storage::write_json(&self.directory()?.join(ENTRY_FILE), self).await
+    }
+}
+
+pub fn entry_directory(id: &Uuid) -> io::Result<PathBuf> {
+    Ok(storage::data_subdirectory("trash")?.join(id.to_string()))
+}
+
+/// Complete entries, newest first. Entries of deletions still running are left out.
+pub fn list() -> io::Result<Vec<TrashEntry>> {
+    let mut entries = Vec::new();
+    for dir in std::fs::read_dir(storage::data_subdirectory("trash")?)? {
+        let path = dir?.path();
+        if !path.join(STACK_DIRECTORY).join("compose.yaml").exists() {
+            continue;
+        }
+        match storage::read_json::<TrashEntry>(&path.join(ENTRY_FILE)) {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => {}
+            Err(e) => log::warn!("Skipping trash entry {}: {}", path.display(), e),
+        }
+    }
+
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.deleted_at));
+    Ok(entries)
+}
+
+/// The last deletion of a stack still in the trash.
+pub fn latest(stack_id: &str) -> io::Result<Option<TrashEntry>> {
+    Ok(list()?.into_iter().find(|entry| entry.stack_id == stack_id))
+}
+
+/// Numbers of the stacks in the trash, new stacks do not take them so they can be restored.
+pub fn stack_numbers() -> io::Result<Vec<u32>> {
+    Ok(list()?
+        .iter()
+        .filter_map(|entry| stacks::parse_id(&entry.stack_id))
+        .collect())
+}
+
+/// Removes entries older than the retention every hour.
+pub fn start_purge(retention: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge(retention) {
+                log::warn!("Failed to purge the trash: {}", e);
+            }
+        }
+    });
+}
+
+fn purge(retention: Duration) -> io::Result<()> {
+    let retention = chrono::Duration::from_std(retention).unwrap_or(chrono::Duration::MAX);
+    for entry in list()? {
+        let expired = entry
+            .deleted_at
+            .checked_add_signed(retention)
+            .is_some_and(|expiry| expiry <= Utc::now());
+        if !expired {
+            continue;
+        }
+        std::fs::remove_dir_all(entry.directory()?)?;
+        log::info!(
+            "Purged stack {} from the trash, deleted on {}",
+            entry.stack_id,
+            entry.deleted_at
+        );
+    }
+    Ok(())
+}
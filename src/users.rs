@@ -0,0 +1,326 @@
+use argon2::password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::Argon2;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::storage;
+
+pub const DEFAULT_MAX_STACKS: u32 = 2;
+pub const DEFAULT_MAX_MEMORY_MB: u32 = 4096;
+const SESSION_LIFETIME_DAYS: i64 = 7;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+pub struct Quota {
+    pub max_stacks: u32,
+    pub max_memory_mb: u32,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            max_stacks: DEFAULT_MAX_STACKS,
+            max_memory_mb: DEFAULT_MAX_MEMORY_MB,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    #[schema(ignore)]
+    pub password_hash: String,
+    pub admin: bool,
+    pub quota: Quota,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Stacks owned by a user and the memory they were given, counted against the quota.
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct Usage {
+    pub stacks: u32,
+    pub memory_mb: u32,
+}
+
+impl User {
+    /// Copy safe to return through the API.
+    pub fn redacted(&self) -> User {
+        User {
+            password_hash: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Session {
+    hash: String,
+    user_id: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub enum UserStoreError {
+    UsernameTaken(String),
+    UserNotFound(String),
+    Io(io::Error),
+}
+
+impl From<io::Error> for UserStoreError {
+    fn from(e: io::Error) -> Self {
+        UserStoreError::Io(e)
+    }
+}
+
+/// Usernames are made of ASCII letters, digits, `.`, `_` and `-`.
+pub fn is_valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Hashes with Argon2id, slow on purpose so run it off the async workers.
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Hash of no account's password, made with the parameters of [`hash_password`] so
+/// that checking it takes as long as checking a real one.
+pub const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$TCZdKVFT4CWs3XTor+aKcA$Fbg2DKx4NFUOVvkopVran463CiNP0tAsDFGfZHoImIE";
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .map(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+        .unwrap_or(false)
+}
+
+/// User accounts and their login sessions, stored in `data/users.json` and `data/sessions.json`.
+pub struct UserStore {
+    users_path: PathBuf,
+    sessions_path: PathBuf,
+    users: Mutex<Vec<User>>,
+    sessions: Mutex<Vec<Session>>,
+    // Held from taking a snapshot of a file's content until it is written, so writes land in order
+    users_persist_lock: tokio::sync::Mutex<()>,
+    sessions_persist_lock: tokio::sync::Mutex<()>,
+}
+
+impl UserStore {
+    pub fn load() -> io::Result<Self> {
+        let data_dir = storage::data_directory()?;
+        let users_path = data_dir.join("users.json");
+        let sessions_path = data_dir.join("sessions.json");
+
+        let users = storage::read_json(&users_path)?.unwrap_or_default();
+        let mut sessions: Vec<Session> = storage::read_json(&sessions_path)?.unwrap_or_default();
+        sessions.retain(|session| session.expires_at > Utc::now());
+
+        Ok(Self {
+            users_path,
+            sessions_path,
+            users: Mutex::new(users),
+            sessions: Mutex::new(sessions),
+            users_persist_lock: tokio::sync::Mutex::new(()),
+            sessions_persist_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn list(&self) -> Vec<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .map(User::redacted)
+            .collect()
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|user| user.id == *id)
+            .cloned()
+    }
+
+    pub fn find_by_username(&self, username: &str) -> Option<User> {
+        self.users
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|user| user.username.eq_ignore_ascii_case(username))
+            .cloned()
+    }
+
+    pub async fn create(
+        &self,
+        username: String,
+        password_hash: String,
+        admin: bool,
+        quota: Quota,
+    ) -> Result<User, UserStoreError> {
+        let user = User {
+            id: Uuid::new_v4(),
+            username,
+            password_hash,
+            admin,
+            quota,
+            created_at: Utc::now(),
+        };
+
+        let _guard = self.users_persist_lock.lock().await;
+        let users = {
+            let mut users = self.users.lock().unwrap();
+            if users
+                .iter()
+                .any(|u| u.username.eq_ignore_ascii_case(&user.username))
+            {
+                return Err(UserStoreError::UsernameTaken(format!(
+                    "Username '{}' is already taken",
+                    user.username
+                )));
+            }
+            users.push(user.clone());
+            users.clone()
+        };
+        storage::write_json(&self.users_path, &users).await?;
+
+        Ok(user.redacted())
+    }
+
+    pub async fn update<F>(&self, id: &Uuid, f: F) -> Result<User, UserStoreError>
+    where
+        F: FnOnce(&mut User),
+    {
+        let _guard = self.users_persist_lock.lock().await;
+        let (user, users) = {
+            let mut users = self.users.lock().unwrap();
+            let user = users
+                .iter_mut()
+                .find(|user| user.id == *id)
+                .ok_or_else(|| {
+                    UserStoreError::UserNotFound(format!("User {} does not exist", id))
+                })?;
+            f(user);
+            (user.clone(), users.clone())
+        };
+        storage::write_json(&self.users_path, &users).await?;
+
+        Ok(user.redacted())
+    }
+
+    pub async fn delete(&self, id: &Uuid) -> Result<(), UserStoreError> {
+        let guard = self.users_persist_lock.lock().await;
+        let users = {
+            let mut users = self.users.lock().unwrap();
+            let count = users.len();
+            users.retain(|user| user.id != *id);
+            if users.len() == count {
+                return Err(UserStoreError::UserNotFound(format!(
+                    "User {} does not exist",
+                    id
+                )));
+            }
+            users.clone()
+        };
+        storage::write_json(&self.users_path, &users).await?;
+        drop(guard);
+
+        self.end_sessions(|session| session.user_id == *id).await;
+        Ok(())
+    }
+
+    /// Opens a session and returns its token, to be sent back as a cookie.
+    pub async fn create_session(&self, user_id: Uuid) -> io::Result<String> {
+        let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+        let _guard = self.sessions_persist_lock.lock().await;
+        let sessions = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|session| session.expires_at > Utc::now());
+            sessions.push(Session {
+                hash: hash_token(&token),
+                user_id,
+                expires_at: Utc::now() + Duration::days(SESSION_LIFETIME_DAYS),
+            });
+            sessions.clone()
+        };
+        storage::write_json(&self.sessions_path, &sessions).await?;
+
+        Ok(token)
+    }
+
+    /// User owning a valid session token.
+    pub fn session_user(&self, token: &str) -> Option<User> {
+        let hash = hash_token(token);
+        let user_id = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|session| session.hash == hash && session.expires_at > Utc::now())
+            .map(|session| session.user_id)?;
+
+        self.get(&user_id)
+    }
+
+    pub async fn end_session(&self, token: &str) {
+        let hash = hash_token(token);
+        self.end_sessions(|session| session.hash == hash).await;
+    }
+
+    async fn end_sessions<F>(&self, matches: F)
+    where
+        F: Fn(&Session) -> bool,
+    {
+        let _guard = self.sessions_persist_lock.lock().await;
+        let sessions = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|session| !matches(session));
+            sessions.clone()
+        };
+        if let Err(e) = storage::write_json(&self.sessions_path, &sessions).await {
+            log::warn!("Failed to save sessions: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dummy_hash_costs_as_much_as_a_real_one() {
+        let real = hash_password("correct horse battery staple").unwrap();
+        let real = PasswordHash::new(&real).unwrap();
+        let dummy = PasswordHash::new(DUMMY_PASSWORD_HASH).unwrap();
+
+        assert_eq!(dummy.algorithm, real.algorithm);
+        assert_eq!(dummy.version, real.version);
+        assert_eq!(dummy.params, real.params);
+        assert!(!verify_password("", DUMMY_PASSWORD_HASH));
+    }
+}
@@ -0,0 +1,198 @@
+use std::future::Future;
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncReadExt;
+use tokio::process::{ChildStdin, ChildStdout, Command};
+
+// Runs tar next to the volume, its archive goes through stdin and stdout so
+// mc_stack's own files need not be visible to Docker
+const HELPER_IMAGE: &str = "alpine:3";
+
+async fn docker(args: &[&str]) -> io::Result<std::process::Output> {
+    let output = Command::new("docker").args(args).output().await?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(output)
+}
+
+pub async fn exists(volume: &str) -> io::Result<bool> {
+    let output = Command::new("docker")
+        .args(["volume", "inspect", volume])
+        .output()
+        .await?;
+    Ok(output.status.success())
+}
+
+/// Removes the volume, doing nothing when it is already gone.
+pub async fn remove(volume: &str) -> io::Result<()> {
+    if !exists(volume).await? {
+        return Ok(());
+    }
+    docker(&["volume", "rm", volume]).await.map(|_| ())
+}
+
+/// Content of a file of the volume, `None` when it does not exist.
+pub async fn read_file(volume: &str, path: &str) -> io::Result<Option<Vec<u8>>> {
+    let mount = format!("{}:/data:ro", volume);
+    let file = format!("/data/{}", path.trim_start_matches('/'));
+    let output = Command::new("docker")
+        .args(["run", "--rm", "-v", &mount, HELPER_IMAGE, "cat", &file])
+        .output()
+        .await?;
+
+    if output.status.success() {
+        Ok(Some(output.stdout))
+    } else if String::from_utf8_lossy(&output.stderr).contains("No such file") {
+        Ok(None)
+    } else {
+        Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+/// Writes the content of the volume to a gzipped tarball.
+pub async fn export(volume: &str, archive: &Path) -> io::Result<()> {
+    let tmp_path = archive.with_extension("tmp");
+    let file = std::fs::File::create(&tmp_path)?;
+
+    let mount = format!("{}:/data:ro", volume);
+    let output = Command::new("docker")
+        .args(["run", "--rm", "-v", &mount, HELPER_IMAGE])
+        .args(["tar", "czf", "-", "-C", "/data", "."])
+        .stdout(Stdio::from(file))
+        .stderr(Stdio::piped())
+        // `output()` would capture stdout instead of writing the file
+        .spawn()?
+        .wait_with_output()
+        .await?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(io::Error::other(format!(
+            "Failed to archive volume {}: {}",
+            volume,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    std::fs::rename(&tmp_path, archive)
+}
+
+/// Passes an uncompressed tarball of the volume to `read` as tar writes it.
+pub async fn export_tar<T, F, Fut>(volume: &str, read: F) -> io::Result<T>
+where
+    F: FnOnce(ChildStdout) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mount = format!("{}:/data:ro", volume);
+    let mut child = Command::new("docker")
+        .args(["run", "--rm", "-v", &mount, HELPER_IMAGE])
+        .args(["tar", "cf", "-", "-C", "/data", "."])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read alongside, tar would block on a full stderr pipe
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let errors = tokio::spawn(async move {
+        let mut errors = String::new();
+        let _ = stderr.read_to_string(&mut errors).await;
+        errors
+    });
+    let result = read(child.stdout.take().expect("stdout is piped")).await;
+
+    // A reader giving up makes tar fail too, the reader's error says more
+    let status = child.wait().await?;
+    let value = result?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Failed to archive volume {}: {}",
+            volume,
+            errors.await.unwrap_or_default().trim()
+        )));
+    }
+    Ok(value)
+}
+
+/// Creates the volume and extracts a tarball written by `export` into it.
+///
+/// The volume is removed again when extraction fails, so it is never left half filled.
+pub async fn import(volume: &str, archive: &Path) -> io::Result<()> {
+    let file = std::fs::File::open(archive)?;
+    docker(&["volume", "create", volume]).await?;
+
+    let mount = format!("{}:/data", volume);
+    let child = Command::new("docker")
+        .args(["run", "--rm", "-i", "-v", &mount, HELPER_IMAGE])
+        .args(["tar", "xzf", "-", "-C", "/data"])
+        .stdin(Stdio::from(file))
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn();
+    let output = match child {
+        Ok(child) => child.wait_with_output().await,
+        Err(e) => Err(e),
+    };
+
+    let error = match output {
+        Ok(output) if output.status.success() => return Ok(()),
+        Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        Err(e) => e.to_string(),
+    };
+    Err(remove_failed_import(volume, error).await)
+}
+
+/// Creates the volume and extracts the uncompressed tarball `write` gives tar into it.
+///
+/// The volume is removed again when extraction fails, as with `import`.
+pub async fn import_tar<F, Fut>(volume: &str, write: F) -> io::Result<()>
+where
+    F: FnOnce(ChildStdin) -> Fut,
+    Fut: Future<Output = io::Result<()>>,
+{
+    docker(&["volume", "create", volume]).await?;
+
+    let mount = format!("{}:/data", volume);
+    let extracted = async {
+        let mut child = Command::new("docker")
+            .args(["run", "--rm", "-i", "-v", &mount, HELPER_IMAGE])
+            .args(["tar", "xf", "-", "-C", "/data"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let written = write(child.stdin.take().expect("stdin is piped")).await;
+
+        let output = child.wait_with_output().await?;
+        if !output.status.success() {
+            return Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        written
+    }
+    .await;
+
+    match extracted {
+        Ok(()) => Ok(()),
+        Err(e) => Err(remove_failed_import(volume, e.to_string()).await),
+    }
+}
+
+async fn remove_failed_import(volume: &str, error: String) -> io::Error {
+    if let Err(e) = remove(volume).await {
+        log::warn!(
+            "Failed to remove volume {} after a failed import: {}",
+            volume,
+            e
+        );
+    }
+    io::Error::other(format!(
+        "Failed to extract into volume {}: {}",
+        volume, error
+    ))
+}
@@ -0,0 +1,309 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use uuid::Uuid;
+
+use crate::config::{WanConfig, WanMethod};
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// Failed lookups are retried sooner than the TTL
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+// Never contacted, only used to pick the outgoing interface
+const ROUTE_PROBE_V4: &str = "8.8.8.8:53";
+const ROUTE_PROBE_V6: &str = "[2001:4860:4860::8888]:53";
+
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// Public address of the host, as given to players.
+#[derive(Debug, Clone)]
+pub struct WanSnapshot {
+    /// `None` until the first lookup succeeded
+    pub ip: Option<IpAddr>,
+    pub hostname: Option<String>,
+}
+
+/// Resolves the host's public address in the background and serves it from memory.
+pub struct WanAddress {
+    config: WanConfig,
+    // Refreshed every TTL, kept when a lookup fails
+    cached: RwLock<Option<IpAddr>>,
+    client: reqwest::Client,
+}
+
+impl WanAddress {
+    pub fn start(config: WanConfig) -> io::Result<Arc<Self>> {
+        let client = reqwest::Client::builder()
+            .timeout(PROBE_TIMEOUT)
+            .user_agent(concat!("mc_stack/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(io::Error::other)?;
+
+        let wan = Arc::new(Self {
+            cached: RwLock::new(config.address),
+            config,
+            client,
+        });
+
+        if wan.config.address.is_none() && !wan.config.methods.is_empty() {
+            let refresher = wan.clone();
+            tokio::spawn(async move {
+                loop {
+                    let delay = match refresher.refresh().await {
+                        Ok(()) => refresher.config.ttl,
+                        Err(e) => {
+                            log::warn!("Failed to detect the public address: {}", e);
+                            RETRY_DELAY.min(refresher.config.ttl)
+                        }
+                    };
+                    tokio::time::sleep(delay).await;
+                }
+            });
+        }
+
+        Ok(wan)
+    }
+
+    /// The cached address, without any lookup.
+    pub fn current(&self) -> WanSnapshot {
+        WanSnapshot {
+            ip: *self.cached.read().unwrap(),
+            hostname: self.config.hostname.clone(),
+        }
+    }
+
+    async fn refresh(&self) -> io::Result<()> {
+        let mut errors = Vec::new();
+        for method in &self.config.methods {
+            let result = match method {
+                WanMethod::Interface => self.probe_interface().await,
+                WanMethod::Stun => self.probe_stun().await,
+                WanMethod::Http => self.probe_http().await,
+            };
+
+            match result {
+                Ok(ip) => {
+                    let previous = self.cached.write().unwrap().replace(ip);
+                    if previous != Some(ip) {
+                        log::info!("Public address is {} ({:?})", ip, method);
+                    }
+                    return Ok(());
+                }
+                Err(e) => errors.push(format!("{:?}: {}", method, e)),
+            }
+        }
+
+        Err(io::Error::other(errors.join(", ")))
+    }
+
+    fn check_family(&self, ip: IpAddr) -> io::Result<IpAddr> {
+        if ip.is_ipv6() != self.config.ipv6 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not of the wanted address family", ip),
+            ));
+        }
+        Ok(ip)
+    }
+
+    async fn bind(&self) -> io::Result<UdpSocket> {
+        if self.config.ipv6 {
+            UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await
+        } else {
+            UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await
+        }
+    }
+
+    // Connecting a UDP socket sends nothing, it only selects the route
+    async fn probe_interface(&self) -> io::Result<IpAddr> {
+        let socket = self.bind().await?;
+        socket
+            .connect(if self.config.ipv6 {
+                ROUTE_PROBE_V6
+            } else {
+                ROUTE_PROBE_V4
+            })
+            .await?;
+
+        let ip = socket.local_addr()?.ip();
+        if !is_public(ip) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("the outgoing interface has the private address {}", ip),
+            ));
+        }
+        Ok(ip)
+    }
+
+    async fn probe_stun(&self) -> io::Result<IpAddr> {
+        let server = tokio::net::lookup_host(&self.config.stun_server)
+            .await?
+            .find(|addr| addr.is_ipv6() == self.config.ipv6)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "{} has no address of the wanted family",
+                        self.config.stun_server
+                    ),
+                )
+            })?;
+
+        let socket = self.bind().await?;
+        socket.connect(server).await?;
+
+        let transaction_id: [u8; 12] = Uuid::new_v4().as_bytes()[..12].try_into().unwrap();
+        let mut request = Vec::with_capacity(20);
+        request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+        request.extend_from_slice(&0u16.to_be_bytes());
+        request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        request.extend_from_slice(&transaction_id);
+
+        let mut response = [0u8; 512];
+        // UDP may drop either datagram, ask a few times
+        for _ in 0..3 {
+            socket.send(&request).await?;
+            match tokio::time::timeout(Duration::from_secs(1), socket.recv(&mut response)).await {
+                Ok(received) => {
+                    let len = received?;
+                    return parse_stun_response(&response[..len], &transaction_id)
+                        .and_then(|addr| self.check_family(addr.ip()));
+                }
+                Err(_) => continue,
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("{} did not answer", self.config.stun_server),
+        ))
+    }
+
+    async fn probe_http(&self) -> io::Result<IpAddr> {
+        let body = self
+            .client
+            .get(&self.config.http_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io::Error::other)?
+            .text()
+            .await
+            .map_err(io::Error::other)?;
+
+        let ip: IpAddr = body.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} did not answer with an address", self.config.http_url),
+            )
+        })?;
+        self.check_family(ip)
+    }
+}
+
+/// Whether the address is reachable from the internet.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                // Unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // Link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+                // Documentation, 2001:db8::/32
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+fn parse_stun_response(response: &[u8], transaction_id: &[u8; 12]) -> io::Result<SocketAddr> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    if response.len() < 20
+        || u16::from_be_bytes([response[0], response[1]]) != STUN_BINDING_SUCCESS
+        || response[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || response[8..20] != transaction_id[..]
+    {
+        return Err(invalid("unexpected STUN response"));
+    }
+
+    let length = usize::from(u16::from_be_bytes([response[2], response[3]]));
+    let attributes = response
+        .get(20..20 + length)
+        .ok_or_else(|| invalid("truncated STUN response"))?;
+
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let kind = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let len = usize::from(u16::from_be_bytes([
+            attributes[offset + 2],
+            attributes[offset + 3],
+        ]));
+        let value = attributes
+            .get(offset + 4..offset + 4 + len)
+            .ok_or_else(|| invalid("truncated STUN attribute"))?;
+
+        match kind {
+            STUN_XOR_MAPPED_ADDRESS => {
+                return stun_address(value, Some(transaction_id))
+                    .ok_or_else(|| invalid("invalid XOR-MAPPED-ADDRESS"));
+            }
+            STUN_MAPPED_ADDRESS => mapped = stun_address(value, None),
+            _ => {}
+        }
+        // Attributes are padded to 4 bytes
+        offset += 4 + len.div_ceil(4) * 4;
+    }
+
+    mapped.ok_or_else(|| invalid("no mapped address in STUN response"))
+}
+
+// Decodes a (XOR-)MAPPED-ADDRESS value, XORed when the transaction id is given
+fn stun_address(value: &[u8], transaction_id: Option<&[u8; 12]>) -> Option<SocketAddr> {
+    let mut key = [0u8; 16];
+    key[..4].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    if let Some(transaction_id) = transaction_id {
+        key[4..].copy_from_slice(transaction_id);
+    }
+    let xor = |bytes: &[u8]| -> Vec<u8> {
+        if transaction_id.is_none() {
+            return bytes.to_vec();
+        }
+        bytes.iter().zip(key.iter()).map(|(b, k)| b ^ k).collect()
+    };
+
+    let port = xor(value.get(2..4)?);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+    let ip = match value.get(1)? {
+        1 => {
+            let octets: [u8; 4] = xor(value.get(4..8)?).try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        2 => {
+            let octets: [u8; 16] = xor(value.get(4..20)?).try_into().ok()?;
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
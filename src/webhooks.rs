@@ -0,0 +1,529 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::events::EventBus;
+use crate::storage;
+
+const MAX_ATTEMPTS: usize = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Deliveries kept in the log across all webhooks
+const MAX_DELIVERY_HISTORY: usize = 500;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub url: String,
+    /// Event types delivered to this webhook, all of them when empty
+    pub events: Vec<String>,
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Webhook {
+    fn accepts(&self, event_type: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event_type)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DeliveryAttempt {
+    pub timestamp: DateTime<Utc>,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Delivery {
+    pub id: Uuid,
+    pub webhook_id: Uuid,
+    pub event_id: String,
+    pub event_type: String,
+    pub state: DeliveryState,
+    pub attempts: Vec<DeliveryAttempt>,
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Delivers events to the registered webhooks, signed with each webhook's secret.
+///
+/// Receivers can check `X-Mc-Stack-Signature`, the hex HMAC-SHA256 of
+/// `<X-Mc-Stack-Timestamp>.<body>` keyed with the secret.
+pub struct WebhookManager {
+    webhooks_path: PathBuf,
+    deliveries_path: PathBuf,
+    webhooks: Mutex<Vec<Webhook>>,
+    deliveries: Mutex<VecDeque<Delivery>>,
+    // Held from taking a snapshot of the webhooks or the delivery log until it is written,
+    // so concurrent writes land in order
+    persist_lock: tokio::sync::Mutex<()>,
+    client: reqwest::Client,
+}
+
+impl WebhookManager {
+    pub fn load() -> io::Result<Arc<Self>> {
+        let data_dir = storage::data_directory()?;
+        let webhooks_path = data_dir.join("webhooks.json");
+        let deliveries_path = data_dir.join("webhook_deliveries.json");
+
+        let webhooks: Vec<Webhook> = storage::read_json(&webhooks_path)?.unwrap_or_default();
+        let mut deliveries: VecDeque<Delivery> =
+            storage::read_json(&deliveries_path)?.unwrap_or_default();
+
+        // Retries do not survive a restart
+        for delivery in deliveries.iter_mut() {
+            if delivery.state == DeliveryState::Pending {
+                delivery.state = DeliveryState::Failed;
+                delivery.next_attempt_at = None;
+            }
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(concat!("mc_stack/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(io::Error::other)?;
+
+        Ok(Arc::new(Self {
+            webhooks_path,
+            deliveries_path,
+            webhooks: Mutex::new(webhooks),
+            deliveries: Mutex::new(deliveries),
+            persist_lock: tokio::sync::Mutex::new(()),
+            client,
+        }))
+    }
+
+    /// Forwards every event published on the bus to the matching webhooks.
+    pub fn start(self: &Arc<Self>, events: &EventBus) {
+        let mut receiver = events.subscribe();
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Webhooks missed {} events", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+
+                let event_type = event.payload.name();
+                let targets: Vec<Webhook> = manager
+                    .webhooks
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|webhook| webhook.accepts(event_type))
+                    .cloned()
+                    .collect();
+
+                let payload = match serde_json::to_value(&event) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        log::error!("Failed to serialize event {}: {}", event.id, e);
+                        continue;
+                    }
+                };
+
+                for webhook in targets {
+                    manager
+                        .dispatch(webhook, &event.id, event_type, payload.clone())
+                        .await;
+                }
+            }
+        });
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.webhooks.lock().unwrap().clone()
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<Webhook> {
+        self.webhooks
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|webhook| webhook.id == *id)
+            .cloned()
+    }
+
+    pub async fn create(
+        &self,
+        url: String,
+        events: Vec<String>,
+        secret: Option<String>,
+    ) -> io::Result<Webhook> {
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            url,
+            events,
+            secret: secret.unwrap_or_else(|| {
+                format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+            }),
+            created_at: Utc::now(),
+        };
+
+        let _guard = self.persist_lock.lock().await;
+        let webhooks = {
+            let mut webhooks = self.webhooks.lock().unwrap();
+            webhooks.push(webhook.clone());
+            webhooks.clone()
+        };
+        storage::write_json(&self.webhooks_path, &webhooks).await?;
+
+        Ok(webhook)
+    }
+
+    /// Returns false when the webhook does not exist.
+    pub async fn delete(&self, id: &Uuid) -> io::Result<bool> {
+        let _guard = self.persist_lock.lock().await;
+        let webhooks = {
+            let mut webhooks = self.webhooks.lock().unwrap();
+            let count = webhooks.len();
+            webhooks.retain(|webhook| webhook.id != *id);
+            if webhooks.len() == count {
+                return Ok(false);
+            }
+            webhooks.clone()
+        };
+        storage::write_json(&self.webhooks_path, &webhooks).await?;
+
+        Ok(true)
+    }
+
+    /// Delivery log of a webhook, most recent first.
+    pub fn deliveries(&self, webhook_id: &Uuid) -> Vec<Delivery> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|delivery| delivery.webhook_id == *webhook_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Sends a `ping` event to the webhook, bypassing its event filter.
+    pub async fn test(self: &Arc<Self>, webhook: Webhook) -> Delivery {
+        let event_id = format!("ping-{}", Uuid::new_v4().simple());
+        let payload = json!({
+            "id": event_id,
+            "timestamp": Utc::now(),
+            "type": "ping",
+            "webhook_id": webhook.id
+        });
+
+        self.dispatch(webhook, &event_id, "ping", payload).await
+    }
+
+    async fn dispatch(
+        self: &Arc<Self>,
+        webhook: Webhook,
+        event_id: &str,
+        event_type: &str,
+        payload: Value,
+    ) -> Delivery {
+        let delivery = Delivery {
+            id: Uuid::new_v4(),
+            webhook_id: webhook.id,
+            event_id: event_id.to_string(),
+            event_type: event_type.to_string(),
+            state: DeliveryState::Pending,
+            attempts: Vec::new(),
+            next_attempt_at: Some(Utc::now()),
+            created_at: Utc::now(),
+        };
+
+        {
+            let mut deliveries = self.deliveries.lock().unwrap();
+            if deliveries.len() == MAX_DELIVERY_HISTORY {
+                deliveries.pop_front();
+            }
+            deliveries.push_back(delivery.clone());
+        }
+        self.persist_deliveries().await;
+
+        let manager = self.clone();
+        let delivery_id = delivery.id;
+        tokio::spawn(async move {
+            manager.deliver(webhook, delivery_id, payload).await;
+        });
+
+        delivery
+    }
+
+    async fn deliver(&self, webhook: Webhook, delivery_id: Uuid, payload: Value) {
+        let body = payload.to_string();
+        let event_type = payload["type"].as_str().unwrap_or_default().to_string();
+        let mut backoff = INITIAL_BACKOFF;
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let timestamp = Utc::now().timestamp().to_string();
+            let signature = sign(&webhook.secret, &timestamp, &body);
+
+            let result = self
+                .client
+                .post(&webhook.url)
+                .header("Content-Type", "application/json")
+                .header("X-Mc-Stack-Event", &event_type)
+                .header("X-Mc-Stack-Delivery", delivery_id.to_string())
+                .header("X-Mc-Stack-Timestamp", &timestamp)
+                .header("X-Mc-Stack-Signature", format!("sha256={}", signature))
+                .body(body.clone())
+                .send()
+                .await;
+
+            let (status_code, error) = match result {
+                Ok(response) if response.status().is_success() => {
+                    (Some(response.status().as_u16()), None)
+                }
+                Ok(response) => (
+                    Some(response.status().as_u16()),
+                    Some(format!("Receiver answered {}", response.status())),
+                ),
+                Err(e) => (None, Some(e.to_string())),
+            };
+
+            let succeeded = error.is_none();
+            let retry = !succeeded && attempt < MAX_ATTEMPTS;
+
+            self.record_attempt(
+                &delivery_id,
+                DeliveryAttempt {
+                    timestamp: Utc::now(),
+                    status_code,
+                    error,
+                },
+                match (succeeded, retry) {
+                    (true, _) => DeliveryState::Succeeded,
+                    (false, true) => DeliveryState::Pending,
+                    (false, false) => DeliveryState::Failed,
+                },
+                retry.then(|| Utc::now() + backoff),
+            )
+            .await;
+
+            if !retry {
+                return;
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    async fn record_attempt(
+        &self,
+        delivery_id: &Uuid,
+        attempt: DeliveryAttempt,
+        state: DeliveryState,
+        next_attempt_at: Option<DateTime<Utc>>,
+    ) {
+        {
+            let mut deliveries = self.deliveries.lock().unwrap();
+            if let Some(delivery) = deliveries.iter_mut().find(|d| d.id == *delivery_id) {
+                delivery.attempts.push(attempt);
+                delivery.state = state;
+                delivery.next_attempt_at = next_attempt_at;
+            }
+        }
+        self.persist_deliveries().await;
+    }
+
+    async fn persist_deliveries(&self) {
+        let _guard = self.persist_lock.lock().await;
+        let deliveries = self.deliveries.lock().unwrap().clone();
+        if let Err(e) = storage::write_json(&self.deliveries_path, &deliveries).await {
+            log::error!("Failed to persist webhook deliveries: {}", e);
+        }
+    }
+}
+
+fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{header::HeaderMap, StatusCode};
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    fn manager() -> Arc<WebhookManager> {
+        let dir = std::env::temp_dir().join(format!("mc_stack-webhooks-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Arc::new(WebhookManager {
+            webhooks_path: dir.join("webhooks.json"),
+            deliveries_path: dir.join("webhook_deliveries.json"),
+            webhooks: Mutex::new(Vec::new()),
+            deliveries: Mutex::new(VecDeque::new()),
+            persist_lock: tokio::sync::Mutex::new(()),
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap(),
+        })
+    }
+
+    /// Answers the queued statuses in turn, then 200, and keeps what it received.
+    #[derive(Default)]
+    struct Receiver {
+        statuses: Mutex<VecDeque<u16>>,
+        requests: Mutex<Vec<(HeaderMap, String)>>,
+    }
+
+    async fn receive(
+        req: HttpRequest,
+        body: String,
+        receiver: web::Data<Receiver>,
+    ) -> HttpResponse {
+        receiver
+            .requests
+            .lock()
+            .unwrap()
+            .push((req.headers().clone(), body));
+        let status = receiver.statuses.lock().unwrap().pop_front().unwrap_or(200);
+        HttpResponse::new(StatusCode::from_u16(status).unwrap())
+    }
+
+    fn serve(statuses: &[u16]) -> (String, web::Data<Receiver>) {
+        let receiver = web::Data::new(Receiver::default());
+        receiver.statuses.lock().unwrap().extend(statuses);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let data = receiver.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/hook", web::post().to(receive))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_web::rt::spawn(server);
+
+        (url, receiver)
+    }
+
+    async fn settled(manager: &WebhookManager, webhook_id: &Uuid) -> Delivery {
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(20);
+        loop {
+            let delivery = manager.deliveries(webhook_id).remove(0);
+            if delivery.state != DeliveryState::Pending {
+                return delivery;
+            }
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "delivery never settled"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    // As a receiver would, without the sender's code
+    fn verifies(secret: &str, headers: &HeaderMap, body: &str) -> bool {
+        let signature = header(headers, "X-Mc-Stack-Signature");
+        let Some(signature) = signature
+            .strip_prefix("sha256=")
+            .and_then(|hex| hex::decode(hex).ok())
+        else {
+            return false;
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(format!("{}.{}", header(headers, "X-Mc-Stack-Timestamp"), body).as_bytes());
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        assert_eq!(
+            sign("It is a secret", "1700000000", r#"{"type":"ping"}"#),
+            "f280960f4b954162f63ea50030a68b18f6fb9624aaaed2757a6da9805d65e600"
+        );
+    }
+
+    #[actix_web::test]
+    async fn retries_after_a_server_error() {
+        let (url, receiver) = serve(&[503]);
+        let manager = manager();
+        let webhook = manager
+            .create(url, Vec::new(), Some("It is a secret".to_string()))
+            .await
+            .unwrap();
+
+        let pending = manager.test(webhook.clone()).await;
+        assert_eq!(pending.state, DeliveryState::Pending);
+
+        let delivery = settled(&manager, &webhook.id).await;
+        assert_eq!(delivery.id, pending.id);
+        assert_eq!(delivery.state, DeliveryState::Succeeded);
+        assert_eq!(delivery.next_attempt_at, None);
+        let statuses: Vec<_> = delivery.attempts.iter().map(|a| a.status_code).collect();
+        assert_eq!(statuses, [Some(503), Some(200)]);
+        assert!(delivery.attempts[0].error.is_some());
+        assert!(delivery.attempts[1].error.is_none());
+        // After the initial backoff
+        assert!(
+            delivery.attempts[1].timestamp - delivery.attempts[0].timestamp
+                >= chrono::Duration::from_std(INITIAL_BACKOFF).unwrap()
+        );
+
+        let requests = receiver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        for (headers, body) in requests.iter() {
+            assert_eq!(header(headers, "X-Mc-Stack-Event"), "ping");
+            assert_eq!(
+                header(headers, "X-Mc-Stack-Delivery"),
+                pending.id.to_string()
+            );
+            assert_eq!(header(headers, "Content-Type"), "application/json");
+            assert!(verifies(&webhook.secret, headers, body));
+            assert!(!verifies("Another secret", headers, body));
+            assert!(!verifies(
+                &webhook.secret,
+                headers,
+                &body.replace("ping", "pong")
+            ));
+
+            let payload: Value = serde_json::from_str(body).unwrap();
+            assert_eq!(payload["type"], "ping");
+            assert_eq!(payload["id"], pending.event_id.as_str());
+            assert_eq!(payload["webhook_id"], webhook.id.to_string());
+        }
+        // The same event on every attempt
+        assert_eq!(requests[0].1, requests[1].1);
+    }
+}
@@ -1,5 +1,6 @@
 use actix_web::{web, HttpResponse, Result};
 use rust_embed::RustEmbed;
+use utoipa_swagger_ui::{Config, SwaggerUi};
 
 #[derive(RustEmbed)]
 #[folder = "website/"]
@@ -32,5 +33,13 @@ async fn serve_file(path: web::Path<String>) -> Result<HttpResponse> {
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
+    // API explorer, reading the documents served by the API itself
+    cfg.service(
+        SwaggerUi::new("/api/docs/{_:.*}").config(
+            Config::new(["/api/v2/openapi.json", "/api/v1/openapi.json"])
+                .persist_authorization(true)
+                .with_credentials(true),
+        ),
+    );
     cfg.route("/{filename:.*}", web::get().to(serve_file));
 }
@@ -1,11 +1,21 @@
 // Constants for configuration
 const CONFIG = {
     AUTO_HIDE_DELAY: 5000,
+    JOB_POLL_INTERVAL: 1000,
+    REFRESH_DEBOUNCE: 500,
+    // Events that change what the server list shows
+    LIST_EVENTS: ['stack_created', 'stack_deleted', 'state_changed', 'crashed'],
     ENDPOINTS: {
         CREATE: '/api/v1/stacks',
         LIST: '/api/v1/stacks',
         STACK: (id) => `/api/v1/stacks/${id}`,
         STACK_STATUS: (id) => `/api/v1/stacks/${id}/status`,
+        JOB: (id) => `/api/v1/jobs/${id}`,
+        EVENTS: '/api/v1/events',
+        ACTIVE_JOBS: '/api/v1/jobs?state=active',
+        LOGIN: '/api/v1/auth/login',
+        LOGOUT: '/api/v1/auth/logout',
+        ME: '/api/v1/auth/me',
     },
     STATUS_CLASSES: {
         success: 'bg-green-50 text-green-700',
@@ -13,6 +23,76 @@ const CONFIG = {
     }
 };
 
+// The session cookie authenticates every request, an expired session brings back the login form
+async function apiFetch(url, options = {}) {
+    const response = await fetch(url, { credentials: 'same-origin', ...options });
+    if (response.status === 401) {
+        showLogin();
+    }
+    return response;
+}
+
+const showLogin = () => {
+    document.getElementById('mainPanel').classList.add('hidden');
+    document.getElementById('loginPanel').classList.remove('hidden');
+};
+
+const showMain = (me) => {
+    const quota = me.user ? ` (${me.usage.stacks}/${me.user.quota.max_stacks} stacks, ${me.usage.memory_mb}/${me.user.quota.max_memory_mb} MB)` : '';
+    document.getElementById('currentUser').textContent = `Signed in as ${me.name}${quota}`;
+    document.getElementById('loginPanel').classList.add('hidden');
+    document.getElementById('mainPanel').classList.remove('hidden');
+};
+
+// Loads the signed-in account, returns false when there is no session
+async function loadCurrentUser() {
+    const response = await apiFetch(CONFIG.ENDPOINTS.ME);
+    if (!response.ok) return false;
+    showMain(await response.json());
+    return true;
+}
+
+async function login(event) {
+    event.preventDefault();
+    const button = event.target.querySelector('button[type="submit"]');
+    const errorElement = document.getElementById('loginError');
+    setButtonState(button, true);
+    errorElement.classList.add('hidden');
+
+    try {
+        const response = await fetch(CONFIG.ENDPOINTS.LOGIN, {
+            method: 'POST',
+            headers: { 'Content-Type': 'application/json' },
+            body: JSON.stringify({
+                username: document.getElementById('loginUsername').value,
+                password: document.getElementById('loginPassword').value
+            })
+        });
+
+        if (!response.ok) {
+            const data = await response.json().catch(() => null);
+            errorElement.textContent = data?.message || 'Sign in failed';
+            errorElement.classList.remove('hidden');
+            return;
+        }
+
+        document.getElementById('loginPassword').value = '';
+        start();
+    } catch (error) {
+        errorElement.textContent = 'An error occurred while signing in';
+        errorElement.classList.remove('hidden');
+    } finally {
+        setButtonState(button, false);
+    }
+}
+
+async function logout() {
+    await fetch(CONFIG.ENDPOINTS.LOGOUT, { method: 'POST', credentials: 'same-origin' });
+    if (eventSource) eventSource.close();
+    eventSource = null;
+    showLogin();
+}
+
 // Show status message
 const showStatus = (message, type = 'success') => {
     const statusElement = document.getElementById('statusMessage');
@@ -39,18 +119,21 @@ const setButtonState = (button, isLoading) => {
 const createServerCard = (stack) => {
     const mcStatus = stack.services.minecraft_server;
     const sftpStatus = stack.services.sftp_server;
+    // Buttons are only shown to the roles allowed to use them
+    const canOperate = stack.role === 'operator' || stack.role === 'admin';
+    const canAdminister = stack.role === 'admin';
     
     return `
         <div class="bg-gray-50 rounded-lg p-4 flex flex-col sm:flex-row justify-between items-start sm:items-center gap-4">
             <div class="space-y-2">
-                <div class="font-semibold text-gray-800">Stack ${stack.stack_id} <span class="text-gray-500 text-sm ml-2">IP: ${stack.wan_ip || 'Not available'}</span></div>
+                <div class="font-semibold text-gray-800">Stack ${stack.stack_id} <span class="text-gray-500 text-sm ml-2">IP: ${stack.wan_ip || 'Not available'}</span> <span class="text-gray-500 text-sm ml-2">${stack.role}</span></div>
                 <div class="text-sm text-gray-600">
                     <div>Minecraft Server: ${mcStatus.status} ${mcStatus.port ? `(Port: ${mcStatus.port})` : ''}</div>
                     <div>SFTP Server: ${sftpStatus.status} ${sftpStatus.port ? `(Port: ${sftpStatus.port})` : ''}</div>
                 </div>
             </div>
             <div class="flex gap-2 w-full sm:w-auto">
-                ${mcStatus.status === 'stopped' ? `
+                ${canOperate ? (mcStatus.status === 'stopped' ? `
                     <button
                         onclick="updateStackStatus(${stack.stack_id}, 'running')"
                         class="btn-action bg-blue-500 hover:bg-blue-600 text-white font-semibold py-2 px-4 rounded-lg transition duration-200 flex-1 sm:flex-none"
@@ -66,14 +149,16 @@ const createServerCard = (stack) => {
                         <span class="btn-text">Stop</span>
                         <div class="spinner hidden"></div>
                     </button>
-                `}
-                <button
-                    onclick="deleteStack(${stack.stack_id})"
-                    class="btn-action bg-red-500 hover:bg-red-600 text-white font-semibold py-2 px-4 rounded-lg transition duration-200 flex-1 sm:flex-none"
-                >
-                    <span class="btn-text">Delete</span>
-                    <div class="spinner hidden"></div>
-                </button>
+                `) : ''}
+                ${canAdminister ? `
+                    <button
+                        onclick="deleteStack(${stack.stack_id})"
+                        class="btn-action bg-red-500 hover:bg-red-600 text-white font-semibold py-2 px-4 rounded-lg transition duration-200 flex-1 sm:flex-none"
+                    >
+                        <span class="btn-text">Delete</span>
+                        <div class="spinner hidden"></div>
+                    </button>
+                ` : ''}
             </div>
         </div>
     `;
@@ -89,6 +174,49 @@ const updateServerList = (stacks) => {
     serverList.innerHTML = stacks.map(stack => createServerCard(stack, stack.wan_ip)).join('');
 };
 
+// Describe a finished job for the status message
+const describeJob = (job) => {
+    if (job.state === 'failed') {
+        return job.error || 'Operation failed';
+    }
+    if (job.kind === 'create_stack' && job.result) {
+        return `Stack ${job.result.stack_id} created successfully`;
+    }
+    return 'Operation completed successfully';
+};
+
+// Poll a job until it finishes, then report its outcome
+async function waitForJob(jobId) {
+    while (true) {
+        const response = await apiFetch(CONFIG.ENDPOINTS.JOB(jobId));
+        if (!response.ok) {
+            showStatus('Lost track of the running operation', 'error');
+            return;
+        }
+
+        const job = await response.json();
+        if (job.state === 'succeeded' || job.state === 'failed') {
+            showStatus(describeJob(job), job.state === 'succeeded' ? 'success' : 'error');
+            refreshServerList();
+            return;
+        }
+
+        await new Promise(resolve => setTimeout(resolve, CONFIG.JOB_POLL_INTERVAL));
+    }
+}
+
+// Reconnect to operations started before the page was loaded
+async function resumeActiveJobs() {
+    try {
+        const response = await apiFetch(CONFIG.ENDPOINTS.ACTIVE_JOBS);
+        if (!response.ok) return;
+        const jobs = await response.json();
+        jobs.forEach(job => waitForJob(job.id));
+    } catch (error) {
+        // Nothing to resume
+    }
+}
+
 // Updated API request handler to handle new status codes and response structures
 async function executeRequest(url, method, buttonElement, body = null) {
     setButtonState(buttonElement, true);
@@ -104,7 +232,7 @@ async function executeRequest(url, method, buttonElement, body = null) {
             options.body = JSON.stringify(body);
         }
 
-        const response = await fetch(url, options);
+        const response = await apiFetch(url, options);
         
         // Handle 204 No Content responses
         if (response.status === 204) {
@@ -115,15 +243,18 @@ async function executeRequest(url, method, buttonElement, body = null) {
             return null;
         }
 
+        // Long-running operations are tracked through a job
+        if (response.status === 202) {
+            const data = await response.json();
+            await waitForJob(data.job_id);
+            return null;
+        }
+
         // For responses with content
         const isJson = response.headers.get('content-type')?.includes('application/json');
         const data = isJson ? await response.json() : null;
 
         if (response.ok) {
-            // For successful GET with content or POST with created resource
-            if (method === 'POST') {
-                showStatus(`Stack ${data.stack_id} created successfully`, 'success');
-            }
             if (method !== 'GET') {
                 refreshServerList();
             }
@@ -178,5 +309,26 @@ async function deleteStack(id) {
     );
 }
 
+// Refresh the list when the server reports a change, the browser handles reconnection
+let refreshTimer = null;
+let eventSource = null;
+const subscribeToEvents = () => {
+    if (!window.EventSource || eventSource) return;
+
+    eventSource = new EventSource(CONFIG.ENDPOINTS.EVENTS);
+    CONFIG.LIST_EVENTS.forEach(type => eventSource.addEventListener(type, () => {
+        clearTimeout(refreshTimer);
+        refreshTimer = setTimeout(refreshServerList, CONFIG.REFRESH_DEBOUNCE);
+    }));
+};
+
+// Load the dashboard once signed in
+async function start() {
+    if (!await loadCurrentUser()) return;
+    refreshServerList();
+    resumeActiveJobs();
+    subscribeToEvents();
+}
+
 // Initial load
-document.addEventListener('DOMContentLoaded', refreshServerList);
+document.addEventListener('DOMContentLoaded', start);